@@ -0,0 +1,156 @@
+// Alignment of a session's trajectory to the world frame of a reference
+// session of the same environment, so that the sessions can be used together,
+// eg for multi-session mapping. Each session has its own world frame, set by
+// its starting pose. The transform from the session's world frame to the
+// reference one is either given directly or fitted to landmarks whose
+// positions are known in both frames, eg loop-closure matches or markers.
+
+use crate::all::*;
+
+#[derive(Deserialize)]
+struct AlignmentLandmark {
+  // Position in the world frame of the session.
+  session: [f64; 3],
+  // Position in the world frame of the reference session.
+  reference: [f64; 3],
+}
+
+#[derive(Deserialize)]
+struct AlignmentLandmarksRoot {
+  landmarks: Vec<AlignmentLandmark>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SessionAlignment {
+  // Session world to reference world.
+  pub R: Matrix3d,
+  pub t: Vector3d,
+}
+
+impl SessionAlignment {
+  // Parses the transform as `tx ty tz qx qy qz qw`, the pose of the session
+  // world frame in the reference frame with the quaternion ordered like in
+  // the TUM format. The values may be separated by commas or whitespace.
+  pub fn parse(s: &str) -> Result<SessionAlignment> {
+    let v = parse_csv_line(s.trim())
+      .map_err(|(column, field)| anyhow!("Invalid number `{}` in column {} of the alignment transform.", field, column))?;
+    if v.len() != 7 { bail!("Expected 7 values in the alignment transform, found {}.", v.len()) }
+    let q = Vector4d::new(v[6], v[3], v[4], v[5]);
+    if q.norm() == 0. { bail!("Alignment transform has a zero quaternion.") }
+    Ok(SessionAlignment {
+      R: to_rotation_matrix(q.normalize()),
+      t: Vector3d::new(v[0], v[1], v[2]),
+    })
+  }
+
+  // Fits the transform to the landmarks listed in a JSON file as
+  //   { "landmarks": [{ "session": [x, y, z], "reference": [x, y, z] }, ...] }
+  pub fn load_landmarks(path: &Path) -> Result<SessionAlignment> {
+    let s = std::fs::read_to_string(path)
+      .context(format!("Failed to read file {}.", path.display()))?;
+    let root: AlignmentLandmarksRoot = serde_json::from_str(&s)
+      .context(format!("Failed to parse {}.", path.display()))?;
+    let pairs: Vec<(Vector3d, Vector3d)> = root.landmarks.iter()
+      .map(|l| (Vector3d::from(l.session), Vector3d::from(l.reference)))
+      .collect();
+    SessionAlignment::fit(&pairs)
+      .ok_or(anyhow!("Could not align to the landmarks of {}, at least 3 are needed.", path.display()))
+  }
+
+  // Least squares fit to `(session, reference)` position pairs. None if there
+  // are too few pairs.
+  pub fn fit(pairs: &[(Vector3d, Vector3d)]) -> Option<SessionAlignment> {
+    let (R, t) = align_rigid(pairs)?;
+    Some(SessionAlignment { R, t })
+  }
+
+  // The session trajectory in the reference world frame.
+  pub fn apply(&self, poses: &[TrajectoryPose]) -> Vec<TrajectoryPose> {
+    poses.iter().map(|pose| TrajectoryPose {
+      time: pose.time,
+      position: self.R * pose.position + self.t,
+      orientation: to_quaternion(self.R * to_rotation_matrix(pose.orientation)),
+    }).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::SeedableRng;
+
+  // Rotation about the z-axis, which together with a translation is how the
+  // world frames of VIO sessions differ.
+  fn yaw(angle: f64) -> Matrix3d {
+    to_rotation_matrix(Vector4d::new((0.5 * angle).cos(), 0., 0., (0.5 * angle).sin()))
+  }
+
+  #[test]
+  fn test_session_alignment() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    // A scene of landmarks and two sessions walking through it along
+    // different paths, with the true poses in the scene frame.
+    let landmarks: Vec<Vector3d> = (0..20)
+      .map(|_| Vector3d::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), rng.gen_range(0.0..3.0)))
+      .collect();
+    let walk = |offset: f64| -> Vec<TrajectoryPose> {
+      (0..50).map(|i| {
+        let s = 0.1 * i as f64 + offset;
+        let imu_to_world = yaw(s);
+        TrajectoryPose {
+          time: s,
+          position: Vector3d::new(2. * s.cos(), 2. * s.sin(), 1. + 0.1 * s),
+          orientation: to_quaternion(imu_to_world),
+        }
+      }).collect()
+    };
+    let truths = [walk(0.), walk(1.)];
+    // World frames of the sessions: `R * scene + t`.
+    let frames = [(yaw(0.3), Vector3d::new(-2., 0.1, -1.)), (yaw(-2.1), Vector3d::new(1., 4., -1.1))];
+    let to_session = |k: usize, poses: &[TrajectoryPose]| -> Vec<TrajectoryPose> {
+      let (R, t) = frames[k];
+      SessionAlignment { R, t }.apply(poses)
+    };
+    let sessions = [to_session(0, &truths[0]), to_session(1, &truths[1])];
+
+    // Both sessions see the landmarks in their own frames, with some error.
+    let pairs: Vec<(Vector3d, Vector3d)> = landmarks.iter().map(|l| {
+      let mut noise = || Vector3d::new(rng.gen(), rng.gen(), rng.gen()) * 0.01;
+      (frames[1].0 * l + frames[1].1 + noise(), frames[0].0 * l + frames[0].1 + noise())
+    }).collect();
+    let alignment = SessionAlignment::fit(&pairs).unwrap();
+    for (session, reference) in &pairs {
+      assert!((alignment.R * session + alignment.t - reference).norm() < 0.03);
+    }
+
+    // The second session co-registers with the first one.
+    let aligned = alignment.apply(&sessions[1]);
+    let expected = to_session(0, &truths[1]);
+    for (pose, expected) in aligned.iter().zip(&expected) {
+      assert_eq!(pose.time, expected.time);
+      assert!((pose.position - expected.position).norm() < 0.02);
+      assert!((pose.orientation - expected.orientation).norm() < 0.01
+        || (pose.orientation + expected.orientation).norm() < 0.01);
+    }
+    // Unaligned it is far off.
+    assert!((sessions[1][0].position - expected[0].position).norm() > 1.);
+
+    // The same from the known transform between the frames.
+    let (R0, t0) = frames[0];
+    let (R1, t1) = frames[1];
+    let R = R0 * R1.transpose();
+    let t = t0 - R * t1;
+    let q = to_quaternion(R);
+    let s = format!("{}, {}, {}, {}, {}, {}, {}", t[0], t[1], t[2], q[1], q[2], q[3], q[0]);
+    let alignment = SessionAlignment::parse(&s).unwrap();
+    let aligned = alignment.apply(&sessions[1]);
+    for (pose, expected) in aligned.iter().zip(&expected) {
+      assert!((pose.position - expected.position).norm() < 1e-9);
+    }
+
+    assert!(SessionAlignment::parse("1 2 3 0 0 0").is_err());
+    assert!(SessionAlignment::parse("1 2 3 0 0 0 0").is_err());
+    assert!(SessionAlignment::fit(&pairs[..2]).is_none());
+  }
+}
@@ -1,33 +1,59 @@
 // NOTE This kind of import-all file isn't a common Rust idiom.
 
 pub use crate::{
+  alignment::*,
+  anchor::*,
+  batch::*,
+  bidirectional::*,
   camera::*,
+  camera_kannala_brandt::*,
   camera_pinhole::*,
-  camera_setup::*,
+  csv_input::*,
   debug::*,
   detector::*,
+  euroc_input::*,
+  event_log::*,
   event_loop::*,
+  flip::*,
+  flow_ransac::*,
+  font::*,
   frame::*,
+  gps::*,
+  ground_truth::*,
   image::*,
+  imu_bias::*,
+  imu_convention::*,
+  imu_noise::*,
   input::*,
+  json_output::*,
   kalman_filter::*,
   math::*,
   optical_flow::*,
+  optical_flow_simd::*,
   parameters::*,
   pyramid::*,
+  realtime::*,
+  rectification::*,
   stationary::*,
   track::*,
   tracker::*,
+  trajectory::*,
   types::*,
   util::*,
   video::*,
   vio::*,
   vio_init::*,
   visualize::*,
-  visualize_3d::*,
+  visualize_recorder::*,
   visual_update::*,
 };
 
+#[cfg(feature = "gui")]
+pub use crate::visualize_3d::*;
+
+#[cfg(test)]
+pub use crate::synthetic::*;
+
 pub use {
   std::{
     collections::VecDeque,
@@ -40,9 +66,9 @@ pub use {
     sync::{Mutex, mpsc},
   },
   anyhow::{anyhow, bail, Context as AnyhowContext, Result},
-  log::{debug, error, info, warn, LevelFilter},
-  nalgebra::{dmatrix, dvector, matrix, DMatrix, DVector},
-  rand::{thread_rng, Rng, rngs::ThreadRng},
+  log::{debug, info, warn, LevelFilter},
+  nalgebra::{matrix, DMatrix, DVector},
+  rand::Rng,
   rand_xoshiro::Xoshiro256PlusPlus,
-  serde::Deserialize,
+  serde::{Deserialize, Serialize},
 };
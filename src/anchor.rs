@@ -0,0 +1,143 @@
+// Landmarks with known world positions, eg surveyed markers. The visual update
+// treats their positions as exact, which ties the trajectory to the world
+// coordinates instead of only being consistent with itself.
+
+use crate::all::*;
+
+const ANCHORS_FILE_NAME: &str = "anchors.json";
+
+#[derive(Deserialize)]
+struct AnchorsRoot {
+  anchors: Vec<[f64; 3]>,
+}
+
+pub struct Anchor {
+  pub position: Vector3d,
+  // Track currently observing the anchor.
+  pub track_id: Option<TrackId>,
+}
+
+pub struct Anchors {
+  anchors: Vec<Anchor>,
+  // Maximum pixel distance between a reprojected anchor and a track.
+  match_max_dist: f64,
+}
+
+impl Anchors {
+  pub fn new() -> Anchors {
+    let p = PARAMETER_SET.lock().unwrap();
+    Anchors {
+      anchors: vec![],
+      match_max_dist: p.anchor_match_max_dist,
+    }
+  }
+
+  // Returns the anchor positions listed in the input folder, if any.
+  pub fn load(path: &Path) -> Result<Vec<Vector3d>> {
+    let path = path.join(ANCHORS_FILE_NAME);
+    if !path.exists() { return Ok(vec![]) }
+    let s = std::fs::read_to_string(&path)
+      .context(format!("Failed to read file {}.", path.display()))?;
+    let root: AnchorsRoot = serde_json::from_str(&s)
+      .context(format!("Failed to parse {}.", path.display()))?;
+    Ok(root.anchors.iter().map(|p| Vector3d::new(p[0], p[1], p[2])).collect())
+  }
+
+  pub fn add(&mut self, position: Vector3d) {
+    self.anchors.push(Anchor { position, track_id: None });
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.anchors.is_empty()
+  }
+
+  pub fn get_position(&self, track_id: TrackId) -> Option<Vector3d> {
+    self.anchors.iter().find(|a| a.track_id == Some(track_id)).map(|a| a.position)
+  }
+
+  // Associate anchors with tracks seen in the current frame by reprojecting the
+  // anchors to the first camera using the current pose estimate. An
+  // association is kept for as long as the track is alive.
+  pub fn match_tracks(
+    &mut self,
+    tracks: &[Track],
+    camera: &Camera,
+    imu_to_world: &Matrix4d,
+    frame_number: usize,
+  ) {
+    let last_point = |track: &Track| track.points.iter().last().unwrap().clone();
+    for anchor in self.anchors.iter_mut() {
+      if let Some(id) = anchor.track_id {
+        if !tracks.iter().any(|t| t.id == id) {
+          anchor.track_id = None;
+        }
+      }
+    }
+
+    let world_to_camera = camera.imu_to_camera * affine_inverse(*imu_to_world);
+    for i in 0..self.anchors.len() {
+      if self.anchors[i].track_id.is_some() { continue }
+      let p = transform_3d(&world_to_camera, &self.anchors[i].position);
+      if p[2] <= 0. { continue }
+      let pixel = if let Some(pixel) = camera.model.ray_to_pixel(p) { pixel } else { continue };
+
+      let mut best: Option<(f64, TrackId)> = None;
+      for track in tracks {
+        let point = last_point(track);
+        if point.frame_number != frame_number { continue }
+        if track.camera == Some(1) { continue }
+        if self.anchors.iter().any(|a| a.track_id == Some(track.id)) { continue }
+        let dist = (point.coordinates[0] - pixel).norm();
+        if dist > self.match_max_dist { continue }
+        if best.is_none_or(|(best_dist, _)| dist < best_dist) {
+          best = Some((dist, track.id));
+        }
+      }
+      self.anchors[i].track_id = best.map(|(_, id)| id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_match_tracks() {
+    let _guard = set_test_parameters(|_| {});
+    let cameras = test_cameras(160, 120);
+    let camera = &cameras[0];
+    let mut imu_to_world = Matrix4d::identity();
+    imu_to_world[(0, 3)] = 0.5;
+
+    let mut anchors = Anchors::new();
+    anchors.add(Vector3d::new(0.5, 0., 2.));
+    anchors.add(Vector3d::new(1.5, 0.2, 2.));
+    // Behind the camera.
+    anchors.add(Vector3d::new(0.5, 0., -2.));
+
+    let world_to_camera = camera.imu_to_camera * affine_inverse(imu_to_world);
+    let pixel = |p: Vector3d| camera.model.ray_to_pixel(transform_3d(&world_to_camera, &p)).unwrap();
+    let track = |id: usize, point: Vector2d, frame_number: usize| {
+      let feature = Feature { point, id: TrackId(id) };
+      Track::new([feature, feature], [Vector2d::zeros(); 2], TrackerStep(0), frame_number)
+    };
+    let p0 = pixel(Vector3d::new(0.5, 0., 2.));
+    let p1 = pixel(Vector3d::new(1.5, 0.2, 2.));
+    let tracks = vec![
+      track(0, p0 + Vector2d::new(2., 0.), 3),
+      track(1, p0 + Vector2d::new(0.5, 0.5), 3),
+      // Not seen in the current frame.
+      track(2, p1, 2),
+    ];
+
+    anchors.match_tracks(&tracks, camera, &imu_to_world, 3);
+    assert_eq!(anchors.get_position(TrackId(1)), Some(Vector3d::new(0.5, 0., 2.)));
+    assert_eq!(anchors.get_position(TrackId(0)), None);
+    assert_eq!(anchors.get_position(TrackId(2)), None);
+
+    // The association is dropped when the track is lost.
+    anchors.match_tracks(&tracks[..1], camera, &imu_to_world, 4);
+    assert_eq!(anchors.get_position(TrackId(1)), None);
+  }
+}
@@ -0,0 +1,253 @@
+// Headless processing of several datasets in sequence, for benchmark sweeps.
+// Every dataset gets a fresh `Vio`, so no filter state carries over.
+
+use crate::all::*;
+
+// Maximum time difference for pairing estimated and ground-truth poses.
+const ATE_MAX_TIME_DIFF: f64 = 0.02;
+const TRAJECTORY_FILE_NAME: &str = "trajectory.tum";
+
+pub struct DatasetSummary {
+  pub name: String,
+  pub frames: usize,
+  // Wall-clock processing time.
+  pub seconds: f64,
+  // Absolute trajectory error (RMSE, meters), if the input has ground-truth poses.
+  pub ate: Option<f64>,
+  // Mean of the per-frame reprojection error RMS, see `Vio::get_reprojection_rms()`.
+  pub reprojection_rms: Option<f64>,
+}
+
+impl DatasetSummary {
+  pub fn fps(&self) -> f64 {
+    if self.seconds > 0. { self.frames as f64 / self.seconds } else { 0. }
+  }
+}
+
+// Dataset folders listed one per line. Relative paths are relative to the
+// manifest file, empty lines and lines starting with `#` are skipped.
+pub fn read_manifest(path: &Path) -> Result<Vec<PathBuf>> {
+  let s = std::fs::read_to_string(path)
+    .context(format!("Failed to read manifest {}.", path.display()))?;
+  let parent = path.parent().unwrap_or_else(|| Path::new(""));
+  Ok(s.lines()
+    .map(|line| line.trim())
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(|line| parent.join(line))
+    .collect())
+}
+
+pub fn run_batch(
+  input_folders: &[PathBuf],
+  output_folder: &Path,
+  imu_biases: Option<ImuBiases>,
+) -> Result<Vec<DatasetSummary>> {
+  let mut summaries = vec![];
+  for (i, input_folder) in input_folders.iter().enumerate() {
+    let name = dataset_name(input_folder, i);
+    info!("Processing dataset {} ({}/{}).", name, i + 1, input_folders.len());
+    let cameras = Camera::load(input_folder)
+      .context(format!("Could not load camera setups for {}.", name))?;
+    let ground_truth = GroundTruth::load(input_folder)
+      .context(format!("Could not load ground truth landmarks for {}.", name))?;
+    let anchors = Anchors::load(input_folder)
+      .context(format!("Could not load anchor landmarks for {}.", name))?;
+    let detection_mask = DetectionMask::load(input_folder)
+      .context(format!("Could not load detection mask for {}.", name))?;
+    let mut input = Input::new(input_folder)?;
+    let vio_init = VioInit::new(cameras, ground_truth, anchors, imu_biases, detection_mask, None);
+    let dataset_output_folder = output_folder.join(&name);
+    summaries.push(run_dataset(&name, &mut input, vio_init, Some(&dataset_output_folder))?);
+  }
+  Ok(summaries)
+}
+
+pub fn run_dataset(
+  name: &str,
+  input: &mut Input,
+  mut vio_init: VioInit,
+  output_folder: Option<&Path>,
+) -> Result<DatasetSummary> {
+  let start = std::time::Instant::now();
+  let mut vio: Option<Vio> = None;
+  let mut ground_truth_poses = vec![];
+  let mut frames = 0;
+  let mut reprojection_rms_values = vec![];
+  while let Some(input_data) = input.next()? {
+    if let InputDataSensor::GroundTruth(imu_to_world) = input_data.sensor {
+      ground_truth_poses.push(TrajectoryPose::new(input_data.time, &imu_to_world));
+    }
+    if vio.is_none() {
+      if let Some(vio_result) = vio_init.try_init(&input_data) {
+        vio = Some(vio_result?);
+      }
+    }
+    if let Some(vio) = &mut vio {
+      if vio.process(&input_data)? {
+        frames += 1;
+        reprojection_rms_values.extend(vio.get_reprojection_rms());
+      }
+    }
+  }
+  if let Some(vio) = &mut vio {
+    if vio.flush()? {
+      frames += 1;
+      reprojection_rms_values.extend(vio.get_reprojection_rms());
+    }
+  }
+  let seconds = start.elapsed().as_secs_f64();
+
+  let trajectory = vio.as_ref().map(|vio| vio.get_trajectory()).unwrap_or(&[]);
+  if let Some(output_folder) = output_folder {
+    std::fs::create_dir_all(output_folder)
+      .context(format!("Failed to create output folder {}.", output_folder.display()))?;
+    write_tum(&output_folder.join(TRAJECTORY_FILE_NAME), trajectory)?;
+  }
+  Ok(DatasetSummary {
+    name: name.to_string(),
+    frames,
+    seconds,
+    ate: compute_ate(trajectory, &ground_truth_poses),
+    reprojection_rms: if reprojection_rms_values.is_empty() {
+      None
+    } else {
+      Some(reprojection_rms_values.iter().sum::<f64>() / reprojection_rms_values.len() as f64)
+    },
+  })
+}
+
+pub fn format_summary(summaries: &[DatasetSummary]) -> String {
+  let width = summaries.iter().map(|s| s.name.len()).max().unwrap_or(0).max("dataset".len());
+  let mut s = format!("{:width$}  {:>8}  {:>8}  {:>10}  {:>10}\n",
+    "dataset", "frames", "fps", "ate [m]", "reproj rms", width = width);
+  for summary in summaries {
+    let ate = summary.ate.map_or("-".to_string(), |ate| format!("{:.4}", ate));
+    let rms = summary.reprojection_rms.map_or("-".to_string(), |rms| format!("{:.6}", rms));
+    s += &format!("{:width$}  {:>8}  {:>8.1}  {:>10}  {:>10}\n",
+      summary.name, summary.frames, summary.fps(), ate, rms, width = width);
+  }
+  s
+}
+
+fn dataset_name(input_folder: &Path, index: usize) -> String {
+  let name = input_folder.file_name().map(|x| x.to_string_lossy().to_string());
+  // Prefix with the index so that datasets with the same name get separate outputs.
+  format!("{}-{}", index, name.unwrap_or_else(|| "dataset".to_string()))
+}
+
+// Root-mean-square position error after rigidly aligning the estimated
+// trajectory to the ground truth (Umeyama's method without scale). The VIO
+// world frame is only defined up to yaw and position, so some alignment is needed.
+pub fn compute_ate(estimate: &[TrajectoryPose], ground_truth: &[TrajectoryPose]) -> Option<f64> {
+  let mut pairs = vec![];
+  for pose in estimate {
+    let nearest = ground_truth.iter()
+      .min_by(|a, b| (a.time - pose.time).abs().total_cmp(&(b.time - pose.time).abs()))?;
+    if (nearest.time - pose.time).abs() <= ATE_MAX_TIME_DIFF {
+      pairs.push((pose.position, nearest.position));
+    }
+  }
+  let (R, t) = align_rigid(&pairs)?;
+  let sum2: f64 = pairs.iter().map(|(e, g)| (R * e + t - g).norm_squared()).sum();
+  Some((sum2 / pairs.len() as f64).sqrt())
+}
+
+// Rotation and translation that map the first points of the pairs to the
+// second ones with the least squared error.
+pub fn align_rigid(pairs: &[(Vector3d, Vector3d)]) -> Option<(Matrix3d, Vector3d)> {
+  if pairs.len() < 3 { return None }
+  let n = pairs.len() as f64;
+  let mean_e = pairs.iter().map(|(e, _)| e).sum::<Vector3d>() / n;
+  let mean_g = pairs.iter().map(|(_, g)| g).sum::<Vector3d>() / n;
+  let C = pairs.iter().map(|(e, g)| (g - mean_g) * (e - mean_e).transpose()).sum::<Matrix3d>() / n;
+  let svd = C.svd(true, true);
+  let (U, V_t) = (svd.u?, svd.v_t?);
+  let mut D = Matrix3d::identity();
+  if (U * V_t).determinant() < 0. {
+    D[(2, 2)] = -1.;
+  }
+  let R = U * D * V_t;
+  let t = mean_g - R * mean_e;
+  Some((R, t))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pose(time: f64, position: Vector3d) -> TrajectoryPose {
+    TrajectoryPose { time, position, orientation: Vector4d::new(1., 0., 0., 0.) }
+  }
+
+  #[test]
+  fn test_compute_ate() {
+    let ground_truth: Vec<_> = (0..10)
+      .map(|i| pose(0.1 * i as f64, Vector3d::new(0.1 * i as f64, (0.3 * i as f64).sin(), 0.05 * i as f64)))
+      .collect();
+    // The same trajectory in a rotated and translated frame.
+    let R = to_rotation_matrix(Vector4d::new(0.9f64.cos(), 0., 0., 0.9f64.sin()));
+    let t = Vector3d::new(1., -2., 0.5);
+    let estimate: Vec<_> = ground_truth.iter()
+      .map(|p| pose(p.time + 0.005, R * p.position + t))
+      .collect();
+    assert!(compute_ate(&estimate, &ground_truth).unwrap() < 1e-9);
+
+    let mut noisy = estimate.clone();
+    noisy[3].position[2] += 0.3;
+    assert!(compute_ate(&noisy, &ground_truth).unwrap() > 0.05);
+    // Too few poses to align.
+    assert!(compute_ate(&estimate[..2], &ground_truth).is_none());
+    assert!(compute_ate(&estimate, &[]).is_none());
+  }
+
+  #[test]
+  fn test_run_datasets() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let folder = std::env::temp_dir().join(format!("violet-batch-{}", std::process::id()));
+    std::fs::create_dir_all(&folder).unwrap();
+
+    // Two static recordings of different lengths, only the first has ground truth.
+    let image = textured_image(width, height, 1);
+    let write_log = |path: &Path, frame_count: usize, with_ground_truth: bool| {
+      let mut writer = EventLogWriter::new(path).unwrap();
+      let mut time = 0.;
+      for _ in 0..frame_count {
+        for _ in 0..10 {
+          writer.write(&InputData { time, sensor: InputDataSensor::Gyroscope(Vector3d::zeros()) }).unwrap();
+          writer.write(&InputData { time, sensor: InputDataSensor::Accelerometer(Vector3d::new(0., 0., gravity)) }).unwrap();
+          time += 0.01;
+        }
+        if with_ground_truth {
+          writer.write(&InputData { time, sensor: InputDataSensor::GroundTruth(Matrix4d::identity()) }).unwrap();
+        }
+        writer.write(&InputData { time, sensor: InputDataSensor::Frame(InputFrame { images: vec![&image, &image] }) }).unwrap();
+      }
+    };
+    let mut summaries = vec![];
+    for (name, frame_count, with_ground_truth) in [("a", 5, true), ("b", 8, false)] {
+      let log_path = folder.join(format!("{}.bin", name));
+      write_log(&log_path, frame_count, with_ground_truth);
+      let mut input = Input::replay(&log_path).unwrap();
+      let vio_init = VioInit::new(test_cameras(width, height), None, vec![], None, None, None);
+      summaries.push(run_dataset(name, &mut input, vio_init, Some(&folder.join(name))).unwrap());
+    }
+
+    // `Vio` is created on the first frame and skips it for lack of IMU data.
+    assert_eq!(summaries[0].frames, 4);
+    assert_eq!(summaries[1].frames, 7);
+    assert!(summaries[0].ate.unwrap() < 0.1);
+    assert!(summaries[1].ate.is_none());
+    assert!(summaries.iter().all(|s| s.reprojection_rms.unwrap() < 0.01));
+    for (name, frame_count) in [("a", 4), ("b", 7)] {
+      let trajectory = std::fs::read_to_string(folder.join(name).join(TRAJECTORY_FILE_NAME)).unwrap();
+      assert_eq!(trajectory.lines().count(), frame_count);
+    }
+    let table = format_summary(&summaries);
+    assert_eq!(table.lines().count(), 3);
+    assert!(table.lines().nth(1).unwrap().starts_with("a "));
+    assert!(table.lines().nth(2).unwrap().starts_with("b "));
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+}
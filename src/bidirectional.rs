@@ -0,0 +1,281 @@
+// Offline forward-backward smoothing. The whole dataset is buffered and the
+// filter is run over it twice: forward as usual and backward on the
+// time-reversed data. The forward estimate drifts more towards the end of the
+// dataset and the backward estimate towards the start, so blending them gives
+// a better trajectory than either pass alone.
+
+use crate::all::*;
+
+pub struct BufferedInput {
+  data: Vec<BufferedData>,
+}
+
+struct BufferedData {
+  time: f64,
+  sensor: BufferedSensor,
+}
+
+enum BufferedSensor {
+  Gyroscope(Vector3d),
+  Accelerometer(Vector3d),
+  Frame(Vec<Image>),
+  // IMU-to-world.
+  GroundTruth(Matrix4d),
+  Gps(GpsFix),
+}
+
+impl BufferedInput {
+  pub fn new() -> BufferedInput {
+    BufferedInput { data: vec![] }
+  }
+
+  // Reads the whole input to memory.
+  pub fn read(input: &mut Input) -> Result<BufferedInput> {
+    let mut buffered = BufferedInput::new();
+    while let Some(input_data) = input.next()? {
+      buffered.push(&input_data);
+    }
+    Ok(buffered)
+  }
+
+  pub fn push(&mut self, input_data: &InputData) {
+    let sensor = match &input_data.sensor {
+      InputDataSensor::Gyroscope(v) => BufferedSensor::Gyroscope(*v),
+      InputDataSensor::Accelerometer(v) => BufferedSensor::Accelerometer(*v),
+      InputDataSensor::Frame(frame) => BufferedSensor::Frame(frame.images.iter().map(|x| (*x).clone()).collect()),
+      InputDataSensor::GroundTruth(imu_to_world) => BufferedSensor::GroundTruth(*imu_to_world),
+      InputDataSensor::Gps(fix) => BufferedSensor::Gps(*fix),
+    };
+    self.data.push(BufferedData { time: input_data.time, sensor });
+  }
+
+  // Feeds the data to a `Vio` created on the first frame, in order or
+  // time-reversed. The returned trajectory is in the original time either way.
+  fn run(&self, mut vio_init: VioInit, reverse: bool) -> Result<Vec<TrajectoryPose>> {
+    let mut vio: Option<Vio> = None;
+    let mut process = |data: &BufferedData| -> Result<()> {
+      // Running time backwards negates the angular velocity, and so the
+      // gyroscope readings. The specific force is a second derivative of the
+      // position and does not change sign. Time is negated so that it still
+      // increases.
+      let (time, sign) = if reverse { (-data.time, -1.) } else { (data.time, 1.) };
+      let sensor = match &data.sensor {
+        BufferedSensor::Gyroscope(v) => InputDataSensor::Gyroscope(sign * v),
+        BufferedSensor::Accelerometer(v) => InputDataSensor::Accelerometer(*v),
+        BufferedSensor::Frame(images) => InputDataSensor::Frame(InputFrame { images: images.iter().collect() }),
+        // Ground-truth poses would be in the wrong world frame for the backward pass.
+        BufferedSensor::GroundTruth(_) if reverse => return Ok(()),
+        BufferedSensor::GroundTruth(imu_to_world) => InputDataSensor::GroundTruth(*imu_to_world),
+        // Both passes align to the fixes, and `combine_passes()` aligns them.
+        BufferedSensor::Gps(fix) => InputDataSensor::Gps(*fix),
+      };
+      let input_data = InputData { time, sensor };
+      if vio.is_none() {
+        if let Some(vio_result) = vio_init.try_init(&input_data) {
+          vio = Some(vio_result?);
+        }
+      }
+      if let Some(vio) = &mut vio {
+        vio.process(&input_data)?;
+      }
+      Ok(())
+    };
+    if reverse {
+      self.data.iter().rev().try_for_each(&mut process)?;
+    }
+    else {
+      self.data.iter().try_for_each(&mut process)?;
+    }
+    if let Some(vio) = &mut vio {
+      vio.flush()?;
+    }
+
+    let mut trajectory = vio.map(|vio| vio.get_trajectory().to_vec()).unwrap_or_default();
+    if reverse {
+      trajectory.reverse();
+      for pose in &mut trajectory {
+        pose.time = -pose.time;
+      }
+    }
+    Ok(trajectory)
+  }
+}
+
+// Runs the filter forward and backward over the data and combines the passes
+// with `combine_passes()`. The passes need their own `VioInit`s. Note that
+// in the backward pass the gyroscope bias has the opposite sign, and anchor
+// landmarks are given in the world frame of the forward pass.
+pub fn run_bidirectional(
+  input: &BufferedInput,
+  forward_init: VioInit,
+  backward_init: VioInit,
+) -> Result<Vec<TrajectoryPose>> {
+  let forward = input.run(forward_init, false)?;
+  let backward = input.run(backward_init, true)?;
+  Ok(combine_passes(&forward, &backward))
+}
+
+// Blends the trajectories of the forward and backward passes into the world
+// frame of the forward pass. The backward pass starts from an arbitrary pose,
+// so it is first aligned rigidly to the forward pass. Drift of a pass grows
+// with the time it has run, so at time t between the first and last poses t0
+// and t1 the forward pose gets weight (t1 - t) / (t1 - t0) and the backward
+// pose the rest. Orientations are blended as sign-aligned quaternions. Poses
+// found in only one pass, such as the first frame of each pass, are kept as is.
+pub fn combine_passes(forward: &[TrajectoryPose], backward: &[TrajectoryPose]) -> Vec<TrajectoryPose> {
+  const MAX_TIME_DIFF: f64 = 1e-9;
+  let mut pairs = vec![];
+  let mut j = 0;
+  for (i, pose) in forward.iter().enumerate() {
+    while j < backward.len() && backward[j].time < pose.time - MAX_TIME_DIFF { j += 1 }
+    if j < backward.len() && (backward[j].time - pose.time).abs() <= MAX_TIME_DIFF {
+      pairs.push((i, j));
+    }
+  }
+  let correspondences: Vec<_> = pairs.iter()
+    .map(|&(i, j)| (backward[j].position, forward[i].position))
+    .collect();
+  let Some((R, t)) = align_rigid(&correspondences) else { return forward.to_vec() };
+  let aligned: Vec<TrajectoryPose> = backward.iter().map(|pose| TrajectoryPose {
+    time: pose.time,
+    position: R * pose.position + t,
+    orientation: to_quaternion(R * to_rotation_matrix(pose.orientation)),
+  }).collect();
+
+  let t0 = forward[0].time.min(aligned[0].time);
+  let t1 = forward[forward.len() - 1].time.max(aligned[aligned.len() - 1].time);
+  let mut combined = vec![];
+  let (mut i, mut j) = (0, 0);
+  while i < forward.len() || j < aligned.len() {
+    if j >= aligned.len() || (i < forward.len() && forward[i].time < aligned[j].time - MAX_TIME_DIFF) {
+      combined.push(forward[i].clone());
+      i += 1;
+    }
+    else if i >= forward.len() || aligned[j].time < forward[i].time - MAX_TIME_DIFF {
+      combined.push(aligned[j].clone());
+      j += 1;
+    }
+    else {
+      let (f, b) = (&forward[i], &aligned[j]);
+      let w = if t1 > t0 { (t1 - f.time) / (t1 - t0) } else { 0.5 };
+      let sign = if f.orientation.dot(&b.orientation) < 0. { -1. } else { 1. };
+      combined.push(TrajectoryPose {
+        time: f.time,
+        position: w * f.position + (1. - w) * b.position,
+        orientation: (w * f.orientation + (1. - w) * sign * b.orientation).normalize(),
+      });
+      i += 1;
+      j += 1;
+    }
+  }
+  combined
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pose(time: f64, position: Vector3d, angle: f64) -> TrajectoryPose {
+    TrajectoryPose { time, position, orientation: Vector4d::new((0.5 * angle).cos(), 0., 0., (0.5 * angle).sin()) }
+  }
+
+  #[test]
+  fn test_combine_passes() {
+    let n = 100;
+    let truth: Vec<_> = (0..n).map(|i| {
+      let t = 0.1 * i as f64;
+      pose(t, Vector3d::new(t.cos(), t.sin(), 0.1 * t), t)
+    }).collect();
+    // Yaw drift accumulates from the start in the forward pass and from the
+    // end in the backward pass, which is also in another world frame.
+    let drift = 0.02;
+    let yaw = |angle: f64| to_rotation_matrix(pose(0., Vector3d::zeros(), angle).orientation);
+    let (R, t) = (yaw(2.), Vector3d::new(3., -1., 0.5));
+    let t1 = truth[n - 1].time;
+    // Each pass skips its first frame.
+    let forward: Vec<_> = truth[1..].iter().map(|p| {
+      pose(p.time, yaw(drift * p.time) * p.position, 0.)
+    }).collect();
+    let backward: Vec<_> = truth[..(n - 1)].iter().map(|p| {
+      pose(p.time, R * yaw(drift * (t1 - p.time)) * p.position + t, 0.)
+    }).collect();
+
+    let combined = combine_passes(&forward, &backward);
+    assert_eq!(combined.len(), n);
+    for (a, b) in combined.iter().zip(truth.iter()) {
+      assert_eq!(a.time, b.time);
+      assert!((a.orientation.norm() - 1.).abs() < 1e-10);
+    }
+    let ate_forward = compute_ate(&forward, &truth).unwrap();
+    let ate_combined = compute_ate(&combined, &truth).unwrap();
+    assert!(ate_combined < 0.75 * ate_forward);
+  }
+
+  #[test]
+  fn test_run_bidirectional() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let image = textured_image(width, height, 1);
+    let frame_count = 10;
+    let mut input = BufferedInput::new();
+    let mut ground_truth = vec![];
+    let mut time = 0.;
+    for _ in 0..frame_count {
+      for _ in 0..10 {
+        input.push(&InputData { time, sensor: InputDataSensor::Gyroscope(Vector3d::zeros()) });
+        input.push(&InputData { time, sensor: InputDataSensor::Accelerometer(Vector3d::new(0., 0., gravity)) });
+        time += 0.01;
+      }
+      ground_truth.push(TrajectoryPose::new(time, &Matrix4d::identity()));
+      input.push(&InputData { time, sensor: InputDataSensor::Frame(InputFrame { images: vec![&image, &image] }) });
+    }
+    let vio_init = || VioInit::new(test_cameras(width, height), None, vec![], None, None, None);
+
+    let backward = input.run(vio_init(), true).unwrap();
+    assert_eq!(backward.len(), frame_count - 1);
+    assert!(backward.windows(2).all(|w| w[0].time < w[1].time));
+    // The last frame is first in the backward pass and skipped.
+    assert_eq!(backward[0].time, ground_truth[0].time);
+
+    let combined = run_bidirectional(&input, vio_init(), vio_init()).unwrap();
+    assert_eq!(combined.len(), frame_count);
+    assert!(compute_ate(&combined, &ground_truth).unwrap() < 0.1);
+  }
+  #[test]
+  fn test_bidirectional_ate() {
+    // Let the position drift with the bias instead of holding it at rest.
+    let _guard = set_test_parameters(|p| p.imu_stationary_window = 0.);
+    let (width, height) = (160, 120);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    // Without visual features the position is integrated from the IMU only.
+    let image = black_image(width, height);
+    let mut input = BufferedInput::new();
+    let mut ground_truth = vec![];
+    let mut time = 0.;
+    for _ in 0..30 {
+      for _ in 0..10 {
+        // An accelerometer bias appears halfway through the static dataset.
+        // Each pass takes the bias it starts with for tilt, so the forward
+        // pass drifts at the end and the backward pass at the start.
+        let bias = if time > 1.5 { 0.05 } else { 0. };
+        input.push(&InputData { time, sensor: InputDataSensor::Gyroscope(Vector3d::zeros()) });
+        input.push(&InputData { time, sensor: InputDataSensor::Accelerometer(Vector3d::new(bias, 0., gravity)) });
+        time += 0.01;
+      }
+      ground_truth.push(TrajectoryPose::new(time, &Matrix4d::identity()));
+      input.push(&InputData { time, sensor: InputDataSensor::Frame(InputFrame { images: vec![&image, &image] }) });
+    }
+    let vio_init = || VioInit::new(test_cameras(width, height), None, vec![], None, None, None);
+
+    let forward = input.run(vio_init(), false).unwrap();
+    let backward = input.run(vio_init(), true).unwrap();
+    let combined = run_bidirectional(&input, vio_init(), vio_init()).unwrap();
+    let ate_forward = compute_ate(&forward, &ground_truth).unwrap();
+    let ate_backward = compute_ate(&backward, &ground_truth).unwrap();
+    let ate_combined = compute_ate(&combined, &ground_truth).unwrap();
+    assert!(ate_forward > 0.01);
+    assert!(ate_combined < ate_forward);
+    assert!(ate_combined < ate_backward);
+  }
+}
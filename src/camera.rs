@@ -3,8 +3,29 @@ use crate::all::*;
 // Intrinsic and extrinsic camera parameters for a single camera.
 pub struct Camera {
   pub imu_to_camera: Matrix4d,
-  pub kind: CameraKind,
   pub model: Box<dyn CameraModel>,
+  pub shutter: Shutter,
+}
+
+impl Camera {
+  // Normalized image coordinates of a pixel, with the lens distortion removed.
+  pub fn unproject(&self, pixel: Vector2d) -> Option<Vector2d> {
+    hnormalize(self.model.pixel_to_ray(pixel)?)
+  }
+
+  // Pixel of normalized image coordinates, with the lens distortion applied.
+  pub fn project(&self, normalized: Vector2d) -> Option<Vector2d> {
+    self.model.ray_to_pixel(Vector3d::new(normalized[0], normalized[1], 1.))
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shutter {
+  // All pixels are exposed at the frame timestamp.
+  Global,
+  // Rows are exposed from top to bottom during `readout_time` seconds
+  // centered at the frame timestamp.
+  Rolling { readout_time: f64 },
 }
 
 #[derive(Debug)]
@@ -13,7 +34,7 @@ pub enum CameraKind {
   KannalaBrandt4,
 }
 
-pub trait CameraModel {
+pub trait CameraModel: Send + Sync {
   fn pixel_to_ray(&self, pixel: Vector2d) -> Option<Vector3d>;
 
   fn ray_to_pixel_d(&self, ray: Vector3d, compute_derivative: bool)
@@ -0,0 +1,235 @@
+// Kannala-Brandt fisheye model with four distortion coefficients, also known as
+// the equidistant model in OpenCV and Kalibr. The distance of the pixel from
+// the principal point depends on the angle `theta` between the ray and the
+// optical axis as
+//   r(theta) = theta * (1 + k1 theta^2 + k2 theta^4 + k3 theta^6 + k4 theta^8),
+// which unlike the pinhole model also handles fields of view of 180 degrees
+// and more.
+
+use crate::all::*;
+
+use std::f64::consts::PI;
+
+const UNPROJECT_MAX_ITERATIONS: usize = 20;
+const UNPROJECT_CONVERGENCE_THRESHOLD: f64 = 1e-12;
+
+pub struct KannalaBrandtModel {
+  pub camera_matrix: Matrix3d,
+  pub distortion_coefficients: [f64; 4],
+}
+
+impl KannalaBrandtModel {
+  pub fn new(
+    camera_matrix: Matrix3d,
+    distortion_coefficients: [f64; 4],
+  ) -> KannalaBrandtModel {
+    KannalaBrandtModel {
+      camera_matrix,
+      distortion_coefficients,
+    }
+  }
+
+  // `r(theta)` and its derivative.
+  fn radius(&self, theta: f64) -> (f64, f64) {
+    let k = &self.distortion_coefficients;
+    let t2 = theta * theta;
+    let r = theta * (1. + t2 * (k[0] + t2 * (k[1] + t2 * (k[2] + t2 * k[3]))));
+    let dr = 1. + t2 * (3. * k[0] + t2 * (5. * k[1] + t2 * (7. * k[2] + t2 * 9. * k[3])));
+    (r, dr)
+  }
+
+  // Solves `r(theta) = r` with Newton's method.
+  fn theta(&self, r: f64) -> Option<f64> {
+    let mut theta = r;
+    for _ in 0..UNPROJECT_MAX_ITERATIONS {
+      let (r0, dr) = self.radius(theta);
+      if dr <= 0. { return None }
+      let delta = (r - r0) / dr;
+      theta += delta;
+      if delta.abs() < UNPROJECT_CONVERGENCE_THRESHOLD { break }
+    }
+    if !(0. ..PI).contains(&theta) { return None }
+    Some(theta)
+  }
+
+  // Unit ray of a pixel, and optionally its derivative wrt the pixel.
+  pub fn pixel_to_ray_d(&self, pixel: Vector2d, compute_derivative: bool)
+    -> (Option<Vector3d>, Option<Matrix32d>)
+  {
+    let (fx, fy) = (self.camera_matrix[(0, 0)], self.camera_matrix[(1, 1)]);
+    let m = Vector2d::new(
+      (pixel[0] - self.camera_matrix[(0, 2)]) / fx,
+      (pixel[1] - self.camera_matrix[(1, 2)]) / fy,
+    );
+    let r = m.norm();
+    // Near the optical axis `sin(theta) / r` tends to 1.
+    if r < 1e-12 {
+      let dray = if compute_derivative {
+        Some(matrix!(
+          1. / fx, 0.;
+          0., 1. / fy;
+          0., 0.;
+        ))
+      }
+      else {
+        None
+      };
+      return (Some(Vector3d::new(0., 0., 1.)), dray);
+    }
+    let Some(theta) = self.theta(r) else { return (None, None) };
+    let (sin, cos) = theta.sin_cos();
+    let s = sin / r;
+    let ray = Vector3d::new(s * m[0], s * m[1], cos);
+    let dray = if compute_derivative {
+      // `theta` and `s` differentiated wrt `r`, then the ray wrt `m`.
+      let dtheta = 1. / self.radius(theta).1;
+      let ds = (cos * dtheta * r - sin) / (r * r);
+      let u = m / r;
+      let dxy = s * Matrix2d::identity() + ds * m * u.transpose();
+      let dz = -sin * dtheta * u;
+      Some(matrix!(
+        dxy[(0, 0)] / fx, dxy[(0, 1)] / fy;
+        dxy[(1, 0)] / fx, dxy[(1, 1)] / fy;
+        dz[0] / fx, dz[1] / fy;
+      ))
+    }
+    else {
+      None
+    };
+    (Some(ray), dray)
+  }
+}
+
+impl CameraModel for KannalaBrandtModel {
+  fn pixel_to_ray(&self, pixel: Vector2d) -> Option<Vector3d> {
+    self.pixel_to_ray_d(pixel, false).0
+  }
+
+  fn ray_to_pixel_d(&self, ray: Vector3d, compute_derivative: bool)
+    -> (Option<Vector2d>, Option<Matrix23d>)
+  {
+    let (x, y, z) = (ray[0], ray[1], ray[2]);
+    let r2 = x * x + y * y;
+    let rho = r2.sqrt();
+    let (fx, fy) = (self.camera_matrix[(0, 0)], self.camera_matrix[(1, 1)]);
+    let (cx, cy) = (self.camera_matrix[(0, 2)], self.camera_matrix[(1, 2)]);
+    // Near the optical axis `r(theta) / rho` tends to `1 / z` like in the
+    // pinhole model.
+    if rho < 1e-12 * z.abs() {
+      if z <= 0. { return (None, None) }
+      let pixel = Vector2d::new(fx * x / z + cx, fy * y / z + cy);
+      let dpixel = if compute_derivative {
+        Some(matrix!(
+          fx / z, 0., -fx * x / (z * z);
+          0., fy / z, -fy * y / (z * z);
+        ))
+      }
+      else {
+        None
+      };
+      return (Some(pixel), dpixel);
+    }
+
+    let theta = rho.atan2(z);
+    let (r, dr) = self.radius(theta);
+    let pixel = Vector2d::new(fx * r * x / rho + cx, fy * r * y / rho + cy);
+    let dpixel = if compute_derivative {
+      // `theta` differentiated wrt the ray.
+      let n2 = r2 + z * z;
+      let dtheta = Vector3d::new(z * x / (rho * n2), z * y / (rho * n2), -rho / n2);
+      // `[x, y] / rho` differentiated wrt the ray.
+      let rho3 = rho * r2;
+      let du = matrix!(
+        y * y / rho3, -x * y / rho3, 0.;
+        -x * y / rho3, x * x / rho3, 0.;
+      );
+      let u = Vector2d::new(x / rho, y / rho);
+      let d = dr * u * dtheta.transpose() + r * du;
+      Some(matrix!(
+        fx * d[(0, 0)], fx * d[(0, 1)], fx * d[(0, 2)];
+        fy * d[(1, 0)], fy * d[(1, 1)], fy * d[(1, 2)];
+      ))
+    }
+    else {
+      None
+    };
+    (Some(pixel), dpixel)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Calibration of a wide-angle lens, from the TUM VI dataset.
+  fn test_model() -> KannalaBrandtModel {
+    let K = Matrix3d::new(
+      190.978, 0., 254.932,
+      0., 190.973, 256.897,
+      0., 0., 1.,
+    );
+    KannalaBrandtModel::new(K, [0.003482, 0.000715, -0.002053, 0.000203])
+  }
+
+  #[test]
+  fn test_kannala_brandt_round_trip() {
+    let camera = test_model();
+    // Rays up to 100 degrees from the optical axis, beyond what a pinhole
+    // model can represent.
+    for i in 0..=20 {
+      let theta = (100. * i as f64 / 20.).to_radians();
+      for j in 0..12 {
+        let phi = 2. * PI * j as f64 / 12.;
+        let ray0 = Vector3d::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+        let pixel = camera.ray_to_pixel(ray0).unwrap();
+        let ray = camera.pixel_to_ray(pixel).unwrap();
+        assert!((ray - ray0).norm() < 1e-6, "{} {}", theta, phi);
+      }
+    }
+    let center = camera.ray_to_pixel(Vector3d::new(0., 0., 2.)).unwrap();
+    assert!((center - Vector2d::new(254.932, 256.897)).norm() < 1e-9);
+  }
+
+  #[test]
+  fn test_kannala_brandt_derivative() {
+    let camera = test_model();
+    let h = 1e-6;
+    for ray in [Vector3d::new(-0.25, 0.11, 1.), Vector3d::new(0.8, -0.6, -0.2), Vector3d::new(0., 0., 1.)] {
+      let (pixel, dpixel) = camera.ray_to_pixel_d(ray, true);
+      let (pixel, dpixel) = (pixel.unwrap(), dpixel.unwrap());
+      for i in 0..3 {
+        let mut r = ray;
+        r[i] += h;
+        let numerical = (camera.ray_to_pixel(r).unwrap() - pixel) / h;
+        assert!((numerical - dpixel.column(i)).norm() < 1e-3);
+      }
+    }
+  }
+
+  #[test]
+  fn test_kannala_brandt_unproject_derivative() {
+    let camera = test_model();
+    let h = 1e-4;
+    // The principal point, a pixel near the center and ones beyond 90 degrees
+    // from the optical axis.
+    for pixel in [
+      Vector2d::new(254.932, 256.897),
+      Vector2d::new(260., 250.),
+      Vector2d::new(40., 470.),
+      Vector2d::new(500., 10.),
+    ] {
+      let (ray, dray) = camera.pixel_to_ray_d(pixel, true);
+      let (ray, dray) = (ray.unwrap(), dray.unwrap());
+      assert!((ray.norm() - 1.).abs() < 1e-12);
+      for i in 0..2 {
+        let mut p = pixel;
+        p[i] += h;
+        let ray1 = camera.pixel_to_ray(p).unwrap();
+        p[i] -= 2. * h;
+        let ray0 = camera.pixel_to_ray(p).unwrap();
+        let numerical = (ray1 - ray0) / (2. * h);
+        assert!((numerical - dray.column(i)).norm() < 1e-7, "{} {} {}", pixel, numerical, dray.column(i));
+      }
+    }
+  }
+}
@@ -1,4 +1,4 @@
-// Pinhole mode with OpenCV distortion model using only the radial components.
+// Pinhole mode with the OpenCV radial-tangential (Brown-Conrady) distortion model.
 //
 // NOTE This code is heavily based on the HybVIO implementation here:
 //   <https://github.com/SpectacularAI/HybVIO/blob/main/src/tracker/camera.cpp>
@@ -11,27 +11,47 @@ const UNDISTORT_CONVERGENCE_THRESHOLD: f64 = 1e-5;
 pub struct PinholeModel {
   pub camera_matrix: Matrix3d,
   pub camera_matrix_inv: Matrix3d,
-  pub distortion_coefficients: Vec<f64>,
+  // k1, k2, k3.
+  radial: [f64; 3],
+  // p1, p2.
+  tangential: [f64; 2],
+  distorted: bool,
 }
 
 impl PinholeModel {
+  // The distortion coefficients are either empty, the radial `[k1, k2, k3]`,
+  // or in the OpenCV order `[k1, k2, p1, p2]` or `[k1, k2, p1, p2, k3]`.
   pub fn new(
     camera_matrix: Matrix3d,
     distortion_coefficients: Vec<f64>,
   ) -> PinholeModel {
+    let c = &distortion_coefficients;
+    let (radial, tangential) = match c.len() {
+      0 => ([0.; 3], [0.; 2]),
+      3 => ([c[0], c[1], c[2]], [0.; 2]),
+      4 => ([c[0], c[1], 0.], [c[2], c[3]]),
+      5 => ([c[0], c[1], c[4]], [c[2], c[3]]),
+      n => panic!("Unsupported number of pinhole distortion coefficients {}.", n),
+    };
     PinholeModel {
       camera_matrix,
       camera_matrix_inv: camera_matrix.try_inverse().unwrap(),
-      distortion_coefficients,
+      radial,
+      tangential,
+      distorted: !c.is_empty(),
     }
   }
 
+  pub fn valid_distortion_coefficient_count(n: usize) -> bool {
+    matches!(n, 0 | 3 | 4 | 5)
+  }
+
   fn distort(&self, p: Vector2d, compute_derivative: bool) -> (Vector2d, Option<Matrix2d>) {
-    if self.distortion_coefficients.is_empty() {
+    if !self.distorted {
       return (p, if compute_derivative { Some(Matrix2d::identity()) } else { None });
     }
-    let c = &self.distortion_coefficients;
-    assert_eq!(c.len(), 3);
+    let c = &self.radial;
+    let [p1, p2] = self.tangential;
     let x = p[0];
     let y = p[1];
     let r2 = x * x + y * y;
@@ -39,18 +59,23 @@ impl PinholeModel {
     let dtheta = c[0] + r2 * (c[1] * 2. + r2 * c[2] * 3.);
     let dp = if compute_derivative {
       Some(Matrix2d::new(
-        theta + x * dtheta * 2. * x, x * dtheta * 2. * y,
-        y * dtheta * 2. * x, theta + y * dtheta * 2. * y
+        theta + x * dtheta * 2. * x + 2. * p1 * y + 6. * p2 * x,
+        x * dtheta * 2. * y + 2. * p1 * x + 2. * p2 * y,
+        y * dtheta * 2. * x + 2. * p1 * x + 2. * p2 * y,
+        theta + y * dtheta * 2. * y + 6. * p1 * y + 2. * p2 * x,
       ))
     }
     else {
       None
     };
-    (Vector2d::new(x * theta, y * theta), dp)
+    (Vector2d::new(
+      x * theta + 2. * p1 * x * y + p2 * (r2 + 2. * x * x),
+      y * theta + p1 * (r2 + 2. * y * y) + 2. * p2 * x * y,
+    ), dp)
   }
 
   fn undistort(&self, dist: Vector2d) -> Vector2d {
-    if self.distortion_coefficients.is_empty() { return dist }
+    if !self.distorted { return dist }
     let mut point = dist;
     for _ in 0..UNDISTORT_MAX_ITERATIONS {
       let (p, dp) = self.distort(point, true);
@@ -64,10 +89,7 @@ impl PinholeModel {
 
 impl CameraModel for PinholeModel {
   fn pixel_to_ray(&self, pixel: Vector2d) -> Option<Vector3d> {
-    let dist = Vector2d::new(
-      (pixel[0] - self.camera_matrix[(0, 2)]) / self.camera_matrix[(0, 0)],
-      (pixel[1] - self.camera_matrix[(1, 2)]) / self.camera_matrix[(1, 1)],
-    );
+    let dist = hnormalize(self.camera_matrix_inv * Vector3d::new(pixel[0], pixel[1], 1.))?;
     let p = self.undistort(dist);
     Some(Vector3d::new(p[0], p[1], 1.).normalize())
   }
@@ -123,5 +145,35 @@ mod tests {
     assert!((pixel - Vector2d::new(310.26612557476517, 273.4325047471033)).norm() < 1e-6);
     let ray = camera.pixel_to_ray(pixel).unwrap();
     assert!((ray - ray0).norm() < 1e-10);
+
+    // Radial-tangential, as in the EuRoC calibration.
+    let camera = PinholeModel::new(K, vec![-0.28340811, 0.07395907, 0.00019359, 1.76187114e-05]);
+    let radial = PinholeModel::new(K, vec![-0.28340811, 0.07395907, 0.]);
+    let pixel = camera.ray_to_pixel(ray0).unwrap();
+    assert!((pixel - radial.ray_to_pixel(ray0).unwrap()).norm() > 1e-3);
+    let ray = camera.pixel_to_ray(pixel).unwrap();
+    assert!((ray - ray0).norm() < 1e-10);
+  }
+
+  #[test]
+  fn test_tangential_distortion_derivative() {
+    let K = Matrix3d::new(
+      458., 0., 367.215,
+      0., 458., 248.375,
+      0., 0., 1.,
+    );
+    let camera = PinholeModel::new(K, vec![-0.28, 0.07, 0.002, -0.003, 0.01]);
+    let ray = Vector3d::new(-0.25, 0.11, 1.);
+    let (pixel, dpixel) = camera.ray_to_pixel_d(ray, true);
+    let (pixel, dpixel) = (pixel.unwrap(), dpixel.unwrap());
+    let h = 1e-6;
+    for i in 0..3 {
+      let mut r = ray;
+      r[i] += h;
+      let numerical = (camera.ray_to_pixel(r).unwrap() - pixel) / h;
+      assert!((numerical - dpixel.column(i)).norm() < 1e-3);
+    }
+    let ray0 = camera.pixel_to_ray(pixel).unwrap();
+    assert!((ray0 - ray.normalize()).norm() < 1e-10);
   }
 }
@@ -9,6 +9,10 @@ pub struct CameraSetup {
   pub distortionCoefficients: Vec<f64>,
   pub imuToCamera: Vec<Vec<f64>>,
   pub model: String,
+  // "global" (default) or "rolling".
+  pub shutter: Option<String>,
+  // Seconds from exposing the first row to the last, required for rolling shutter.
+  pub rollingShutterReadoutTime: Option<f64>,
   // TODO Should enable these but my test data is missing them.
   //      They are needed to scale camera intrinsics if input video is scaled.
   // pub imageWidth: usize,
@@ -21,7 +25,7 @@ pub struct CameraSetupRoot {
 }
 
 const MAX_PARENT_DIRECTORY_HEIGHT: usize = 1;
-const SETUP_FILE_NAME: &'static str = "calibration.json";
+const SETUP_FILE_NAME: &str = "calibration.json";
 
 impl Camera {
   pub fn load(path: &Path) -> Result<Vec<Camera>> {
@@ -32,7 +36,7 @@ impl Camera {
         return parse_setup(&setup_path);
       }
       path = path.parent()
-        .expect(&format!("Cannot look {} in a parent directory", SETUP_FILE_NAME))
+        .unwrap_or_else(|| panic!("Cannot look {} in a parent directory", SETUP_FILE_NAME))
         .to_path_buf();
     }
     bail!("Failed to find a {}.", SETUP_FILE_NAME);
@@ -45,7 +49,7 @@ fn parse_setup(path: &Path) -> Result<Vec<Camera>> {
   let root: CameraSetupRoot = serde_json::from_str(&s)
     .context(format!("Failed to parse {}.", path.display()))?;
   root.cameras.into_iter()
-    .map(|x| convert_setup(x))
+    .map(convert_setup)
     .collect::<Result<Vec<_>>>()
 }
 
@@ -56,12 +60,23 @@ fn convert_setup(d: CameraSetup) -> Result<Camera> {
     0., 0., 1.
   );
   let kind = convert_model(&d.model)?;
-  let model = match kind {
-    CameraKind::Pinhole => Box::new(PinholeModel::new(camera_matrix, d.distortionCoefficients)),
-    CameraKind::KannalaBrandt4 => unimplemented!(),
+  let coefficient_count = d.distortionCoefficients.len();
+  let model: Box<dyn CameraModel> = match kind {
+    CameraKind::Pinhole => {
+      if !PinholeModel::valid_distortion_coefficient_count(coefficient_count) {
+        bail!("Expected 0, 3, 4 or 5 pinhole distortion coefficients, found {}.", coefficient_count);
+      }
+      Box::new(PinholeModel::new(camera_matrix, d.distortionCoefficients))
+    },
+    CameraKind::KannalaBrandt4 => {
+      let coefficients: [f64; 4] = d.distortionCoefficients.try_into()
+        .map_err(|_| anyhow!("Expected 4 Kannala-Brandt distortion coefficients, found {}.", coefficient_count))?;
+      Box::new(KannalaBrandtModel::new(camera_matrix, coefficients))
+    },
   };
+  let shutter = convert_shutter(d.shutter.as_deref(), d.rollingShutterReadoutTime)?;
   Ok(Camera {
-    kind,
+    shutter,
     imu_to_camera: Matrix4d::from_iterator(d.imuToCamera.into_iter().flatten()).transpose(),
     model,
   })
@@ -74,3 +89,75 @@ fn convert_model(model: &str) -> Result<CameraKind> {
     _ => bail!("Unknown camera model {}.", &model),
   }
 }
+
+fn convert_shutter(shutter: Option<&str>, readout_time: Option<f64>) -> Result<Shutter> {
+  match shutter.unwrap_or("global") {
+    "global" => {
+      if readout_time.is_some() {
+        warn!("Rolling shutter readout time is set but the shutter is global, ignoring it. \
+          Set `\"shutter\": \"rolling\"` to enable the compensation.");
+      }
+      Ok(Shutter::Global)
+    },
+    "rolling" => {
+      let readout_time = readout_time
+        .ok_or_else(|| anyhow!("Rolling shutter requires rollingShutterReadoutTime."))?;
+      if readout_time.is_nan() || readout_time < 0. { bail!("Invalid rolling shutter readout time {}.", readout_time) }
+      Ok(Shutter::Rolling { readout_time })
+    },
+    shutter => bail!("Unknown shutter type {}.", shutter),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_convert_shutter() {
+    assert_eq!(convert_shutter(None, None).unwrap(), Shutter::Global);
+    assert_eq!(convert_shutter(Some("global"), None).unwrap(), Shutter::Global);
+    // Readout time alone does not enable the compensation.
+    assert_eq!(convert_shutter(None, Some(0.02)).unwrap(), Shutter::Global);
+    assert_eq!(
+      convert_shutter(Some("rolling"), Some(0.02)).unwrap(),
+      Shutter::Rolling { readout_time: 0.02 },
+    );
+    assert!(convert_shutter(Some("rolling"), None).is_err());
+    assert!(convert_shutter(Some("rolling"), Some(-1.)).is_err());
+    assert!(convert_shutter(Some("electronic"), None).is_err());
+
+    // Read from the calibration of a camera.
+    let camera = convert_setup(serde_json::from_str(r#"{
+      "focalLengthX": 100.0, "focalLengthY": 100.0,
+      "principalPointX": 80.0, "principalPointY": 60.0, "distortionCoefficients": [],
+      "imuToCamera": [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, 1]],
+      "model": "pinhole", "shutter": "rolling", "rollingShutterReadoutTime": 0.03
+    }"#).unwrap()).unwrap();
+    assert_eq!(camera.shutter, Shutter::Rolling { readout_time: 0.03 });
+  }
+
+  #[test]
+  fn test_convert_setup_models() {
+    let setup = |model: &str, coefficients: &str| -> Result<Camera> {
+      convert_setup(serde_json::from_str(&format!(r#"{{
+        "focalLengthX": 190.0, "focalLengthY": 190.0,
+        "principalPointX": 255.0, "principalPointY": 256.0,
+        "distortionCoefficients": [{}],
+        "imuToCamera": [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, 1]],
+        "model": "{}"
+      }}"#, coefficients, model)).unwrap())
+    };
+    // Both models give normalized coordinates for the visual update.
+    for camera in [
+      setup("pinhole", "-0.28, 0.07, 0.0002, 0.00002").unwrap(),
+      setup("kannala-brandt4", "0.003482, 0.000715, -0.002053, 0.000203").unwrap(),
+    ] {
+      let normalized = Vector2d::new(-0.4, 0.3);
+      let pixel = camera.project(normalized).unwrap();
+      assert!((camera.unproject(pixel).unwrap() - normalized).norm() < 1e-9);
+    }
+    assert!(setup("pinhole", "0.1, 0.2").is_err());
+    assert!(setup("kannala-brandt4", "0.1, 0.2, 0.3").is_err());
+  }
+}
@@ -0,0 +1,296 @@
+// IMU samples of the `csv` input format, see `InputFormat::Csv`. Each row is
+//   time, gx, gy, gz, ax, ay, az
+// with time in seconds, not decreasing. Fields may be separated by commas,
+// spaces or tabs and padded with whitespace. Lines starting with `#` and a
+// header row are skipped.
+
+use crate::all::*;
+
+pub struct CsvImuInput {
+  reader: BufReader<File>,
+  path: PathBuf,
+  line: String,
+  line_number: usize,
+  // The accelerometer half of the last row, returned after the gyroscope.
+  pending: Option<(f64, Vector3d)>,
+  // Next row that has not been returned yet.
+  next_row: Option<[f64; 7]>,
+  last_time: Option<f64>,
+}
+
+impl CsvImuInput {
+  pub fn new(path: &Path) -> Result<CsvImuInput> {
+    let file = File::open(path)
+      .context(format!("Failed to open IMU CSV file {}.", path.display()))?;
+    Ok(CsvImuInput {
+      reader: BufReader::new(file),
+      path: path.to_path_buf(),
+      line: String::new(),
+      line_number: 0,
+      pending: None,
+      next_row: None,
+      last_time: None,
+    })
+  }
+
+  // Next sample with time at most `max_time`, if any.
+  pub fn next_until(&mut self, max_time: f64) -> Result<Option<InputData<'static>>> {
+    if let Some((time, v)) = self.pending.take() {
+      return Ok(Some(InputData { time, sensor: InputDataSensor::Accelerometer(v) }));
+    }
+    if self.next_row.is_none() {
+      self.next_row = self.read_row()?;
+    }
+    let row = match self.next_row {
+      Some(row) if row[0] <= max_time => row,
+      _ => return Ok(None),
+    };
+    self.next_row = None;
+    self.pending = Some((row[0], Vector3d::new(row[4], row[5], row[6])));
+    Ok(Some(InputData {
+      time: row[0],
+      sensor: InputDataSensor::Gyroscope(Vector3d::new(row[1], row[2], row[3])),
+    }))
+  }
+
+  // Time of the next sample, if any.
+  pub fn peek_time(&mut self) -> Result<Option<f64>> {
+    if let Some((time, _)) = self.pending { return Ok(Some(time)) }
+    if self.next_row.is_none() {
+      self.next_row = self.read_row()?;
+    }
+    Ok(self.next_row.map(|row| row[0]))
+  }
+
+  fn read_row(&mut self) -> Result<Option<[f64; 7]>> {
+    loop {
+      self.line.clear();
+      if self.reader.read_line(&mut self.line)? == 0 { return Ok(None) }
+      self.line_number += 1;
+      let line = self.line.trim();
+      if line.is_empty() || line.starts_with('#') { continue }
+      let fields = match parse_csv_line(line) {
+        Ok(fields) => fields,
+        Err((column, _)) if self.line_number == 1 && column == 1 => continue, // Header.
+        Err((column, field)) => bail!("{}:{}: column {}: invalid number `{}`.",
+          self.path.display(), self.line_number, column, field),
+      };
+      if fields.len() < 7 {
+        bail!("{}:{}: expected 7 columns, found {}.", self.path.display(), self.line_number, fields.len());
+      }
+      let mut row = [0.; 7];
+      row.copy_from_slice(&fields[..7]);
+      check_time(&self.path, self.line_number, row[0], &mut self.last_time)?;
+      return Ok(Some(row));
+    }
+  }
+}
+
+// GPS fixes from a CSV file, for the `csv` input format. Each row is
+//   time, latitude, longitude, altitude, accuracy
+// with time in seconds, not decreasing, the coordinates as in `GpsFix` and
+// the accuracy the standard deviation of the position in meters. The fields
+// are separated as in `CsvImuInput`.
+pub struct CsvGpsInput {
+  reader: BufReader<File>,
+  path: PathBuf,
+  line: String,
+  line_number: usize,
+  // Next row that has not been returned yet.
+  next_row: Option<[f64; 5]>,
+  last_time: Option<f64>,
+}
+
+impl CsvGpsInput {
+  pub fn new(path: &Path) -> Result<CsvGpsInput> {
+    let file = File::open(path)
+      .context(format!("Failed to open GPS CSV file {}.", path.display()))?;
+    Ok(CsvGpsInput {
+      reader: BufReader::new(file),
+      path: path.to_path_buf(),
+      line: String::new(),
+      line_number: 0,
+      next_row: None,
+      last_time: None,
+    })
+  }
+
+  // Next fix with time at most `max_time`, if any.
+  pub fn next_until(&mut self, max_time: f64) -> Result<Option<InputData<'static>>> {
+    if self.next_row.is_none() {
+      self.next_row = self.read_row()?;
+    }
+    let row = match self.next_row {
+      Some(row) if row[0] <= max_time => row,
+      _ => return Ok(None),
+    };
+    self.next_row = None;
+    Ok(Some(InputData {
+      time: row[0],
+      sensor: InputDataSensor::Gps(GpsFix {
+        latitude: row[1],
+        longitude: row[2],
+        altitude: row[3],
+        accuracy: row[4],
+      }),
+    }))
+  }
+
+  fn read_row(&mut self) -> Result<Option<[f64; 5]>> {
+    loop {
+      self.line.clear();
+      if self.reader.read_line(&mut self.line)? == 0 { return Ok(None) }
+      self.line_number += 1;
+      let line = self.line.trim();
+      if line.is_empty() || line.starts_with('#') { continue }
+      let fields = match parse_csv_line(line) {
+        Ok(fields) => fields,
+        Err((column, _)) if self.line_number == 1 && column == 1 => continue, // Header.
+        Err((column, field)) => bail!("{}:{}: column {}: invalid number `{}`.",
+          self.path.display(), self.line_number, column, field),
+      };
+      if fields.len() < 5 {
+        bail!("{}:{}: expected 5 columns, found {}.", self.path.display(), self.line_number, fields.len());
+      }
+      let mut row = [0.; 5];
+      row.copy_from_slice(&fields[..5]);
+      check_time(&self.path, self.line_number, row[0], &mut self.last_time)?;
+      return Ok(Some(row));
+    }
+  }
+}
+
+// Image files from a CSV file, for the `csv` input format. Each row is
+//   time, left_path, right_path
+// with time in seconds, not decreasing, and the paths relative to the folder
+// of the file. Monocular rows have only the left path. Fields are separated by
+// commas and padded with whitespace. Lines starting with `#` and a header row
+// are skipped.
+pub struct CsvFrameInput {
+  reader: BufReader<File>,
+  path: PathBuf,
+  line: String,
+  line_number: usize,
+  // Next row that has not been returned yet.
+  next_row: Option<(f64, Vec<PathBuf>)>,
+  last_time: Option<f64>,
+}
+
+impl CsvFrameInput {
+  pub fn new(path: &Path) -> Result<CsvFrameInput> {
+    let file = File::open(path)
+      .context(format!("Failed to open frame CSV file {}.", path.display()))?;
+    Ok(CsvFrameInput {
+      reader: BufReader::new(file),
+      path: path.to_path_buf(),
+      line: String::new(),
+      line_number: 0,
+      next_row: None,
+      last_time: None,
+    })
+  }
+
+  // Time of the next frame, if any.
+  pub fn peek_time(&mut self) -> Result<Option<f64>> {
+    if self.next_row.is_none() {
+      self.next_row = self.read_row()?;
+    }
+    Ok(self.next_row.as_ref().map(|(time, _)| *time))
+  }
+
+  // Next frame time and image paths, if any.
+  pub fn next(&mut self) -> Result<Option<(f64, Vec<PathBuf>)>> {
+    self.peek_time()?;
+    Ok(self.next_row.take())
+  }
+
+  fn read_row(&mut self) -> Result<Option<(f64, Vec<PathBuf>)>> {
+    let folder = self.path.parent().unwrap_or(Path::new("")).to_path_buf();
+    loop {
+      self.line.clear();
+      if self.reader.read_line(&mut self.line)? == 0 { return Ok(None) }
+      self.line_number += 1;
+      let line = self.line.trim();
+      if line.is_empty() || line.starts_with('#') { continue }
+      let fields: Vec<&str> = line.split(',').map(|x| x.trim()).collect();
+      let time = match fields[0].parse::<f64>() {
+        Ok(time) => time,
+        Err(_) if self.line_number == 1 => continue, // Header.
+        Err(_) => bail!("{}:{}: invalid time `{}`.", self.path.display(), self.line_number, fields[0]),
+      };
+      let paths = &fields[1..fields.len().min(3)];
+      if paths.is_empty() || paths.iter().any(|path| path.is_empty()) {
+        bail!("{}:{}: expected a time and one or two image paths.", self.path.display(), self.line_number);
+      }
+      check_time(&self.path, self.line_number, time, &mut self.last_time)?;
+      return Ok(Some((time, paths.iter().map(|path| folder.join(path)).collect())));
+    }
+  }
+}
+
+// Fails if `time` on line `line_number` of the file is before the previous time.
+fn check_time(path: &Path, line_number: usize, time: f64, last_time: &mut Option<f64>) -> Result<()> {
+  if let Some(last_time) = *last_time {
+    if time < last_time {
+      bail!("{}:{}: time {} goes backwards from the previous {}.", path.display(), line_number, time, last_time);
+    }
+  }
+  *last_time = Some(time);
+  Ok(())
+}
+
+// Parse numeric fields separated by commas, or by spaces and tabs if the line
+// has no commas. On failure returns the 1-based column and text of the bad field.
+pub fn parse_csv_line(line: &str) -> std::result::Result<Vec<f64>, (usize, String)> {
+  let fields: Vec<&str> = if line.contains(',') {
+    line.split(',').map(|x| x.trim()).collect()
+  }
+  else {
+    line.split_whitespace().collect()
+  };
+  fields.iter().enumerate()
+    .map(|(i, field)| field.parse::<f64>().map_err(|_| (i + 1, field.to_string())))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_csv_line() {
+    assert_eq!(parse_csv_line("1.5e-3,2E2,-3.0e+1"), Ok(vec![1.5e-3, 200., -30.]));
+    assert_eq!(parse_csv_line("1\t2.5\t-3"), Ok(vec![1., 2.5, -3.]));
+    assert_eq!(parse_csv_line("  1 ,\t2 , 3e0  "), Ok(vec![1., 2., 3.]));
+    assert_eq!(parse_csv_line("1  2 \t 3"), Ok(vec![1., 2., 3.]));
+    assert_eq!(parse_csv_line("1, x, 3"), Err((2, "x".to_string())));
+    assert_eq!(parse_csv_line("1,,3"), Err((2, "".to_string())));
+  }
+
+  #[test]
+  fn test_csv_imu_input() {
+    let path = std::env::temp_dir().join(format!("violet-csv-imu-{}.csv", std::process::id()));
+    std::fs::write(&path, "\
+      time,gx,gy,gz,ax,ay,az\n\
+      # Comment.\n\
+      1.0e-2, 1, 2, 3, 4, 5, 6\n\
+      \n\
+      2e-2\t0\t0\t0\t0\t0\t9.81\n\
+      3e-2 0 0 oops 0 0 9.81\n").unwrap();
+    let mut input = CsvImuInput::new(&path).unwrap();
+
+    let x = input.next_until(0.015).unwrap().unwrap();
+    assert_eq!(x.time, 0.01);
+    assert!(matches!(x.sensor, InputDataSensor::Gyroscope(v) if v == Vector3d::new(1., 2., 3.)));
+    let x = input.next_until(0.015).unwrap().unwrap();
+    assert!(matches!(x.sensor, InputDataSensor::Accelerometer(v) if v == Vector3d::new(4., 5., 6.)));
+    assert!(input.next_until(0.015).unwrap().is_none());
+
+    let x = input.next_until(f64::INFINITY).unwrap().unwrap();
+    assert_eq!(x.time, 0.02);
+    input.next_until(f64::INFINITY).unwrap().unwrap();
+    let err = input.next_until(f64::INFINITY).err().unwrap().to_string();
+    assert!(err.contains(":6: column 4: invalid number `oops`."), "{}", err);
+    std::fs::remove_file(&path).unwrap();
+  }
+}
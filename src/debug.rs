@@ -11,10 +11,54 @@ pub struct DebugData {
   pub detection_mask: Vec<bool>,
   pub flow0: Vec<Feature>,
   pub flow1: Vec<Feature>,
+  // Camera, previous and current position of the features that failed the
+  // forward-backward check or the RANSAC of the optical flow on the last
+  // frame.
+  pub flow_rejected: Vec<(usize, Vector2d, Vector2d)>,
   pub tracks: Vec<Track>,
+  // Recent first camera positions of each track, oldest first.
+  pub trails: Vec<Vec<Vector2d>>,
   pub epipolar: Vec<DebugEpipolar>,
+  // Landmarks triangulated by the visual update on the last frame.
+  pub landmark_uncertainty: Vec<DebugLandmark>,
+  // Known landmarks reprojected using the ground-truth pose, for both cameras.
+  pub ground_truth_reprojections: [Vec<Vector2d>; 2],
   // Any images with a scaling factor, will be shown side by side.
   pub images: Vec<(Image, f64)>,
+  pub hud: HudStats,
+  // Filter resets after divergence, from the start of the run.
+  pub divergence_events: Vec<DivergenceEvent>,
+}
+
+// Values shown by `show_hud`, from the last processed frame.
+#[derive(Clone, Default)]
+pub struct HudStats {
+  pub frame_number: usize,
+  // Processed frames per second (wall clock).
+  pub fps: f64,
+  // Milliseconds spent processing the frame.
+  pub processing_time: f64,
+  // Tracks seen on the frame.
+  pub track_count: usize,
+  // Features detected on the frame.
+  pub detected_count: usize,
+  // Tracks ended by `max_track_length` and tracks started on the frame.
+  pub retired_count: usize,
+  pub new_track_count: usize,
+  // Tracks rejected by the visual update outlier gate.
+  pub outlier_count: usize,
+  // Meters per second.
+  pub speed: f64,
+  // Estimated IMU position in world coordinates.
+  pub position: Vector3d,
+  // See `Vio::get_reprojection_rms()`.
+  pub reprojection_rms: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DivergenceEvent {
+  pub frame_number: usize,
+  pub divergence: Divergence,
 }
 
 #[derive(Default)]
@@ -25,6 +69,19 @@ pub struct DebugEpipolar {
   pub curve1: Vec<Vector2d>,
 }
 
+// A triangulated landmark and its position covariance, which includes the
+// uncertainty of the poses it was triangulated from.
+#[derive(Clone)]
+pub struct DebugLandmark {
+  // Camera of the newest observation, in whose coordinates the covariance is.
+  pub camera: usize,
+  pub covariance: Matrix3d,
+  // The landmark reprojected to the camera and the derivative of the pixel
+  // with respect to `position`.
+  pub pixel: Vector2d,
+  pub d_pixel: Matrix23d,
+}
+
 #[derive(Default)]
 pub struct DebugData3d {
   pub pose_trail: Vec<Matrix4d>,
@@ -11,37 +11,248 @@ const CIRCLE: [[i32; 2]; 16] = [
   [ 0,  3], [-1,  3], [-2,  2], [-3,  1], [-3,  0], [-3, -1], [-2, -2], [-1, -3],
 ];
 
+// Half-width of the window for the Shi-Tomasi score. Needs to be smaller than
+// `CIRCLE_RADIUS` so that the gradients stay inside the image.
+const SCORE_RADIUS: i32 = 2;
+// Relative change of the threshold per relative error of the detection count,
+// see `target_feature_count`.
+const THRESHOLD_GAIN: f64 = 0.3;
+
+// Circular image region where features are not detected.
+#[derive(Clone, Copy, Debug)]
+pub struct ExclusionZone {
+  pub center: Vector2d,
+  pub radius: f64,
+}
+
+impl ExclusionZone {
+  pub fn contains(&self, point: Vector2d) -> bool {
+    (point - self.center).norm_squared() < self.radius * self.radius
+  }
+}
+
+// Static image region where features are not detected, see `detection_mask`.
+#[derive(Clone)]
+pub struct DetectionMask {
+  image: Image,
+}
+
+impl DetectionMask {
+  // Reads the `detection_mask` image, if set.
+  pub fn load(input_folder: &Path) -> Result<Option<DetectionMask>> {
+    let Some(path) = PARAMETER_SET.lock().unwrap().detection_mask.clone() else { return Ok(None) };
+    let mut image = Image::empty();
+    read_png(&input_folder.join(path), &mut image)?;
+    Ok(Some(DetectionMask { image }))
+  }
+
+  // The pixels with value zero are masked.
+  #[cfg(test)]
+  pub fn from_image(image: Image) -> DetectionMask {
+    DetectionMask { image }
+  }
+
+  pub fn check_size(&self, image: &Image) -> Result<()> {
+    if (image.width, image.height) != (self.image.width, self.image.height) {
+      bail!(
+        "Detection mask size {}x{} differs from the image size {}x{}.",
+        self.image.width, self.image.height, image.width, image.height,
+      );
+    }
+    Ok(())
+  }
+
+  // The mask is given in the coordinates of the input images, so it is
+  // flipped along with them, see `image_flip`.
+  pub fn flipped(&self, flip: ImageFlip) -> DetectionMask {
+    let mut image = Image::empty();
+    flip_image(&self.image, flip, &mut image);
+    DetectionMask { image }
+  }
+
+  // Points outside the image are not masked.
+  pub fn contains(&self, point: Vector2d) -> bool {
+    let (x, y) = (point[0].round(), point[1].round());
+    if x < 0. || y < 0. || x >= self.image.width as f64 || y >= self.image.height as f64 { return false }
+    self.image.value(x as usize, y as usize) == 0
+  }
+}
+
+// Cells of the detection image, see `detection_grid_cols`.
+struct DetectionGrid {
+  cols: usize,
+  rows: usize,
+  width: usize,
+  height: usize,
+  // Maximum number of features kept in each cell.
+  cap: usize,
+}
+
+// A detection in `DetectionGrid` waiting to be selected.
+struct GridCandidate {
+  cell: usize,
+  score: f64,
+  point: Vector2d,
+}
+
+impl DetectionGrid {
+  fn cell(&self, x: usize, y: usize) -> usize {
+    (y * self.rows / self.height) * self.cols + x * self.cols / self.width
+  }
+
+  // Number of candidates that fit under the caps of their cells.
+  fn kept_count(&self, candidates: &[GridCandidate]) -> usize {
+    let mut counts = vec![0; self.cols * self.rows];
+    for candidate in candidates {
+      counts[candidate.cell] += 1;
+    }
+    counts.iter().map(|n| usize::min(*n, self.cap)).sum()
+  }
+
+  // Keeps the highest scoring candidates of each cell up to the cap, and of
+  // those the `count` highest scoring ones, in the order of the scores.
+  fn select(&self, candidates: &mut Vec<GridCandidate>, count: usize) {
+    candidates.sort_by(|a, b| a.cell.cmp(&b.cell).then(b.score.total_cmp(&a.score)));
+    let mut cell_counts = vec![0; self.cols * self.rows];
+    candidates.retain(|candidate| {
+      cell_counts[candidate.cell] += 1;
+      cell_counts[candidate.cell] <= self.cap
+    });
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    candidates.truncate(count);
+  }
+}
+
 pub struct Detector {
   start_threshold: i16,
+  // See `target_feature_count`, zero if not used.
+  target_feature_count: usize,
+  // Adjusted threshold carried across frames.
+  threshold: f64,
+  // Number of corners found by the last call to `process()`.
+  detection_count: usize,
+  // See `detection_grid_cols`, zero if not used.
+  grid_cols: usize,
+  grid_rows: usize,
+  grid_candidates: Vec<GridCandidate>,
+  // Pyramid level the features are detected on.
+  level: usize,
+  // Minimum Shi-Tomasi score for detections to become tracks.
+  track_min_score: f64,
+  // Maximum structure tensor anisotropy for detections to become tracks.
+  edge_max_anisotropy: f64,
+  exclusion: Option<ExclusionZone>,
+  detection_mask: Option<DetectionMask>,
   mask: Vec<bool>,
+  // Detections that were not good enough to track.
+  unpromoted: Vec<Feature>,
 }
 
 impl Detector {
   pub fn new() -> Detector {
+    let p = PARAMETER_SET.lock().unwrap();
+    if p.detection_level > p.lk_levels {
+      warn!("Detection level {} is larger than the number of levels {}.", p.detection_level, p.lk_levels);
+    }
     Detector {
-      start_threshold: 128,
+      start_threshold: p.detection_threshold,
+      target_feature_count: p.target_feature_count,
+      threshold: p.detection_threshold as f64,
+      detection_count: 0,
+      grid_cols: p.detection_grid_cols,
+      grid_rows: p.detection_grid_rows,
+      grid_candidates: vec![],
+      level: p.detection_level.min(p.lk_levels),
+      track_min_score: p.track_min_score,
+      edge_max_anisotropy: p.edge_max_anisotropy,
+      exclusion: None,
+      detection_mask: None,
       mask: vec![],
+      unpromoted: vec![],
     }
   }
 
+  pub fn set_exclusion(&mut self, exclusion: Option<ExclusionZone>) {
+    self.exclusion = exclusion;
+  }
+
+  pub fn set_detection_mask(&mut self, detection_mask: Option<DetectionMask>) {
+    self.detection_mask = detection_mask;
+  }
+
+  #[cfg(test)]
+  pub fn get_unpromoted(&self) -> &[Feature] {
+    &self.unpromoted
+  }
+
+  #[cfg(test)]
+  pub fn get_threshold(&self) -> f64 {
+    self.threshold
+  }
+
+  #[cfg(test)]
+  pub fn get_detection_count(&self) -> usize {
+    self.detection_count
+  }
+
+  // Detects on the configured pyramid level of the camera image. The returned
+  // coordinates are at full resolution.
   pub fn process(
     &mut self,
-    image: &Image,
+    frame_camera: &FrameCamera,
     detections: &mut Vec<Feature>,
     needed_features_count: usize,
     next_id: &mut TrackId,
   ) {
+    let level = self.level.min(frame_camera.pyramid.levels.len());
+    let image = frame_camera.get_level(level);
+    let scale = frame_camera.pyramid.level_scale(level);
     assert!(image.width > 1 + 2 * CIRCLE_RADIUS);
     assert!(image.height > 1 + 2 * CIRCLE_RADIUS);
     detections.clear();
+    self.unpromoted.clear();
     if needed_features_count == 0 { return }
     self.mask.clear();
     for _ in 0 .. (image.width * image.height) {
       self.mask.push(false);
     }
-    let mut threshold = self.start_threshold;
+    if let Some(exclusion) = self.exclusion {
+      for y in 0..image.height {
+        for x in 0..image.width {
+          if exclusion.contains(scale * Vector2d::new(x as f64, y as f64)) {
+            self.mask[y * image.width + x] = true;
+          }
+        }
+      }
+    }
+    if let Some(detection_mask) = &self.detection_mask {
+      for y in 0..image.height {
+        for x in 0..image.width {
+          if detection_mask.contains(scale * Vector2d::new(x as f64, y as f64)) {
+            self.mask[y * image.width + x] = true;
+          }
+        }
+      }
+    }
+    let adaptive = self.target_feature_count > 0;
+    let mut threshold = if adaptive { self.threshold.round() as i16 } else { self.start_threshold };
     let mask_radius = ((image.width.max(image.height) as f32) / 100.0).round() as i32;
-    let threshold_halving_iterations = 4;
+    let threshold_halving_iterations = if adaptive { 1 } else { 4 };
+    self.detection_count = 0;
+    let grid = if self.grid_cols > 0 && self.grid_rows > 0 {
+      let cols = self.grid_cols.min(image.width);
+      let rows = self.grid_rows.min(image.height);
+      Some(DetectionGrid {
+        cols,
+        rows,
+        width: image.width,
+        height: image.height,
+        cap: needed_features_count.div_ceil(cols * rows),
+      })
+    } else {
+      None
+    };
+    self.grid_candidates.clear();
 
     'detection:
     for _ in 0..threshold_halving_iterations {
@@ -49,30 +260,84 @@ impl Detector {
         for y in CIRCLE_RADIUS .. (image.height - CIRCLE_RADIUS) {
           if self.mask[y * image.width + x] { continue }
           if !self.detect_at_pixel(x as i32, y as i32, image, threshold) { continue }
-          detections.push(Feature {
-            point: Vector2d::new(x as f64, y as f64),
+          add_mask(&mut self.mask, x as i32, y as i32, image.width, image.height, mask_radius);
+          self.detection_count += 1;
+          // Only reached when adapting the threshold, to count the rest.
+          if detections.len() >= needed_features_count { continue }
+          let feature = Feature {
+            point: scale * refine_corner(image, Vector2d::new(x as f64, y as f64)),
             id: *next_id,
-          });
+          };
+          // The refinement may move the point into the mask.
+          if self.detection_mask.as_ref().is_some_and(|mask| mask.contains(feature.point)) { continue }
+          if !self.promote(image, x as i32, y as i32) {
+            self.unpromoted.push(feature);
+            continue;
+          }
+          if let Some(grid) = &grid {
+            self.grid_candidates.push(GridCandidate {
+              cell: grid.cell(x, y),
+              score: shi_tomasi_score(structure_tensor(image, x as i32, y as i32)),
+              point: feature.point,
+            });
+            continue;
+          }
+          detections.push(feature);
           next_id.0 += 1;
-          add_mask(&mut self.mask, x as i32, y as i32, image.width, image.height, mask_radius);
-          if detections.len() >= needed_features_count { break 'detection }
+          if !adaptive && detections.len() >= needed_features_count { break 'detection }
         }
       }
+      if let Some(grid) = &grid {
+        if grid.kept_count(&self.grid_candidates) >= needed_features_count { break }
+      }
       threshold /= 2;
     }
+    if adaptive { self.adapt_threshold() }
+    if let Some(grid) = &grid {
+      grid.select(&mut self.grid_candidates, needed_features_count);
+      for candidate in &self.grid_candidates {
+        detections.push(Feature { point: candidate.point, id: *next_id });
+        next_id.0 += 1;
+      }
+    }
 
     let d = &mut DEBUG_DATA.lock().unwrap();
     let p = PARAMETER_SET.lock().unwrap();
     if p.show_features {
       d.detections.clear();
       d.detections.extend(detections.iter());
+      d.detections.extend(self.unpromoted.iter());
     }
     if p.show_mask {
+      // Drawn at full resolution.
       d.detection_mask.clear();
-      d.detection_mask.extend(self.mask.iter());
+      let full = &frame_camera.image;
+      let level_coordinate = |x: usize, size: usize| ((x as f64 / scale) as usize).min(size - 1);
+      for y in 0..full.height {
+        let y = level_coordinate(y, image.height);
+        for x in 0..full.width {
+          d.detection_mask.push(self.mask[y * image.width + level_coordinate(x, image.width)]);
+        }
+      }
     }
   }
 
+  // Proportional control of the threshold towards `target_feature_count`
+  // detections. Higher thresholds find fewer corners.
+  fn adapt_threshold(&mut self) {
+    let target = self.target_feature_count as f64;
+    let error = ((self.detection_count as f64 - target) / target).clamp(-1., 1.);
+    self.threshold = (self.threshold * (1. + THRESHOLD_GAIN * error)).clamp(1., 255.);
+  }
+
+  fn promote(&self, image: &Image, x: i32, y: i32) -> bool {
+    if self.track_min_score <= 0. && self.edge_max_anisotropy >= 1. { return true }
+    let tensor = structure_tensor(image, x, y);
+    if shi_tomasi_score(tensor) < self.track_min_score { return false }
+    if self.edge_max_anisotropy < 1. && anisotropy(tensor) > self.edge_max_anisotropy { return false }
+    true
+  }
+
   fn detect_at_pixel(
     &mut self,
     x: i32,
@@ -87,6 +352,56 @@ impl Detector {
   }
 }
 
+// Mean squared intensity gradient magnitude over the image, sampled on a
+// sparse grid. Measures how much texture there is to detect features from.
+pub fn texture_energy(image: &Image) -> f64 {
+  let step = 2;
+  let mut sum = 0.;
+  let mut n = 0;
+  for y in (1..(image.height as i32 - 1)).step_by(step) {
+    for x in (1..(image.width as i32 - 1)).step_by(step) {
+      let gx = 0.5 * (image.value_i32(x + 1, y) as f64 - image.value_i32(x - 1, y) as f64);
+      let gy = 0.5 * (image.value_i32(x, y + 1) as f64 - image.value_i32(x, y - 1) as f64);
+      sum += gx * gx + gy * gy;
+      n += 1;
+    }
+  }
+  if n == 0 { 0. } else { sum / n as f64 }
+}
+
+// Averaged products of the intensity gradients (xx, yy, xy) around the pixel.
+fn structure_tensor(image: &Image, x: i32, y: i32) -> (f64, f64, f64) {
+  let mut xx = 0.;
+  let mut yy = 0.;
+  let mut xy = 0.;
+  for v in (y - SCORE_RADIUS)..=(y + SCORE_RADIUS) {
+    for u in (x - SCORE_RADIUS)..=(x + SCORE_RADIUS) {
+      let gx = 0.5 * (image.value_i32(u + 1, v) as f64 - image.value_i32(u - 1, v) as f64);
+      let gy = 0.5 * (image.value_i32(u, v + 1) as f64 - image.value_i32(u, v - 1) as f64);
+      xx += gx * gx;
+      yy += gy * gy;
+      xy += gx * gy;
+    }
+  }
+  let n = ((2 * SCORE_RADIUS + 1) * (2 * SCORE_RADIUS + 1)) as f64;
+  (xx / n, yy / n, xy / n)
+}
+
+// Smaller eigenvalue of the structure tensor, in squared intensity units.
+// Large when the image has strong gradients in two directions, which makes
+// the point reliable to track.
+fn shi_tomasi_score((xx, yy, xy): (f64, f64, f64)) -> f64 {
+  0.5 * (xx + yy) - (0.25 * (xx - yy).powi(2) + xy * xy).sqrt()
+}
+
+// Difference of the structure tensor eigenvalues relative to their sum. Zero
+// for isotropic corners and one for straight edges.
+fn anisotropy((xx, yy, xy): (f64, f64, f64)) -> f64 {
+  let sum = xx + yy;
+  if sum <= 0. { return 0. }
+  ((xx - yy).powi(2) + 4. * xy * xy).sqrt() / sum
+}
+
 fn continuous<F: Fn(u8) -> bool>(x: i32, y: i32, image: &Image, f: F) -> bool {
   // There are also other quick rejection schemes depending on the threshold.
   if FAST_VARIANT_N >= 9
@@ -138,7 +453,7 @@ impl Iterator for CircleIterator {
 }
 
 fn add_mask(
-  mask: &mut Vec<bool>,
+  mask: &mut [bool],
   cx: i32,
   cy: i32,
   width: usize,
@@ -153,3 +468,219 @@ fn add_mask(
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn make_camera(image: Image) -> FrameCamera {
+    let mut pyramid = Pyramid::empty();
+    Pyramid::compute(&mut pyramid, &image, 3, 2.).unwrap();
+    FrameCamera { image, pyramid }
+  }
+
+  #[test]
+  fn test_track_promotion() {
+    let _guard = set_test_parameters(|p| p.track_min_score = 100.);
+    let mut image = Image { data: vec![10; 64 * 64], width: 64, height: 64 };
+    // A high-contrast and a faint blob.
+    for (cx, cy, value) in [(16, 16, 210), (48, 48, 30)] {
+      for y in (cy - 1)..=(cy + 1) {
+        for x in (cx - 1)..=(cx + 1) {
+          image.set_value(x, y, value);
+        }
+      }
+    }
+    let near = |f: &Feature, c: f64| (f.point - Vector2d::new(c, c)).norm() < 3.;
+
+    let mut detector = Detector::new();
+    let mut detections = vec![];
+    let mut next_id = TrackId(0);
+    detector.process(&make_camera(image), &mut detections, 100, &mut next_id);
+    assert!(!detections.is_empty());
+    assert!(detections.iter().all(|f| near(f, 16.)));
+    assert!(!detector.get_unpromoted().is_empty());
+    assert!(detector.get_unpromoted().iter().all(|f| near(f, 48.)));
+    assert_eq!(next_id.0, detections.len());
+  }
+
+  #[test]
+  fn test_edge_anisotropy() {
+    let _guard = set_test_parameters(|p| p.edge_max_anisotropy = 0.4);
+    let mut image = Image { data: vec![10; 64 * 64], width: 64, height: 64 };
+    // Interior corner: a bright blob.
+    for y in 15..=17 {
+      for x in 15..=17 {
+        image.set_value(x, y, 210);
+      }
+    }
+    // Silhouette: tip of a thin bright structure ending at (48, 40).
+    for x in 30..=48 {
+      image.set_value(x, 40, 210);
+    }
+    let blob = structure_tensor(&image, 16, 16);
+    let edge = structure_tensor(&image, 48, 40);
+    assert!(anisotropy(blob) < 0.1);
+    assert!(anisotropy(edge) > 0.4);
+
+    let mut detector = Detector::new();
+    let mut detections = vec![];
+    let mut next_id = TrackId(0);
+    detector.process(&make_camera(image), &mut detections, 100, &mut next_id);
+    let near = |f: &Feature, x: f64, y: f64| (f.point - Vector2d::new(x, y)).norm() < 3.;
+    assert!(!detections.is_empty());
+    assert!(detections.iter().all(|f| near(f, 16., 16.)));
+    assert!(detector.get_unpromoted().iter().any(|f| near(f, 48., 40.)));
+  }
+
+  #[test]
+  fn test_detection_level() {
+    let mut image = Image { data: vec![10; 128 * 128], width: 128, height: 128 };
+    // A blob that is a few pixels wide on the half resolution level.
+    for y in 37..=43 {
+      for x in 77..=83 {
+        image.set_value(x, y, 210);
+      }
+    }
+    let camera = make_camera(image);
+    let detect = |camera: &FrameCamera, detection_level: usize| {
+      let _guard = set_test_parameters(|p| p.detection_level = detection_level);
+      let mut detections = vec![];
+      Detector::new().process(camera, &mut detections, 100, &mut TrackId(0));
+      detections.iter().map(|f| f.point).collect::<Vec<_>>()
+    };
+
+    let points = detect(&camera, 1);
+    assert!(!points.is_empty());
+    for point in &points {
+      assert!((point - Vector2d::new(80., 40.)).norm() < 3.);
+    }
+    // Detecting on level one is detecting on the half resolution image, with
+    // the coordinates scaled to full resolution.
+    let half_points = detect(&make_camera(camera.get_level(1).clone()), 0);
+    assert_eq!(points.len(), half_points.len());
+    for (point, half_point) in points.iter().zip(&half_points) {
+      assert!((point - 2. * half_point).amax() < 1e-12);
+    }
+    // The blob is too large to be a corner at full resolution.
+    assert!(detect(&camera, 0).is_empty());
+  }
+
+  #[test]
+  fn test_target_feature_count() {
+    let _guard = set_test_parameters(|p| {
+      p.target_feature_count = 150;
+      p.detection_threshold = 128;
+    });
+    // The same frame over and over, like a video of a constant texture.
+    let camera = make_camera(textured_image(320, 240, 5));
+    let mut detector = Detector::new();
+    let mut detections = vec![];
+    let mut next_id = TrackId(0);
+    let mut counts = vec![];
+    let mut thresholds = vec![];
+    for _ in 0..10 {
+      // Fewer features are needed than counted.
+      detector.process(&camera, &mut detections, 20, &mut next_id);
+      assert!(detections.len() <= 20);
+      counts.push(detector.get_detection_count());
+      thresholds.push(detector.get_threshold());
+    }
+    // Too few corners at the initial threshold, then close to the target
+    // within a few frames.
+    assert!(counts[0] < 100);
+    for (count, threshold) in counts[3..].iter().zip(&thresholds[3..]) {
+      assert!((*count as f64 - 150.).abs() < 20., "{:?}", counts);
+      assert!((threshold - thresholds[9]).abs() < 0.05 * thresholds[9], "{:?}", thresholds);
+    }
+  }
+
+  #[test]
+  fn test_detection_grid() {
+    // Strong texture in the top-left corner and faint texture elsewhere.
+    let (width, height) = (160, 120);
+    let strong = textured_image(width, height, 6);
+    let faint = textured_image(width, height, 7);
+    let mut image = Image { data: vec![0; width * height], width, height };
+    for y in 0..height {
+      for x in 0..width {
+        let v = if x < 40 && y < 30 { strong.value(x, y) } else { 100 + faint.value(x, y) / 6 };
+        image.set_value(x, y, v);
+      }
+    }
+    let camera = make_camera(image);
+    let cells = |detections: &[Feature]| {
+      let mut counts = [0; 16];
+      for f in detections {
+        counts[(f.point[1] as usize * 4 / height) * 4 + f.point[0] as usize * 4 / width] += 1;
+      }
+      counts
+    };
+
+    let detect = || {
+      let mut detector = Detector::new();
+      let mut detections = vec![];
+      let mut next_id = TrackId(0);
+      detector.process(&camera, &mut detections, 32, &mut next_id);
+      assert_eq!(next_id.0, detections.len());
+      detections
+    };
+    {
+      let _guard = set_test_parameters(|_| {});
+      let detections = detect();
+      assert_eq!(detections.len(), 32);
+      assert!(cells(&detections)[0] >= 30, "{:?}", cells(&detections));
+    }
+
+    let _guard = set_test_parameters(|p| {
+      p.detection_grid_cols = 4;
+      p.detection_grid_rows = 4;
+    });
+    let detections = detect();
+    // At most two features in each cell, and few cells without any.
+    let counts = cells(&detections);
+    assert!(counts.iter().all(|n| *n <= 2), "{:?}", counts);
+    assert!(counts.iter().filter(|n| **n > 0).count() >= 12, "{:?}", counts);
+    assert!(detections.len() >= 24);
+  }
+
+  #[test]
+  fn test_detection_mask() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let camera = make_camera(textured_image(width, height, 8));
+    let in_rectangle = |f: &Feature| f.point[0] >= 40. && f.point[0] < 120. && f.point[1] >= 80.;
+    let detect = |detector: &mut Detector| {
+      let mut detections = vec![];
+      let mut next_id = TrackId(0);
+      detector.process(&camera, &mut detections, 200, &mut next_id);
+      detections
+    };
+
+    let mut detector = Detector::new();
+    let detections = detect(&mut detector);
+    assert!(detections.iter().filter(|f| in_rectangle(f)).count() > 10);
+
+    // Mask a rectangle at the bottom, like a timestamp overlay.
+    let mut mask = Image { data: vec![255; width * height], width, height };
+    for y in 80..height {
+      for x in 40..120 {
+        mask.set_value(x, y, 0);
+      }
+    }
+    let mask = DetectionMask::from_image(mask);
+    assert!(mask.contains(Vector2d::new(50.2, 100.)));
+    assert!(!mask.contains(Vector2d::new(20., 100.)));
+    assert!(!mask.contains(Vector2d::new(50., 200.)));
+    assert!(mask.check_size(&camera.image).is_ok());
+    assert!(mask.check_size(&Image { data: vec![0; 4], width: 2, height: 2 }).is_err());
+    let flipped = mask.flipped(ImageFlip::Both);
+    assert!(flipped.contains(Vector2d::new(159. - 50., 119. - 100.)));
+    assert!(!flipped.contains(Vector2d::new(50., 100.)));
+    assert!(mask.flipped(ImageFlip::None).contains(Vector2d::new(50., 100.)));
+    detector.set_detection_mask(Some(mask));
+    let detections = detect(&mut detector);
+    assert!(detections.len() > 50);
+    assert!(!detections.iter().any(in_rectangle));
+  }
+}
@@ -0,0 +1,177 @@
+// EuRoC MAV datasets in the ASL folder format, as downloaded:
+//   mav0/cam0/data.csv       timestamp, file name
+//   mav0/cam0/data/*.png
+//   mav0/cam1/...            the same for the second camera
+//   mav0/imu0/data.csv       timestamp, gx, gy, gz, ax, ay, az
+// Timestamps are integer nanoseconds. Both cameras are triggered together, so
+// a stereo frame is the pair of images with the same timestamp. Without the
+// `cam1` folder the frames are monocular. The calibration is not read from the
+// `sensor.yaml` files, see `Camera::load()`.
+
+use crate::all::*;
+
+use std::collections::HashMap;
+
+const ROOT_FOLDER_NAME: &str = "mav0";
+const NANOSECONDS: f64 = 1e9;
+
+pub struct EurocInput {
+  // Timestamps and image paths of the frames seen by all the cameras.
+  frames: Vec<(u64, Vec<PathBuf>)>,
+  // Timestamps and gyroscope and accelerometer values.
+  imu: Vec<(u64, [f64; 6])>,
+  next_frame: usize,
+  next_imu: usize,
+  // The accelerometer half of the last IMU sample, returned after the gyroscope.
+  pending: Option<(f64, Vector3d)>,
+  images: Vec<Image>,
+}
+
+impl EurocInput {
+  // The `mav0` folder of the dataset, if `path` is a EuRoC dataset or its `mav0` folder.
+  pub fn find_root(path: &Path) -> Option<PathBuf> {
+    [path.join(ROOT_FOLDER_NAME), path.to_path_buf()].into_iter()
+      .find(|root| root.join("cam0").join("data.csv").exists())
+  }
+
+  pub fn new(root: &Path) -> Result<EurocInput> {
+    let mut cameras = vec![root.join("cam0")];
+    let camera1 = root.join("cam1");
+    if camera1.join("data.csv").exists() { cameras.push(camera1) }
+    let rows = cameras.iter()
+      .map(|camera| read_rows(&camera.join("data.csv")))
+      .collect::<Result<Vec<_>>>()?;
+    let other_rows: Vec<HashMap<u64, &Vec<String>>> = rows[1..].iter()
+      .map(|rows| rows.iter().map(|(time, fields)| (*time, fields)).collect())
+      .collect();
+    let mut frames = vec![];
+    let mut unmatched = 0;
+    for (time, fields0) in &rows[0] {
+      let mut fields = vec![fields0];
+      fields.extend(other_rows.iter().filter_map(|rows| rows.get(time).copied()));
+      if fields.len() < cameras.len() {
+        unmatched += 1;
+        continue;
+      }
+      let paths = cameras.iter().zip(fields)
+        .map(|(camera, fields)| camera.join("data").join(fields.first().cloned().unwrap_or_default()))
+        .collect();
+      frames.push((*time, paths));
+    }
+    if unmatched > 0 {
+      warn!("Skipping {} frames of the first camera without an image from the second one.", unmatched);
+    }
+
+    let imu_path = root.join("imu0").join("data.csv");
+    let mut imu = vec![];
+    for (time, fields) in read_rows(&imu_path)? {
+      if fields.len() < 6 {
+        bail!("{}: expected 7 columns at time {}, found {}.", imu_path.display(), time, fields.len() + 1);
+      }
+      let mut values = [0.; 6];
+      for i in 0..6 {
+        values[i] = fields[i].parse()
+          .map_err(|_| anyhow!("{}: invalid number `{}` at time {}.", imu_path.display(), fields[i], time))?;
+      }
+      imu.push((time, values));
+    }
+    Ok(EurocInput {
+      frames,
+      imu,
+      next_frame: 0,
+      next_imu: 0,
+      pending: None,
+      images: vec![],
+    })
+  }
+
+  // The IMU samples and frames in timestamp order. IMU samples go before a
+  // frame with the same timestamp.
+  pub fn next(&mut self) -> Result<Option<InputData<'_>>> {
+    if let Some((time, v)) = self.pending.take() {
+      return Ok(Some(InputData { time, sensor: InputDataSensor::Accelerometer(v) }));
+    }
+    let frame_time = self.frames.get(self.next_frame).map(|(time, _)| *time);
+    if let Some((time, v)) = self.imu.get(self.next_imu) {
+      if frame_time.is_none_or(|frame_time| *time <= frame_time) {
+        self.next_imu += 1;
+        let time = *time as f64 / NANOSECONDS;
+        self.pending = Some((time, Vector3d::new(v[3], v[4], v[5])));
+        return Ok(Some(InputData {
+          time,
+          sensor: InputDataSensor::Gyroscope(Vector3d::new(v[0], v[1], v[2])),
+        }));
+      }
+    }
+    let Some(frame_time) = frame_time else { return Ok(None) };
+    let paths = &self.frames[self.next_frame].1;
+    self.next_frame += 1;
+    self.images.resize_with(paths.len(), Image::empty);
+    for (path, image) in paths.iter().zip(&mut self.images) {
+      read_png(path, image)?;
+    }
+    Ok(Some(InputData {
+      time: frame_time as f64 / NANOSECONDS,
+      sensor: InputDataSensor::Frame(InputFrame { images: self.images.iter().collect() }),
+    }))
+  }
+}
+
+// Rows of a EuRoC CSV file as the timestamp and the other fields, sorted by
+// the timestamp. Comment lines, such as the header, are skipped.
+fn read_rows(path: &Path) -> Result<Vec<(u64, Vec<String>)>> {
+  let s = std::fs::read_to_string(path)
+    .context(format!("Failed to read file {}.", path.display()))?;
+  let mut rows = vec![];
+  for (i, line) in s.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') { continue }
+    let mut fields = line.split(',').map(|x| x.trim());
+    let time = fields.next().unwrap_or_default();
+    let time = time.parse::<u64>()
+      .map_err(|_| anyhow!("{}:{}: invalid timestamp `{}`.", path.display(), i + 1, time))?;
+    rows.push((time, fields.map(|x| x.to_string()).collect()));
+  }
+  rows.sort_by_key(|(time, _)| *time);
+  Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_euroc_input() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/euroc");
+    assert_eq!(EurocInput::find_root(&path), Some(path.join("mav0")));
+    assert_eq!(EurocInput::find_root(&path.join("mav0")), Some(path.join("mav0")));
+    assert!(EurocInput::find_root(&path.join("mav0/imu0")).is_none());
+
+    // The frame at 8 ms has no image from the second camera and is skipped.
+    let mut input = Input::new(&path).unwrap();
+    let mut events = vec![];
+    while let Some(data) = input.next().unwrap() {
+      let ms = ((data.time - 1403636580.) * 1e3).round() as usize;
+      match data.sensor {
+        InputDataSensor::Gyroscope(v) => {
+          assert!((v - Vector3d::new(0.001 * ms as f64, -0.02, 0.03)).norm() < 1e-12);
+          events.push(format!("g{}", ms));
+        },
+        InputDataSensor::Accelerometer(v) => {
+          assert!((v - Vector3d::new(9.81, 0.1, -0.001 * ms as f64)).norm() < 1e-12);
+          events.push(format!("a{}", ms));
+        },
+        InputDataSensor::Frame(frame) => {
+          frame.validate().unwrap();
+          for (j, image) in frame.images.iter().enumerate() {
+            assert_eq!((image.width, image.height), (16, 16));
+            assert_eq!(image.value(3, 7), (100 * j + 10 * ms + 3) as u8);
+          }
+          events.push(format!("f{}", ms));
+        },
+        InputDataSensor::GroundTruth(_) | InputDataSensor::Gps(_) => unreachable!(),
+      }
+    }
+    assert_eq!(events, ["g0", "a0", "g5", "a5", "f5", "g10", "a10", "f12", "g15", "a15"]);
+  }
+}
@@ -0,0 +1,260 @@
+// Compact binary log of the `InputData` consumed during a run. Replaying the
+// log gives exactly the same input as the original run, which together with
+// the same seed reproduces the results.
+//
+// Format: a header followed by records of
+//   tag: u8, time: f64, payload,
+// where the payload depends on the tag. All numbers are little-endian.
+
+use crate::all::*;
+
+use std::io::{BufWriter, Write};
+
+const HEADER: &[u8; 8] = b"VIOLOG1\n";
+
+const TAG_GYROSCOPE: u8 = 0;
+const TAG_ACCELEROMETER: u8 = 1;
+const TAG_FRAME: u8 = 2;
+const TAG_GROUND_TRUTH: u8 = 3;
+const TAG_GPS: u8 = 4;
+
+pub struct EventLogWriter {
+  writer: BufWriter<File>,
+}
+
+pub struct EventLogReader {
+  reader: BufReader<File>,
+  images: Vec<Image>,
+}
+
+impl EventLogWriter {
+  pub fn new(path: &Path) -> Result<EventLogWriter> {
+    let file = File::create(path)
+      .context(format!("Failed to create event log {}.", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(HEADER)?;
+    Ok(EventLogWriter { writer })
+  }
+
+  pub fn write(&mut self, input_data: &InputData) -> Result<()> {
+    let w = &mut self.writer;
+    let write_f64s = |w: &mut BufWriter<File>, v: &[f64]| -> Result<()> {
+      for x in v { w.write_all(&x.to_le_bytes())? }
+      Ok(())
+    };
+    match &input_data.sensor {
+      InputDataSensor::Gyroscope(v) => {
+        w.write_all(&[TAG_GYROSCOPE])?;
+        write_f64s(w, &[input_data.time])?;
+        write_f64s(w, v.as_slice())?;
+      },
+      InputDataSensor::Accelerometer(v) => {
+        w.write_all(&[TAG_ACCELEROMETER])?;
+        write_f64s(w, &[input_data.time])?;
+        write_f64s(w, v.as_slice())?;
+      },
+      InputDataSensor::Frame(frame) => {
+        w.write_all(&[TAG_FRAME])?;
+        write_f64s(w, &[input_data.time])?;
+        w.write_all(&(frame.images.len() as u32).to_le_bytes())?;
+        for image in &frame.images {
+          w.write_all(&(image.width as u32).to_le_bytes())?;
+          w.write_all(&(image.height as u32).to_le_bytes())?;
+          w.write_all(&image.data)?;
+        }
+      },
+      InputDataSensor::GroundTruth(T) => {
+        w.write_all(&[TAG_GROUND_TRUTH])?;
+        write_f64s(w, &[input_data.time])?;
+        write_f64s(w, T.as_slice())?;
+      },
+      InputDataSensor::Gps(fix) => {
+        w.write_all(&[TAG_GPS])?;
+        write_f64s(w, &[input_data.time, fix.latitude, fix.longitude, fix.altitude, fix.accuracy])?;
+      },
+    }
+    Ok(())
+  }
+}
+
+impl Drop for EventLogWriter {
+  fn drop(&mut self) {
+    if let Err(err) = self.writer.flush() {
+      warn!("Failed to flush event log: {}", err);
+    }
+  }
+}
+
+impl EventLogReader {
+  pub fn new(path: &Path) -> Result<EventLogReader> {
+    let file = File::open(path)
+      .context(format!("Failed to open event log {}.", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if &header != HEADER {
+      bail!("{} is not an event log.", path.display());
+    }
+    Ok(EventLogReader {
+      reader,
+      images: vec![],
+    })
+  }
+
+  pub fn next(&mut self) -> Result<Option<InputData<'_>>> {
+    let mut tag = [0; 1];
+    match self.reader.read(&mut tag) {
+      Ok(0) => return Ok(None),
+      Err(err) => bail!("Failed to read event log. {}", err),
+      _ => {},
+    }
+    let time = read_f64(&mut self.reader)?;
+    let sensor = match tag[0] {
+      TAG_GYROSCOPE => InputDataSensor::Gyroscope(read_vector3d(&mut self.reader)?),
+      TAG_ACCELEROMETER => InputDataSensor::Accelerometer(read_vector3d(&mut self.reader)?),
+      TAG_FRAME => {
+        let n = read_u32(&mut self.reader)? as usize;
+        self.images.resize(n, Image::empty());
+        for image in self.images.iter_mut() {
+          image.width = read_u32(&mut self.reader)? as usize;
+          image.height = read_u32(&mut self.reader)? as usize;
+          image.data.resize(image.width * image.height, 0);
+          self.reader.read_exact(&mut image.data)?;
+        }
+        InputDataSensor::Frame(InputFrame {
+          images: self.images.iter().collect(),
+        })
+      },
+      TAG_GROUND_TRUTH => {
+        let mut T = Matrix4d::zeros();
+        for i in 0..16 {
+          T[i] = read_f64(&mut self.reader)?;
+        }
+        InputDataSensor::GroundTruth(T)
+      },
+      TAG_GPS => InputDataSensor::Gps(GpsFix {
+        latitude: read_f64(&mut self.reader)?,
+        longitude: read_f64(&mut self.reader)?,
+        altitude: read_f64(&mut self.reader)?,
+        accuracy: read_f64(&mut self.reader)?,
+      }),
+      x => bail!("Unknown event log record type {}.", x),
+    };
+    Ok(Some(InputData { time, sensor }))
+  }
+}
+
+fn read_f64(reader: &mut BufReader<File>) -> Result<f64> {
+  let mut bytes = [0; 8];
+  reader.read_exact(&mut bytes).context("Truncated event log.")?;
+  Ok(f64::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut BufReader<File>) -> Result<u32> {
+  let mut bytes = [0; 4];
+  reader.read_exact(&mut bytes).context("Truncated event log.")?;
+  Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_vector3d(reader: &mut BufReader<File>) -> Result<Vector3d> {
+  Ok(Vector3d::new(read_f64(reader)?, read_f64(reader)?, read_f64(reader)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_record_and_replay() {
+    let path = std::env::temp_dir().join(format!("violet-event-log-{}.bin", std::process::id()));
+    let image0 = Image { data: (0..12).collect(), width: 4, height: 3 };
+    let image1 = Image { data: (100..112).collect(), width: 4, height: 3 };
+    let mut T = Matrix4d::identity();
+    T[(0, 3)] = 1.5;
+    let fix = GpsFix { latitude: 60.17, longitude: 24.94, altitude: 20.5, accuracy: 3. };
+    {
+      let mut writer = EventLogWriter::new(&path).unwrap();
+      let session = [
+        InputData { time: 0.1, sensor: InputDataSensor::Gyroscope(Vector3d::new(0.1, -0.2, 0.3)) },
+        InputData { time: 0.1, sensor: InputDataSensor::Accelerometer(Vector3d::new(0., 0., 9.81)) },
+        InputData { time: 0.15, sensor: InputDataSensor::GroundTruth(T) },
+        InputData { time: 0.18, sensor: InputDataSensor::Gps(fix) },
+        InputData { time: 0.2, sensor: InputDataSensor::Frame(InputFrame { images: vec![&image0, &image1] }) },
+      ];
+      for input_data in &session {
+        writer.write(input_data).unwrap();
+      }
+    }
+
+    let mut reader = EventLogReader::new(&path).unwrap();
+    let x = reader.next().unwrap().unwrap();
+    assert_eq!(x.time, 0.1);
+    assert!(matches!(x.sensor, InputDataSensor::Gyroscope(v) if v == Vector3d::new(0.1, -0.2, 0.3)));
+    let x = reader.next().unwrap().unwrap();
+    assert!(matches!(x.sensor, InputDataSensor::Accelerometer(v) if v == Vector3d::new(0., 0., 9.81)));
+    let x = reader.next().unwrap().unwrap();
+    assert_eq!(x.time, 0.15);
+    assert!(matches!(x.sensor, InputDataSensor::GroundTruth(v) if v == T));
+    let x = reader.next().unwrap().unwrap();
+    assert_eq!(x.time, 0.18);
+    assert!(matches!(x.sensor, InputDataSensor::Gps(v) if v == fix));
+    let x = reader.next().unwrap().unwrap();
+    assert_eq!(x.time, 0.2);
+    if let InputDataSensor::Frame(frame) = x.sensor {
+      assert_eq!(frame.images.len(), 2);
+      assert_eq!(frame.images[0].data, image0.data);
+      assert_eq!(frame.images[1].data, image1.data);
+      assert_eq!((frame.images[1].width, frame.images[1].height), (4, 3));
+    }
+    else {
+      panic!("Expected a frame.");
+    }
+    assert!(reader.next().unwrap().is_none());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_replay_reproduces_trajectory() {
+    let _guard = set_test_parameters(|_| {});
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let path = std::env::temp_dir().join(format!("violet-event-log-vio-{}.bin", std::process::id()));
+    let (width, height) = (160, 120);
+    // The texture scrolls as if the device moved sideways.
+    let texture = textured_image(width + 60, height, 1);
+    let images: Vec<Image> = (0..20).map(|i| texture.get_sub_image(3 * i, 0, width, height)).collect();
+
+    // Run on the live input, recording it.
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let mut writer = EventLogWriter::new(&path).unwrap();
+    let mut time = 0.;
+    for image in &images {
+      for _ in 0..10 {
+        for sensor in [
+          InputDataSensor::Gyroscope(Vector3d::new(0.01, -0.02, 0.)),
+          InputDataSensor::Accelerometer(Vector3d::new(0.1, 0., gravity)),
+        ] {
+          let input_data = InputData { time, sensor };
+          writer.write(&input_data).unwrap();
+          vio.process(&input_data).unwrap();
+        }
+        time += 0.01;
+      }
+      let input_data = InputData { time, sensor: InputDataSensor::Frame(InputFrame { images: vec![image, image] }) };
+      writer.write(&input_data).unwrap();
+      vio.process(&input_data).unwrap();
+    }
+    drop(writer);
+    let live = vio.get_trajectory().to_vec();
+    assert_eq!(live.len(), images.len());
+    assert!(vio.get_frame_state().unwrap().tracks_used > 0);
+
+    // Run on the replayed log.
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let mut reader = EventLogReader::new(&path).unwrap();
+    while let Some(input_data) = reader.next().unwrap() {
+      vio.process(&input_data).unwrap();
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(vio.get_trajectory(), live.as_slice());
+  }
+}
@@ -1,66 +1,130 @@
 use crate::all::*;
 
+#[cfg(feature = "gui")]
 use softbuffer::GraphicsContext;
+#[cfg(feature = "gui")]
 use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+#[cfg(feature = "gui")]
 use winit::event_loop::ControlFlow;
+#[cfg(feature = "gui")]
 use winit::window::Window;
 
+// Keys of the window that toggle the visualizations while running. `show_3d`
+// is missing since its window opens only at startup.
+type ShowFlag = fn(&mut ParameterSet) -> &mut bool;
+
+pub const SHOW_FLAG_KEYS: [(char, &str, ShowFlag); 13] = [
+  ('1', "show_features", |p| &mut p.show_features),
+  ('2', "show_mask", |p| &mut p.show_mask),
+  ('3', "show_pyramid", |p| &mut p.show_pyramid),
+  ('4', "show_tracks", |p| &mut p.show_tracks),
+  ('5', "show_trails", |p| &mut p.show_trails),
+  ('6', "show_flow0", |p| &mut p.show_flow0),
+  ('7', "show_flow1", |p| &mut p.show_flow1),
+  ('8', "show_flow2", |p| &mut p.show_flow2),
+  ('9', "show_epipolar", |p| &mut p.show_epipolar),
+  ('0', "show_flow_rejected", |p| &mut p.show_flow_rejected),
+  ('g', "show_ground_truth", |p| &mut p.show_ground_truth),
+  ('h', "show_hud", |p| &mut p.show_hud),
+  ('u', "show_landmark_uncertainty", |p| &mut p.show_landmark_uncertainty),
+];
+
+// At most one of these is on, see `toggle_show_flag()`.
+const FLOW_OVERLAYS: [&str; 3] = ["show_flow0", "show_flow1", "show_flow2"];
+
+// See `EventLoopArgs::reopen`.
+pub type Reopen<'a> = Box<dyn FnMut() -> Result<(Input, VioInit, Outputs)> + 'a>;
+
+// Files written while processing the input.
+pub struct Outputs {
+  // Camera poses of the processed frames, see `--output`.
+  pub output: Option<TumWriter>,
+  // See `--json-output`.
+  #[cfg(feature = "json-output")]
+  pub json_output: Option<JsonWriter>,
+  // See `--record`.
+  pub recorder: Option<VisualizeRecorder>,
+}
+
 pub struct EventLoopArgs<'a> {
   pub input: &'a mut Input,
+  // Draw the frames into `buffer`, for the window or `--record`. Otherwise
+  // the buffer is left empty.
+  pub visualize: bool,
   pub buffer: &'a mut Vec<u32>,
-  pub graphics_context: &'a mut GraphicsContext<Window>,
+  pub buffer_w: usize,
+  pub buffer_h: usize,
+  // Keyboard control of the window: paused, and process up to the next frame
+  // while paused.
+  #[cfg_attr(not(feature = "gui"), allow(dead_code))]
   pub step_mode: bool,
+  #[cfg_attr(not(feature = "gui"), allow(dead_code))]
   pub advance: bool,
+  // Opens the input, the initialization and the outputs again, to restart
+  // from the beginning. The outputs are then overwritten.
+  #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+  pub reopen: Option<Reopen<'a>>,
+  // See `--realtime`.
+  pub realtime: Option<Realtime>,
   pub vio_init: VioInit,
   pub vio: Option<Vio>,
+  pub outputs: Outputs,
 }
 
+#[cfg(feature = "gui")]
 pub fn handle_event(
   event: Event<()>,
   control_flow: &mut ControlFlow,
+  graphics_context: &mut GraphicsContext<Window>,
   args: &mut EventLoopArgs,
 ) -> Result<()> {
-  let (window_width, window_height) = {
-    let size = args.graphics_context.window().inner_size();
-    (size.width as usize, size.height as usize)
-  };
-  if args.buffer.is_empty() {
-    *args.buffer = vec![0; window_width * window_height];
+  {
+    let size = graphics_context.window().inner_size();
+    assert_eq!((size.width as usize, size.height as usize), (args.buffer_w, args.buffer_h));
   }
-  assert_eq!(window_width * window_height, args.buffer.len());
 
   match event {
-    Event::RedrawRequested(window_id) if window_id == args.graphics_context.window().id() => {
-      args.graphics_context.set_buffer(&args.buffer, window_width as u16, window_height as u16);
+    Event::RedrawRequested(window_id) if window_id == graphics_context.window().id() => {
+      graphics_context.set_buffer(args.buffer, args.buffer_w as u16, args.buffer_h as u16);
     },
     Event::WindowEvent {
       event,
       window_id,
     } => {
-      if event == WindowEvent::CloseRequested && window_id == args.graphics_context.window().id() {
+      if event == WindowEvent::CloseRequested && window_id == graphics_context.window().id() {
         *control_flow = ControlFlow::Exit;
       }
-      match event {
-        WindowEvent::KeyboardInput {
-          input: KeyboardInput {
-            state: ElementState::Pressed,
-            virtual_keycode: Some(keycode),
-            scancode: _,
-            ..
-          },
-          is_synthetic: _,
-          device_id: _,
-        } => {
-          args.advance = true;
-          match keycode {
-            VirtualKeyCode::Escape | VirtualKeyCode::Q => {
-              *control_flow = ControlFlow::Exit;
-            },
-            VirtualKeyCode::A => args.step_mode = !args.step_mode,
-            _ => {}, // Other keys.
-          }
+      if let WindowEvent::KeyboardInput {
+        input: KeyboardInput {
+          state: ElementState::Pressed,
+          virtual_keycode: Some(keycode),
+          scancode: _,
+          ..
         },
-        _ => {}, // Other window events.
+        is_synthetic: _,
+        device_id: _,
+      } = event {
+        match keycode {
+          VirtualKeyCode::Escape | VirtualKeyCode::Q => {
+            *control_flow = ControlFlow::Exit;
+          },
+          VirtualKeyCode::Space | VirtualKeyCode::A => {
+            args.step_mode = !args.step_mode;
+            if let Some(realtime) = &mut args.realtime {
+              if args.step_mode { realtime.pause() } else { realtime.resume() }
+            }
+          },
+          VirtualKeyCode::Right => args.advance = true,
+          VirtualKeyCode::R => {
+            restart(args)?;
+            info!("Restarted from the beginning of the input.");
+          },
+          _ => if let Some(key) = key_char(keycode) {
+            if toggle_show_flag(key) && redraw(args)? {
+              graphics_context.window().request_redraw();
+            }
+          },
+        }
       }
     },
     _ => {}, // Other events.
@@ -68,34 +132,273 @@ pub fn handle_event(
 
   if args.step_mode && !args.advance { return Ok(()) }
 
-  match args.input.next()? {
-    Some(input_data) => {
-      if args.vio.is_none() {
-        if let Some(vio_result) = args.vio_init.try_init(&input_data) {
-          args.vio = Some(vio_result?);
-        }
-      }
-
-      if let Some(vio) = &mut args.vio {
-        let processed_frame = vio.process(&input_data)?;
-        if !processed_frame { return Ok(()) }
-
-        if let InputDataSensor::Frame(ref frame) = input_data.sensor {
-          let mut visualize_args = VisualizeArgs {
-            buffer: &mut args.buffer,
-            frames: vio.get_frames(),
-            video_w: frame.images[0].width,
-            video_h: frame.images[0].height,
-            buffer_w: window_width,
-            buffer_h: window_height,
-          };
-          visualize(&mut visualize_args)?;
-          args.graphics_context.window().request_redraw();
-          args.advance = false;
-        }
-      }
+  match process_input(args)? {
+    Some(true) => {
+      graphics_context.window().request_redraw();
+      args.advance = false;
     },
+    Some(false) => {},
     None => *control_flow = ControlFlow::Exit,
   }
   Ok(())
 }
+
+// Logs the keys handled by `handle_event()`.
+#[cfg(feature = "gui")]
+pub fn log_key_bindings() {
+  info!("Keys: space pause/resume, right arrow next frame when paused, r restart, q or escape quit.");
+  for (key, name, _) in &SHOW_FLAG_KEYS {
+    info!("  {}: toggle {}", key, name);
+  }
+}
+
+#[cfg(feature = "gui")]
+fn key_char(keycode: VirtualKeyCode) -> Option<char> {
+  use VirtualKeyCode::*;
+  Some(match keycode {
+    Key1 => '1',
+    Key2 => '2',
+    Key3 => '3',
+    Key4 => '4',
+    Key5 => '5',
+    Key6 => '6',
+    Key7 => '7',
+    Key8 => '8',
+    Key9 => '9',
+    Key0 => '0',
+    G => 'g',
+    H => 'h',
+    U => 'u',
+    _ => return None,
+  })
+}
+
+// Flips the `show_*` flag bound to `key` in `PARAMETER_SET`. Turning on one
+// of the optical flow overlays turns off the others. Returns false if there
+// is no flag for the key.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn toggle_show_flag(key: char) -> bool {
+  let Some((_, name, flag)) = SHOW_FLAG_KEYS.iter().find(|(k, _, _)| *k == key) else { return false };
+  let p = &mut PARAMETER_SET.lock().unwrap();
+  let on = !*flag(p);
+  *flag(p) = on;
+  if on && FLOW_OVERLAYS.contains(name) {
+    for (_, other, flag) in &SHOW_FLAG_KEYS {
+      if other != name && FLOW_OVERLAYS.contains(other) { *flag(p) = false }
+    }
+  }
+  info!("{} {}", name, if on { "on" } else { "off" });
+  true
+}
+
+// Drops the filter and continues from the beginning of the input, see `reopen`.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn restart(args: &mut EventLoopArgs) -> Result<()> {
+  let Some(reopen) = &mut args.reopen else { bail!("Restarting is not supported.") };
+  // Write the frames recorded so far before they are recorded again.
+  if let Some(recorder) = &mut args.outputs.recorder {
+    recorder.finish()?;
+  }
+  let (input, vio_init, outputs) = reopen()?;
+  *args.input = input;
+  args.vio_init = vio_init;
+  args.outputs = outputs;
+  args.vio = None;
+  Ok(())
+}
+
+// Visualizes the last processed frame into the buffer, also again after
+// changing the `show_*` flags while paused. The debug data of flags that were
+// off is collected only from the next frame on. Returns false if nothing was
+// drawn.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn redraw(args: &mut EventLoopArgs) -> Result<bool> {
+  if !args.visualize { return Ok(false) }
+  let Some(vio) = &args.vio else { return Ok(false) };
+  let Some(frame) = vio.get_frames().iter().last() else { return Ok(false) };
+  let image = &frame.cameras[0].image;
+  let mut visualize_args = VisualizeArgs {
+    buffer: args.buffer,
+    frames: vio.get_frames(),
+    video_w: image.width,
+    buffer_w: args.buffer_w,
+    buffer_h: args.buffer_h,
+  };
+  visualize(&mut visualize_args)?;
+  Ok(true)
+}
+
+// Processes the next input sample. Returns None at the end of the input,
+// otherwise whether a frame was visualized into the buffer.
+pub fn process_input(args: &mut EventLoopArgs) -> Result<Option<bool>> {
+  let Some(input_data) = args.input.next()? else {
+    // The last frame may still wait for IMU samples.
+    let Some(vio) = &mut args.vio else { return Ok(None) };
+    if !vio.flush()? { return Ok(None) }
+    return output_frame(args).map(Some);
+  };
+  if let (Some(realtime), InputDataSensor::Frame(_)) = (&mut args.realtime, &input_data.sensor) {
+    realtime.wait(input_data.time);
+  }
+  if args.vio.is_none() {
+    if let Some(vio_result) = args.vio_init.try_init(&input_data) {
+      args.vio = Some(vio_result?);
+    }
+  }
+
+  let Some(vio) = &mut args.vio else { return Ok(Some(false)) };
+  let processed_frame = vio.process(&input_data)?;
+  if !processed_frame { return Ok(Some(false)) }
+  output_frame(args).map(Some)
+}
+
+// Writes the outputs of the frame just processed. Returns whether the frame
+// was visualized into the buffer.
+fn output_frame(args: &mut EventLoopArgs) -> Result<bool> {
+  let Some(vio) = &args.vio else { return Ok(false) };
+  if let (Some(output), Some(pose)) = (&mut args.outputs.output, vio.get_camera_pose()) {
+    output.write(&pose, &vio.get_camera_pose_stds())?;
+  }
+  #[cfg(feature = "json-output")]
+  if let (Some(json_output), Some(state)) = (&mut args.outputs.json_output, vio.get_frame_state()) {
+    json_output.write(&state)?;
+  }
+
+  let frame_number = vio.get_frame_number();
+  if !redraw(args)? { return Ok(false) }
+  if let Some(recorder) = &mut args.outputs.recorder {
+    recorder.record(args.buffer, args.buffer_w, args.buffer_h, frame_number)?;
+  }
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_process_input_headless() {
+    let _guard = set_test_parameters(|_| {});
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/euroc");
+    let mut input = Input::new(&path).unwrap();
+    let output_path = std::env::temp_dir().join(format!("violet-headless-{}.txt", std::process::id()));
+    let mut buffer = vec![];
+    let mut args = EventLoopArgs {
+      input: &mut input,
+      visualize: false,
+      buffer: &mut buffer,
+      buffer_w: 1920,
+      buffer_h: 1080,
+      step_mode: false,
+      advance: false,
+      reopen: None,
+      realtime: None,
+      vio_init: VioInit::new(test_cameras(16, 16), None, vec![], None, None, None),
+      vio: None,
+      outputs: Outputs {
+        output: Some(TumWriter::create(&output_path, false).unwrap()),
+        #[cfg(feature = "json-output")]
+        json_output: None,
+        recorder: None,
+      },
+    };
+    let mut visualized = false;
+    while let Some(v) = process_input(&mut args).unwrap() {
+      visualized |= v;
+    }
+    // The filter ran on the frames without drawing them.
+    assert!(!visualized);
+    assert!(args.buffer.is_empty());
+    // `--output` has a line for each processed frame.
+    let trajectory_len = args.vio.unwrap().get_trajectory().len();
+    assert!(trajectory_len > 0);
+    let output = std::fs::read_to_string(&output_path).unwrap();
+    std::fs::remove_file(&output_path).unwrap();
+    assert_eq!(output.lines().count(), trajectory_len);
+  }
+
+  #[test]
+  fn test_restart() {
+    let _guard = set_test_parameters(|_| {});
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/euroc");
+    let output_path = std::env::temp_dir().join(format!("violet-restart-{}.txt", std::process::id()));
+    let open_outputs = || -> Result<Outputs> {
+      Ok(Outputs {
+        output: Some(TumWriter::create(&output_path, false)?),
+        #[cfg(feature = "json-output")]
+        json_output: None,
+        recorder: None,
+      })
+    };
+    let mut input = Input::new(&path).unwrap();
+    let mut buffer = vec![];
+    let mut args = EventLoopArgs {
+      input: &mut input,
+      visualize: false,
+      buffer: &mut buffer,
+      buffer_w: 1920,
+      buffer_h: 1080,
+      step_mode: false,
+      advance: false,
+      reopen: None,
+      realtime: None,
+      vio_init: VioInit::new(test_cameras(16, 16), None, vec![], None, None, None),
+      vio: None,
+      outputs: Outputs {
+        output: None,
+        #[cfg(feature = "json-output")]
+        json_output: None,
+        recorder: None,
+      },
+    };
+    assert!(restart(&mut args).is_err());
+    args.outputs = open_outputs().unwrap();
+    args.reopen = Some(Box::new(|| {
+      Ok((Input::new(&path)?, VioInit::new(test_cameras(16, 16), None, vec![], None, None, None), open_outputs()?))
+    }));
+    // Restarting in the middle and at the end runs the whole input again.
+    let mut trajectory_lens = vec![];
+    for steps in [5, usize::MAX, usize::MAX] {
+      let mut i = 0;
+      while i < steps && process_input(&mut args).unwrap().is_some() {
+        i += 1;
+      }
+      let trajectory_len = args.vio.as_ref().unwrap().get_trajectory().len();
+      // `--output` has the poses of this run only.
+      let output = std::fs::read_to_string(&output_path).unwrap();
+      assert_eq!(output.lines().count(), trajectory_len);
+      trajectory_lens.push(trajectory_len);
+      restart(&mut args).unwrap();
+      assert!(args.vio.is_none());
+    }
+    std::fs::remove_file(&output_path).unwrap();
+    assert!(trajectory_lens[0] < trajectory_lens[1]);
+    assert_eq!(trajectory_lens[1], trajectory_lens[2]);
+  }
+
+  #[test]
+  fn test_show_flag_keys() {
+    let _guard = set_test_parameters(|_| {});
+    for (i, (key, name, _)) in SHOW_FLAG_KEYS.iter().enumerate() {
+      assert!(SHOW_FLAG_KEYS[..i].iter().all(|(k, n, _)| k != key && n != name));
+    }
+    assert!(toggle_show_flag('4'));
+    assert!(PARAMETER_SET.lock().unwrap().show_tracks);
+    assert!(!PARAMETER_SET.lock().unwrap().show_trails);
+    assert!(toggle_show_flag('4'));
+    assert!(!PARAMETER_SET.lock().unwrap().show_tracks);
+    assert!(!toggle_show_flag('x'));
+
+    // The flow overlays replace each other.
+    let flows = || {
+      let p = PARAMETER_SET.lock().unwrap();
+      [p.show_flow0, p.show_flow1, p.show_flow2]
+    };
+    toggle_show_flag('6');
+    assert_eq!(flows(), [true, false, false]);
+    toggle_show_flag('8');
+    assert_eq!(flows(), [false, false, true]);
+    toggle_show_flag('8');
+    assert_eq!(flows(), [false, false, false]);
+  }
+}
@@ -0,0 +1,220 @@
+// Images that are mirrored relative to the calibration make the whole geometry
+// wrong. `ImageFlip` undoes a known flip of the input and `FlipCheck` tries to
+// detect an unknown one by comparing the feature motion to the camera rotation
+// predicted from the gyroscope.
+
+use crate::all::*;
+
+// Minimum mean predicted feature motion in pixels for a frame to be used in
+// the check. Slower rotation does not tell the flips apart.
+const FLIP_CHECK_MIN_FLOW: f64 = 1.;
+const FLIP_CHECK_MIN_TRACKS: usize = 10;
+// Number of usable frames before drawing any conclusions.
+const FLIP_CHECK_MIN_FRAMES: usize = 5;
+// Fraction of the usable frames where a flip must explain the motion best.
+const FLIP_CHECK_MIN_RATIO: f64 = 0.8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(clap::ArgEnum)]
+pub enum ImageFlip {
+  #[default]
+  None,
+  // Mirrored left to right.
+  Horizontal,
+  // Mirrored top to bottom.
+  Vertical,
+  // Both, same as rotating by 180 degrees.
+  Both,
+}
+
+const FLIPS: [ImageFlip; 4] = [ImageFlip::None, ImageFlip::Horizontal, ImageFlip::Vertical, ImageFlip::Both];
+
+impl ImageFlip {
+  fn flips_x(self) -> bool {
+    matches!(self, ImageFlip::Horizontal | ImageFlip::Both)
+  }
+
+  fn flips_y(self) -> bool {
+    matches!(self, ImageFlip::Vertical | ImageFlip::Both)
+  }
+
+  // Pixel coordinates in an image of the given size after flipping. Flipping
+  // twice gives the original coordinates.
+  pub fn apply(self, p: Vector2d, width: usize, height: usize) -> Vector2d {
+    Vector2d::new(
+      if self.flips_x() { (width - 1) as f64 - p[0] } else { p[0] },
+      if self.flips_y() { (height - 1) as f64 - p[1] } else { p[1] },
+    )
+  }
+}
+
+pub fn flip_image(image: &Image, flip: ImageFlip, flipped: &mut Image) {
+  let (w, h) = (image.width, image.height);
+  flipped.width = w;
+  flipped.height = h;
+  flipped.data.clear();
+  for y in 0..h {
+    let y0 = if flip.flips_y() { h - 1 - y } else { y };
+    let row = &image.data[(y0 * w)..((y0 + 1) * w)];
+    if flip.flips_x() {
+      flipped.data.extend(row.iter().rev());
+    }
+    else {
+      flipped.data.extend_from_slice(row);
+    }
+  }
+}
+
+pub struct FlipCheck {
+  // Number of frames where each of `FLIPS` explained the feature motion best.
+  votes: [usize; 4],
+  suspected: Option<ImageFlip>,
+}
+
+impl FlipCheck {
+  pub fn new() -> FlipCheck {
+    FlipCheck {
+      votes: [0; 4],
+      suspected: None,
+    }
+  }
+
+  // Flip of the input images that the feature motion suggests, if any.
+  #[cfg(test)]
+  pub fn get_suspected(&self) -> Option<ImageFlip> {
+    self.suspected
+  }
+
+  // Compares the motion of tracks seen on both frames to the motion that
+  // `rotation` of the first camera from `frame_numbers[0]` to
+  // `frame_numbers[1]` predicts, assuming each possible flip of the images.
+  // Translation is ignored, so the check works best when the device rotates.
+  pub fn process(
+    &mut self,
+    tracks: &[Track],
+    camera: &Camera,
+    rotation: &Matrix3d,
+    frame_numbers: [usize; 2],
+    image_size: (usize, usize),
+  ) {
+    let (w, h) = image_size;
+    let mut errors = [0.; 4];
+    let mut flow = 0.;
+    let mut count = 0;
+    for track in tracks {
+      if track.camera == Some(1) { continue }
+      let mut it = track.points.iter().rev();
+      let (Some(point1), Some(point0)) = (it.next(), it.next()) else { continue };
+      if [point0.frame_number, point1.frame_number] != frame_numbers { continue }
+      let (p0, p1) = (point0.coordinates[0], point1.coordinates[0]);
+      let mut predictions = [Vector2d::zeros(); 4];
+      let mut ok = true;
+      for (i, flip) in FLIPS.iter().enumerate() {
+        let ray = camera.model.pixel_to_ray(flip.apply(p0, w, h));
+        match ray.and_then(|ray| camera.model.ray_to_pixel(rotation * ray)) {
+          Some(q) => predictions[i] = flip.apply(q, w, h),
+          None => ok = false,
+        }
+      }
+      if !ok { continue }
+      for i in 0..FLIPS.len() {
+        errors[i] += (predictions[i] - p1).norm_squared();
+      }
+      flow += (predictions[0] - p0).norm();
+      count += 1;
+    }
+    if count < FLIP_CHECK_MIN_TRACKS || flow / (count as f64) < FLIP_CHECK_MIN_FLOW { return }
+
+    // Prefer fewer flips when they explain the motion about equally well, for
+    // example vertical flips do not matter for horizontal motion.
+    let min_error = errors.iter().cloned().fold(f64::INFINITY, f64::min);
+    let best = errors.iter().position(|e| *e <= 1.1 * min_error).unwrap();
+    self.votes[best] += 1;
+
+    let total: usize = self.votes.iter().sum();
+    if total < FLIP_CHECK_MIN_FRAMES || self.suspected.is_some() { return }
+    for (flip, votes) in FLIPS.iter().zip(self.votes).skip(1) {
+      if votes as f64 >= FLIP_CHECK_MIN_RATIO * total as f64 {
+        self.suspected = Some(*flip);
+        warn!(
+          "Feature motion is consistently inverted relative to the gyroscope, \
+          the images may be flipped ({:?}) relative to the calibration. See `--image-flip`.",
+          flip,
+        );
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_flip_image() {
+    let image = Image { data: vec![1, 2, 3, 4, 5, 6], width: 3, height: 2 };
+    let mut flipped = Image::empty();
+    for (flip, data) in [
+      (ImageFlip::None, vec![1, 2, 3, 4, 5, 6]),
+      (ImageFlip::Horizontal, vec![3, 2, 1, 6, 5, 4]),
+      (ImageFlip::Vertical, vec![4, 5, 6, 1, 2, 3]),
+      (ImageFlip::Both, vec![6, 5, 4, 3, 2, 1]),
+    ] {
+      flip_image(&image, flip, &mut flipped);
+      assert_eq!(flipped.data, data);
+      assert_eq!((flipped.width, flipped.height), (3, 2));
+      // Pixel coordinates match the image data.
+      let p = flip.apply(Vector2d::new(0., 1.), 3, 2);
+      assert_eq!(flipped.value(p[0] as usize, p[1] as usize), 4);
+    }
+  }
+
+  // Processes a device rotating about its y-axis, which looks like the
+  // images panning left. Returns the suspected flip.
+  fn suspected_flip(input_flip: ImageFlip, image_flip: ImageFlip) -> Option<ImageFlip> {
+    let _guard = set_test_parameters(|p| {
+      p.image_flip = image_flip;
+      p.flip_check = true;
+      // Keeps the visual updates from the mirrored tracks, which triangulate
+      // close to the cameras, from turning the filter away from the rotation
+      // that the gyroscope measures.
+      p.visual_update_min_depth = 0.1;
+    });
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let (width, height) = (160, 120);
+    let frame_count = 15;
+    let shift = 2;
+    // Rotation speed that moves the image by `shift` pixels per frame, 0.1
+    // seconds apart.
+    let omega = shift as f64 / TEST_FOCAL_LENGTH / 0.1;
+
+    let scene = textured_image(width + shift * frame_count, height, 1);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let mut image = Image { data: vec![0; width * height], width, height };
+    let mut flipped = Image::empty();
+    let mut time = 0.;
+    // Gravity rotates in the device coordinates.
+    let imu = |time: f64| {
+      let angle = omega * time;
+      (Vector3d::new(0., omega, 0.), gravity * Vector3d::new(-angle.sin(), 0., angle.cos()))
+    };
+    for i in 0..frame_count {
+      for y in 0..height {
+        for x in 0..width {
+          image.set_value(x, y, scene.value(x + shift * i, y));
+        }
+      }
+      flip_image(&image, input_flip, &mut flipped);
+      process_imu_and_frame(&mut vio, &mut time, imu, [&flipped, &flipped]);
+    }
+    vio.get_suspected_flip()
+  }
+
+  #[test]
+  fn test_flip_check() {
+    assert_eq!(suspected_flip(ImageFlip::None, ImageFlip::None), None);
+    assert_eq!(suspected_flip(ImageFlip::Horizontal, ImageFlip::None), Some(ImageFlip::Horizontal));
+    // Flipping the input back corrects the geometry.
+    assert_eq!(suspected_flip(ImageFlip::Horizontal, ImageFlip::Horizontal), None);
+  }
+}
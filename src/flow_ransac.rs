@@ -0,0 +1,124 @@
+// Outlier rejection for the optical flow from a previous frame to the current
+// one. With the rotation between the frames known from the gyroscope, the
+// epipolar geometry has only the direction of the translation left, and each
+// correspondence constrains it to a plane, so two correspondences are enough
+// for a hypothesis. Correspondences that the rotation alone explains are
+// always inliers, which keeps the flow of a rotating or still camera, where
+// the translation is too small to estimate. If no translation has enough
+// support and the rotation does not explain most of the flow either, eg with
+// only a few features on a translating camera, nothing is rejected.
+
+use crate::all::*;
+
+const ITERATIONS: usize = 64;
+// Hypotheses from nearly parallel constraint planes are not used.
+const MIN_CONSTRAINT_ANGLE: f64 = 1e-6;
+// Number of correspondences a translation must explain beyond those that the
+// rotation alone does. Any two outliers of a still camera fit a translation.
+const MIN_TRANSLATION_SUPPORT: usize = 5;
+
+// Inlier mask of the `flow` correspondences given as rays of the previous and
+// current camera, with `rotation` taking the previous rays to the current
+// camera. `max_angle` is the largest angle in radians between a current ray
+// and the epipolar plane of the hypothesis.
+pub fn ransac_flow(
+  flow: &[(Vector3d, Vector3d)],
+  rotation: &Matrix3d,
+  max_angle: f64,
+  rng: &mut Xoshiro256PlusPlus,
+) -> Vec<bool> {
+  if flow.len() < 3 { return vec![true; flow.len()] }
+  // Previous rays rotated to the current camera, current rays, and the
+  // normals of the planes containing both, which the translation lies on.
+  let rays: Vec<(Vector3d, Vector3d)> = flow.iter()
+    .map(|(r0, r1)| ((rotation * r0).normalize(), r1.normalize()))
+    .collect();
+  let normals: Vec<Vector3d> = rays.iter().map(|(u, v)| u.cross(v)).collect();
+  let rotation_inliers: Vec<bool> = rays.iter().map(|(u, v)| u.angle(v) <= max_angle).collect();
+
+  let mut best = rotation_inliers.clone();
+  let rotation_count = best.iter().filter(|x| **x).count();
+  let mut best_count = rotation_count;
+  let mut inliers = vec![false; flow.len()];
+  for _ in 0..ITERATIONS {
+    let i = rng.gen_range(0..flow.len());
+    let j = rng.gen_range(0..(flow.len() - 1));
+    let j = if j >= i { j + 1 } else { j };
+    let t = normals[i].cross(&normals[j]);
+    if t.norm() < MIN_CONSTRAINT_ANGLE * normals[i].norm() * normals[j].norm() { continue }
+    let t = t.normalize();
+    for (k, (u, _)) in rays.iter().enumerate() {
+      // Sine of the angle between the current ray and the plane spanned by
+      // the translation and the rotated previous ray.
+      let plane_norm = t.cross(u).norm();
+      let epipolar = plane_norm > MIN_CONSTRAINT_ANGLE && t.dot(&normals[k]).abs() <= max_angle.sin() * plane_norm;
+      inliers[k] = rotation_inliers[k] || epipolar;
+    }
+    let count = inliers.iter().filter(|x| **x).count();
+    if count > best_count && count >= rotation_count + MIN_TRANSLATION_SUPPORT {
+      best_count = count;
+      mem::swap(&mut best, &mut inliers);
+    }
+  }
+  if best_count == rotation_count && 2 * rotation_count < flow.len() {
+    return vec![true; flow.len()];
+  }
+  best
+}
+
+// Approximate pixels per radian near the optical axis of the camera, to
+// convert pixel thresholds to angles.
+pub fn pixels_per_radian(camera: &Camera) -> Option<f64> {
+  let angle: f64 = 1e-3;
+  let p0 = camera.model.ray_to_pixel(Vector3d::z())?;
+  let p1 = camera.model.ray_to_pixel(Vector3d::new(angle.sin(), 0., angle.cos()))?;
+  Some((p1 - p0).norm() / angle)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::SeedableRng;
+
+  #[test]
+  fn test_ransac_flow() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let rotation = to_rotation_matrix(Vector4d::new(1., 0.02, -0.03, 0.01).normalize());
+    let translation = Vector3d::new(0.1, -0.02, 0.03);
+    // Landmarks in the previous camera coordinates and their rays from both
+    // cameras.
+    let mut flow: Vec<(Vector3d, Vector3d)> = (0..40).map(|_| {
+      let p = Vector3d::new(rng.gen_range(-2.0..2.0), rng.gen_range(-1.5..1.5), rng.gen_range(2.0..8.0));
+      (p, rotation * p + translation)
+    }).collect();
+    // Outliers that move the current ray off the epipolar plane.
+    let outliers = [3, 11, 17, 30];
+    for i in outliers {
+      let z = flow[i].1[2];
+      flow[i].1 += Vector3d::new(0.3 * z, -0.2 * z, 0.);
+    }
+    let max_angle = 2e-3;
+    let inliers = ransac_flow(&flow, &rotation, max_angle, &mut rng);
+    for (i, inlier) in inliers.iter().enumerate() {
+      assert_eq!(*inlier, !outliers.contains(&i), "{}", i);
+    }
+
+    // Without translation the rotation explains all but the outliers.
+    let still: Vec<_> = flow.iter().map(|(r0, r1)| (*r0, r1 - translation)).collect();
+    let inliers = ransac_flow(&still, &rotation, max_angle, &mut rng);
+    assert_eq!(inliers.iter().filter(|x| !**x).count(), outliers.len());
+
+    // Translation with too few correspondences for a hypothesis to reach the
+    // support.
+    let few: Vec<_> = flow.iter().enumerate()
+      .filter(|(i, _)| !outliers.contains(i))
+      .take(4)
+      .map(|(_, correspondence)| *correspondence)
+      .collect();
+    assert_eq!(ransac_flow(&few, &rotation, max_angle, &mut rng), [true; 4]);
+
+    // Too few correspondences to reject any.
+    assert_eq!(ransac_flow(&flow[3..5], &rotation, max_angle, &mut rng), [true, true]);
+  }
+}
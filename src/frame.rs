@@ -33,15 +33,15 @@ impl Frame {
       Frame { cameras }
     };
 
-    let lk_levels = {
+    let (lk_levels, pyramid_scale) = {
       let p = PARAMETER_SET.lock().unwrap();
-      p.lk_levels
+      (p.lk_levels, p.pyramid_scale)
     };
     for (i, camera) in frame.cameras.iter_mut().enumerate() {
       camera.image.data.extend(input_frame.images[i].data.iter());
       camera.image.width = input_frame.images[i].width;
       camera.image.height = input_frame.images[i].height;
-      Pyramid::compute(&mut camera.pyramid, &input_frame.images[i], lk_levels)?;
+      Pyramid::compute(&mut camera.pyramid, input_frame.images[i], lk_levels, pyramid_scale)?;
     }
     Ok(frame)
   }
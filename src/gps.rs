@@ -0,0 +1,84 @@
+// GPS fixes and their conversion to a local east-north-up (ENU) frame, whose
+// origin is the first fix, for `KalmanFilter::gps_update()`. The geodetic
+// coordinates are WGS 84.
+
+use crate::all::*;
+
+// WGS 84 semi-major axis (m) and first eccentricity squared.
+const WGS84_A: f64 = 6378137.;
+const WGS84_E2: f64 = 6.69437999014e-3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpsFix {
+  // Degrees.
+  pub latitude: f64,
+  pub longitude: f64,
+  // Meters above the ellipsoid.
+  pub altitude: f64,
+  // Standard deviation of each coordinate of the position (m).
+  pub accuracy: f64,
+}
+
+// Tangent plane at the first fix.
+pub struct LocalEnu {
+  origin: Vector3d,
+  ecef_to_enu: Matrix3d,
+}
+
+impl LocalEnu {
+  pub fn new(origin: &GpsFix) -> LocalEnu {
+    let (lat, lon) = (origin.latitude.to_radians(), origin.longitude.to_radians());
+    let (sin_lat, cos_lat) = lat.sin_cos();
+    let (sin_lon, cos_lon) = lon.sin_cos();
+    LocalEnu {
+      origin: geodetic_to_ecef(origin),
+      ecef_to_enu: Matrix3d::new(
+        -sin_lon, cos_lon, 0.,
+        -sin_lat * cos_lon, -sin_lat * sin_lon, cos_lat,
+        cos_lat * cos_lon, cos_lat * sin_lon, sin_lat,
+      ),
+    }
+  }
+
+  pub fn to_enu(&self, fix: &GpsFix) -> Vector3d {
+    self.ecef_to_enu * (geodetic_to_ecef(fix) - self.origin)
+  }
+}
+
+// Earth-centered, Earth-fixed coordinates of the fix.
+fn geodetic_to_ecef(fix: &GpsFix) -> Vector3d {
+  let (lat, lon) = (fix.latitude.to_radians(), fix.longitude.to_radians());
+  let (sin_lat, cos_lat) = lat.sin_cos();
+  // Prime vertical radius of curvature.
+  let n = WGS84_A / (1. - WGS84_E2 * sin_lat.powi(2)).sqrt();
+  Vector3d::new(
+    (n + fix.altitude) * cos_lat * lon.cos(),
+    (n + fix.altitude) * cos_lat * lon.sin(),
+    (n * (1. - WGS84_E2) + fix.altitude) * sin_lat,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_local_enu() {
+    let fix = |latitude, longitude, altitude| GpsFix { latitude, longitude, altitude, accuracy: 1. };
+    let origin = fix(60.17, 24.94, 20.);
+    let enu = LocalEnu::new(&origin);
+    assert!(enu.to_enu(&origin).norm() < 1e-6);
+    // Up.
+    assert!((enu.to_enu(&fix(60.17, 24.94, 120.)) - Vector3d::new(0., 0., 100.)).norm() < 1e-6);
+    // A thousandth of a degree is about 111 m north and, at 60 degrees
+    // latitude, half that east. The Earth curves away from the plane by
+    // d^2 / 2R.
+    let north = enu.to_enu(&fix(60.171, 24.94, 20.));
+    assert!(north[0].abs() < 1e-6);
+    assert!((north[1] - 111.4).abs() < 0.5, "{}", north);
+    assert!((north[2] + 1e-3).abs() < 1e-3, "{}", north);
+    let east = enu.to_enu(&fix(60.17, 24.941, 20.));
+    assert!((east[0] - 55.7).abs() < 0.5, "{}", east);
+    assert!(east[1].abs() < 0.01, "{}", east);
+  }
+}
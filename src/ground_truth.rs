@@ -0,0 +1,110 @@
+// Ground-truth data for algorithm development, eg from synthetic datasets.
+// Reprojecting known landmarks with the ground-truth poses shows directly how
+// far the tracked features are from where they should be.
+
+use crate::all::*;
+
+const LANDMARKS_FILE_NAME: &str = "landmarks.json";
+
+#[derive(Deserialize)]
+struct LandmarksRoot {
+  landmarks: Vec<[f64; 3]>,
+}
+
+pub struct GroundTruth {
+  // Known landmark positions in world coordinates.
+  pub landmarks: Vec<Vector3d>,
+  // Latest ground-truth pose seen in the input.
+  pub imu_to_world: Option<Matrix4d>,
+}
+
+impl GroundTruth {
+  // Returns None if the input folder does not have a landmarks file.
+  pub fn load(path: &Path) -> Result<Option<GroundTruth>> {
+    let path = path.join(LANDMARKS_FILE_NAME);
+    if !path.exists() { return Ok(None) }
+    let s = std::fs::read_to_string(&path)
+      .context(format!("Failed to read file {}.", path.display()))?;
+    let root: LandmarksRoot = serde_json::from_str(&s)
+      .context(format!("Failed to parse {}.", path.display()))?;
+    Ok(Some(GroundTruth {
+      landmarks: root.landmarks.iter().map(|p| Vector3d::new(p[0], p[1], p[2])).collect(),
+      imu_to_world: None,
+    }))
+  }
+
+  // Pixel coordinates of the landmarks visible in the given camera.
+  pub fn reproject(&self, camera: &Camera, reprojections: &mut Vec<Vector2d>) {
+    reprojections.clear();
+    let imu_to_world = if let Some(imu_to_world) = self.imu_to_world { imu_to_world } else { return };
+    let world_to_camera = camera.imu_to_camera * affine_inverse(imu_to_world);
+    for landmark in &self.landmarks {
+      let p = transform_3d(&world_to_camera, landmark);
+      if let Some(pixel) = camera.model.ray_to_pixel(p) {
+        reprojections.push(pixel);
+      }
+    }
+  }
+}
+
+// Ground-truth poses are given in the same representation as the input
+// orientation, ie IMU-to-world with quaternion order w, x, y, z.
+pub fn ground_truth_imu_to_world(position: Vector3d, orientation: Vector4d) -> Matrix4d {
+  let mut T = Matrix4d::identity();
+  T.fixed_slice_mut::<3, 3>(0, 0).copy_from(&to_rotation_matrix(orientation.normalize()));
+  T.fixed_slice_mut::<3, 1>(0, 3).copy_from(&position);
+  T
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reproject() {
+    let K = Matrix3d::new(
+      400., 0., 320.,
+      0., 400., 240.,
+      0., 0., 1.,
+    );
+    let mut imu_to_camera = Matrix4d::identity();
+    imu_to_camera[(0, 3)] = 0.05;
+    let camera = Camera {
+      imu_to_camera,
+      shutter: Shutter::Global,
+      model: Box::new(PinholeModel::new(K, vec![])),
+    };
+    // Rotation of 0.2 radians around the y-axis.
+    let orientation = Vector4d::new(0.1f64.cos(), 0., 0.1f64.sin(), 0.);
+    let position = Vector3d::new(0.3, -0.2, 0.1);
+    let ground_truth = GroundTruth {
+      landmarks: vec![
+        Vector3d::new(0., 0., 3.),
+        Vector3d::new(1., 0.5, 4.),
+        Vector3d::new(-0.7, 0.2, 2.),
+        // Behind the camera.
+        Vector3d::new(0., 0., -3.),
+      ],
+      imu_to_world: Some(ground_truth_imu_to_world(position, orientation)),
+    };
+
+    // Synthetic observations computed directly with the pinhole equations.
+    let R = to_rotation_matrix(orientation);
+    let observations: Vec<Vector2d> = ground_truth.landmarks.iter()
+      .map(|l| R.transpose() * (l - position) + Vector3d::new(0.05, 0., 0.))
+      .filter(|p| p[2] > 0.)
+      .map(|p| {
+        let q = K * (p / p[2]);
+        Vector2d::new(q[0], q[1])
+      })
+      .collect();
+
+    let mut reprojections = vec![];
+    ground_truth.reproject(&camera, &mut reprojections);
+    assert_eq!(reprojections.len(), 3);
+    assert_eq!(reprojections.len(), observations.len());
+    for (r, o) in reprojections.iter().zip(observations.iter()) {
+      assert!((r - o).norm() < 1e-9);
+    }
+  }
+}
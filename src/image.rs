@@ -25,6 +25,14 @@ impl Image {
     self.height = 0;
   }
 
+  // Copies `image` into this one, reusing the allocation.
+  pub fn copy_from(&mut self, image: &Image) {
+    self.data.clear();
+    self.data.extend_from_slice(&image.data);
+    self.width = image.width;
+    self.height = image.height;
+  }
+
   pub fn size(&self, dim: usize) -> usize {
     if dim == 0 { self.width } else { self.height }
   }
@@ -109,16 +117,19 @@ impl fmt::Display for Image {
   }
 }
 
+// Image value at sub-pixel coordinates `u`, interpolated from the four
+// nearest pixels. Coordinates outside the image are clamped to the border,
+// which repeats the edge pixels.
 #[inline(always)]
 pub fn bilinear(image: &Image, u: Vector2d) -> f64 {
-  assert!(u[0] >= 0.0 && u[0] <= image.width as f64 - 1.);
-  assert!(u[1] >= 0.0 && u[1] <= image.height as f64 - 1.);
-  let x0 = u[0] as usize;
-  let y0 = u[1] as usize;
+  let x = u[0].clamp(0., image.width as f64 - 1.);
+  let y = u[1].clamp(0., image.height as f64 - 1.);
+  let x0 = x as usize;
+  let y0 = y as usize;
   let x1 = x0 + 1;
   let y1 = y0 + 1;
-  let xa = u[0].fract();
-  let ya = u[1].fract();
+  let xa = x.fract();
+  let ya = y.fract();
   // Besides improving computation speed, these allow to work one pixel
   // closer to the right and bottom edges when coordinates are integers.
   let eps = 1e-5;
@@ -140,3 +151,121 @@ pub fn bilinear(image: &Image, u: Vector2d) -> f64 {
       + xa * ya * image.data[y1 * image.width + x1] as f64
   }
 }
+
+// Decodes a PNG file to grayscale, reusing the buffer of `image`. Samples of
+// less than 8 bits are expanded by the decoder and 16-bit samples are scaled
+// to 8 bits here, see `input_significant_bits`.
+pub fn read_png(path: &Path, image: &mut Image) -> Result<()> {
+  let significant_bits = PARAMETER_SET.lock().unwrap().input_significant_bits;
+  if significant_bits != 0 && !(8..=16).contains(&significant_bits) {
+    bail!("Invalid number of significant bits {}, expected 8 to 16.", significant_bits);
+  }
+  let shift16 = if significant_bits == 0 { 8 } else { significant_bits - 8 };
+  let file = File::open(path)
+    .context(format!("Failed to open image {}.", path.display()))?;
+  let mut decoder = png::Decoder::new(file);
+  decoder.set_transformations(png::Transformations::EXPAND);
+  let (info, mut reader) = decoder.read_info()
+    .context(format!("Failed to decode image {}.", path.display()))?;
+  let mut buffer = vec![0; info.buffer_size()];
+  reader.next_frame(&mut buffer)
+    .context(format!("Failed to decode image {}.", path.display()))?;
+  let (width, height) = (info.width as usize, info.height as usize);
+  let channels = info.color_type.samples();
+  let wide = info.bit_depth == png::BitDepth::Sixteen;
+  image.width = width;
+  image.height = height;
+  image.data.clear();
+  for y in 0..height {
+    let row = &buffer[(y * info.line_size)..];
+    for x in 0..width {
+      let sample = |c: usize| -> u32 {
+        let i = x * channels + c;
+        if wide {
+          (u16::from_be_bytes([row[2 * i], row[2 * i + 1]]) >> shift16).min(255) as u32
+        } else {
+          row[i] as u32
+        }
+      };
+      let value = match channels {
+        // Ignore the alpha channel.
+        1 | 2 => sample(0),
+        _ => (sample(0) + sample(1) + sample(2)) / 3,
+      };
+      image.data.push(value as u8);
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bilinear() {
+    let image = Image { data: vec![10, 20, 30, 40, 50, 60], width: 3, height: 2 };
+    for y in 0..2 {
+      for x in 0..3 {
+        assert_eq!(bilinear(&image, Vector2d::new(x as f64, y as f64)), image.value(x, y) as f64);
+      }
+    }
+    // Halfway between four pixels is their average.
+    assert_eq!(bilinear(&image, Vector2d::new(0.5, 0.5)), 0.25 * (10. + 20. + 40. + 50.));
+    assert_eq!(bilinear(&image, Vector2d::new(1.5, 0.5)), 0.25 * (20. + 30. + 50. + 60.));
+    assert!((bilinear(&image, Vector2d::new(0.25, 0.)) - 12.5).abs() < 1e-12);
+    // Up to the right and bottom edges.
+    assert_eq!(bilinear(&image, Vector2d::new(2., 0.5)), 45.);
+    assert_eq!(bilinear(&image, Vector2d::new(1.5, 1.)), 55.);
+    // Outside the image the border pixels repeat.
+    assert_eq!(bilinear(&image, Vector2d::new(-3., -1.)), 10.);
+    assert_eq!(bilinear(&image, Vector2d::new(7., 0.5)), 45.);
+    assert_eq!(bilinear(&image, Vector2d::new(0.5, 9.)), 45.);
+  }
+
+  fn write_gray_png(path: &Path, width: usize, height: usize, bit_depth: png::BitDepth, data: &[u8]) {
+    let file = File::create(path).unwrap();
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(bit_depth);
+    encoder.write_header().unwrap().write_image_data(data).unwrap();
+  }
+
+  #[test]
+  fn test_read_png_bit_depths() {
+    let folder = std::env::temp_dir().join(format!("violet-png-{}", std::process::id()));
+    std::fs::create_dir_all(&folder).unwrap();
+    let path = folder.join("image.png");
+    let mut image = Image::empty();
+
+    // 16-bit samples, 3x2 pixels.
+    let samples: [u16; 6] = [0, 0x00ff, 0x1234, 0x0fff, 0x8000, 0xffff];
+    let data: Vec<u8> = samples.iter().flat_map(|v| v.to_be_bytes()).collect();
+    write_gray_png(&path, 3, 2, png::BitDepth::Sixteen, &data);
+    {
+      let _guard = set_test_parameters(|_| {});
+      read_png(&path, &mut image).unwrap();
+      assert_eq!((image.width, image.height), (3, 2));
+      assert_eq!(image.data, [0, 0, 0x12, 0x0f, 0x80, 0xff]);
+    }
+    {
+      // 12-bit values in the low bits, larger ones saturate.
+      let _guard = set_test_parameters(|p| p.input_significant_bits = 12);
+      read_png(&path, &mut image).unwrap();
+      assert_eq!(image.data, [0, 0x0f, 0xff, 0xff, 0xff, 0xff]);
+    }
+    {
+      let _guard = set_test_parameters(|p| p.input_significant_bits = 4);
+      assert!(read_png(&path, &mut image).is_err());
+    }
+
+    // 2-bit samples are scaled to the full range, 5 pixels per row so that
+    // the rows are padded.
+    let _guard = set_test_parameters(|_| {});
+    write_gray_png(&path, 5, 2, png::BitDepth::Two, &[0b00011011, 0b11000000, 0b10010000, 0b00000000]);
+    read_png(&path, &mut image).unwrap();
+    assert_eq!((image.width, image.height), (5, 2));
+    assert_eq!(image.data, [0, 85, 170, 255, 255, 170, 85, 0, 0, 0]);
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+}
@@ -0,0 +1,229 @@
+// IMU bias estimates saved at the end of a run, to warm-start the next run on
+// the same device. Biases of a given IMU drift slowly, so starting from the
+// previous estimates is much closer than starting from zero.
+
+use crate::all::*;
+
+use std::io::Write;
+
+// Anything larger than these is more likely a wrong file or device than a
+// real bias, in which case starting from zero is safer.
+const MAX_GYROSCOPE_BIAS: f64 = 0.5; // rad/s
+const MAX_ACCELEROMETER_BIAS: f64 = 2.; // m/s^2
+
+// Filter state relevant to the IMU model, for plotting bias convergence.
+#[derive(Clone, Debug)]
+pub struct ImuState {
+  pub time: f64,
+  // Orientation relative to gravity, see `KalmanFilter::get_roll_pitch()`.
+  pub roll: f64,
+  pub pitch: f64,
+  // Standard deviation of roll and pitch, radians.
+  pub tilt_std: f64,
+  // Estimated gravity in IMU coordinates, m/s^2.
+  pub gravity: Vector3d,
+  pub biases: ImuBiases,
+  pub bias_stds: ImuBiases,
+  // Acceleration left after removing the bias and estimated gravity from the
+  // last accelerometer sample, IMU coordinates. Persistently non-zero values
+  // while the device is still suggest a wrong bias or orientation.
+  pub residual_acceleration: Vector3d,
+  // Velocity of the IMU in world coordinates (z-axis up, see
+  // `write_ros_pose_stamped()`), m/s, and its standard deviation.
+  pub velocity: Vector3d,
+  pub velocity_std: Vector3d,
+}
+
+#[derive(Deserialize)]
+struct ImuBiasesRoot {
+  gyroscope: [f64; 3],
+  accelerometer: [f64; 3],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImuBiases {
+  pub gyroscope: Vector3d,
+  pub accelerometer: Vector3d,
+}
+
+impl ImuBiases {
+  pub fn load(path: &Path) -> Result<ImuBiases> {
+    let s = std::fs::read_to_string(path)
+      .context(format!("Failed to read file {}.", path.display()))?;
+    let root: ImuBiasesRoot = serde_json::from_str(&s)
+      .context(format!("Failed to parse {}.", path.display()))?;
+    let biases = ImuBiases {
+      gyroscope: Vector3d::from(root.gyroscope),
+      accelerometer: Vector3d::from(root.accelerometer),
+    };
+    biases.validate().context(format!("Implausible IMU biases in {}.", path.display()))?;
+    Ok(biases)
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let mut file = File::create(path)
+      .context(format!("Failed to create IMU bias file {}.", path.display()))?;
+    let (g, a) = (self.gyroscope, self.accelerometer);
+    writeln!(file, "{{\n  \"gyroscope\": [{}, {}, {}],\n  \"accelerometer\": [{}, {}, {}]\n}}",
+      g[0], g[1], g[2], a[0], a[1], a[2])?;
+    Ok(())
+  }
+
+  fn validate(&self) -> Result<()> {
+    if !self.gyroscope.iter().chain(self.accelerometer.iter()).all(|x| x.is_finite()) {
+      bail!("Biases must be finite.");
+    }
+    if self.gyroscope.norm() > MAX_GYROSCOPE_BIAS {
+      bail!("Gyroscope bias norm {} exceeds {}.", self.gyroscope.norm(), MAX_GYROSCOPE_BIAS);
+    }
+    if self.accelerometer.norm() > MAX_ACCELEROMETER_BIAS {
+      bail!("Accelerometer bias norm {} exceeds {}.", self.accelerometer.norm(), MAX_ACCELEROMETER_BIAS);
+    }
+    Ok(())
+  }
+}
+
+// One row per frame with the time, roll, pitch, their standard deviation,
+// gravity, gyroscope bias, its standard deviations, accelerometer bias, its
+// standard deviations, the residual acceleration and the velocity with its
+// standard deviations.
+pub fn write_imu_states(path: &Path, states: &[ImuState]) -> Result<()> {
+  let file = File::create(path)
+    .context(format!("Failed to create IMU state file {}.", path.display()))?;
+  let mut writer = std::io::BufWriter::new(file);
+  writeln!(writer, "time,roll,pitch,tilt_std,grav_x,grav_y,grav_z,bga_x,bga_y,bga_z,bga_std_x,bga_std_y,bga_std_z,\
+    baa_x,baa_y,baa_z,baa_std_x,baa_std_y,baa_std_z,res_acc_x,res_acc_y,res_acc_z,\
+    vel_x,vel_y,vel_z,vel_std_x,vel_std_y,vel_std_z")?;
+  for state in states {
+    let (b, s) = (&state.biases, &state.bias_stds);
+    write!(writer, "{},{},{},{}", state.time, state.roll, state.pitch, state.tilt_std)?;
+    for v in [
+      state.gravity, b.gyroscope, s.gyroscope, b.accelerometer, s.accelerometer, state.residual_acceleration,
+      state.velocity, state.velocity_std,
+    ] {
+      write!(writer, ",{},{},{}", v[0], v[1], v[2])?;
+    }
+    writeln!(writer)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_save_and_load() {
+    let path = std::env::temp_dir().join(format!("violet-imu-biases-{}.json", std::process::id()));
+    let biases = ImuBiases {
+      gyroscope: Vector3d::new(0.01, -0.02, 0.003),
+      accelerometer: Vector3d::new(0.1, 0.05, -0.2),
+    };
+    biases.save(&path).unwrap();
+    assert_eq!(ImuBiases::load(&path).unwrap(), biases);
+
+    let implausible = ImuBiases { gyroscope: Vector3d::new(1., 0., 0.), ..biases };
+    implausible.save(&path).unwrap();
+    assert!(ImuBiases::load(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_warm_start() {
+    let _guard = set_test_parameters(|_| {});
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let kf_noise_zero_velocity = PARAMETER_SET.lock().unwrap().kf_noise_zero_velocity;
+    let true_biases = ImuBiases {
+      gyroscope: Vector3d::new(0.02, -0.01, 0.),
+      accelerometer: Vector3d::zeros(),
+    };
+
+    // A device at rest whose gyroscope reads only its bias.
+    let run = |biases: Option<ImuBiases>| {
+      let mut kalman_filter = KalmanFilter::new();
+      if let Some(biases) = biases {
+        kalman_filter.set_imu_biases(&biases);
+      }
+      for i in 0..200 {
+        kalman_filter.predict(0.01 * i as f64, true_biases.gyroscope, Vector3d::new(0., 0., gravity));
+        if i % 10 == 0 {
+          kalman_filter.update_zero_velocity(kf_noise_zero_velocity);
+        }
+      }
+      kalman_filter.get_imu_biases()
+    };
+
+    let warm = run(Some(true_biases));
+    let cold = run(None);
+    let error = |b: ImuBiases| (b.gyroscope - true_biases.gyroscope).norm();
+    assert!(error(warm) < 1e-3);
+    assert!(error(warm) < 0.5 * error(cold));
+  }
+
+  #[test]
+  fn test_write_imu_states() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let image = textured_image(width, height, 1);
+    let mut time = 0.;
+    for _ in 0..5 {
+      process_static_frame(&mut vio, &mut time, [&image, &image]);
+    }
+
+    let path = std::env::temp_dir().join(format!("violet-imu-states-{}.csv", std::process::id()));
+    write_imu_states(&path, vio.get_imu_states()).unwrap();
+    let s = std::fs::read_to_string(&path).unwrap();
+    let mut lines = s.lines();
+    assert_eq!(lines.next().unwrap().split(',').count(), 28);
+    let rows: Vec<Vec<f64>> = lines.map(|line| line.split(',').map(|x| x.parse().unwrap()).collect()).collect();
+    assert_eq!(rows.len(), 5);
+    for row in &rows {
+      assert_eq!(row.len(), 28);
+      assert!(row.iter().all(|x| x.is_finite()));
+    }
+    // Standard deviations are positive.
+    assert!(rows.iter().all(|row| row[3] > 0. && row[10..13].iter().chain(&row[16..19]).all(|x| *x > 0.)));
+    // The device is level and still, so gravity explains all of the acceleration.
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    assert!(rows.iter().all(|row| row[1].abs() < 1e-3 && row[2].abs() < 1e-3), "{:?}", rows);
+    assert!(rows.iter().all(|row| (Vector3d::new(row[4], row[5], row[6]) + Vector3d::new(0., 0., gravity)).norm() < 1e-2));
+    assert!(rows.iter().all(|row| row[19..22].iter().all(|x| x.abs() < 1e-3)), "{:?}", rows);
+    assert!(rows.iter().all(|row| row[22..25].iter().all(|x| x.abs() < 1e-2)), "{:?}", rows);
+    assert!(rows.iter().all(|row| row[25..28].iter().all(|x| *x > 0.)));
+    std::fs::remove_file(&path).unwrap();
+  }
+  #[test]
+  fn test_velocity_output() {
+    // Noiseless constant acceleration would look like rest to the IMU check.
+    let _guard = set_test_parameters(|p| p.imu_stationary_window = 0.);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    // Nothing to track, so the velocity comes from the IMU alone.
+    let image = black_image(width, height);
+    let acceleration = Vector3d::new(0.5, -0.2, 0.1);
+    process_static_imu(&mut vio, 0.);
+    let mut time = 0.;
+    let mut true_velocities = vec![];
+    for _ in 0..10 {
+      for _ in 0..10 {
+        time += 0.01;
+        vio.process(&InputData { time, sensor: InputDataSensor::Gyroscope(Vector3d::zeros()) }).unwrap();
+        let accelerometer = acceleration + Vector3d::new(0., 0., gravity);
+        vio.process(&InputData { time, sensor: InputDataSensor::Accelerometer(accelerometer) }).unwrap();
+      }
+      process_frame(&mut vio, time, [&image, &image]);
+      true_velocities.push(time * acceleration);
+    }
+
+    let states = vio.get_imu_states();
+    assert_eq!(states.len(), true_velocities.len());
+    for (state, velocity) in states.iter().zip(&true_velocities) {
+      assert!((state.velocity - velocity).norm() < 1e-6, "{} {}", state.velocity, velocity);
+      assert!(state.velocity_std.iter().all(|x| *x > 0.));
+    }
+    // The uncertainty grows without visual updates.
+    assert!(states[9].velocity_std.norm() > states[0].velocity_std.norm());
+  }
+}
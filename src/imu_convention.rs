@@ -0,0 +1,112 @@
+// Devices report the IMU samples in different units and sign conventions.
+// The filter expects the gyroscope in rad/s, positive for counter-clockwise
+// rotation, and the accelerometer as specific force in m/s^2, so that a device
+// at rest measures +g along the up axis, both in the right-handed IMU frame of
+// the calibration.
+
+use crate::all::*;
+
+// Relative difference of the accelerometer norm from `gravity` at rest above
+// which `check_gravity()` warns.
+const MAX_GRAVITY_ERROR: f64 = 0.2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(clap::ArgEnum)]
+pub enum ImuConvention {
+  // Use the samples as they are.
+  #[default]
+  None,
+  // EuRoC MAV datasets (ADIS16448). Already in the expected convention.
+  Euroc,
+  // Android `TYPE_ACCELEROMETER` and `TYPE_GYROSCOPE` sensors. Already in the
+  // expected convention, with x right, y up and z out of the screen.
+  Android,
+  // iOS Core Motion raw accelerometer and gyroscope, with the axes of Android.
+  // The accelerometer is in units of g and measures -1 along the up axis at rest.
+  Ios,
+}
+
+const CONVENTIONS: [ImuConvention; 4] = [
+  ImuConvention::None,
+  ImuConvention::Euroc,
+  ImuConvention::Android,
+  ImuConvention::Ios,
+];
+
+impl ImuConvention {
+  // Matrices that take the gyroscope and accelerometer samples to the
+  // expected convention.
+  fn transforms(self, gravity: f64) -> (Matrix3d, Matrix3d) {
+    match self {
+      ImuConvention::None | ImuConvention::Euroc | ImuConvention::Android => {
+        (Matrix3d::identity(), Matrix3d::identity())
+      },
+      ImuConvention::Ios => (Matrix3d::identity(), -gravity * Matrix3d::identity()),
+    }
+  }
+
+  pub fn gyroscope(self, v: Vector3d, gravity: f64) -> Vector3d {
+    self.transforms(gravity).0 * v
+  }
+
+  pub fn accelerometer(self, v: Vector3d, gravity: f64) -> Vector3d {
+    self.transforms(gravity).1 * v
+  }
+}
+
+// Checks the accelerometer sample of a static device, after applying
+// `convention`, against gravity. Returns a warning with the conventions that
+// would fit better, if any.
+pub fn check_gravity(accelerometer: Vector3d, gravity: f64, convention: ImuConvention) -> Option<String> {
+  let fits = |convention: ImuConvention| {
+    let a = convention.accelerometer(accelerometer, gravity);
+    ((a.norm() - gravity) / gravity).abs() <= MAX_GRAVITY_ERROR
+  };
+  if fits(convention) { return None }
+  let suggested: Vec<String> = CONVENTIONS.iter()
+    .filter(|c| fits(**c))
+    .map(|c| format!("{:?}", c).to_lowercase())
+    .collect();
+  let norm = convention.accelerometer(accelerometer, gravity).norm();
+  let mut warning = format!(
+    "Accelerometer norm at start is {:.3} but gravity is {:.3}, check `imu_convention`.",
+    norm, gravity,
+  );
+  if !suggested.is_empty() {
+    warning += &format!(" Suggested: {}.", suggested.join(", "));
+  }
+  Some(warning)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_imu_conventions() {
+    let gravity = 9.81;
+    let gyroscope = Vector3d::new(0.1, -0.2, 0.3);
+    // Lying flat on the screen side down, slightly tilted.
+    let up = Vector3d::new(0.1, 0., -1.).normalize();
+    let expected = gravity * up;
+
+    for convention in [ImuConvention::None, ImuConvention::Euroc, ImuConvention::Android] {
+      assert_eq!(convention.gyroscope(gyroscope, gravity), gyroscope);
+      assert_eq!(convention.accelerometer(expected, gravity), expected);
+      assert!(check_gravity(expected, gravity, convention).is_none());
+    }
+
+    let ios = ImuConvention::Ios;
+    assert_eq!(ios.gyroscope(gyroscope, gravity), gyroscope);
+    // Core Motion reports the opposite direction in units of g.
+    assert!((ios.accelerometer(-up, gravity) - expected).norm() < 1e-12);
+    assert!(check_gravity(-up, gravity, ios).is_none());
+
+    // iOS data without the preset.
+    let warning = check_gravity(-up, gravity, ImuConvention::None).unwrap();
+    assert!(warning.ends_with("Suggested: ios."));
+    // Nothing fits eg accelerometer in mg.
+    let warning = check_gravity(1000. * up, gravity, ImuConvention::None).unwrap();
+    assert!(!warning.contains("Suggested"));
+  }
+}
@@ -0,0 +1,183 @@
+// Estimation of the IMU noise parameters from a static recording, so that they
+// do not have to be looked up from data sheets or tuned by hand. The Allan
+// variance of a sensor with white noise density N and bias random walk
+// density K is
+//   AVAR(tau) = N^2 / tau + K^2 tau / 3,
+// ignoring bias instability and quantization, and both densities are fitted
+// to the Allan variances of the samples over cluster times tau.
+
+use crate::all::*;
+
+// Clusters longer than this fraction of the samples give too noisy variances.
+const MAX_CLUSTER_FRACTION: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseDensities {
+  // Units of the sensor times sqrt(s), eg rad/s/sqrt(Hz) for a gyroscope.
+  pub white_noise: f64,
+  // Units of the sensor per sqrt(s), eg rad/s^2/sqrt(Hz) for a gyroscope.
+  pub random_walk: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImuNoise {
+  // Sample interval, seconds.
+  pub dt: f64,
+  pub gyroscope: NoiseDensities,
+  pub accelerometer: NoiseDensities,
+}
+
+impl ImuNoise {
+  // Standard deviations of a single sample, which are what `kf_noise_g` and
+  // `kf_noise_a` give.
+  pub fn sample_stds(&self) -> (f64, f64) {
+    (self.gyroscope.white_noise / self.dt.sqrt(), self.accelerometer.white_noise / self.dt.sqrt())
+  }
+}
+
+// Overlapping Allan variances of evenly spaced samples for cluster sizes of
+// powers of two, as (tau, variance) pairs.
+pub fn allan_variances(samples: &[f64], dt: f64) -> Vec<(f64, f64)> {
+  let n = samples.len();
+  let mut sums = Vec::with_capacity(n + 1);
+  sums.push(0.);
+  for (i, x) in samples.iter().enumerate() {
+    sums.push(sums[i] + x);
+  }
+  let mut variances = vec![];
+  let mut m = 1;
+  while m as f64 <= MAX_CLUSTER_FRACTION * n as f64 {
+    let mut sum2 = 0.;
+    let count = n + 1 - 2 * m;
+    for k in 0..count {
+      let d = (sums[k + 2 * m] - 2. * sums[k + m] + sums[k]) / m as f64;
+      sum2 += d * d;
+    }
+    variances.push((m as f64 * dt, 0.5 * sum2 / count as f64));
+    m *= 2;
+  }
+  variances
+}
+
+// Fits the noise model to the Allan variances of each axis, minimizing the
+// relative errors, and averages the axes.
+pub fn estimate_noise_densities(samples: &[Vector3d], dt: f64) -> Option<NoiseDensities> {
+  let mut white_noise2 = 0.;
+  let mut random_walk2 = 0.;
+  for axis in 0..3 {
+    let values: Vec<f64> = samples.iter().map(|x| x[axis]).collect();
+    let variances = allan_variances(&values, dt);
+    if variances.len() < 2 { return None }
+    // With u = 1 / (tau v) and w = tau / v, solve a u + b w = 1 in the least squares sense.
+    let (mut uu, mut uw, mut ww, mut u1, mut w1) = (0., 0., 0., 0., 0.);
+    for (tau, v) in variances {
+      if v <= 0. { continue }
+      let (u, w) = (1. / (tau * v), tau / v);
+      uu += u * u;
+      uw += u * w;
+      ww += w * w;
+      u1 += u;
+      w1 += w;
+    }
+    let det = uu * ww - uw * uw;
+    if det <= 0. { return None }
+    let mut a = (ww * u1 - uw * w1) / det;
+    let mut b = (uu * w1 - uw * u1) / det;
+    // Either term may be below what the data can show.
+    if b < 0. {
+      a = u1 / uu;
+      b = 0.;
+    }
+    else if a < 0. {
+      a = 0.;
+      b = w1 / ww;
+    }
+    white_noise2 += a;
+    random_walk2 += 3. * b;
+  }
+  Some(NoiseDensities {
+    white_noise: (white_noise2 / 3.).sqrt(),
+    random_walk: (random_walk2 / 3.).sqrt(),
+  })
+}
+
+// Collects the IMU samples of a static segment at the start of the input.
+pub struct ImuNoiseCalibration {
+  duration: f64,
+  first_time: Option<f64>,
+  times: Vec<f64>,
+  gyroscope: Vec<Vector3d>,
+  accelerometer: Vec<Vector3d>,
+}
+
+impl ImuNoiseCalibration {
+  pub fn new(duration: f64) -> ImuNoiseCalibration {
+    ImuNoiseCalibration {
+      duration,
+      first_time: None,
+      times: vec![],
+      gyroscope: vec![],
+      accelerometer: vec![],
+    }
+  }
+
+  // Returns the estimate once `duration` seconds of samples have been added.
+  pub fn add(&mut self, time: f64, gyroscope: Vector3d, accelerometer: Vector3d) -> Option<ImuNoise> {
+    let first_time = *self.first_time.get_or_insert(time);
+    if time - first_time < self.duration {
+      self.times.push(time);
+      self.gyroscope.push(gyroscope);
+      self.accelerometer.push(accelerometer);
+      return None;
+    }
+    if self.times.len() < 2 { return None }
+    // Median interval, robust to occasional dropped samples.
+    let mut intervals: Vec<f64> = self.times.windows(2).map(|w| w[1] - w[0]).collect();
+    intervals.sort_by(|a, b| a.total_cmp(b));
+    let dt = intervals[intervals.len() / 2];
+    if dt <= 0. { return None }
+    Some(ImuNoise {
+      dt,
+      gyroscope: estimate_noise_densities(&self.gyroscope, dt)?,
+      accelerometer: estimate_noise_densities(&self.accelerometer, dt)?,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::SeedableRng;
+
+  #[test]
+  fn test_estimate_noise_densities() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let dt = 0.01;
+    let n = 200_000;
+    let offset = Vector3d::new(0.01, -0.02, 9.81);
+    for (white_noise, random_walk) in [(5e-3, 1e-4), (2e-2, 1e-3)] {
+      let mut calibration = ImuNoiseCalibration::new(n as f64 * dt);
+      let mut bias = Vector3d::zeros();
+      let mut estimate = None;
+      for i in 0..=n {
+        let noise = Vector3d::from_fn(|_, _| standard_normal(&mut rng));
+        let drift = Vector3d::from_fn(|_, _| standard_normal(&mut rng));
+        let x = offset + bias + white_noise / dt.sqrt() * noise;
+        bias += random_walk * dt.sqrt() * drift;
+        // Both sensors get the same noise model.
+        estimate = calibration.add(i as f64 * dt, x, x);
+        assert_eq!(estimate.is_some(), i == n);
+      }
+      let estimate = estimate.unwrap();
+      assert!((estimate.dt - dt).abs() < 1e-9);
+      for densities in [estimate.gyroscope, estimate.accelerometer] {
+        assert!((densities.white_noise / white_noise - 1.).abs() < 0.1);
+        assert!((densities.random_walk / random_walk - 1.).abs() < 0.3);
+      }
+      let (g, a) = estimate.sample_stds();
+      assert!((g / (white_noise / dt.sqrt()) - 1.).abs() < 0.1);
+      assert_eq!(g, a);
+    }
+  }
+}
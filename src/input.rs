@@ -1,15 +1,76 @@
 use crate::all::*;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(clap::ArgEnum)]
+pub enum InputFormat {
+  // The JSONL + video format described in the README, or a EuRoC dataset,
+  // which is recognized by its folder structure.
+  #[default]
+  Benchmark,
+  // IMU samples in `imu.csv`, image files listed in `frames.csv` and
+  // optionally GPS fixes in `gps.csv`, see `CsvImuInput`, `CsvFrameInput`
+  // and `CsvGpsInput`. Images are PNG.
+  Csv,
+}
+
 pub struct Input {
+  source: InputSource,
+  // Records everything read from `source`.
+  event_log: Option<EventLogWriter>,
+}
+
+enum InputSource {
+  Benchmark(Box<BenchmarkInput>),
+  Euroc(Box<EurocInput>),
+  Csv(Box<CsvInput>),
+  EventLog(EventLogReader),
+}
+
+const IMU_CSV_FILE_NAME: &str = "imu.csv";
+const FRAMES_CSV_FILE_NAME: &str = "frames.csv";
+const GPS_CSV_FILE_NAME: &str = "gps.csv";
+
+// Smallest image width and height that the pyramid, feature detector and
+// optical flow can work with.
+const MIN_IMAGE_SIZE: usize = 16;
+
+// The JSONL + video format described in the README.
+struct BenchmarkInput {
   reader: BufReader<File>,
   line: String,
   video_inputs: Vec<VideoInput>,
 }
 
+// The `csv` input format, see `InputFormat::Csv`.
+struct CsvInput {
+  imu: CsvImuInput,
+  frames: CsvFrameInput,
+  gps: Option<CsvGpsInput>,
+  images: Vec<Image>,
+}
+
 pub struct InputFrame<'a> {
   pub images: Vec<&'a Image>,
 }
 
+impl InputFrame<'_> {
+  // Fails for frames that cannot be processed, eg empty placeholders for
+  // dropped video frames, so that they can be skipped.
+  pub fn validate(&self) -> Result<()> {
+    if self.images.is_empty() { bail!("Frame has no images.") }
+    for image in &self.images {
+      if image.width < MIN_IMAGE_SIZE || image.height < MIN_IMAGE_SIZE {
+        bail!("Image size {}x{} is below the minimum {}x{}.",
+          image.width, image.height, MIN_IMAGE_SIZE, MIN_IMAGE_SIZE);
+      }
+      if image.data.len() != image.width * image.height {
+        bail!("Image data has {} pixels but the size is {}x{}.", image.data.len(), image.width, image.height);
+      }
+    }
+    Ok(())
+  }
+}
+
 pub struct InputData<'a> {
   pub time: f64,
   pub sensor: InputDataSensor<'a>,
@@ -19,27 +80,73 @@ pub enum InputDataSensor<'a> {
   Gyroscope(Vector3d),
   Accelerometer(Vector3d),
   Frame(InputFrame<'a>),
+  // IMU-to-world.
+  GroundTruth(Matrix4d),
+  Gps(GpsFix),
 }
 
 impl Input {
+  // Reads the folder in the format of `input_format`. In the benchmark format
+  // EuRoC datasets are recognized.
   pub fn new(path: &Path) -> Result<Input> {
-    let file = File::open(path.join("data.jsonl"))?;
-    let video_inputs = vec![
-      VideoInput::new(&path.join("data.mp4"))?,
-      VideoInput::new(&path.join("data2.mp4"))?,
-    ];
+    let input_format = PARAMETER_SET.lock().unwrap().input_format;
+    let source = match (input_format, EurocInput::find_root(path)) {
+      (InputFormat::Csv, _) => InputSource::Csv(Box::new(CsvInput::new(path)?)),
+      (InputFormat::Benchmark, Some(root)) => InputSource::Euroc(Box::new(EurocInput::new(&root)?)),
+      (InputFormat::Benchmark, None) => InputSource::Benchmark(Box::new(BenchmarkInput::new(path)?)),
+    };
     Ok(Input {
-      reader: BufReader::new(file),
-      line: String::new(),
-      video_inputs,
+      source,
+      event_log: None,
+    })
+  }
+
+  // Read input data from a log written by `Input::record()`.
+  pub fn replay(event_log_path: &Path) -> Result<Input> {
+    Ok(Input {
+      source: InputSource::EventLog(EventLogReader::new(event_log_path)?),
+      event_log: None,
     })
   }
 
+  pub fn record(&mut self, event_log_path: &Path) -> Result<()> {
+    self.event_log = Some(EventLogWriter::new(event_log_path)?);
+    Ok(())
+  }
+
   // Not using the Iterator trait here because "streaming iterators" are not
   // supported by the trait. Specifically, the yielded items are not allowed
   // to borrow from the `Input` struct, but the InputData::Frame variant does
   // that to avoid allocating a new buffers for the images.
-  pub fn next(&mut self) -> Result<Option<InputData>> {
+  pub fn next(&mut self) -> Result<Option<InputData<'_>>> {
+    let input_data = match &mut self.source {
+      InputSource::Benchmark(x) => x.next()?,
+      InputSource::Euroc(x) => x.next()?,
+      InputSource::Csv(x) => x.next()?,
+      InputSource::EventLog(x) => x.next()?,
+    };
+    if let (Some(event_log), Some(input_data)) = (&mut self.event_log, &input_data) {
+      event_log.write(input_data)?;
+    }
+    Ok(input_data)
+  }
+}
+
+impl BenchmarkInput {
+  fn new(path: &Path) -> Result<BenchmarkInput> {
+    let file = File::open(path.join("data.jsonl"))?;
+    // Without the second video the frames have a single image.
+    let mut video_inputs = vec![VideoInput::new(&path.join("data.mp4"))?];
+    let video2_path = path.join("data2.mp4");
+    if video2_path.exists() { video_inputs.push(VideoInput::new(&video2_path)?) }
+    Ok(BenchmarkInput {
+      reader: BufReader::new(file),
+      line: String::new(),
+      video_inputs,
+    })
+  }
+
+  fn next(&mut self) -> Result<Option<InputData<'_>>> {
     loop {
       self.line.clear();
       match self.reader.read_line(&mut self.line) {
@@ -89,8 +196,16 @@ impl Input {
           sensor: InputDataSensor::Frame(input_frame),
         }));
       }
-      else if let Some(_) = value.get("groundTruth") {
-        // Pass.
+      else if let Some(ground_truth) = value.get("groundTruth") {
+        let position = parse_vector(&ground_truth["position"], &["x", "y", "z"])?;
+        let orientation = parse_vector(&ground_truth["orientation"], &["w", "x", "y", "z"])?;
+        return Ok(Some(InputData {
+          time,
+          sensor: InputDataSensor::GroundTruth(ground_truth_imu_to_world(
+            Vector3d::new(position[0], position[1], position[2]),
+            Vector4d::new(orientation[0], orientation[1], orientation[2], orientation[3]),
+          )),
+        }));
       }
       else {
         warn!("Unrecognized data: {}", self.line);
@@ -99,3 +214,114 @@ impl Input {
     }
   }
 }
+
+impl CsvInput {
+  fn new(path: &Path) -> Result<CsvInput> {
+    let gps_path = path.join(GPS_CSV_FILE_NAME);
+    Ok(CsvInput {
+      imu: CsvImuInput::new(&path.join(IMU_CSV_FILE_NAME))?,
+      frames: CsvFrameInput::new(&path.join(FRAMES_CSV_FILE_NAME))?,
+      gps: if gps_path.exists() { Some(CsvGpsInput::new(&gps_path)?) } else { None },
+      images: vec![],
+    })
+  }
+
+  // Merges the GPS fixes, IMU samples and frames by time, in that order for
+  // the same time.
+  fn next(&mut self) -> Result<Option<InputData<'_>>> {
+    let frame_time = self.frames.peek_time()?;
+    if let Some(gps) = &mut self.gps {
+      let max_time = frame_time.unwrap_or(f64::INFINITY).min(self.imu.peek_time()?.unwrap_or(f64::INFINITY));
+      if let Some(input_data) = gps.next_until(max_time)? {
+        return Ok(Some(input_data));
+      }
+    }
+    if let Some(input_data) = self.imu.next_until(frame_time.unwrap_or(f64::INFINITY))? {
+      return Ok(Some(input_data));
+    }
+    let Some((time, paths)) = self.frames.next()? else { return Ok(None) };
+    self.images.resize_with(paths.len(), Image::empty);
+    for (path, image) in paths.iter().zip(&mut self.images) {
+      read_png(path, image)?;
+    }
+    Ok(Some(InputData {
+      time,
+      sensor: InputDataSensor::Frame(InputFrame { images: self.images.iter().collect() }),
+    }))
+  }
+}
+
+fn parse_vector(value: &serde_json::Value, keys: &[&str]) -> Result<Vec<f64>> {
+  keys.iter()
+    .map(|key| value[key].as_f64().ok_or(anyhow!("Ground truth field {} is not a number.", key)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_csv_input() {
+    let _guard = set_test_parameters(|p| p.input_format = InputFormat::Csv);
+    let folder = std::env::temp_dir().join(format!("violet-csv-input-{}", std::process::id()));
+    std::fs::create_dir_all(&folder).unwrap();
+    // Reuse the images of the EuRoC test data with absolute paths.
+    let images = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/euroc/mav0");
+    let image = |j: usize, t: usize| images.join(format!("cam{}/data/14036365800{:02}000000.png", j, t)).display().to_string();
+    let imu = "time,gx,gy,gz,ax,ay,az\n0.000,1,2,3,4,5,6\n0.005,1,2,3,4,5,6\n0.010,1,2,3,4,5,6\n";
+    std::fs::write(folder.join("imu.csv"), imu).unwrap();
+    let frames = format!("time,left,right\n0.005,{},{}\n0.012, {} , {}\n", image(0, 5), image(1, 5), image(0, 12), image(1, 12));
+    std::fs::write(folder.join("frames.csv"), &frames).unwrap();
+
+    let read = || -> Result<Vec<String>> {
+      let mut input = Input::new(&folder)?;
+      let mut events = vec![];
+      while let Some(data) = input.next()? {
+        let ms = (data.time * 1e3).round() as usize;
+        events.push(match data.sensor {
+          InputDataSensor::Gyroscope(_) => format!("g{}", ms),
+          InputDataSensor::Accelerometer(_) => format!("a{}", ms),
+          InputDataSensor::Frame(frame) => {
+            assert_eq!(frame.images[1].value(0, 0), (100 + 10 * ms) as u8);
+            format!("f{}", ms)
+          },
+          InputDataSensor::Gps(fix) => format!("p{}:{}", ms, fix.accuracy),
+          InputDataSensor::GroundTruth(_) => unreachable!(),
+        });
+      }
+      Ok(events)
+    };
+    assert_eq!(read().unwrap(), ["g0", "a0", "g5", "a5", "f5", "g10", "a10", "f12"]);
+
+    // GPS fixes go before IMU samples and frames with the same time.
+    std::fs::write(folder.join("gps.csv"), "time,lat,lon,alt,acc\n0.003,60,25,10,1\n0.010,60,25,10,2\n0.020,60,25,10,3\n").unwrap();
+    assert_eq!(read().unwrap(), ["g0", "a0", "p3:1", "g5", "a5", "f5", "p10:2", "g10", "a10", "f12", "p20:3"]);
+    std::fs::write(folder.join("gps.csv"), "time,lat,lon,alt,acc\n0.003,60,25,10\n").unwrap();
+    let err = read().err().unwrap().to_string();
+    assert!(err.contains("gps.csv:2: expected 5 columns, found 4."), "{}", err);
+    std::fs::remove_file(folder.join("gps.csv")).unwrap();
+
+    // Times going backwards in either file are an error.
+    std::fs::write(folder.join("frames.csv"), frames.replace("0.012", "0.004")).unwrap();
+    let err = read().err().unwrap().to_string();
+    assert!(err.contains("frames.csv:3: time 0.004 goes backwards from the previous 0.005."), "{}", err);
+    std::fs::write(folder.join("frames.csv"), &frames).unwrap();
+    std::fs::write(folder.join("imu.csv"), imu.replace("0.010", "0.001")).unwrap();
+    let err = read().err().unwrap().to_string();
+    assert!(err.contains("imu.csv:4: time 0.001 goes backwards"), "{}", err);
+    std::fs::write(folder.join("imu.csv"), imu).unwrap();
+
+    // Rows with one path give monocular frames.
+    std::fs::write(folder.join("frames.csv"), format!("time,path\n0.005,{}\n", image(0, 5))).unwrap();
+    let mut input = Input::new(&folder).unwrap();
+    let mut image_counts = vec![];
+    while let Some(data) = input.next().unwrap() {
+      if let InputDataSensor::Frame(frame) = data.sensor {
+        image_counts.push(frame.images.len());
+      }
+    }
+    assert_eq!(image_counts, [1]);
+    std::fs::remove_dir_all(&folder).unwrap();
+  }
+}
@@ -0,0 +1,90 @@
+// Per-frame estimator state as JSON lines, one object per processed frame, for
+// post-processing eg with `pandas.read_json(path, lines=True)`. The fields are
+// those of `FrameState`. Fields may be added, but the existing ones keep their
+// names and meaning.
+
+#[cfg(feature = "json-output")]
+use crate::all::*;
+#[cfg(feature = "json-output")]
+use std::io::{BufWriter, Write};
+
+#[cfg_attr(feature = "json-output", derive(Serialize))]
+#[cfg_attr(not(feature = "json-output"), allow(dead_code))]
+pub struct FrameState {
+  // Input time of the frame in seconds.
+  pub time: f64,
+  // Number of frames seen, including those skipped by `frame_sub`.
+  pub frame_number: usize,
+  // IMU position in the VIO world frame, whose z-axis points up.
+  pub position: [f64; 3],
+  // IMU-to-world rotation as a quaternion [w, x, y, z].
+  pub orientation: [f64; 4],
+  // IMU velocity in the world frame (m/s).
+  pub velocity: [f64; 3],
+  pub gyroscope_bias: [f64; 3],
+  pub accelerometer_bias: [f64; 3],
+  // Tracks used in the visual update of the frame, zero if it was skipped.
+  pub tracks_used: usize,
+  // Tracks rejected by the outlier gate.
+  pub outliers: usize,
+}
+
+// Lines are flushed as they are written so that the file can be followed
+// during the run, eg with `tail -f`.
+#[cfg(feature = "json-output")]
+pub struct JsonWriter {
+  writer: BufWriter<File>,
+}
+
+#[cfg(feature = "json-output")]
+impl JsonWriter {
+  pub fn create(path: &Path) -> Result<JsonWriter> {
+    let file = File::create(path)
+      .context(format!("Failed to create JSON output file {}.", path.display()))?;
+    Ok(JsonWriter { writer: BufWriter::new(file) })
+  }
+
+  pub fn write(&mut self, state: &FrameState) -> Result<()> {
+    serde_json::to_writer(&mut self.writer, state)?;
+    writeln!(self.writer)?;
+    self.writer.flush()?;
+    Ok(())
+  }
+}
+
+#[cfg(all(test, feature = "json-output"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_json_output() {
+    let path = std::env::temp_dir().join(format!("violet-json-output-{}.jsonl", std::process::id()));
+    let state = |frame_number: usize| FrameState {
+      time: 0.1 * frame_number as f64,
+      frame_number,
+      position: [1., 2., 3.],
+      orientation: [1., 0., 0., 0.],
+      velocity: [0.5, 0., -0.25],
+      gyroscope_bias: [1e-3, 0., 0.],
+      accelerometer_bias: [0., 2e-2, 0.],
+      tracks_used: 40,
+      outliers: 3,
+    };
+    let mut writer = JsonWriter::create(&path).unwrap();
+    writer.write(&state(1)).unwrap();
+    writer.write(&state(2)).unwrap();
+    // Readable before the writer is closed.
+    let s = std::fs::read_to_string(&path).unwrap();
+    drop(writer);
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<serde_json::Value> = s.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["frame_number"], 2);
+    assert_eq!(lines[1]["time"], 0.2);
+    assert_eq!(lines[0]["velocity"], serde_json::json!([0.5, 0., -0.25]));
+    assert_eq!(lines[0]["tracks_used"], 40);
+    assert_eq!(lines[0]["outliers"], 3);
+    let keys: Vec<&String> = lines[0].as_object().unwrap().keys().collect();
+    assert_eq!(keys.len(), 9);
+  }
+}
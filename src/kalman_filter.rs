@@ -16,6 +16,8 @@
 
 use crate::all::*;
 
+use std::io::{BufWriter, Write};
+
 // Repeated poses forming the pose trail, starting from `CAM0`.
 const CAM_POS: usize = 0; // Position [x, y, z].
 const CAM_ORI: usize = 3; // Orientation [w, x, y, z].
@@ -25,7 +27,8 @@ const CAM_SIZE: usize = 7;
 const F_VEL: usize = 0; // Velocity.
 const F_BGA: usize = 3; // [B]ias [G]yroscope [A]dditive.
 const F_BAA: usize = 6; // [B]ias [A]ccelerometer [A]dditive.
-const CAM0: usize = 9; // Start of camera poses. The most recent comes first.
+const F_TD: usize = 9; // Camera-IMU [T]ime offset ([D]elay), see `get_time_offset()`.
+const CAM0: usize = 10; // Start of camera poses. The most recent comes first.
 const F_POS: usize = CAM0;
 const F_ORI: usize = CAM0 + CAM_ORI;
 const F_SIZE: usize = CAM0 + CAM_SIZE;
@@ -37,6 +40,32 @@ const Q_BGA: usize = 6; // BGA drift.
 const Q_BAA: usize = 9; // BAA drift.
 const Q_SIZE: usize = 12;
 
+// Format version of the files of `KalmanFilter::save()`. Bump it when the
+// saved fields or the state layout change.
+const STATE_FILE_VERSION: u32 = 3;
+
+// Contents of the files of `KalmanFilter::save()`.
+#[derive(Deserialize, Serialize)]
+struct FilterStateFile {
+  version: u32,
+  pose_trail_len: usize,
+  predict_count: usize,
+  augment_count: usize,
+  update_count: usize,
+  rejected_update_count: usize,
+  // Times of the newest pose and of the rest of the pose trail.
+  pose_times: Vec<Option<f64>>,
+  // Position and orientation quaternion of each pose of the trail, see
+  // `KalmanFilter::first_estimates`.
+  first_estimates: Vec<Option<[f64; 7]>>,
+  // See `KalmanFilter::get_state_labels()`.
+  state_labels: String,
+  landmark_ids: Vec<usize>,
+  x: Vec<f64>,
+  // Row by row.
+  P: Vec<Vec<f64>>,
+}
+
 // Note that these create references. To clone use eg:
 //   let x: VectorN = ori!().into();
 macro_rules! vel { ($x: expr) => { $x.fixed_slice::<3, 1>(F_VEL, 0) } }
@@ -82,24 +111,83 @@ const D_OMEGA: [Matrix4d; 3] = [
 
 // Internal pose representation needed for manipulation of the derivatives for EKF
 // updates. Inherits the mixed representation.
+#[derive(Clone)]
 pub struct KalmanFilterPose {
   // Device-to-world.
   pub p: Vector3d,
   // World-to-device.
   pub R: Matrix3d,
+  // `R` and `p` differentiated wrt the orientation quaternion of the IMU pose
+  // in the filter state. Camera positions depend on it through the lever arm.
   pub dR_dq: [Matrix3d; 4],
-  // For convenience.
-  pub camera_to_world: Matrix4d,
+  pub dp_dq: Matrix34d,
+}
+
+// Why the filter was found diverged, see `KalmanFilter::check_divergence()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Divergence {
+  // Standard deviation (meters) of the current position.
+  PositionStd(f64),
+  // Consecutive visual updates prevented by the outlier gate.
+  RejectedUpdates(usize),
+}
+
+impl std::fmt::Display for Divergence {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Divergence::PositionStd(std) => write!(f, "position standard deviation {:.2} m", std),
+      Divergence::RejectedUpdates(count) => write!(f, "{} consecutive visual updates rejected", count),
+    }
+  }
+}
+
+// Transform from the local ENU frame of the GPS fixes to the world frame.
+#[derive(Clone, Copy)]
+struct GpsAlignment {
+  // World position of the first fix, the origin of the ENU frame.
+  origin: Vector3d,
+  // Covariance of `origin`, from the position estimate and the first fix.
+  origin_covariance: Matrix3d,
+  // Rotation about the vertical, once estimated.
+  yaw: Option<f64>,
+  yaw_variance: f64,
+  // Horizontal distance of the fix the yaw was estimated from.
+  yaw_baseline: f64,
 }
 
 pub struct KalmanFilter {
   last_time: Option<f64>,
+  // Times of the poses of the trail after the newest one, which is at `last_time`.
+  pose_times: Vec<Option<f64>>,
+  // Position and orientation of each pose of the trail when it was first
+  // used in a visual update, or copied down the trail without one, see
+  // `use_fej`. None for the newest pose until then.
+  first_estimates: Vec<Option<(Vector3d, Vector4d)>>,
   pose_trail_len: usize,
+  // Includes the landmarks.
   state_len: usize,
+  // Tracks of the landmarks kept in the state after the pose trail, see
+  // `LandmarkMode::Slam`.
+  landmark_ids: Vec<TrackId>,
   gravity: Vector3d,
+  // Initial velocity standard deviation.
+  noise_vel: f64,
+  // See `disable_bias_estimation`.
+  bias_estimation: bool,
 
   predict_count: usize,
   augment_count: usize,
+  update_count: usize,
+
+  // See `check_divergence()`.
+  divergence_max_position_std: f64,
+  divergence_max_rejected_updates: usize,
+  rejected_update_count: usize,
+
+  // See `gps_update()`.
+  gps_alignment: Option<GpsAlignment>,
+  gps_alignment_distance: f64,
+  gps_outlier_confidence: f64,
 
   // State mean.
   x: Vectord,
@@ -160,6 +248,7 @@ impl KalmanFilter {
     F.fixed_slice_mut::<3, 3>(F_VEL, F_VEL).copy_from(&Matrix3d::identity());
     F.fixed_slice_mut::<3, 3>(F_BGA, F_BGA).copy_from(&Matrix3d::identity());
     F.fixed_slice_mut::<3, 3>(F_BAA, F_BAA).copy_from(&Matrix3d::identity());
+    F[(F_TD, F_TD)] = 1.;
 
     let mut L = DMatrix::zeros(F_SIZE, Q_SIZE);
     L.fixed_slice_mut::<3, 3>(F_BGA, Q_BGA).copy_from(&Matrix3d::identity());
@@ -168,6 +257,11 @@ impl KalmanFilter {
     let mut Q = DMatrix::zeros(Q_SIZE, Q_SIZE);
     set_diagonal(&mut Q, Q_A, 3, p.kf_noise_a);
     set_diagonal(&mut Q, Q_G, 3, p.kf_noise_g);
+    // The bias drift is scaled by the time step in `predict()`.
+    if !p.disable_bias_estimation {
+      set_diagonal(&mut Q, Q_BGA, 3, p.kf_noise_bga_walk);
+      set_diagonal(&mut Q, Q_BAA, 3, p.kf_noise_baa_walk);
+    }
 
     let mut aug_F = DMatrix::zeros(state_len, state_len);
     // Do not change state before the second pose in the trail.
@@ -179,21 +273,41 @@ impl KalmanFilter {
 
     let mut P = DMatrix::zeros(state_len, state_len);
     set_diagonal(&mut P, F_VEL, 3, p.kf_noise_vel);
-    set_diagonal(&mut P, F_BGA, 3, p.kf_noise_bga);
-    set_diagonal(&mut P, F_BAA, 3, p.kf_noise_baa);
+    // Without any bias uncertainty the updates never change the biases.
+    if !p.disable_bias_estimation {
+      set_diagonal(&mut P, F_BGA, 3, p.kf_noise_bga);
+      set_diagonal(&mut P, F_BAA, 3, p.kf_noise_baa);
+    }
+    // Without uncertainty the time offset stays at the initial guess.
+    set_diagonal(&mut P, F_TD, 1, p.time_offset_std);
     for i in 0..p.pose_trail_len {
       set_diagonal(&mut P, CAM0 + i * CAM_SIZE + CAM_POS, 3, p.kf_noise_pos);
       set_diagonal(&mut P, CAM0 + i * CAM_SIZE + CAM_ORI, 4, p.kf_noise_ori);
     }
 
+    let mut x = DVector::zeros(state_len);
+    x[F_TD] = p.time_offset;
+
     KalmanFilter {
       last_time: None,
+      pose_times: vec![None; pose_trail_len - 1],
+      first_estimates: vec![None; pose_trail_len],
       pose_trail_len,
       state_len,
+      landmark_ids: vec![],
       gravity: Vector3d::new(0., 0., -p.gravity),
+      noise_vel: p.kf_noise_vel,
+      bias_estimation: !p.disable_bias_estimation,
       predict_count: 0,
       augment_count: 0,
-      x: DVector::zeros(state_len),
+      update_count: 0,
+      divergence_max_position_std: p.divergence_max_position_std,
+      divergence_max_rejected_updates: p.divergence_max_rejected_updates,
+      rejected_update_count: 0,
+      gps_alignment: None,
+      gps_alignment_distance: p.gps_alignment_distance,
+      gps_outlier_confidence: p.gps_outlier_confidence,
+      x,
       P,
       Q,
       aug_F,
@@ -215,13 +329,52 @@ impl KalmanFilter {
     }
   }
 
+  // Number of visual, zero-velocity and GPS updates so far.
+  #[cfg(test)]
+  pub fn get_update_count(&self) -> usize {
+    self.update_count
+  }
+
   pub fn get_state_len(&self) -> usize {
     self.state_len
   }
 
-  #[allow(dead_code)]
-  pub fn get_camera_state_len(&self) -> usize {
-    CAM_SIZE * self.pose_trail_len
+  pub fn get_pose_trail_len(&self) -> usize {
+    self.pose_trail_len
+  }
+
+  #[cfg(test)]
+  pub fn get_state_mut(&mut self) -> &mut Vectord {
+    &mut self.x
+  }
+
+  pub fn get_velocity_ind(&self) -> usize {
+    F_VEL
+  }
+
+  #[cfg(test)]
+  pub fn get_gyro_bias_ind(&self) -> usize {
+    F_BGA
+  }
+
+  #[cfg(test)]
+  pub fn get_accel_bias_ind(&self) -> usize {
+    F_BAA
+  }
+
+  pub fn get_time_offset_ind(&self) -> usize {
+    F_TD
+  }
+
+  // Seconds added to the frame timestamps to get the IMU time at which the
+  // frame was exposed.
+  pub fn get_time_offset(&self) -> f64 {
+    self.x[F_TD]
+  }
+
+  // Time of pose `i` of the trail, None before the first prediction.
+  pub fn get_pose_time(&self, i: usize) -> Option<f64> {
+    if i == 0 { self.last_time } else { self.pose_times[i - 1] }
   }
 
   pub fn get_camera_pos_ind(&self, i: usize) -> usize {
@@ -232,6 +385,126 @@ impl KalmanFilter {
     CAM0 + CAM_ORI + i * CAM_SIZE
   }
 
+  pub fn get_velocity(&self) -> Vector3d {
+    vel!(self.x).into()
+  }
+
+  pub fn get_imu_biases(&self) -> ImuBiases {
+    ImuBiases {
+      gyroscope: bga!(self.x).into(),
+      accelerometer: baa!(self.x).into(),
+    }
+  }
+
+  // Standard deviations of the bias estimates.
+  pub fn get_imu_bias_stds(&self) -> ImuBiases {
+    let std = |i: usize| Vector3d::new(self.P[(i, i)].sqrt(), self.P[(i + 1, i + 1)].sqrt(), self.P[(i + 2, i + 2)].sqrt());
+    ImuBiases {
+      gyroscope: std(F_BGA),
+      accelerometer: std(F_BAA),
+    }
+  }
+
+  // Covariance of the newest pose: the position and then the orientation as a
+  // rotation vector in IMU coordinates. The filter stores the orientation as
+  // a world-to-IMU quaternion with additive errors `dq`, which rotate the IMU
+  // coordinates by `vee(dR/dq * dq * R^T)`. The component of `dq` along the
+  // quaternion only changes its norm and does not appear in the rotation.
+  pub fn current_pose_covariance(&self) -> Matrix6d {
+    let q: Vector4d = ori!(self.x, 0).into();
+    let q_as_R = to_rotation_matrix_d(q);
+    let mut J = Matrix34d::zeros();
+    for i in 0..4 {
+      let A = q_as_R.dR_dq[i] * q_as_R.R.transpose();
+      let A = 0.5 * (A - A.transpose());
+      J.column_mut(i).copy_from(&Vector3d::new(A[(2, 1)], A[(0, 2)], A[(1, 0)]));
+    }
+    let P_pp = self.P.fixed_slice::<3, 3>(F_POS, F_POS);
+    let P_pq = self.P.fixed_slice::<3, 4>(F_POS, F_ORI);
+    let P_qq = self.P.fixed_slice::<4, 4>(F_ORI, F_ORI);
+    let mut C = Matrix6d::zeros();
+    C.fixed_slice_mut::<3, 3>(0, 0).copy_from(&P_pp);
+    let C_pq = P_pq * J.transpose();
+    C.fixed_slice_mut::<3, 3>(0, 3).copy_from(&C_pq);
+    C.fixed_slice_mut::<3, 3>(3, 0).copy_from(&C_pq.transpose());
+    C.fixed_slice_mut::<3, 3>(3, 3).copy_from(&(J * P_qq * J.transpose()));
+    C
+  }
+
+  // As `current_pose_covariance()`, but of the camera with the extrinsics
+  // `imu_to_camera`, its orientation in camera coordinates. An orientation
+  // error `θ` of the IMU rotates the camera by `R_c θ`, where `R_c` is the
+  // rotation of `imu_to_camera`, and moves it by `R^T [c]_x θ`, where `R` is
+  // the world-to-IMU rotation and `c` the camera position in IMU coordinates.
+  pub fn current_camera_pose_covariance(&self, imu_to_camera: &Matrix4d) -> Matrix6d {
+    let R = to_rotation_matrix(ori!(self.x, 0).into());
+    let c: Vector3d = position!(affine_inverse(*imu_to_camera)).into();
+    let mut J = Matrix6d::identity();
+    J.fixed_slice_mut::<3, 3>(0, 3).copy_from(&(R.transpose() * c.cross_matrix()));
+    J.fixed_slice_mut::<3, 3>(3, 3).copy_from(&rotation!(imu_to_camera));
+    J * self.current_pose_covariance() * J.transpose()
+  }
+
+  // Standard deviation (radians) of the gravity direction in IMU coordinates,
+  // ie of the roll and pitch. The orientation covariance is propagated
+  // through `R(q) * [0, 0, 1]`, which is small-angle accurate.
+  pub fn get_tilt_std(&self) -> f64 {
+    let q: Vector4d = ori!(self.x, 0).into();
+    if q == Vector4d::zeros() { return f64::NAN }
+    let q_as_R = to_rotation_matrix_d(q);
+    let mut J = Matrix34d::zeros();
+    for i in 0..4 {
+      J.column_mut(i).copy_from(&q_as_R.dR_dq[i].column(2));
+    }
+    let P_q = self.P.fixed_slice::<4, 4>(F_ORI, F_ORI);
+    (J * P_q * J.transpose()).trace().max(0.).sqrt()
+  }
+
+  // Gravity (m/s^2) in IMU coordinates given the current orientation. At rest
+  // the accelerometer reads its negation plus the bias.
+  pub fn get_gravity(&self) -> Vector3d {
+    let R = to_rotation_matrix(ori!(self.x, 0).into());
+    R * self.gravity
+  }
+
+  // Roll and pitch (radians) of the IMU, ie its orientation relative to
+  // gravity, which unlike the yaw the IMU observes. At rest the unbiased
+  // accelerometer reads
+  //   g * [-sin(pitch), sin(roll) cos(pitch), cos(roll) cos(pitch)].
+  pub fn get_roll_pitch(&self) -> (f64, f64) {
+    let up = -self.get_gravity();
+    (up[1].atan2(up[2]), (-up[0]).atan2(up[1].hypot(up[2])))
+  }
+
+  // Accelerometer sample minus its bias and the gravity rotated into the IMU
+  // coordinates with the current orientation. This is the acceleration of
+  // the device and should be near zero when it is still.
+  pub fn get_residual_acceleration(&self, accelerometer: Vector3d) -> Vector3d {
+    let R = to_rotation_matrix(ori!(self.x, 0).into());
+    accelerometer - baa!(self.x) + R * self.gravity
+  }
+
+  // Start bias estimation from previously estimated values instead of zero.
+  pub fn set_imu_biases(&mut self, biases: &ImuBiases) {
+    self.x.fixed_slice_mut::<3, 1>(F_BGA, 0).copy_from(&biases.gyroscope);
+    self.x.fixed_slice_mut::<3, 1>(F_BAA, 0).copy_from(&biases.accelerometer);
+  }
+
+  // Sets the orientation so that `gravity`, the accelerometer reading of the
+  // device at rest, points to world +z, opposite to the gravitational
+  // acceleration. The yaw is arbitrary.
+  pub fn initialize_orientation(&mut self, gravity: Vector3d) {
+    // Based on <https://math.stackexchange.com/a/2313401>.
+    let x = &mut self.x;
+    let u = -self.gravity;
+    let v = gravity;
+    let un = u.norm();
+    let vn = v.norm();
+    let n = 1. / (u * vn + un * v).norm();
+    x[F_ORI] = n * (un * vn + (u.transpose() * v)[(0, 0)]); // w component
+    x.fixed_slice_mut::<3, 1>(F_ORI + 1, 0).copy_from(&(n * u.cross(&v))); // xyz components
+  }
+
   // Prediction step that uses a dynamic model derived from physics with a
   // control model based on the IMU measurements.
   pub fn predict(
@@ -240,25 +513,16 @@ impl KalmanFilter {
     gyroscope: Vector3d,
     accelerometer: Vector3d,
   ) {
-    let x = &mut self.x;
-    if ori!(x, 0) == Vector4d::zeros() {
-      // Initialize orientation based on the first accelometer sample.
-      // Based on <https://math.stackexchange.com/a/2313401>.
-      let u = -self.gravity;
-      let v = accelerometer;
-      let un = u.norm();
-      let vn = v.norm();
-      let n = 1. / (u * vn + un * v).norm();
-      x[F_ORI] = n * (un * vn + (u.transpose() * v)[(0, 0)]); // w component
-      x.fixed_slice_mut::<3, 1>(F_ORI + 1, 0).copy_from(&(n * u.cross(&v))); // xyz components
+    if ori!(self.x, 0) == Vector4d::zeros() {
+      // Assume the device is at rest at the first sample.
+      self.initialize_orientation(accelerometer);
     }
+    let x = &mut self.x;
 
     let dt = if let Some(last_time) = self.last_time { time - last_time } else { 0. };
     self.last_time = Some(time);
     if dt <= 0. { return }
 
-    // TODO Bias random walk.
-
     let g = gyroscope - bga!(x); // Unbiased gyroscope.
 
     let Omega = (-0.5 * dt * Matrix4d::new(
@@ -287,8 +551,8 @@ impl KalmanFilter {
     F.fixed_slice_mut::<3, 3>(F_POS, F_VEL).copy_from(&(dt * Matrix3d::identity()));
 
     let mut Y = Matrix34d::zeros();
-    for i in 0..4 {
-      Y.fixed_slice_mut::<3, 1>(0, i).copy_from(&(dt * dR[i].transpose() * a));
+    for (i, dR_i) in dR.iter().enumerate() {
+      Y.fixed_slice_mut::<3, 1>(0, i).copy_from(&(dt * dR_i.transpose() * a));
     }
     F.fixed_slice_mut::<3, 4>(F_VEL, F_ORI).copy_from(&(Y * Omega));
 
@@ -297,8 +561,12 @@ impl KalmanFilter {
     let L = &mut self.tmp.L;
     L.fixed_slice_mut::<3, 3>(F_VEL, Q_A).copy_from(&(dt * R.transpose()));
 
-    for i in 0..3 {
-      L.fixed_slice_mut::<4, 1>(F_ORI, Q_G + i).copy_from(&(dt * Omega * D_OMEGA[i] * last_q));
+    // The biases are random walks, whose variance grows linearly in time.
+    L.fixed_slice_mut::<3, 3>(F_BGA, Q_BGA).copy_from(&(dt.sqrt() * Matrix3d::identity()));
+    L.fixed_slice_mut::<3, 3>(F_BAA, Q_BAA).copy_from(&(dt.sqrt() * Matrix3d::identity()));
+
+    for (i, d_omega) in D_OMEGA.iter().enumerate() {
+      L.fixed_slice_mut::<4, 1>(F_ORI, Q_G + i).copy_from(&(dt * Omega * d_omega * last_q));
     }
 
     let Z = F.fixed_slice::<3, 4>(F_VEL, F_ORI) * L.fixed_slice::<4, 3>(F_ORI, Q_G);
@@ -320,6 +588,10 @@ impl KalmanFilter {
           + &*L * &self.Q * &L.transpose()
       ));
     let n = self.state_len;
+    self.tmp.P.resize_mut(n, n, 0.);
+    // `tmp.P` holds an older covariance, so also copy the unchanged part.
+    self.tmp.P.slice_mut((F_SIZE, F_SIZE), (n - F_SIZE, n - F_SIZE))
+      .copy_from(&P.slice((F_SIZE, F_SIZE), (n - F_SIZE, n - F_SIZE)));
     self.tmp.P.slice_mut((F_SIZE, 0), (n - F_SIZE, F_SIZE))
       .copy_from(&(P.slice_mut((F_SIZE, 0), (n - F_SIZE, F_SIZE)) * &F.transpose()));
     self.tmp.P.slice_mut((0, F_SIZE), (F_SIZE, n - F_SIZE))
@@ -329,15 +601,125 @@ impl KalmanFilter {
     self.predict_count += 1;
   }
 
-  // Prediction step that shifts the pose trail.
+  // Continue from `time` without propagating over the time since the last
+  // prediction, when the IMU samples in between are missing. The velocity
+  // may have changed arbitrarily during the gap, so its uncertainty is reset
+  // to the initial value instead of being grown by the missing interval.
+  pub fn skip_gap(&mut self, time: f64) {
+    self.last_time = Some(time);
+    let n = self.state_len;
+    self.P.slice_mut((F_VEL, 0), (3, n)).fill(0.);
+    self.P.slice_mut((0, F_VEL), (n, 3)).fill(0.);
+    for i in F_VEL..(F_VEL + 3) {
+      self.P[(i, i)] = self.noise_vel.powi(2);
+    }
+  }
+
+  // Per-sample standard deviations of the gyroscope and accelerometer noise
+  // and the random walks of their biases, overriding `kf_noise_g`,
+  // `kf_noise_a`, `kf_noise_bga_walk` and `kf_noise_baa_walk`. Zero random
+  // walks, too small to estimate, keep the configured ones.
+  pub fn set_imu_noise(&mut self, noise_g: f64, noise_a: f64, walk_g: f64, walk_a: f64) {
+    for i in 0..3 {
+      self.Q[(Q_G + i, Q_G + i)] = noise_g.powi(2);
+      self.Q[(Q_A + i, Q_A + i)] = noise_a.powi(2);
+      if !self.bias_estimation { continue }
+      if walk_g > 0. { self.Q[(Q_BGA + i, Q_BGA + i)] = walk_g.powi(2) }
+      if walk_a > 0. { self.Q[(Q_BAA + i, Q_BAA + i)] = walk_a.powi(2) }
+    }
+  }
+
+  // Counts the consecutive visual updates that were skipped because the
+  // outlier gate rejected their tracks, for `check_divergence()`.
+  pub fn record_visual_update(&mut self, rejected: bool) {
+    if rejected { self.rejected_update_count += 1 } else { self.rejected_update_count = 0 }
+  }
+
+  // Detects a filter that has lost track of the pose, eg after a tracking
+  // failure during fast motion: the standard deviation of the position, the
+  // root of the trace of its covariance, above `divergence_max_position_std`,
+  // or `divergence_max_rejected_updates` consecutive visual updates rejected
+  // by the outlier gate. Zero thresholds disable the checks.
+  pub fn check_divergence(&self) -> Option<Divergence> {
+    let max_rejected = self.divergence_max_rejected_updates;
+    if max_rejected > 0 && self.rejected_update_count >= max_rejected {
+      return Some(Divergence::RejectedUpdates(self.rejected_update_count));
+    }
+    let position_std = self.P.fixed_slice::<3, 3>(F_POS, F_POS).trace().sqrt();
+    let max_std = self.divergence_max_position_std;
+    if max_std > 0. && (position_std > max_std || position_std.is_nan()) {
+      return Some(Divergence::PositionStd(position_std));
+    }
+    None
+  }
+
+  // Restarts the filter at the current position after `check_divergence()`.
+  // The orientation is initialized from the accelerometer reading `gravity`
+  // as in `initialize_orientation()`, the velocity is zeroed, the pose trail
+  // and the landmarks are dropped and the covariance is set back to the
+  // initial one. The bias and time offset estimates are kept.
+  pub fn soft_reset(&mut self, gravity: Vector3d) {
+    let mut reset = KalmanFilter::new();
+    let n = F_TD + 1 - F_BGA;
+    reset.x.rows_mut(F_BGA, n).copy_from(&self.x.rows(F_BGA, n));
+    reset.x.fixed_slice_mut::<3, 1>(F_POS, 0).copy_from(&pos!(self.x, 0));
+    reset.initialize_orientation(gravity);
+    reset.last_time = self.last_time;
+    // Keeps the noise of `set_imu_noise()`.
+    mem::swap(&mut reset.Q, &mut self.Q);
+    reset.predict_count = self.predict_count;
+    reset.augment_count = self.augment_count;
+    reset.update_count = self.update_count;
+    reset.gps_alignment = self.gps_alignment;
+    *self = reset;
+  }
+
+  // Velocity standard deviations.
+  pub fn get_velocity_std(&self) -> Vector3d {
+    Vector3d::new(self.P[(F_VEL, F_VEL)], self.P[(F_VEL + 1, F_VEL + 1)], self.P[(F_VEL + 2, F_VEL + 2)]).map(|x| x.sqrt())
+  }
+
+  // Removes the oldest pose of the trail from the state, leaving its slot
+  // zero. In the covariance form marginalizing out a part of the state only
+  // drops its rows and columns: the covariance of the rest stays as is. The
+  // Schur complement of the dropped block would instead condition the rest
+  // on the dropped pose, as if it were known exactly, and make the filter
+  // overconfident.
+  pub fn marginalize_oldest_pose(&mut self) {
+    let start = CAM0 + CAM_SIZE * (self.pose_trail_len - 1);
+    self.x.rows_mut(start, CAM_SIZE).fill(0.);
+    self.P.rows_mut(start, CAM_SIZE).fill(0.);
+    self.P.columns_mut(start, CAM_SIZE).fill(0.);
+    self.first_estimates[self.pose_trail_len - 1] = None;
+  }
+
+  // Prediction step that shifts the pose trail. At `pose_trail_len` the oldest
+  // pose is marginalized to make room for the new one.
   pub fn augment_pose(&mut self) {
     self.check_nan(); // Periodic check for development use.
+    self.marginalize_oldest_pose();
 
-    self.tmp.x.copy_from(&(&self.aug_F * &self.x));
+    // The landmarks after the pose trail do not change.
+    let n = self.state_len;
+    let m = self.landmark0();
+    self.tmp.x.copy_from(&self.x);
+    self.tmp.x.rows_mut(0, m).copy_from(&(&self.aug_F * self.x.rows(0, m)));
     mem::swap(&mut self.x, &mut self.tmp.x);
 
-    self.tmp.P.copy_from(&(&self.aug_F * &self.P * &self.aug_F.transpose()));
+    self.tmp.P.copy_from(&self.P);
+    self.tmp.P.slice_mut((0, 0), (m, m))
+      .copy_from(&(&self.aug_F * self.P.slice((0, 0), (m, m)) * self.aug_F.transpose()));
+    let P_pl = &self.aug_F * self.P.slice((0, m), (m, n - m));
+    self.tmp.P.slice_mut((m, 0), (n - m, m)).copy_from(&P_pl.transpose());
+    self.tmp.P.slice_mut((0, m), (m, n - m)).copy_from(&P_pl);
     mem::swap(&mut self.P, &mut self.tmp.P);
+    self.pose_times.insert(0, self.last_time);
+    self.pose_times.truncate(self.pose_trail_len - 1);
+    if self.first_estimates[0].is_none() {
+      self.first_estimates[0] = Some(self.pose_estimate(1));
+    }
+    self.first_estimates.insert(0, None);
+    self.first_estimates.truncate(self.pose_trail_len);
 
     // May not be necessary.
     for i in CAM0..(CAM0 + CAM_SIZE) {
@@ -347,18 +729,54 @@ impl KalmanFilter {
     self.augment_count += 1;
   }
 
+  // `r` has the standard deviation of each measurement row.
   pub fn update_visual(
     &mut self,
     H: &Matrixd,
     y: &Vectord,
-    r: f64,
+    r: &Vectord,
   ) {
-    let n = H.nrows();
-    self.tmp_update.R = r.powi(2) * Matrixd::identity(n, n);
-    // TODO Outlier check.
+    self.tmp_update.R = Matrixd::from_diagonal(&r.map(|x| x.powi(2)));
+    if self.first_estimates[0].is_none() {
+      self.first_estimates[0] = Some(self.pose_estimate(0));
+    }
 
     update(&mut self.x, &mut self.P, H, y, &self.tmp_update.R, &mut self.tmp);
     self.normalize_quaternions();
+    self.update_count += 1;
+  }
+
+  // Squared Mahalanobis distance of the measurement residual `y` divided by
+  // its dimension, for gating outliers before `update_visual()`.
+  pub fn normalized_innovation(&self, H: &Matrixd, y: &Vectord, r: &Vectord) -> Option<f64> {
+    let ny = y.nrows();
+    let nh = H.ncols();
+    let S = H * self.P.slice((0, 0), (nh, nh)) * H.transpose() + Matrixd::from_diagonal(&r.map(|x| x.powi(2)));
+    let chol = nalgebra::linalg::Cholesky::new(S)?;
+    Some(y.dot(&chol.solve(y)) / ny as f64)
+  }
+
+  // Whether the measurement passes the outlier gate, ie its normalized
+  // innovation is at most `dof_threshold`, the bound of the squared
+  // Mahalanobis distance per dimension. Fails if the innovation covariance is
+  // not positive definite.
+  pub fn mahalanobis_gate(&self, H: &Matrixd, y: &Vectord, r: &Vectord, dof_threshold: f64) -> bool {
+    self.normalized_innovation(H, y, r).is_some_and(|d| d <= dof_threshold)
+  }
+
+  // Covariance `D * P * D^T` of a function of the state with Jacobian `D`,
+  // whose columns may cover only the start of the state.
+  pub fn propagate_covariance(&self, D: &Matrixd) -> Matrixd {
+    let nd = D.ncols();
+    D * self.P.slice((0, 0), (nd, nd)) * D.transpose()
+  }
+
+  // Zero-velocity update with noise `r` if the IMU samples in `imu_window`
+  // show the device at rest. Returns true if the update was applied.
+  pub fn zero_velocity_update(&mut self, imu_window: &mut ImuStationary, r: f64) -> bool {
+    if !imu_window.check() { return false }
+    self.update_zero_velocity(r);
+    true
   }
 
   pub fn update_zero_velocity(&mut self, r: f64) {
@@ -378,6 +796,90 @@ impl KalmanFilter {
       &mut self.tmp,
     );
     self.normalize_quaternions();
+    self.update_count += 1;
+  }
+
+  // Absolute position measurement from a GPS fix at `time`, given in the
+  // local ENU frame whose origin is the first fix, with its `covariance`.
+  // The first fix places that origin at the current position estimate. The
+  // VIO yaw is arbitrary, so the rotation between the frames is estimated
+  // from the first fix `gps_alignment_distance` away from the origin, and
+  // before that only the altitude is used. The yaw is estimated again each
+  // time the fixes reach twice as far, as the longer baseline gives a better
+  // estimate. The uncertainties of the origin and the yaw are added to the
+  // measurement noise. Fixes arrive much slower than the IMU samples and
+  // between them, so the position is moved to the time of the fix with the
+  // velocity. Returns false if the fix was rejected by
+  // `gps_outlier_confidence`.
+  pub fn gps_update(&mut self, time: f64, position_enu: Vector3d, covariance: Matrix3d) -> bool {
+    let dt = self.last_time.map_or(0., |last_time| time - last_time);
+    let position: Vector3d = pos!(self.x, 0) + dt * vel!(self.x);
+    let H_position = Matrixd::from_fn(3, F_POS + 3, |i, j| {
+      if j == F_VEL + i { dt } else if j == F_POS + i { 1. } else { 0. }
+    });
+    let position_covariance: Matrix3d = self.propagate_covariance(&H_position).fixed_slice::<3, 3>(0, 0).into();
+    let Some(alignment) = &mut self.gps_alignment else {
+      self.gps_alignment = Some(GpsAlignment {
+        origin: position,
+        origin_covariance: position_covariance + covariance,
+        yaw: None,
+        yaw_variance: 0.,
+        yaw_baseline: 0.,
+      });
+      return true;
+    };
+    let baseline = position_enu.xy().norm();
+    let min_baseline = match alignment.yaw {
+      Some(_) => 2. * alignment.yaw_baseline,
+      None => self.gps_alignment_distance,
+    };
+    if baseline >= min_baseline {
+      let d = position - alignment.origin;
+      alignment.yaw = Some(d[1].atan2(d[0]) - position_enu[1].atan2(position_enu[0]));
+      // Per-axis horizontal variances of the two ends of the baseline.
+      let horizontal_variance = |C: &Matrix3d| 0.5 * (C[(0, 0)] + C[(1, 1)]);
+      let end_variance = horizontal_variance(&position_covariance) + horizontal_variance(&covariance);
+      alignment.yaw_variance = (horizontal_variance(&alignment.origin_covariance) + end_variance) / baseline.powi(2);
+      alignment.yaw_baseline = baseline;
+    }
+    let (enu_to_world, first_row) = match alignment.yaw {
+      Some(yaw) => {
+        let (s, c) = yaw.sin_cos();
+        (Matrix3d::new(c, -s, 0., s, c, 0., 0., 0., 1.), 0)
+      },
+      None => (Matrix3d::identity(), 2),
+    };
+    let y = alignment.origin + enu_to_world * position_enu - position;
+    // Derivative of the fix in the world frame with respect to the yaw.
+    let yaw_derivative = Vector3d::new(0., 0., 1.).cross(&(enu_to_world * position_enu));
+    let R = enu_to_world * covariance * enu_to_world.transpose()
+      + alignment.origin_covariance
+      + alignment.yaw_variance * yaw_derivative * yaw_derivative.transpose();
+
+    let n = 3 - first_row;
+    self.tmp_update.H.resize_mut(n, F_POS + 3, 0.);
+    self.tmp_update.H.copy_from(&H_position.rows(first_row, n));
+    self.tmp_update.y.resize_vertically_mut(n, 0.);
+    self.tmp_update.y.copy_from(&y.rows(first_row, n));
+    self.tmp_update.R.resize_mut(n, n, 0.);
+    self.tmp_update.R.copy_from(&R.slice((first_row, first_row), (n, n)));
+    if self.gps_outlier_confidence > 0. {
+      let S = self.propagate_covariance(&self.tmp_update.H) + &self.tmp_update.R;
+      let Some(S_inv) = S.try_inverse() else { return false };
+      let d = (self.tmp_update.y.transpose() * S_inv * &self.tmp_update.y)[0];
+      if d > chi_squared_quantile(self.gps_outlier_confidence, n) { return false }
+    }
+    update(
+      &mut self.x,
+      &mut self.P,
+      &self.tmp_update.H,
+      &self.tmp_update.y,
+      &self.tmp_update.R,
+      &mut self.tmp,
+    );
+    self.normalize_quaternions();
+    self.update_count += 1;
+    true
   }
 
   pub fn normalize_quaternions(&mut self) {
@@ -401,41 +903,228 @@ impl KalmanFilter {
   pub fn get_camera_pose_trail(
     &self,
     indices: &[usize],
-    cameras: [&Camera; 2],
-    poses: &mut Vec<[KalmanFilterPose; 2]>,
+    cameras: &[&Camera],
+    poses: &mut Vec<Vec<KalmanFilterPose>>,
+  ) -> bool {
+    self.camera_poses(indices, cameras, poses, |i| self.pose_estimate(i))
+  }
+
+  // As `get_camera_pose_trail()`, but at the first estimates of the poses
+  // where set, for evaluating the visual update Jacobians, see `use_fej`.
+  pub fn get_first_estimate_camera_pose_trail(
+    &self,
+    indices: &[usize],
+    cameras: &[&Camera],
+    poses: &mut Vec<Vec<KalmanFilterPose>>,
+  ) -> bool {
+    self.camera_poses(indices, cameras, poses, |i| self.first_estimates[i].unwrap_or_else(|| self.pose_estimate(i)))
+  }
+
+  // Position and orientation of pose `i` of the trail.
+  fn pose_estimate(&self, i: usize) -> (Vector3d, Vector4d) {
+    (pos!(self.x, i).into(), ori!(self.x, i).into())
+  }
+
+  fn camera_poses<F: Fn(usize) -> (Vector3d, Vector4d)>(
+    &self,
+    indices: &[usize],
+    cameras: &[&Camera],
+    poses: &mut Vec<Vec<KalmanFilterPose>>,
+    estimate: F,
   ) -> bool {
     let transform = |A: &KalmanFilterPose, q, imu_to_camera| {
       let imu_to_world = imu_to_world(A.p, q);
-      let camera_to_world = imu_to_world * affine_inverse(imu_to_camera);
+      let camera_to_imu = affine_inverse(imu_to_camera);
+      let camera_to_world = imu_to_world * camera_to_imu;
       let world_to_camera = affine_inverse(camera_to_world);
+      // The camera position is `A.p + A.R^T * position!(camera_to_imu)`.
+      let mut dp_dq = Matrix34d::zeros();
+      for k in 0..4 {
+        dp_dq.column_mut(k).copy_from(&(A.dR_dq[k].transpose() * position!(camera_to_imu)));
+      }
       KalmanFilterPose {
-        camera_to_world: camera_to_world,
         p: position!(camera_to_world).into(),
         R: rotation!(world_to_camera).into(),
-        dR_dq: A.dR_dq.map(|dR_dqi| rotation!(world_to_camera) * dR_dqi),
+        dR_dq: A.dR_dq.map(|dR_dqi| rotation!(imu_to_camera) * dR_dqi),
+        dp_dq,
       }
     };
 
-    poses.clear();
-    for i in indices {
-      let q = ori!(self.x, i).into();
-      let p = pos!(self.x, i).into();
-      if q == Vector4d::zeros() { return false }
+    // The per-camera vectors are reused to avoid allocating for every track.
+    poses.resize_with(indices.len(), Vec::new);
+    for (i, camera_poses) in indices.iter().zip(poses.iter_mut()) {
+      camera_poses.clear();
+      // Marginalized poses are zero.
+      if ori!(self.x, i) == Vector4d::zeros() { return false }
+      let (p, q) = estimate(*i);
       let q_as_R = to_rotation_matrix_d(q);
       let imu_pose = KalmanFilterPose {
-        camera_to_world: Matrix4d::zeros(),
         p,
         R: q_as_R.R,
         dR_dq: q_as_R.dR_dq,
+        dp_dq: Matrix34d::zeros(),
       };
-      poses.push([
-        transform(&imu_pose, q, cameras[0].imu_to_camera),
-        transform(&imu_pose, q, cameras[1].imu_to_camera),
-      ]);
+      camera_poses.extend(cameras.iter().map(|camera| transform(&imu_pose, q, camera.imu_to_camera)));
     }
     true
   }
 
+  // One character per state element for debug output: `v` for velocity, `g`
+  // and `a` for the gyroscope and accelerometer biases, `t` for the time
+  // offset, `p` and `q` for the position and orientation of each pose of the
+  // trail (upper case for every other pose) and `l` for landmarks.
+  pub fn get_state_labels(&self, labels: &mut String) {
+    labels.clear();
+    labels.push_str("vvvgggaaat");
+    for i in 0..self.pose_trail_len {
+      let (p, q) = if i % 2 == 0 { ('p', 'q') } else { ('P', 'Q') };
+      labels.extend([p; 3]);
+      labels.extend([q; 4]);
+    }
+    for _ in &self.landmark_ids {
+      labels.push_str("lll");
+    }
+  }
+
+  // Index of the first landmark in the state.
+  fn landmark0(&self) -> usize {
+    CAM0 + CAM_SIZE * self.pose_trail_len
+  }
+
+  pub fn get_landmark_ids(&self) -> &[TrackId] {
+    &self.landmark_ids
+  }
+
+  pub fn get_landmark_ind(&self, i: usize) -> usize {
+    self.landmark0() + 3 * i
+  }
+
+  pub fn get_landmark(&self, i: usize) -> Vector3d {
+    self.x.fixed_slice::<3, 1>(self.get_landmark_ind(i), 0).into()
+  }
+
+  // Writes the state, its covariance, the pose trail timestamps and the
+  // landmarks as JSON, from which `load()` continues exactly as this filter
+  // would. The numbers are written with enough digits to read back exactly.
+  // The noise parameters are not saved and come from `PARAMETER_SET` when
+  // loading, and the GPS alignment is estimated again from the next fixes.
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let file = File::create(path)
+      .context(format!("Failed to create filter state file {}.", path.display()))?;
+    let mut state_labels = String::new();
+    self.get_state_labels(&mut state_labels);
+    let state = FilterStateFile {
+      version: STATE_FILE_VERSION,
+      pose_trail_len: self.pose_trail_len,
+      predict_count: self.predict_count,
+      augment_count: self.augment_count,
+      update_count: self.update_count,
+      rejected_update_count: self.rejected_update_count,
+      pose_times: std::iter::once(self.last_time).chain(self.pose_times.iter().copied()).collect(),
+      first_estimates: self.first_estimates.iter()
+        .map(|estimate| estimate.map(|(p, q)| [p[0], p[1], p[2], q[0], q[1], q[2], q[3]]))
+        .collect(),
+      state_labels,
+      landmark_ids: self.landmark_ids.iter().map(|id| id.0).collect(),
+      x: self.x.iter().copied().collect(),
+      P: self.P.row_iter().map(|row| row.iter().copied().collect()).collect(),
+    };
+    let mut w = BufWriter::new(file);
+    serde_json::to_writer(&mut w, &state)?;
+    writeln!(w)?;
+    w.flush()?;
+    Ok(())
+  }
+
+  // Reads a filter written by `save()`. The pose trail length must match
+  // `pose_trail_len`.
+  pub fn load(path: &Path) -> Result<KalmanFilter> {
+    let file = File::open(path)
+      .context(format!("Failed to open filter state file {}.", path.display()))?;
+    let value: serde_json::Value = serde_json::from_reader(BufReader::new(file))
+      .context(format!("{} is not a filter state file.", path.display()))?;
+    // Check the version first to not report other format changes as parse errors.
+    let version = value["version"].as_u64()
+      .ok_or(anyhow!("{} is not a filter state file.", path.display()))?;
+    if version != STATE_FILE_VERSION as u64 {
+      bail!("{} has format version {}, expected {}.", path.display(), version, STATE_FILE_VERSION);
+    }
+    let state: FilterStateFile = serde_json::from_value(value)
+      .context(format!("Failed to parse filter state file {}.", path.display()))?;
+    let mut kalman_filter = KalmanFilter::new();
+    let pose_trail_len = kalman_filter.pose_trail_len;
+    if state.pose_trail_len != pose_trail_len {
+      bail!(
+        "{} has a pose trail of {} poses, but `pose_trail_len` is {}.",
+        path.display(), state.pose_trail_len, pose_trail_len,
+      );
+    }
+    let n = kalman_filter.landmark0() + 3 * state.landmark_ids.len();
+    if state.pose_times.len() != pose_trail_len || state.first_estimates.len() != pose_trail_len
+      || state.x.len() != n || state.P.len() != n || state.P.iter().any(|row| row.len() != n)
+    {
+      bail!("{} has inconsistent state dimensions.", path.display());
+    }
+    kalman_filter.predict_count = state.predict_count;
+    kalman_filter.augment_count = state.augment_count;
+    kalman_filter.update_count = state.update_count;
+    kalman_filter.rejected_update_count = state.rejected_update_count;
+    kalman_filter.last_time = state.pose_times[0];
+    kalman_filter.pose_times = state.pose_times[1..].to_vec();
+    kalman_filter.first_estimates = state.first_estimates.iter()
+      .map(|estimate| estimate.map(|e| (Vector3d::new(e[0], e[1], e[2]), Vector4d::new(e[3], e[4], e[5], e[6]))))
+      .collect();
+    kalman_filter.landmark_ids = state.landmark_ids.into_iter().map(TrackId).collect();
+    kalman_filter.state_len = n;
+    kalman_filter.x = DVector::from_vec(state.x);
+    kalman_filter.P = DMatrix::from_fn(n, n, |i, j| state.P[i][j]);
+    kalman_filter.resize_tmp();
+    Ok(kalman_filter)
+  }
+
+  // Appends a landmark to the state. `d_position` is the derivative of the
+  // triangulated `position` with respect to the state, whose columns may
+  // cover only the start of the state. The covariance of the landmark is that
+  // of the state propagated through it, correlating the landmark with the
+  // poses it was triangulated from, plus `std` for the triangulation itself.
+  pub fn add_landmark(&mut self, id: TrackId, position: Vector3d, d_position: &Matrixd, std: f64) {
+    let n = self.state_len;
+    let nd = d_position.ncols();
+    self.x.resize_vertically_mut(n + 3, 0.);
+    self.x.fixed_slice_mut::<3, 1>(n, 0).copy_from(&position);
+    let cross_covariance = self.P.slice((0, 0), (n, nd)) * d_position.transpose();
+    let covariance = d_position * cross_covariance.rows(0, nd) + Matrix3d::identity() * std.powi(2);
+    self.P.resize_mut(n + 3, n + 3, 0.);
+    self.P.slice_mut((0, n), (n, 3)).copy_from(&cross_covariance);
+    self.P.slice_mut((n, 0), (3, n)).copy_from(&cross_covariance.transpose());
+    self.P.fixed_slice_mut::<3, 3>(n, n).copy_from(&covariance);
+    self.landmark_ids.push(id);
+    self.state_len = n + 3;
+    self.resize_tmp();
+  }
+
+  // Marginalizes the landmarks for which `keep` returns false, by dropping
+  // them from the state.
+  pub fn retain_landmarks<F: Fn(TrackId) -> bool>(&mut self, keep: F) {
+    for i in (0..self.landmark_ids.len()).rev() {
+      if keep(self.landmark_ids[i]) { continue }
+      let ind = self.get_landmark_ind(i);
+      let x = mem::replace(&mut self.x, DVector::zeros(0));
+      self.x = x.remove_rows(ind, 3);
+      let P = mem::replace(&mut self.P, DMatrix::zeros(0, 0));
+      self.P = P.remove_rows(ind, 3).remove_columns(ind, 3);
+      self.landmark_ids.remove(i);
+      self.state_len -= 3;
+    }
+    self.resize_tmp();
+  }
+
+  fn resize_tmp(&mut self) {
+    let n = self.state_len;
+    self.tmp.x.resize_vertically_mut(n, 0.);
+    self.tmp.P.resize_mut(n, n, 0.);
+  }
+
   #[allow(dead_code)]
   fn check_nan(&self) {
     for i in 0..self.state_len {
@@ -461,7 +1150,7 @@ fn update(
 ) {
   let ny = y.nrows(); // Measurement size.
   let nh = H.ncols(); // (Truncated) state size.
-  let nx = tmp.P.ncols(); // Full state size.
+  let nx = P.ncols(); // Full state size.
   assert_eq!(H.nrows(), ny);
   assert_eq!(R.shape(), (ny, ny));
   tmp.HP.resize_mut(ny, nx, 0.);
@@ -495,3 +1184,395 @@ fn update(
   tmp.P = &tmp.IKH * &*P * &tmp.IKH.transpose() + &tmp.K * R * &tmp.K.transpose();
   mem::swap(&mut *P, &mut tmp.P);
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::SeedableRng;
+
+  #[test]
+  fn test_predict_keeps_pose_trail_covariance() {
+    let _guard = set_test_parameters(|_| {});
+    let acceleration = Vector3d::new(0., 0., PARAMETER_SET.lock().unwrap().gravity);
+    let mut kalman_filter = KalmanFilter::new();
+    for i in 0..10 {
+      kalman_filter.predict(0.01 * i as f64, Vector3d::zeros(), acceleration);
+    }
+    kalman_filter.augment_pose();
+    kalman_filter.predict(0.1, Vector3d::zeros(), acceleration);
+    // Changes the covariance of the pose trail through its correlation with
+    // the velocity.
+    let n = kalman_filter.state_len;
+    let pose_trail_covariance = |kalman_filter: &KalmanFilter| {
+      kalman_filter.P.slice((F_SIZE, F_SIZE), (n - F_SIZE, n - F_SIZE)).clone_owned()
+    };
+    let before_update = pose_trail_covariance(&kalman_filter);
+    kalman_filter.update_zero_velocity(0.01);
+    let after_update = pose_trail_covariance(&kalman_filter);
+    assert!((&after_update - &before_update).amax() > 1e-6);
+    // The prediction does not move the pose trail.
+    kalman_filter.predict(0.11, Vector3d::zeros(), acceleration);
+    assert_eq!(pose_trail_covariance(&kalman_filter), after_update);
+  }
+
+  #[test]
+  fn test_residual_acceleration() {
+    let _guard = set_test_parameters(|_| {});
+    let g = PARAMETER_SET.lock().unwrap().gravity;
+    // A tilted device at rest.
+    let gravity = Vector3d::new(0.3, -2., 9.5).normalize() * g;
+    let mut kalman_filter = KalmanFilter::new();
+    kalman_filter.initialize_orientation(gravity);
+    assert!(kalman_filter.get_residual_acceleration(gravity).norm() < 1e-9);
+    assert!((kalman_filter.get_gravity() + gravity).norm() < 1e-9);
+    let (roll, pitch) = kalman_filter.get_roll_pitch();
+    let reading = g * Vector3d::new(-pitch.sin(), roll.sin() * pitch.cos(), roll.cos() * pitch.cos());
+    assert!((reading - gravity).norm() < 1e-9);
+    assert!(roll < -0.1 && pitch < 0.);
+    // The bias is removed.
+    let bias = Vector3d::new(0.1, 0.2, -0.3);
+    kalman_filter.set_imu_biases(&ImuBiases { gyroscope: Vector3d::zeros(), accelerometer: bias });
+    assert!(kalman_filter.get_residual_acceleration(gravity + bias).norm() < 1e-9);
+    // What remains is the acceleration of the device in IMU coordinates.
+    let acceleration = Vector3d::new(1., -0.5, 0.25);
+    let residual = kalman_filter.get_residual_acceleration(gravity + bias + acceleration);
+    assert!((residual - acceleration).norm() < 1e-9);
+  }
+
+  #[test]
+  fn test_mahalanobis_gate() {
+    let _guard = set_test_parameters(|_| {});
+    let mut kalman_filter = KalmanFilter::new();
+    kalman_filter.P.fixed_slice_mut::<3, 3>(F_VEL, F_VEL).copy_from(&Matrix3d::from_diagonal_element(0.04));
+    // Measures the velocity, and a fourth coordinate that only has noise.
+    let mut H = Matrixd::zeros(4, 3);
+    H.fixed_slice_mut::<3, 3>(0, F_VEL).fill_with_identity();
+    let r = Vectord::from_element(4, 0.1);
+    let S = Vectord::from_vec(vec![0.05, 0.05, 0.05, 0.01]);
+    let threshold = chi_squared_quantile(0.95, 4) / 4.;
+    // Residuals at a squared Mahalanobis distance of `scale` times the 95%
+    // quantile.
+    let residual = |scale: f64| {
+      let u = Vectord::from_vec(vec![1., -2., 0.5, 1.]).normalize();
+      u.component_mul(&S.map(f64::sqrt)) * (scale * 4. * threshold).sqrt()
+    };
+    assert!(kalman_filter.mahalanobis_gate(&H, &residual(0.99), &r, threshold));
+    assert!(!kalman_filter.mahalanobis_gate(&H, &residual(1.01), &r, threshold));
+    // Without the state uncertainty the same residual is an outlier.
+    kalman_filter.P.fixed_slice_mut::<3, 3>(F_VEL, F_VEL).fill(0.);
+    assert!(!kalman_filter.mahalanobis_gate(&H, &residual(0.5), &r, threshold));
+  }
+
+  #[test]
+  fn test_set_imu_noise() {
+    // Gyroscope bias standard deviation after a second of propagation.
+    let bias_std = |walk_g: f64| {
+      let acceleration = Vector3d::new(0., 0., PARAMETER_SET.lock().unwrap().gravity);
+      let mut kalman_filter = KalmanFilter::new();
+      kalman_filter.P[(F_BGA, F_BGA)] = 0.;
+      kalman_filter.set_imu_noise(1e-3, 1e-2, walk_g, 1e-3);
+      for i in 0..=100 {
+        kalman_filter.predict(0.01 * i as f64, Vector3d::zeros(), acceleration);
+      }
+      kalman_filter.P[(F_BGA, F_BGA)].sqrt()
+    };
+    {
+      let _guard = set_test_parameters(|p| p.kf_noise_bga_walk = 1e-4);
+      assert!((bias_std(1e-2) - 1e-2).abs() < 1e-6);
+      // Too small to estimate.
+      assert!((bias_std(0.) - 1e-4).abs() < 1e-8);
+    }
+    let _guard = set_test_parameters(|p| p.disable_bias_estimation = true);
+    assert_eq!(bias_std(1e-2), 0.);
+  }
+
+  #[test]
+  fn test_add_landmark() {
+    let _guard = set_test_parameters(|_| {});
+    let acceleration = Vector3d::new(0., 0., PARAMETER_SET.lock().unwrap().gravity);
+    let mut kalman_filter = KalmanFilter::new();
+    for i in 0..10 {
+      kalman_filter.predict(0.01 * i as f64, Vector3d::zeros(), acceleration);
+    }
+    kalman_filter.augment_pose();
+    // A landmark at a fixed offset from the position of the second pose.
+    let n = kalman_filter.get_state_len();
+    let col_pos = kalman_filter.get_camera_pos_ind(1);
+    let mut d_position = Matrixd::zeros(3, col_pos + 3);
+    d_position.fixed_slice_mut::<3, 3>(0, col_pos).copy_from(&Matrix3d::identity());
+    let std = 0.1;
+    kalman_filter.add_landmark(TrackId(0), Vector3d::new(0., 0., 2.), &d_position, std);
+    assert_eq!(kalman_filter.get_state_len(), n + 3);
+    assert_eq!(kalman_filter.get_landmark(0), Vector3d::new(0., 0., 2.));
+
+    let P = &kalman_filter.P;
+    let P_pos = P.fixed_slice::<3, 3>(col_pos, col_pos).clone_owned();
+    assert!(P_pos.amax() > 0.);
+    let P_landmark = P.fixed_slice::<3, 3>(n, n) - Matrix3d::identity() * std.powi(2);
+    assert!((P_landmark - P_pos).amax() < 1e-12);
+    // Correlated with the state like the position it was derived from.
+    assert!((P.slice((0, n), (n, 3)) - P.slice((0, col_pos), (n, 3))).amax() < 1e-12);
+    assert!((P.slice((n, 0), (3, n)) - P.slice((col_pos, 0), (3, n))).amax() < 1e-12);
+    // An update of the landmark moves the pose.
+    let mut H = Matrixd::zeros(3, n + 3);
+    H.fixed_slice_mut::<3, 3>(0, n).copy_from(&Matrix3d::identity());
+    let y = Vectord::from_column_slice(&[0.01, 0., 0.]);
+    let position_before = kalman_filter.x.fixed_slice::<3, 1>(col_pos, 0).clone_owned();
+    kalman_filter.update_visual(&H, &y, &Vectord::from_element(3, 1e-3));
+    let position_after = kalman_filter.x.fixed_slice::<3, 1>(col_pos, 0).clone_owned();
+    assert!(position_after[0] - position_before[0] > 0.);
+  }
+
+  #[test]
+  fn test_marginalize_oldest_pose() {
+    let _guard = set_test_parameters(|p| p.pose_trail_len = 3);
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let mut kalman_filter = KalmanFilter::new();
+    let n = kalman_filter.state_len;
+    let m = CAM0 + 2 * CAM_SIZE;
+    // A covariance where all the variables are correlated.
+    let A = Matrixd::from_fn(n, n, |_, _| rng.gen_range(-1.0..1.0));
+    kalman_filter.P = &A * A.transpose();
+    kalman_filter.x = Vectord::from_fn(n, |_, _| rng.gen_range(-1.0..1.0));
+    let P0 = kalman_filter.P.clone();
+    let x0 = kalman_filter.x.clone();
+
+    kalman_filter.marginalize_oldest_pose();
+    let P = &kalman_filter.P;
+    assert_eq!(P.slice((0, 0), (m, m)), P0.slice((0, 0), (m, m)));
+    assert_eq!(kalman_filter.x.rows(0, m), x0.rows(0, m));
+    assert!(P.rows(m, CAM_SIZE).iter().chain(P.columns(m, CAM_SIZE).iter()).all(|x| *x == 0.));
+    assert_eq!(P.trace(), P0.slice((0, 0), (m, m)).trace());
+    // The Schur complement would have shrunk the uncertainty.
+    let P_mm = P0.slice((m, m), (CAM_SIZE, CAM_SIZE)).try_inverse().unwrap();
+    let conditioned = P0.slice((0, 0), (m, m)) - P0.slice((0, m), (m, CAM_SIZE)) * P_mm * P0.slice((m, 0), (CAM_SIZE, m));
+    assert!(conditioned.trace() < P.trace() - 0.1);
+
+    // Augmentation moves the two newest poses along the trail.
+    kalman_filter.P = P0.clone();
+    kalman_filter.augment_pose();
+    let P = &kalman_filter.P;
+    let k = CAM0 + CAM_SIZE;
+    assert!((P.slice((k, k), (2 * CAM_SIZE, 2 * CAM_SIZE)) - P0.slice((CAM0, CAM0), (2 * CAM_SIZE, 2 * CAM_SIZE))).norm() < 1e-9);
+    assert!((P.slice((k, k), (2 * CAM_SIZE, 2 * CAM_SIZE)).trace() - P0.slice((CAM0, CAM0), (2 * CAM_SIZE, 2 * CAM_SIZE)).trace()).abs() < 1e-8);
+  }
+
+  #[test]
+  fn test_save_and_load() {
+    let _guard = set_test_parameters(|p| {
+      p.time_offset_std = 0.01;
+      p.use_fej = true;
+    });
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let path = std::env::temp_dir().join(format!("violet-filter-state-{}.json", std::process::id()));
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let step = |kalman_filter: &mut KalmanFilter, time: f64, rng: &mut Xoshiro256PlusPlus| {
+      for i in 0..10 {
+        kalman_filter.predict(time + 0.01 * i as f64, Vector3d::new(0.1, -0.2, 0.3), Vector3d::new(0.5, 0.2, gravity));
+      }
+      let n = kalman_filter.get_state_len();
+      let H = Matrixd::from_fn(4, n, |_, _| rng.gen_range(-1.0..1.0));
+      let y = Vectord::from_fn(4, |_, _| rng.gen_range(-0.01..0.01));
+      kalman_filter.update_visual(&H, &y, &Vectord::from_element(4, 0.1));
+      kalman_filter.augment_pose();
+    };
+    let mut kalman_filter = KalmanFilter::new();
+    for frame in 0..3 {
+      step(&mut kalman_filter, 0.1 * frame as f64, &mut rng);
+    }
+    kalman_filter.add_landmark(TrackId(7), Vector3d::new(1., 2., 3.), &Matrixd::zeros(3, 0), 0.5);
+    kalman_filter.save(&path).unwrap();
+    let s = std::fs::read_to_string(&path).unwrap();
+    let state: FilterStateFile = serde_json::from_str(&s).unwrap();
+    assert_eq!(state.state_labels.len(), kalman_filter.get_state_len());
+
+    let mut loaded = KalmanFilter::load(&path).unwrap();
+    assert_eq!(loaded.get_landmark_ids(), &[TrackId(7)]);
+    assert_eq!(loaded.pose_times, kalman_filter.pose_times);
+    assert_eq!(loaded.first_estimates, kalman_filter.first_estimates);
+    assert_eq!(loaded.x, kalman_filter.x);
+    assert_eq!(loaded.P, kalman_filter.P);
+
+    // The loaded filter continues exactly like the original one.
+    let mut rng_loaded = rng.clone();
+    step(&mut kalman_filter, 0.3, &mut rng);
+    step(&mut loaded, 0.3, &mut rng_loaded);
+    assert_eq!(loaded.x, kalman_filter.x);
+    assert_eq!(loaded.P, kalman_filter.P);
+    assert_eq!(loaded.last_time, kalman_filter.last_time);
+
+    let error = |path: &Path| KalmanFilter::load(path).err().unwrap().to_string();
+    PARAMETER_SET.lock().unwrap().pose_trail_len += 1;
+    assert!(error(&path).contains("pose trail"));
+    let version = format!("\"version\":{}", STATE_FILE_VERSION);
+    std::fs::write(&path, s.replace(&version, "\"version\":1")).unwrap();
+    assert!(error(&path).contains("version 1"));
+    std::fs::write(&path, b"VIOLOG1\n").unwrap();
+    assert!(error(&path).contains("not a filter state"));
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_current_pose_covariance() {
+    let _guard = set_test_parameters(|_| {});
+    let (gravity, noise_pos, noise_ori) = {
+      let p = PARAMETER_SET.lock().unwrap();
+      (p.gravity, p.kf_noise_pos, p.kf_noise_ori)
+    };
+    let mut kalman_filter = KalmanFilter::new();
+    // Rotated so that the quaternion has several nonzero components.
+    kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0.3, -0.4, 0.8).normalize() * gravity);
+    // A quaternion error `dq` rotates by about `2 * |dq|`, except along the
+    // quaternion itself.
+    let stds = kalman_filter.current_pose_covariance().diagonal().map(|x| x.sqrt());
+    for i in 0..3 {
+      assert!((stds[i] - noise_pos).abs() < 1e-12);
+      assert!((stds[3 + i] - 2. * noise_ori).abs() < 1e-9, "{}", stds);
+    }
+
+    // The covariance of a single small quaternion error is that of the
+    // rotation it causes.
+    let q: Vector4d = ori!(kalman_filter.x, 0).into();
+    let dq = Vector4d::new(2e-4, 1e-3, -2e-3, 5e-4);
+    let A = to_rotation_matrix(q + dq) * to_rotation_matrix(q).transpose();
+    let rotation = 0.5 * Vector3d::new(A[(2, 1)] - A[(1, 2)], A[(0, 2)] - A[(2, 0)], A[(1, 0)] - A[(0, 1)]);
+    let n = kalman_filter.state_len;
+    kalman_filter.P = Matrixd::zeros(n, n);
+    kalman_filter.P.fixed_slice_mut::<4, 4>(F_ORI, F_ORI).copy_from(&(dq * dq.transpose()));
+    let C = kalman_filter.current_pose_covariance();
+    let error = C.fixed_slice::<3, 3>(3, 3) - rotation * rotation.transpose();
+    assert!(error.norm() < 1e-2 * rotation.norm_squared(), "{}", error);
+    assert_eq!(C.fixed_slice::<3, 3>(0, 0), Matrix3d::zeros());
+  }
+
+  #[test]
+  fn test_current_camera_pose_covariance() {
+    let _guard = set_test_parameters(|_| {});
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let mut kalman_filter = KalmanFilter::new();
+    kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0.3, -0.4, 0.8).normalize() * gravity);
+    // A camera away from the IMU and rotated relative to it.
+    let camera_to_imu = imu_to_world(Vector3d::new(0.1, -0.05, 0.02), Vector4d::new(0.9, 0.1, -0.3, 0.2).normalize());
+    let imu_to_camera = affine_inverse(camera_to_imu);
+    let camera_pose = |kalman_filter: &KalmanFilter| {
+      let mut imu_to_worlds = vec![];
+      kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+      imu_to_worlds[0] * camera_to_imu
+    };
+
+    // The covariance of a single small error of the IMU position and
+    // orientation is that of the camera pose change it causes.
+    let n = kalman_filter.state_len;
+    let dx = nalgebra::SVector::<f64, 7>::from_column_slice(&[1e-3, -2e-3, 5e-4, 2e-4, 1e-3, -2e-3, 5e-4]);
+    let before = camera_pose(&kalman_filter);
+    for (i, d) in dx.iter().enumerate() {
+      kalman_filter.x[F_POS + i] += d;
+    }
+    let after = camera_pose(&kalman_filter);
+    let A = rotation!(affine_inverse(after)) * rotation!(affine_inverse(before)).transpose();
+    let change = Vector6d::from_column_slice(&[
+      after[(0, 3)] - before[(0, 3)],
+      after[(1, 3)] - before[(1, 3)],
+      after[(2, 3)] - before[(2, 3)],
+      0.5 * (A[(2, 1)] - A[(1, 2)]),
+      0.5 * (A[(0, 2)] - A[(2, 0)]),
+      0.5 * (A[(1, 0)] - A[(0, 1)]),
+    ]);
+    kalman_filter.P = Matrixd::zeros(n, n);
+    kalman_filter.P.fixed_slice_mut::<7, 7>(F_POS, F_POS).copy_from(&(dx * dx.transpose()));
+    let C = kalman_filter.current_camera_pose_covariance(&imu_to_camera);
+    let error = C - change * change.transpose();
+    assert!(error.norm() < 1e-2 * change.norm_squared(), "{}", error);
+    // Not the covariance of the IMU pose.
+    assert!((C - kalman_filter.current_pose_covariance()).norm() > 0.1 * C.norm());
+  }
+
+  #[test]
+  fn test_soft_reset() {
+    let _guard = set_test_parameters(|p| p.divergence_max_position_std = 1.);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let mut kalman_filter = KalmanFilter::new();
+    kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+    kalman_filter.augment_pose();
+    let biases = ImuBiases { gyroscope: Vector3d::new(1e-3, 0., 0.), accelerometer: Vector3d::zeros() };
+    kalman_filter.set_imu_biases(&biases);
+    // Without visual updates the position uncertainty grows until divergence.
+    let mut time = 0.;
+    while kalman_filter.check_divergence().is_none() {
+      assert!(time < 100.);
+      time += 0.01;
+      kalman_filter.predict(time, Vector3d::new(0.1, 0., 0.), Vector3d::new(0., 0., gravity));
+    }
+    assert!(matches!(kalman_filter.check_divergence(), Some(Divergence::PositionStd(std)) if std > 1.));
+    let position: Vector3d = pos!(kalman_filter.x, 0).into();
+    assert!(position.norm() > 0.);
+
+    // Restarts where it was, level with gravity and without the pose trail.
+    let accelerometer = Vector3d::new(0., gravity, 0.);
+    kalman_filter.soft_reset(accelerometer);
+    assert_eq!(kalman_filter.check_divergence(), None);
+    assert_eq!(Vector3d::from(pos!(kalman_filter.x, 0)), position);
+    assert_eq!(Vector3d::from(vel!(kalman_filter.x)), Vector3d::zeros());
+    assert_eq!(Vector4d::from(ori!(kalman_filter.x, 1)), Vector4d::zeros());
+    assert_eq!(kalman_filter.get_pose_time(0), Some(time));
+    assert_eq!(kalman_filter.get_pose_time(1), None);
+    assert_eq!(kalman_filter.get_imu_biases().gyroscope, biases.gyroscope);
+    let R = to_rotation_matrix(ori!(kalman_filter.x, 0).into());
+    assert!((R.transpose() * accelerometer - Vector3d::new(0., 0., gravity)).norm() < 1e-9);
+    // Continues from the time of the reset.
+    kalman_filter.predict(time + 0.01, biases.gyroscope, accelerometer);
+    assert!(kalman_filter.get_velocity().norm() < 1e-9);
+  }
+
+  #[test]
+  fn test_gps_update() {
+    let _guard = set_test_parameters(|_| {});
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let (s, c) = 0.5f64.sin_cos();
+    let world_to_enu = Matrix3d::new(c, s, 0., -s, c, 0., 0., 0., 1.);
+    // Accelerating along the x-axis, with an accelerometer bias that the
+    // filter does not know about, so that the position drifts.
+    let acceleration = 0.5;
+    let bias = Vector3d::new(0.05, 0.03, -0.02);
+    let truth = |time: f64| Vector3d::new(0.5 * acceleration * time.powi(2), 0., 0.);
+    let run = |gps: bool| {
+      let mut kalman_filter = KalmanFilter::new();
+      kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+      for step in 1..=3000 {
+        let time = 0.01 * step as f64;
+        kalman_filter.predict(time, Vector3d::zeros(), Vector3d::new(acceleration, 0., gravity) + bias);
+        // Fixes at 1 Hz, slightly before the IMU sample they follow.
+        if gps && step % 100 == 0 {
+          let fix_time = time - 0.005;
+          kalman_filter.gps_update(fix_time, world_to_enu * (truth(fix_time) - truth(0.995)), Matrix3d::identity());
+        }
+      }
+      kalman_filter
+    };
+    let drift = (Vector3d::from(pos!(run(false).x, 0)) - truth(30.)).norm();
+    assert!(drift > 20., "{}", drift);
+    // The ENU frame is rotated about the vertical from the world frame by the
+    // estimated yaw. Its error rotates the whole trajectory, so the position
+    // is compared with the truth in the ENU frame.
+    let mut kalman_filter = run(true);
+    let alignment = kalman_filter.gps_alignment.unwrap();
+    let yaw = alignment.yaw.unwrap();
+    assert!((yaw - 0.5).abs() < 0.1, "{}", yaw);
+    // Estimated again from farther fixes.
+    assert!(alignment.yaw_baseline > 4. * kalman_filter.gps_alignment_distance, "{}", alignment.yaw_baseline);
+    assert!(alignment.yaw_variance.sqrt() < 0.05, "{}", alignment.yaw_variance);
+    let (s, c) = yaw.sin_cos();
+    let world_to_enu_estimate = Matrix3d::new(c, s, 0., -s, c, 0., 0., 0., 1.);
+    let enu = world_to_enu_estimate * (Vector3d::from(pos!(kalman_filter.x, 0)) - alignment.origin);
+    let error = (enu - world_to_enu * (truth(30.) - truth(0.995))).norm();
+    assert!(error < 0.1 * drift, "{} {}", error, drift);
+
+    // A fix 50 m off, eg from multipath, is skipped.
+    let x = kalman_filter.x.clone();
+    let fix = world_to_enu * (truth(30.) - truth(0.995)) + Vector3d::new(50., 0., 0.);
+    assert!(!kalman_filter.gps_update(30., fix, Matrix3d::identity()));
+    assert_eq!(kalman_filter.x, x);
+    assert!(kalman_filter.gps_update(30., fix - Vector3d::new(50., 0., 0.), Matrix3d::identity()));
+  }
+}
@@ -3,30 +3,53 @@
 
 #[macro_use] mod math;
 
+mod alignment;
 mod all;
+mod anchor;
+mod batch;
+mod bidirectional;
 mod camera;
+mod camera_kannala_brandt;
 mod camera_pinhole;
 mod camera_setup;
+mod csv_input;
 mod debug;
 mod detector;
+mod euroc_input;
+mod event_log;
 mod event_loop;
+mod flip;
+mod flow_ransac;
+mod font;
 mod frame;
+mod gps;
+mod ground_truth;
 mod image;
+mod imu_bias;
+mod imu_convention;
+mod imu_noise;
 mod input;
+mod json_output;
 mod kalman_filter;
 mod optical_flow;
+mod optical_flow_simd;
 mod parameters;
 mod pyramid;
+mod realtime;
+mod rectification;
 mod stationary;
+#[cfg(test)] mod synthetic;
 mod track;
 mod tracker;
+mod trajectory;
 mod types;
 mod util;
 mod video;
 mod vio;
 mod vio_init;
 mod visualize;
-mod visualize_3d;
+#[cfg(feature = "gui")] mod visualize_3d;
+mod visualize_recorder;
 mod visual_update;
 
 use all::*;
@@ -34,15 +57,109 @@ use all::*;
 #[macro_use] extern crate lazy_static;
 use clap::Parser;
 
+#[cfg(feature = "gui")]
 use softbuffer::GraphicsContext;
+#[cfg(feature = "gui")]
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::WindowBuilder;
+#[cfg(feature = "gui")]
+use winit::window::{Window, WindowBuilder};
+#[cfg(feature = "gui")]
 use winit::platform::run_return::EventLoopExtRunReturn;
 
 #[derive(Parser)]
 struct Args {
-  #[clap(short)]
-  input_folder: String,
+  // Give several times, or use `--manifest`, to process multiple datasets
+  // in sequence without visualization. The single dataset outputs such as
+  // `--output` are then rejected, see `--batch-output`.
+  #[clap(short, required_unless_present = "manifest")]
+  input_folder: Vec<String>,
+  // File listing input folders, one per line.
+  #[clap(long)]
+  manifest: Option<String>,
+  // Per-dataset outputs of multiple dataset processing are written under this folder.
+  #[clap(long, default_value = "output")]
+  batch_output: String,
+  // Record all input data to a binary log file.
+  #[clap(long)]
+  record_input: Option<String>,
+  // Read input data from a log written with `--record-input` instead of the
+  // input folder. The input folder is still used for calibration.
+  #[clap(long)]
+  replay_input: Option<String>,
+  // Write the visualization of each frame as a PNG numbered by the frame into
+  // this folder.
+  #[clap(long)]
+  record: Option<String>,
+  // Process the input without a window, eg on a server. Use with `--record`
+  // to still get the visualization. Builds without the `gui` feature always
+  // run like this.
+  #[clap(long)]
+  headless: bool,
+  // Process the frames no faster than their timestamps, to watch the
+  // visualization or to see how a live system would keep up.
+  #[clap(long)]
+  realtime: bool,
+  // Playback speed of `--realtime`, eg 0.5 for half speed.
+  #[clap(long, default_value = "1", requires = "realtime")]
+  speed: f64,
+  // Write the pose of the first camera in TUM format after each processed
+  // frame, see `TumWriter`.
+  #[clap(long)]
+  output: Option<String>,
+  // Append the standard deviations of the position and orientation of the
+  // camera pose to the lines of `--output`.
+  #[clap(long)]
+  output_stds: bool,
+  // Write the estimator state after each processed frame as JSON lines, see `FrameState`.
+  #[cfg(feature = "json-output")]
+  #[clap(long)]
+  json_output: Option<String>,
+  // Write the estimated trajectory, smoothed offline, in TUM format at the end of the run.
+  #[clap(long)]
+  smoothed_output: Option<String>,
+  // Number of neighbour averaging passes for `--smoothed-output`, see `smooth_trajectory()`.
+  #[clap(long, default_value = "5")]
+  smoothing_strength: usize,
+  // Write the estimated trajectory as ROS `geometry_msgs/PoseStamped` CSV at the end of the run.
+  #[clap(long)]
+  ros_output: Option<String>,
+  #[clap(long, default_value = "world")]
+  ros_frame_id: String,
+  // Write the estimated trajectory in TUM format at the end of the run,
+  // aligned to the world frame of a reference session of the same environment
+  // with `--align-landmarks` or `--align-transform`.
+  #[clap(long)]
+  aligned_output: Option<String>,
+  // JSON file of landmarks with positions in the world frames of both this
+  // and the reference session, see `SessionAlignment::load_landmarks()`.
+  #[clap(long)]
+  align_landmarks: Option<String>,
+  // Pose of the world frame of this session in that of the reference session,
+  // as `tx,ty,tz,qx,qy,qz,qw`.
+  #[clap(long)]
+  align_transform: Option<String>,
+  // Buffer the whole input, run the filter forward and then backward over it
+  // without visualization, and write the combined trajectory in TUM format.
+  #[clap(long)]
+  bidirectional_output: Option<String>,
+  // Start from IMU biases saved with `--imu-bias-output` in a previous run on the same device.
+  #[clap(long)]
+  imu_bias_input: Option<String>,
+  // Write the final IMU bias estimates at the end of the run.
+  #[clap(long)]
+  imu_bias_output: Option<String>,
+  // Continue from a Kalman filter state saved with `--filter-state-output`
+  // at the end of a previous run, see `Vio::set_kalman_filter()`.
+  #[clap(long)]
+  filter_state_input: Option<String>,
+  // Write the final Kalman filter state and covariance, see `KalmanFilter::save()`.
+  #[clap(long)]
+  filter_state_output: Option<String>,
+  // Write the roll, pitch, IMU biases and velocity with their standard
+  // deviations, the estimated gravity and the residual acceleration for each
+  // frame as CSV.
+  #[clap(long)]
+  imu_state_output: Option<String>,
   #[clap(flatten)]
   parameter_set: ParameterSet,
 }
@@ -59,19 +176,209 @@ fn main() {
   }
 }
 
+// Options of processing a single dataset frame by frame, which the multiple
+// dataset and bidirectional runs do not support.
+fn single_run_options(args: &Args) -> Vec<&'static str> {
+  #[cfg(feature = "json-output")]
+  let json_output = args.json_output.is_some();
+  #[cfg(not(feature = "json-output"))]
+  let json_output = false;
+  [
+    ("--output", args.output.is_some()),
+    ("--output-stds", args.output_stds),
+    ("--json-output", json_output),
+    ("--smoothed-output", args.smoothed_output.is_some()),
+    ("--ros-output", args.ros_output.is_some()),
+    ("--aligned-output", args.aligned_output.is_some()),
+    ("--filter-state-input", args.filter_state_input.is_some()),
+    ("--filter-state-output", args.filter_state_output.is_some()),
+    ("--imu-bias-output", args.imu_bias_output.is_some()),
+    ("--imu-state-output", args.imu_state_output.is_some()),
+    ("--record", args.record.is_some()),
+    ("--realtime", args.realtime),
+  ].into_iter().filter(|(_, given)| *given).map(|(option, _)| option).collect()
+}
+
 fn run() -> Result<()> {
   let args = Args::parse();
+  let single_run_options = single_run_options(&args);
   *PARAMETER_SET.lock().unwrap() = args.parameter_set;
 
-  let input_folder_path = Path::new(&args.input_folder);
-  let mut input = Input::new(&input_folder_path)?;
-  let cameras = Camera::load(&input_folder_path)
+  let imu_biases = match &args.imu_bias_input {
+    Some(path) => Some(ImuBiases::load(Path::new(path)).context("Could not load IMU biases.")?),
+    None => None,
+  };
+
+  let alignment = match (&args.align_landmarks, &args.align_transform) {
+    (Some(path), None) => Some(SessionAlignment::load_landmarks(Path::new(path))?),
+    (None, Some(transform)) => Some(SessionAlignment::parse(transform)?),
+    (None, None) => None,
+    _ => bail!("Give only one of `--align-landmarks` and `--align-transform`."),
+  };
+  if args.aligned_output.is_some() && alignment.is_none() {
+    bail!("`--aligned-output` requires `--align-landmarks` or `--align-transform`.");
+  }
+
+  let mut input_folders: Vec<PathBuf> = args.input_folder.iter().map(PathBuf::from).collect();
+  if let Some(manifest) = &args.manifest {
+    input_folders.extend(read_manifest(Path::new(manifest))?);
+  }
+  if input_folders.len() > 1 {
+    if let Some(option) = single_run_options.first() {
+      bail!("`{}` applies to a single input folder. Multiple datasets write their trajectories under `--batch-output`.", option);
+    }
+    if args.record_input.is_some() || args.replay_input.is_some() {
+      bail!("`--record-input` and `--replay-input` apply to a single input folder.");
+    }
+    env_logger::Builder::new()
+      .filter_level(LevelFilter::Info)
+      .format(util::format_log)
+      .init();
+    let summaries = run_batch(&input_folders, Path::new(&args.batch_output), imu_biases)?;
+    print!("{}", format_summary(&summaries));
+    return Ok(());
+  }
+
+  let input_folder_path = input_folders.first().ok_or(anyhow!("No input folder given."))?.as_path();
+  let open_input = || -> Result<Input> {
+    let mut input = if let Some(replay_input) = &args.replay_input {
+      Input::replay(Path::new(replay_input))?
+    }
+    else {
+      Input::new(input_folder_path)?
+    };
+    if let Some(record_input) = &args.record_input {
+      input.record(Path::new(record_input))?;
+    }
+    Ok(input)
+  };
+  let mut input = open_input()?;
+  let cameras = Camera::load(input_folder_path)
     .context("Could not load camera setups.")?;
+  if let Some(path) = &args.bidirectional_output {
+    if let Some(option) = single_run_options.first() {
+      bail!("`{}` cannot be combined with `--bidirectional-output`.", option);
+    }
+    env_logger::Builder::new()
+      .filter_level(LevelFilter::Info)
+      .format(util::format_log)
+      .init();
+    let buffered = BufferedInput::read(&mut input)?;
+    let anchors = Anchors::load(input_folder_path)
+      .context("Could not load anchor landmarks.")?;
+    let detection_mask = DetectionMask::load(input_folder_path)
+      .context("Could not load detection mask.")?;
+    let forward_init = VioInit::new(cameras, None, anchors, imu_biases, detection_mask.clone(), None);
+    // Time reversal flips the sign of the gyroscope bias.
+    let backward_biases = imu_biases.map(|b| ImuBiases { gyroscope: -b.gyroscope, ..b });
+    let backward_init = VioInit::new(Camera::load(input_folder_path)?, None, vec![], backward_biases, detection_mask, None);
+    let trajectory = run_bidirectional(&buffered, forward_init, backward_init)?;
+    write_tum(Path::new(path), &trajectory)?;
+    return Ok(());
+  }
+  let open_vio_init = |cameras: Vec<Camera>| -> Result<VioInit> {
+    let ground_truth = GroundTruth::load(input_folder_path)
+      .context("Could not load ground truth landmarks.")?;
+    let anchors = Anchors::load(input_folder_path)
+      .context("Could not load anchor landmarks.")?;
+    let detection_mask = DetectionMask::load(input_folder_path)
+      .context("Could not load detection mask.")?;
+    let kalman_filter = match &args.filter_state_input {
+      Some(path) => Some(KalmanFilter::load(Path::new(path)).context("Could not load the filter state.")?),
+      None => None,
+    };
+    Ok(VioInit::new(cameras, ground_truth, anchors, imu_biases, detection_mask, kalman_filter))
+  };
+  let vio_init = open_vio_init(cameras)?;
 
   let width = 1920;
   let height = 1080;
-  let size = winit::dpi::PhysicalSize::new(width, height);
-  let mut event_loop = EventLoop::new();
+  // The single switch between the window and `run_headless()`.
+  let headless = args.headless || !cfg!(feature = "gui");
+  #[cfg(feature = "gui")]
+  let mut display = if headless { None } else { Some(open_window(width, height)) };
+
+  // Start logging after winit setup to skip a specific useless debug print.
+  env_logger::Builder::new()
+    .filter_level(LevelFilter::Info)
+    .format(util::format_log)
+    .init();
+
+  let open_outputs = || -> Result<Outputs> {
+    Ok(Outputs {
+      output: match &args.output {
+        Some(path) => Some(TumWriter::create(Path::new(path), args.output_stds)?),
+        None => None,
+      },
+      #[cfg(feature = "json-output")]
+      json_output: match &args.json_output {
+        Some(path) => Some(JsonWriter::create(Path::new(path))?),
+        None => None,
+      },
+      recorder: match &args.record {
+        Some(path) => Some(VisualizeRecorder::new(Path::new(path))?),
+        None => None,
+      },
+    })
+  };
+
+  let visualize = !headless || args.record.is_some();
+  let mut buffer = if visualize { vec![0; width * height] } else { vec![] };
+  let mut event_loop_args = EventLoopArgs {
+    input: &mut input,
+    visualize,
+    buffer: &mut buffer,
+    buffer_w: width,
+    buffer_h: height,
+    step_mode: false,
+    advance: false,
+    realtime: if args.realtime { Some(Realtime::new(args.speed)?) } else { None },
+    reopen: Some(Box::new(|| {
+      let cameras = Camera::load(input_folder_path)?;
+      Ok((open_input()?, open_vio_init(cameras)?, open_outputs()?))
+    })),
+    vio_init,
+    vio: None,
+    outputs: open_outputs()?,
+  };
+
+  #[cfg(feature = "gui")]
+  if let Some((event_loop, graphics_context)) = &mut display {
+    run_window(event_loop, graphics_context, &mut event_loop_args);
+  }
+  if headless {
+    run_headless(&mut event_loop_args);
+  }
+  if let Some(recorder) = &mut event_loop_args.outputs.recorder {
+    recorder.finish()?;
+  }
+
+  if let (Some(path), Some(vio)) = (&args.smoothed_output, &event_loop_args.vio) {
+    let smoothed = smooth_trajectory(vio.get_trajectory(), args.smoothing_strength);
+    write_tum(Path::new(path), &smoothed)?;
+  }
+  if let (Some(path), Some(vio)) = (&args.ros_output, &event_loop_args.vio) {
+    write_ros_pose_stamped(Path::new(path), vio.get_trajectory(), &args.ros_frame_id)?;
+  }
+  if let (Some(path), Some(alignment), Some(vio)) = (&args.aligned_output, &alignment, &event_loop_args.vio) {
+    write_tum(Path::new(path), &alignment.apply(vio.get_trajectory()))?;
+  }
+  if let (Some(path), Some(vio)) = (&args.filter_state_output, &event_loop_args.vio) {
+    vio.get_kalman_filter().save(Path::new(path))?;
+  }
+  if let (Some(path), Some(vio)) = (&args.imu_bias_output, &event_loop_args.vio) {
+    vio.get_imu_biases().save(Path::new(path))?;
+  }
+  if let (Some(path), Some(vio)) = (&args.imu_state_output, &event_loop_args.vio) {
+    write_imu_states(Path::new(path), vio.get_imu_states())?;
+  }
+  Ok(())
+}
+
+#[cfg(feature = "gui")]
+fn open_window(width: usize, height: usize) -> (EventLoop<()>, GraphicsContext<Window>) {
+  let size = winit::dpi::PhysicalSize::new(width as u32, height as u32);
+  let event_loop = EventLoop::new();
   let window = WindowBuilder::new()
     .with_resizable(false)
     .with_decorations(false)
@@ -79,14 +386,16 @@ fn run() -> Result<()> {
     .with_max_inner_size(size)
     .build(&event_loop)
     .unwrap();
-  let mut graphics_context = unsafe { GraphicsContext::new(window) }.unwrap();
-
-  // Start logging after winit setup to skip a specific useless debug print.
-  env_logger::Builder::new()
-    .filter_level(LevelFilter::Info)
-    .format(util::format_log)
-    .init();
+  let graphics_context = unsafe { GraphicsContext::new(window) }.unwrap();
+  (event_loop, graphics_context)
+}
 
+#[cfg(feature = "gui")]
+fn run_window(
+  event_loop: &mut EventLoop<()>,
+  graphics_context: &mut GraphicsContext<Window>,
+  event_loop_args: &mut EventLoopArgs,
+) {
   let (quit_3d_tx, quit_3d_rx) = mpsc::channel();
   let (quit_2d_tx, quit_2d_rx) = mpsc::channel();
   let mut visualize_3d_handle = None;
@@ -97,23 +406,13 @@ fn run() -> Result<()> {
     }));
   }
 
-  let mut buffer = vec![];
-  let mut args = EventLoopArgs {
-    input: &mut input,
-    buffer: &mut buffer,
-    graphics_context: &mut graphics_context,
-    step_mode: false,
-    advance: false,
-    vio_init: VioInit::new(cameras),
-    vio: None,
-  };
-
-  event_loop.run_return(move |event, _, mut control_flow| {
-    if let Err(err) = handle_event(event, &mut control_flow, &mut args) {
+  log_key_bindings();
+  event_loop.run_return(|event, _, control_flow| {
+    if let Err(err) = handle_event(event, control_flow, graphics_context, event_loop_args) {
       handle_error(&err);
       *control_flow = ControlFlow::Exit;
     }
-    if let Ok(_) = quit_2d_rx.try_recv() {
+    if quit_2d_rx.try_recv().is_ok() {
       *control_flow = ControlFlow::Exit;
     }
   });
@@ -123,5 +422,18 @@ fn run() -> Result<()> {
     _ = quit_3d_tx.send(());
     _ = visualize_3d_handle.join();
   }
-  Ok(())
+}
+
+// Processes the whole input without a window.
+fn run_headless(event_loop_args: &mut EventLoopArgs) {
+  loop {
+    match process_input(event_loop_args) {
+      Ok(Some(_)) => {},
+      Ok(None) => break,
+      Err(err) => {
+        handle_error(&err);
+        break;
+      },
+    }
+  }
 }
@@ -72,3 +72,64 @@ pub fn to_rotation_matrix_d(q: Vector4d) -> QuaternionAsRotation {
 pub fn transform_3d(T: &Matrix4d, x: &Vector3d) -> Vector3d {
   T.fixed_slice::<3, 3>(0, 0) * x + T.fixed_slice::<3, 1>(0, 3)
 }
+
+// Inverse of `to_rotation_matrix()`. Returns a quaternion with non-negative w.
+pub fn to_quaternion(R: Matrix3d) -> Vector4d {
+  // Shepperd's method: pick the numerically largest component first.
+  let t = R.trace();
+  let q = if t > 0. {
+    let s = 0.5 / (t + 1.).sqrt();
+    Vector4d::new(0.25 / s, (R[(2, 1)] - R[(1, 2)]) * s, (R[(0, 2)] - R[(2, 0)]) * s, (R[(1, 0)] - R[(0, 1)]) * s)
+  }
+  else if R[(0, 0)] > R[(1, 1)] && R[(0, 0)] > R[(2, 2)] {
+    let s = 2. * (1. + R[(0, 0)] - R[(1, 1)] - R[(2, 2)]).sqrt();
+    Vector4d::new((R[(2, 1)] - R[(1, 2)]) / s, 0.25 * s, (R[(0, 1)] + R[(1, 0)]) / s, (R[(0, 2)] + R[(2, 0)]) / s)
+  }
+  else if R[(1, 1)] > R[(2, 2)] {
+    let s = 2. * (1. + R[(1, 1)] - R[(0, 0)] - R[(2, 2)]).sqrt();
+    Vector4d::new((R[(0, 2)] - R[(2, 0)]) / s, (R[(0, 1)] + R[(1, 0)]) / s, 0.25 * s, (R[(1, 2)] + R[(2, 1)]) / s)
+  }
+  else {
+    let s = 2. * (1. + R[(2, 2)] - R[(0, 0)] - R[(1, 1)]).sqrt();
+    Vector4d::new((R[(1, 0)] - R[(0, 1)]) / s, (R[(0, 2)] + R[(2, 0)]) / s, (R[(1, 2)] + R[(2, 1)]) / s, 0.25 * s)
+  };
+  let q = q.normalize();
+  if q[0] < 0. { -q } else { q }
+}
+
+// Approximate quantile of the chi-squared distribution with `dof` degrees of
+// freedom at the probability `p`, eg 0.95, using the Wilson-Hilferty cube
+// transformation of a normal quantile. Within a few percent, which is enough
+// for gating.
+pub fn chi_squared_quantile(p: f64, dof: usize) -> f64 {
+  let k = dof as f64;
+  let a = 2. / (9. * k);
+  k * (1. - a + normal_quantile(p) * a.sqrt()).max(0.).powi(3)
+}
+
+// Approximate quantile of the standard normal distribution, with absolute
+// error below 5e-4, Abramowitz & Stegun 26.2.23.
+fn normal_quantile(p: f64) -> f64 {
+  let q = if p > 0.5 { 1. - p } else { p };
+  let t = (-2. * q.max(f64::MIN_POSITIVE).ln()).sqrt();
+  let z = t - (2.515517 + 0.802853 * t + 0.010328 * t * t)
+    / (1. + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t);
+  if p > 0.5 { z } else { -z }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_chi_squared_quantile() {
+    assert!(normal_quantile(0.5).abs() < 5e-4);
+    assert!((normal_quantile(0.975) - 1.95996).abs() < 5e-4);
+    assert!((normal_quantile(0.025) + 1.95996).abs() < 5e-4);
+    // Table values.
+    for (p, dof, x) in [(0.95, 1, 3.841), (0.95, 2, 5.991), (0.95, 4, 9.488), (0.99, 4, 13.277), (0.95, 20, 31.410)] {
+      let q = chi_squared_quantile(p, dof);
+      assert!((q - x).abs() < 0.03 * x, "{} {} {}", p, dof, q);
+    }
+  }
+}
@@ -5,17 +5,30 @@
 
 use crate::all::*;
 
-type Range = [[i16; 2]; 2];
+pub type Range = [[i16; 2]; 2];
 
 const AVERAGE_DISTANCE_METERS: f64 = 5.;
+// Smaller intensity gains, eg of a flat frame, are taken as unknown.
+const MIN_INTENSITY_GAIN: f64 = 1e-3;
 
 pub struct OpticalFlow {
   lk_iters: usize,
-  lk_levels: usize,
+  // Coarsest pyramid level where the tracking starts, at most `lk_levels`.
+  lk_start_level: usize,
   lk_win_size: usize,
+  // If set, used instead of `lk_win_size`.
+  adaptive_win: Option<AdaptiveWindow>,
   lk_term: f64,
   lk_min_eig: f64,
   lk_epipolar_max_dist: f64,
+  // Gain and bias of the second image intensities relative to the first, see
+  // `set_intensity_gain()`.
+  intensity_gain: (f64, f64),
+  // See `lk_backward_max_dist`, zero if not used.
+  backward_max_dist: f64,
+  // Features of the last `process()` call that failed the backward check and
+  // where they were tracked to.
+  backward_rejected: Vec<(Feature, Feature)>,
   Ix: Matrixd,
   Iy: Matrixd,
   It: Matrixd,
@@ -23,7 +36,15 @@ pub struct OpticalFlow {
   grid0: Matrixd,
 }
 
+// See `ParameterSet::lk_adaptive_win`.
+struct AdaptiveWindow {
+  min_size: usize,
+  max_size: usize,
+  min_eig: f64,
+}
+
 #[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum OpticalFlowKind {
   LeftPreviousToCurrent,
   LeftCurrentToRightCurrent,
@@ -33,34 +54,48 @@ pub enum OpticalFlowKind {
 impl OpticalFlow {
   pub fn new() -> Result<OpticalFlow> {
     // TODO This pattern is getting unwieldy with so many parameters.
-    let (lk_iters, lk_levels, lk_win_size, lk_term, lk_min_eig, lk_epipolar_max_dist) = {
+    let (lk_iters, lk_levels, lk_start_level, lk_win_size, lk_term, lk_min_eig, lk_epipolar_max_dist) = {
+      let p = PARAMETER_SET.lock().unwrap();
+      (p.lk_iters, p.lk_levels, p.lk_start_level, p.lk_win_size, p.lk_term, p.lk_min_eig, p.lk_epipolar_max_dist)
+    };
+    let (lk_adaptive_win, lk_min_win_size, lk_max_win_size, lk_adaptive_win_min_eig, lk_backward_max_dist) = {
       let p = PARAMETER_SET.lock().unwrap();
-      (p.lk_iters, p.lk_levels, p.lk_win_size, p.lk_term, p.lk_min_eig, p.lk_epipolar_max_dist)
+      (p.lk_adaptive_win, p.lk_min_win_size, p.lk_max_win_size, p.lk_adaptive_win_min_eig, p.lk_backward_max_dist)
     };
-    Self::new_custom(lk_iters, lk_levels, lk_win_size, lk_term, lk_min_eig, lk_epipolar_max_dist)
+    let mut optical_flow = Self::new_custom(lk_iters, lk_levels, lk_start_level, lk_win_size, lk_term, lk_min_eig, lk_epipolar_max_dist)?;
+    if lk_adaptive_win {
+      optical_flow.set_adaptive_window(lk_min_win_size, lk_max_win_size, lk_adaptive_win_min_eig)?;
+    }
+    optical_flow.set_backward_max_dist(lk_backward_max_dist);
+    Ok(optical_flow)
   }
 
   pub fn new_custom(
     lk_iters: usize,
     lk_levels: usize,
+    // If None, start from the coarsest level.
+    lk_start_level: Option<usize>,
     lk_win_size: usize,
     lk_term: f64,
     lk_min_eig: f64,
     lk_epipolar_max_dist: f64,
   ) -> Result<OpticalFlow> {
-    if lk_win_size % 2 != 1 {
-      bail!("Lucas-Kanade window size must be odd number.");
-    }
-    if lk_win_size < 3 {
-      bail!("Lucas-Kanade window size must be at least 3.");
+    check_window_size(lk_win_size)?;
+    let lk_start_level = lk_start_level.unwrap_or(lk_levels);
+    if lk_start_level > lk_levels {
+      warn!("Lucas-Kanade start level {} is larger than the number of levels {}.", lk_start_level, lk_levels);
     }
     Ok(OpticalFlow {
       lk_iters,
-      lk_levels,
+      lk_start_level: lk_start_level.min(lk_levels),
       lk_win_size,
+      adaptive_win: None,
       lk_term,
       lk_min_eig,
       lk_epipolar_max_dist,
+      intensity_gain: (1., 0.),
+      backward_max_dist: 0.,
+      backward_rejected: vec![],
       Ix: DMatrix::zeros(lk_win_size, lk_win_size),
       Iy: DMatrix::zeros(lk_win_size, lk_win_size),
       It: DMatrix::zeros(lk_win_size, lk_win_size),
@@ -68,6 +103,52 @@ impl OpticalFlow {
     })
   }
 
+  pub fn set_adaptive_window(&mut self, min_size: usize, max_size: usize, min_eig: f64) -> Result<()> {
+    check_window_size(min_size)?;
+    check_window_size(max_size)?;
+    if min_size > max_size {
+      bail!("Lucas-Kanade minimum window size must not exceed the maximum.");
+    }
+    self.adaptive_win = Some(AdaptiveWindow { min_size, max_size, min_eig });
+    Ok(())
+  }
+
+  // Features tracked from the previous frame to the current one are dropped
+  // if tracking them back ends farther than `max_dist` pixels from where they
+  // started. Zero disables the check.
+  pub fn set_backward_max_dist(&mut self, max_dist: f64) {
+    self.backward_max_dist = max_dist;
+  }
+
+  // The features of the last `process()` call that failed the backward check,
+  // paired with where they were tracked to.
+  pub fn get_backward_rejected(&self) -> &[(Feature, Feature)] {
+    &self.backward_rejected
+  }
+
+  // Intensities of the second image in `process()` are taken to be
+  // `gain * I + bias` of those in the first one, and mapped back before
+  // comparing them. See `estimate_intensity_gain()`. Gains that cannot be
+  // divided by leave the intensities unchanged.
+  pub fn set_intensity_gain(&mut self, gain: f64, bias: f64) {
+    let valid = gain.is_finite() && bias.is_finite() && gain > MIN_INTENSITY_GAIN;
+    self.intensity_gain = if valid { (gain, bias) } else { (1., 0.) };
+  }
+
+  // Window size for tracking a feature at `point` of the image.
+  fn window_size(&mut self, frame_camera0: &FrameCamera, point: Vector2d) -> usize {
+    let Some(adaptive_win) = &self.adaptive_win else { return self.lk_win_size };
+    let level0 = frame_camera0.get_level(0);
+    for size in (adaptive_win.min_size..adaptive_win.max_size).step_by(2) {
+      let Some(range) = integration_range(level0, point, (size - 1) / 2, 1) else { break };
+      scharr(level0, point, range, &mut self.Ix, &mut self.Iy, &mut self.grid0);
+      let G = spatial_gradient(range, &self.Ix, &self.Iy);
+      if G.symmetric_eigenvalues().min() >= adaptive_win.min_eig { return size }
+    }
+    adaptive_win.max_size
+  }
+
+  #[allow(clippy::too_many_arguments)]
   pub fn process(
     &mut self,
     kind: OpticalFlowKind,
@@ -79,6 +160,9 @@ impl OpticalFlow {
     features0: &mut Vec<Feature>,
     // Successfully tracked features, same size as `features0`.
     features1: &mut Vec<Feature>,
+    // If given, predict the features in `frame_camera1` by this rotation from
+    // the first camera to the second, eg from the IMU.
+    rotation: Option<&Matrix3d>,
   ) {
     {
       let d = &mut DEBUG_DATA.lock().unwrap();
@@ -89,12 +173,24 @@ impl OpticalFlow {
 
     features0.clear();
     features1.clear();
+    self.backward_rejected.clear();
     let cam0_to_cam1 = cameras[1].imu_to_camera * cameras[0].imu_to_camera.try_inverse().unwrap();
     for feature0 in features0_in {
-      let point1_in = compute_initial_guess(feature0.point, cameras, &cam0_to_cam1);
+      let point1_in = if let Some(rotation) = rotation {
+        predict_rotation(feature0.point, cameras[0], rotation)
+      }
+      else {
+        compute_initial_guess(feature0.point, cameras, &cam0_to_cam1)
+      };
       let feature1 = self.process_feature(frame_camera0, frame_camera1, *feature0, point1_in);
       let feature1 = if let Some(feature1) = feature1 { feature1 } else { continue };
-      if !epipolar_check(&feature0, &feature1, kind, cameras, &cam0_to_cam1, lk_epipolar_max_dist2) {
+      if !epipolar_check(feature0, &feature1, kind, cameras, &cam0_to_cam1, lk_epipolar_max_dist2) {
+        continue;
+      }
+      if kind == OpticalFlowKind::LeftPreviousToCurrent && self.backward_max_dist > 0.
+        && !self.backward_check(frame_camera0, frame_camera1, feature0, &feature1)
+      {
+        self.backward_rejected.push((*feature0, feature1));
         continue;
       }
       features1.push(feature1);
@@ -117,6 +213,21 @@ impl OpticalFlow {
     }
   }
 
+  // True if `feature1` tracked back from `frame_camera1` returns near `feature0`.
+  fn backward_check(
+    &mut self,
+    frame_camera0: &FrameCamera,
+    frame_camera1: &FrameCamera,
+    feature0: &Feature,
+    feature1: &Feature,
+  ) -> bool {
+    let (gain, bias) = self.intensity_gain;
+    self.intensity_gain = (1. / gain, -bias / gain);
+    let back = self.process_feature(frame_camera1, frame_camera0, *feature1, Some(feature0.point));
+    self.intensity_gain = (gain, bias);
+    back.is_some_and(|back| (back.point - feature0.point).norm() <= self.backward_max_dist)
+  }
+
   fn process_feature(
     &mut self,
     frame_camera0: &FrameCamera,
@@ -125,22 +236,23 @@ impl OpticalFlow {
     point1_in: Option<Vector2d>,
   ) -> Option<Feature> {
     let term2 = self.lk_term.powi(2);
-    let r = (self.lk_win_size - 1) / 2;
+    let r = (self.window_size(frame_camera0, feature0.point) - 1) / 2;
+    let pyramid = &frame_camera0.pyramid;
     let mut g = point1_in.map(|p| p - feature0.point).unwrap_or(Vector2d::zeros())
-      / u32::pow(2, self.lk_levels as u32) as f64;
+      / pyramid.level_scale(self.lk_start_level);
     let mut d = Vector2d::zeros();
-    for L in (0..self.lk_levels + 1).rev() {
+    for L in (0..self.lk_start_level + 1).rev() {
       let level0 = frame_camera0.get_level(L);
       let level1 = frame_camera1.get_level(L);
-      let u = feature0.point / u32::pow(2, L as u32) as f64;
-      let range = integration_range(&level0, u, r, 1)?;
-      scharr(&level0, u, range, &mut self.Ix, &mut self.Iy, &mut self.grid0);
+      let u = feature0.point / pyramid.level_scale(L);
+      let range = integration_range(level0, u, r, 1)?;
+      scharr(level0, u, range, &mut self.Ix, &mut self.Iy, &mut self.grid0);
       let G = spatial_gradient(range, &self.Ix, &self.Iy);
       if G.eigenvalues()?.min() < self.lk_min_eig { return None }
       let mut converged = false;
       let mut nu = Vector2d::zeros();
       for _ in 0..self.lk_iters {
-        image_difference(range, r, &self.grid0, &mut self.It, &level1, u + g + nu)?;
+        image_difference(range, r, &self.grid0, &mut self.It, level1, u + g + nu, self.intensity_gain)?;
         let eta = flow_vector(&G, &self.Ix, &self.Iy, &self.It)?;
         nu += eta;
         if eta.norm_squared() < term2 {
@@ -150,7 +262,7 @@ impl OpticalFlow {
       }
       d = nu;
       if !converged { return None }
-      if L > 0 { g = 2. * (g + d) }
+      if L > 0 { g = pyramid.scale * (g + d) }
     }
     // Verify match in the one-camera tracking where distortions are expected to
     // be smaller.
@@ -166,13 +278,24 @@ impl OpticalFlow {
   }
 }
 
+fn check_window_size(lk_win_size: usize) -> Result<()> {
+  if lk_win_size % 2 != 1 {
+    bail!("Lucas-Kanade window size must be odd number.");
+  }
+  if lk_win_size < 3 {
+    bail!("Lucas-Kanade window size must be at least 3.");
+  }
+  Ok(())
+}
+
 fn image_difference(
   prev_range: Range,
   r: usize,
   I0: &Matrixd,
-  mut It: &mut Matrixd,
+  It: &mut Matrixd,
   level: &Image,
   center: Vector2d,
+  (gain, bias): (f64, f64),
 ) -> Option<()> {
   let range = integration_range(level, center, r, 0)?;
   // TODO The new range can be larger, should reduce it.
@@ -180,7 +303,11 @@ fn image_difference(
   if range != prev_range {
     return None;
   }
-  fill_grid(level, range, center, &mut It);
+  fill_grid(level, range, center, It);
+  if (gain, bias) != (1., 0.) {
+    It.add_scalar_mut(-bias);
+    *It /= gain;
+  }
   *It *= -1.;
   *It += I0.slice((1, 1), (It.nrows(), It.ncols()));
   Some(())
@@ -192,13 +319,8 @@ fn flow_vector(
   Iy: &Matrixd,
   It: &Matrixd,
 ) -> Option<Vector2d> {
-  let mut b = Vector2d::zeros();
-  for y in 0..Ix.nrows() {
-    for x in 0..Ix.ncols() {
-      b[0] += It[(y, x)] * Ix[(y, x)];
-      b[1] += It[(y, x)] * Iy[(y, x)];
-    }
-  }
+  let [bx, by] = mismatch_sums(It.as_slice(), Ix.as_slice(), Iy.as_slice());
+  let b = Vector2d::new(bx, by);
 
   // Could instead solve the linear equation?
   G.try_inverse().map(|invG| invG * b)
@@ -212,16 +334,7 @@ fn spatial_gradient(
 ) -> Matrix2d {
   assert_eq!(Ix.nrows(), Iy.nrows());
   assert_eq!(Ix.ncols(), Iy.ncols());
-  let mut x2 = 0.;
-  let mut y2 = 0.;
-  let mut xy = 0.;
-  for y in 0..Ix.nrows() {
-    for x in 0..Ix.ncols() {
-      x2 += Ix[(y, x)] * Ix[(y, x)];
-      y2 += Iy[(y, x)] * Iy[(y, x)];
-      xy += Ix[(y, x)] * Iy[(y, x)];
-    }
-  }
+  let [x2, y2, xy] = gradient_sums(Ix.as_slice(), Iy.as_slice());
   Matrix2d::new(x2, xy, xy, y2)
 }
 
@@ -246,7 +359,7 @@ fn integration_range(
   Some(range)
 }
 
-fn fill_grid(
+pub fn fill_grid(
   level: &Image,
   range: Range,
   center: Vector2d,
@@ -260,17 +373,17 @@ fn fill_grid(
   }
 }
 
-fn scharr(
+pub fn scharr(
   level: &Image,
   center: Vector2d,
   range: Range,
   out_x: &mut Matrixd,
   out_y: &mut Matrixd,
   // Workspace.
-  mut grid: &mut Matrixd,
+  grid: &mut Matrixd,
 ) {
   let grange = [[range[0][0] - 1, range[0][1] + 1], [range[1][0] - 1, range[1][1] + 1]];
-  fill_grid(level, grange, center, &mut grid);
+  fill_grid(level, grange, center, grid);
   // TODO Unclear if these kind of statements cause allocations.
   *out_x = Matrixd::zeros(grid.nrows() - 2, grid.ncols() - 2);
   *out_y = Matrixd::zeros(grid.nrows() - 2, grid.ncols() - 2);
@@ -306,7 +419,7 @@ fn compute_initial_guess(
   if cameras[0].imu_to_camera == cameras[1].imu_to_camera { return None }
   if let Some(ray) = cameras[0].model.pixel_to_ray(p0) {
     let r0 = AVERAGE_DISTANCE_METERS * ray;
-    let r1 = transform_vector3d(&cam0_to_cam1, &r0);
+    let r1 = transform_vector3d(cam0_to_cam1, &r0);
     let r1 = r1.normalize(); // TODO needed?
     // TODO Check these are correct by a visualization?
     cameras[1].model.ray_to_pixel(r1)
@@ -316,6 +429,11 @@ fn compute_initial_guess(
   }
 }
 
+fn predict_rotation(p0: Vector2d, camera: &Camera, rotation: &Matrix3d) -> Option<Vector2d> {
+  let ray = camera.model.pixel_to_ray(p0)?;
+  camera.model.ray_to_pixel(rotation * ray)
+}
+
 // Heavily based on:
 //   <https://github.com/SpectacularAI/HybVIO/blob/main/src/tracker/tracker.cpp>
 fn epipolar_check(
@@ -336,7 +454,7 @@ fn epipolar_check(
     let mut s = 0.5;
     for _ in 0..curve_point_count {
       let r0 = s * ray;
-      let r1 = transform_vector3d(&cam0_to_cam1, &r0);
+      let r1 = transform_vector3d(cam0_to_cam1, &r0);
       let r1 = r1.normalize(); // TODO needed?
       if let Some(pixel) = cameras[1].model.ray_to_pixel(r1) {
         curve.push(pixel);
@@ -364,7 +482,7 @@ fn epipolar_check(
   let d = &mut DEBUG_DATA.lock().unwrap();
   let p = PARAMETER_SET.lock().unwrap();
   if p.show_epipolar {
-    let p1_initial = compute_initial_guess(feature0.point, cameras, &cam0_to_cam1);
+    let p1_initial = compute_initial_guess(feature0.point, cameras, cam0_to_cam1);
     d.epipolar.push(DebugEpipolar {
       p0: feature0.point,
       p1: feature1.point,
@@ -378,10 +496,15 @@ fn epipolar_check(
 #[cfg(test)]
 mod tests {
   use super::*;
+  use nalgebra::dmatrix;
 
   fn make_camera(image: Image, lk_levels: usize) -> FrameCamera {
+    make_scaled_camera(image, lk_levels, 2.)
+  }
+
+  fn make_scaled_camera(image: Image, lk_levels: usize, scale: f64) -> FrameCamera {
     let mut pyramid = Pyramid::empty();
-    Pyramid::compute(&mut pyramid, &image, lk_levels).unwrap();
+    Pyramid::compute(&mut pyramid, &image, lk_levels, scale).unwrap();
     FrameCamera {
       image,
       pyramid,
@@ -437,7 +560,7 @@ mod tests {
       point: Vector2d::new((x + r) as f64, (y + r) as f64),
       id: TrackId(0),
     };
-    let mut flow = OpticalFlow::new_custom(lk_iters, lk_levels, lk_win_size, lk_term, lk_min_eig).unwrap();
+    let mut flow = OpticalFlow::new_custom(lk_iters, lk_levels, None, lk_win_size, lk_term, lk_min_eig, 2.).unwrap();
     // let guess = feature0.point + Vector2d::new(dx as f64, dy as f64);
     // if let Some(feature1) = flow.process_feature(&camera0, &camera1, feature0, Some(guess)) {
     if let Some(feature1) = flow.process_feature(&camera0, &camera1, feature0, None) {
@@ -447,8 +570,128 @@ mod tests {
       // dbg!(feature1, err);
     }
     else {
-      assert!(false);
+      panic!("Optical flow failed.");
+    }
+  }
+
+  // Images with a blob moved by (dx, dy) and a feature at the first blob.
+  fn make_displaced_cameras(dx: i32, dy: i32, lk_levels: usize) -> (FrameCamera, FrameCamera, Feature) {
+    let mut image0 = Image {
+      data: vec![0; 128 * 128],
+      width: 128,
+      height: 128,
+    };
+    let mut image1 = image0.clone();
+    let mut patch = Image {
+      data: vec![0; 9 * 9],
+      width: 9,
+      height: 9,
+    };
+    for y in 0..9 {
+      for x in 0..9 {
+        let r = (x as i32 - 4).abs().max((y as i32 - 4).abs()) as u8;
+        patch.set_value(x, y, 99 - 11 * r);
+      }
     }
+    image0.set_sub_image_i32(60, 60, &patch);
+    image1.set_sub_image_i32(60 + dx, 60 + dy, &patch);
+    let feature0 = Feature {
+      point: Vector2d::new(64., 64.),
+      id: TrackId(0),
+    };
+    (make_camera(image0, lk_levels), make_camera(image1, lk_levels), feature0)
+  }
+
+  #[test]
+  fn test_flow_start_level() {
+    let lk_levels = 3;
+    let track = |dx: i32, dy: i32, lk_start_level: Option<usize>| -> Option<f64> {
+      let (camera0, camera1, feature0) = make_displaced_cameras(dx, dy, lk_levels);
+      let mut flow = OpticalFlow::new_custom(5, lk_levels, lk_start_level, 7, 0.1, 1e-4, 2.).unwrap();
+      let feature1 = flow.process_feature(&camera0, &camera1, feature0, None)?;
+      Some(((feature1.point - feature0.point) - Vector2d::new(dx as f64, dy as f64)).norm())
+    };
+
+    // Small motion can be tracked from the finest levels.
+    assert!(track(2, -1, Some(0)).unwrap() < 0.1);
+    assert!(track(2, -1, Some(1)).unwrap() < 0.1);
+    // Large motion needs to start from a coarse level.
+    assert!(track(-14, 7, Some(3)).unwrap() < 0.1);
+    assert!(track(-14, 7, Some(0)).map(|err| err > 1.).unwrap_or(true));
+    // Start level is bounded by the built levels.
+    assert!(track(-14, 7, Some(10)).unwrap() < 0.1);
+  }
+
+  #[test]
+  fn test_flow_pyramid_levels() {
+    // A large blob moved further than the window reaches at full resolution.
+    let (dx, dy) = (-24, 16);
+    let blob = |cx: i32, cy: i32| {
+      let mut image = Image { data: vec![0; 160 * 160], width: 160, height: 160 };
+      for y in 0..160 {
+        for x in 0..160 {
+          let r = (x - cx).abs().max((y - cy).abs());
+          image.set_value(x as usize, y as usize, (200 - 16 * r).max(0) as u8);
+        }
+      }
+      image
+    };
+    let feature0 = Feature { point: Vector2d::new(80., 80.), id: TrackId(0) };
+    let track = |lk_levels: usize, scale: f64| -> Option<f64> {
+      let camera0 = make_scaled_camera(blob(80, 80), lk_levels, scale);
+      let camera1 = make_scaled_camera(blob(80 + dx, 80 + dy), lk_levels, scale);
+      let mut flow = OpticalFlow::new_custom(10, lk_levels, None, 7, 0.1, 1e-4, 2.).unwrap();
+      let feature1 = flow.process_feature(&camera0, &camera1, feature0, None)?;
+      Some(((feature1.point - feature0.point) - Vector2d::new(dx as f64, dy as f64)).norm())
+    };
+    let failed = |err: Option<f64>| err.map(|err| err > 1.).unwrap_or(true);
+    // The motion is about 3.6 pixels on the coarsest level of the default pyramid.
+    assert!(failed(track(0, 2.)));
+    assert!(failed(track(2, 2.)));
+    assert!(track(3, 2.).unwrap() < 0.1);
+    // A larger scale reaches as far with fewer levels.
+    assert!(failed(track(1, 3.)));
+    assert!(track(2, 3.).unwrap() < 0.1);
+  }
+
+  #[test]
+  fn test_adaptive_window() {
+    let lk_levels = 1;
+    let (dx, dy) = (2, -1);
+    let cone = |radius: f64, contrast: f64| -> Image {
+      let size = (2. * radius) as usize + 1;
+      let mut patch = Image { data: vec![0; size * size], width: size, height: size };
+      for y in 0..size {
+        for x in 0..size {
+          let d = ((x as f64 - radius).powi(2) + (y as f64 - radius).powi(2)).sqrt() / radius;
+          patch.set_value(x, y, (contrast * (1. - d).max(0.)).round() as u8);
+        }
+      }
+      patch
+    };
+    // A sharp high-contrast blob and a smooth low-contrast one, centered at
+    // the features.
+    let mut image0 = Image { data: vec![0; 128 * 128], width: 128, height: 128 };
+    let mut image1 = image0.clone();
+    let features = [Vector2d::new(30., 30.), Vector2d::new(80., 80.)];
+    for (patch, corner) in [(cone(4., 200.), 26), (cone(12., 60.), 68)] {
+      image0.set_sub_image_i32(corner, corner, &patch);
+      image1.set_sub_image_i32(corner + dx, corner + dy, &patch);
+    }
+    let (camera0, camera1) = (make_camera(image0, lk_levels), make_camera(image1, lk_levels));
+
+    let mut flow = OpticalFlow::new_custom(10, lk_levels, None, 7, 0.01, 1e-4, 2.).unwrap();
+    assert_eq!(flow.window_size(&camera0, features[1]), 7);
+    flow.set_adaptive_window(5, 21, 3e3).unwrap();
+    assert_eq!(flow.window_size(&camera0, features[0]), 5);
+    let size = flow.window_size(&camera0, features[1]);
+    assert!(size > 11 && size < 21);
+    for point in features {
+      let feature1 = flow.process_feature(&camera0, &camera1, Feature { point, id: TrackId(0) }, None).unwrap();
+      assert!(((feature1.point - point) - Vector2d::new(dx as f64, dy as f64)).norm() < 0.01);
+    }
+    assert!(flow.set_adaptive_window(9, 5, 3e3).is_err());
+    assert!(flow.set_adaptive_window(4, 9, 3e3).is_err());
   }
 
   #[test]
@@ -530,4 +773,83 @@ mod tests {
     assert_eq!(integration_range(&image, Vector2d::new(8.5, 2.0), 3, 0).unwrap(), [[-3, 0], [-2, 3]]);
     assert_eq!(integration_range(&image, Vector2d::new(9.5, 2.0), 3, 0), None);
   }
+
+  #[test]
+  fn test_backward_check() {
+    // A texture moving a pixel left, where a square of another texture
+    // occludes part of the second image.
+    let _guard = set_test_parameters(|p| p.lk_start_level = Some(1));
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    let texture = textured_image(width + 1, height, 1);
+    let crop = |offset: usize| Image {
+      data: (0..height).flat_map(|y| {
+        let row = y * texture.width + offset;
+        texture.data[row..(row + width)].to_vec()
+      }).collect(),
+      width,
+      height,
+    };
+    let mut image1 = crop(1);
+    image1.set_sub_image_i32(68, 48, &textured_image(24, 24, 2));
+    let lk_levels = PARAMETER_SET.lock().unwrap().lk_levels;
+    let camera0 = make_camera(crop(0), lk_levels);
+    let camera1 = make_camera(image1, lk_levels);
+    // Features far from the square and inside it.
+    let grid = |x0: usize, y0: usize| (0..49).map(move |i| Vector2d::new((x0 + 2 * (i % 7)) as f64, (y0 + 2 * (i / 7)) as f64));
+    let features: Vec<Feature> = grid(20, 30).chain(grid(74, 54))
+      .enumerate()
+      .map(|(i, point)| Feature { point, id: TrackId(i) })
+      .collect();
+    let occluded = |f: &Feature| f.id.0 >= 49;
+
+    let mut flow = OpticalFlow::new().unwrap();
+    let (mut features0, mut features1) = (vec![], vec![]);
+    let cameras = [&cameras[0], &cameras[0]];
+    flow.process(OpticalFlowKind::LeftPreviousToCurrent, &camera0, &camera1, &cameras, &features, &mut features0, &mut features1, None);
+    // Without the check some features inside the square are matched to the
+    // occluding texture.
+    let count = |features0: &[Feature], occluded_features: bool| {
+      features0.iter().filter(|f| occluded(f) == occluded_features).count()
+    };
+    assert_eq!(count(&features0, false), 49);
+    let occluded_count = count(&features0, true);
+    assert!(occluded_count > 0);
+
+    flow.set_backward_max_dist(0.5);
+    flow.process(OpticalFlowKind::LeftPreviousToCurrent, &camera0, &camera1, &cameras, &features, &mut features0, &mut features1, None);
+    assert_eq!(count(&features0, false), 49);
+    assert_eq!(count(&features0, true), 0);
+    let rejected = flow.get_backward_rejected();
+    assert_eq!(rejected.len(), occluded_count);
+    assert!(rejected.iter().all(|(f0, _)| occluded(f0)));
+    for (f0, f1) in features0.iter().zip(&features1) {
+      assert!((f1.point - f0.point - Vector2d::new(-1., 0.)).norm() < 0.2);
+    }
+  }
+
+  #[test]
+  fn test_flat_intensity_gain() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    let lk_levels = PARAMETER_SET.lock().unwrap().lk_levels;
+    let camera = make_camera(textured_image(width, height, 1), lk_levels);
+    let features: Vec<Feature> = (0..20)
+      .map(|i| Feature { point: Vector2d::new((30 + 5 * i) as f64, 60.), id: TrackId(i) })
+      .collect();
+    let cameras = [&cameras[0], &cameras[0]];
+    let mut flow = OpticalFlow::new().unwrap();
+    let (mut features0, mut features1) = (vec![], vec![]);
+    flow.process(OpticalFlowKind::LeftPreviousToCurrent, &camera, &camera, &cameras, &features, &mut features0, &mut features1, None);
+    let tracked: Vec<_> = features1.iter().map(|f| (f.id, f.point)).collect();
+    assert!(tracked.len() > features.len() / 2);
+    // `estimate_intensity_gain()` of a flat second frame is zero. Such gains
+    // leave the intensities as they are.
+    for gain in [0., -1., f64::NAN, f64::INFINITY] {
+      flow.set_intensity_gain(gain, 128.);
+      flow.process(OpticalFlowKind::LeftPreviousToCurrent, &camera, &camera, &cameras, &features, &mut features0, &mut features1, None);
+      assert_eq!(features1.iter().map(|f| (f.id, f.point)).collect::<Vec<_>>(), tracked);
+    }
+  }
 }
@@ -0,0 +1,139 @@
+// Sums over the Lucas-Kanade window, the per-pixel inner loops of the optical
+// flow. On x86-64 CPUs with AVX four pixels are accumulated at a time, chosen
+// at runtime, and otherwise the scalar loops are used. The vectorized sums add
+// the products in a different order, so the results differ by rounding only.
+
+// Sums of `Ix^2`, `Iy^2` and `Ix * Iy`, the entries of the spatial gradient
+// matrix.
+pub fn gradient_sums(ix: &[f64], iy: &[f64]) -> [f64; 3] {
+  assert_eq!(ix.len(), iy.len());
+  #[cfg(target_arch = "x86_64")]
+  if is_x86_feature_detected!("avx") {
+    return unsafe { avx::gradient_sums(ix, iy) };
+  }
+  gradient_sums_scalar(ix, iy)
+}
+
+// Sums of `It * Ix` and `It * Iy`, the image mismatch vector.
+pub fn mismatch_sums(it: &[f64], ix: &[f64], iy: &[f64]) -> [f64; 2] {
+  assert_eq!(it.len(), ix.len());
+  assert_eq!(it.len(), iy.len());
+  #[cfg(target_arch = "x86_64")]
+  if is_x86_feature_detected!("avx") {
+    return unsafe { avx::mismatch_sums(it, ix, iy) };
+  }
+  mismatch_sums_scalar(it, ix, iy)
+}
+
+fn gradient_sums_scalar(ix: &[f64], iy: &[f64]) -> [f64; 3] {
+  let mut sums = [0.; 3];
+  for (x, y) in ix.iter().zip(iy) {
+    sums[0] += x * x;
+    sums[1] += y * y;
+    sums[2] += x * y;
+  }
+  sums
+}
+
+fn mismatch_sums_scalar(it: &[f64], ix: &[f64], iy: &[f64]) -> [f64; 2] {
+  let mut sums = [0.; 2];
+  for ((t, x), y) in it.iter().zip(ix).zip(iy) {
+    sums[0] += t * x;
+    sums[1] += t * y;
+  }
+  sums
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx {
+  use std::arch::x86_64::*;
+
+  const LANES: usize = 4;
+
+  // Callers must check that the CPU supports AVX.
+  #[target_feature(enable = "avx")]
+  pub unsafe fn gradient_sums(ix: &[f64], iy: &[f64]) -> [f64; 3] {
+    let n = ix.len() / LANES * LANES;
+    let mut xx = _mm256_setzero_pd();
+    let mut yy = _mm256_setzero_pd();
+    let mut xy = _mm256_setzero_pd();
+    for i in (0..n).step_by(LANES) {
+      let x = _mm256_loadu_pd(ix.as_ptr().add(i));
+      let y = _mm256_loadu_pd(iy.as_ptr().add(i));
+      xx = _mm256_add_pd(xx, _mm256_mul_pd(x, x));
+      yy = _mm256_add_pd(yy, _mm256_mul_pd(y, y));
+      xy = _mm256_add_pd(xy, _mm256_mul_pd(x, y));
+    }
+    let tail = super::gradient_sums_scalar(&ix[n..], &iy[n..]);
+    [horizontal_sum(xx) + tail[0], horizontal_sum(yy) + tail[1], horizontal_sum(xy) + tail[2]]
+  }
+
+  // Callers must check that the CPU supports AVX.
+  #[target_feature(enable = "avx")]
+  pub unsafe fn mismatch_sums(it: &[f64], ix: &[f64], iy: &[f64]) -> [f64; 2] {
+    let n = it.len() / LANES * LANES;
+    let mut tx = _mm256_setzero_pd();
+    let mut ty = _mm256_setzero_pd();
+    for i in (0..n).step_by(LANES) {
+      let t = _mm256_loadu_pd(it.as_ptr().add(i));
+      let x = _mm256_loadu_pd(ix.as_ptr().add(i));
+      let y = _mm256_loadu_pd(iy.as_ptr().add(i));
+      tx = _mm256_add_pd(tx, _mm256_mul_pd(t, x));
+      ty = _mm256_add_pd(ty, _mm256_mul_pd(t, y));
+    }
+    let tail = super::mismatch_sums_scalar(&it[n..], &ix[n..], &iy[n..]);
+    [horizontal_sum(tx) + tail[0], horizontal_sum(ty) + tail[1]]
+  }
+
+  #[target_feature(enable = "avx")]
+  unsafe fn horizontal_sum(v: __m256d) -> f64 {
+    let mut lanes = [0.; LANES];
+    _mm256_storeu_pd(lanes.as_mut_ptr(), v);
+    lanes.iter().sum()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::all::*;
+
+  #[test]
+  fn test_simd_sums() {
+    // Gradients and differences of a textured image as computed by the
+    // optical flow, for several window sizes so that some have a tail that
+    // does not fill a vector.
+    let image = textured_image(64, 48, 3);
+    let image1 = textured_image(64, 48, 4);
+    for size in [7, 9, 15, 21] {
+      let r = (size - 1) / 2;
+      let center = Vector2d::new(30.3, 20.6);
+      let mut Ix = Matrixd::zeros(0, 0);
+      let mut Iy = Matrixd::zeros(0, 0);
+      let mut It = Matrixd::zeros(0, 0);
+      let mut grid = Matrixd::zeros(0, 0);
+      let range = [[-(r as i16), r as i16], [-(r as i16), r as i16]];
+      scharr(&image, center, range, &mut Ix, &mut Iy, &mut grid);
+      fill_grid(&image1, range, center + Vector2d::new(0.7, -0.2), &mut It);
+      It = grid.slice((1, 1), (size, size)) - It;
+      let (ix, iy, it) = (Ix.as_slice(), Iy.as_slice(), It.as_slice());
+      assert_eq!(ix.len(), size * size);
+
+      let gradient = gradient_sums(ix, iy);
+      let mismatch = mismatch_sums(it, ix, iy);
+      let gradient_scalar = gradient_sums_scalar(ix, iy);
+      let mismatch_scalar = mismatch_sums_scalar(it, ix, iy);
+      assert!(gradient_scalar[0] > 1e3);
+      for (a, b) in gradient.iter().zip(&gradient_scalar).chain(mismatch.iter().zip(&mismatch_scalar)) {
+        assert!((a - b).abs() <= 1e-12 * b.abs().max(1.), "{} {}", a, b);
+      }
+      #[cfg(target_arch = "x86_64")]
+      if is_x86_feature_detected!("avx") {
+        let gradient_avx = unsafe { avx::gradient_sums(ix, iy) };
+        let mismatch_avx = unsafe { avx::mismatch_sums(it, ix, iy) };
+        assert_eq!(gradient, gradient_avx);
+        assert_eq!(mismatch, mismatch_avx);
+      }
+    }
+  }
+}
@@ -4,21 +4,144 @@ lazy_static! {
   pub static ref PARAMETER_SET: Mutex<ParameterSet> = Mutex::new(ParameterSet::default());
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(clap::ArgEnum)]
+pub enum AssertionPolicy {
+  // Panic on failed invariants.
+  #[default]
+  Development,
+  // Log failed invariants and skip the offending track or frame.
+  Production,
+}
+
 #[derive(Debug, Default)]
 #[derive(clap::Parser)]
 pub struct ParameterSet {
-  // Random seed.
+  // Seed of the random number generators of every randomized stage: the
+  // flow RANSAC of the tracker, the track selection of the visual update
+  // and the epipolar curve colors of the visualization. Runs on the same
+  // input with the same seed and parameters give identical output.
   #[clap(long, default_value = "0")]
   pub seed: u64,
 
+  // What to do when an internal invariant fails, see `invariant()`.
+  #[clap(long, arg_enum, default_value = "development")]
+  pub assertion_policy: AssertionPolicy,
+
   #[clap(long, default_value = "1")]
   pub frame_sub: usize,
 
+  // Format of the input folder, see `InputFormat`.
+  #[clap(long = "format", arg_enum, default_value = "benchmark")]
+  pub input_format: InputFormat,
+  // Number of significant bits in the samples of 16-bit PNG images, for
+  // cameras that store eg 10 or 12-bit values in the low bits. Zero uses all
+  // the 16 bits. The images are scaled to 8 bits in either case.
+  #[clap(long, default_value = "0")]
+  pub input_significant_bits: usize,
+
   // Tracker module.
   #[clap(long, default_value = "400")]
   pub max_tracks: usize,
+  // Number of features detected on the first frame, which has nothing to
+  // track from. Defaults to `max_tracks`, and larger values are clamped to it.
+  #[clap(long)]
+  pub bootstrap_tracks: Option<usize>,
+  // Scale the number of tracks per frame between `min_tracks` and
+  // `max_tracks` by the image texture, so that bland scenes spend less time
+  // detecting and tracking features that would be poor anyway.
+  #[clap(long)]
+  pub adaptive_tracks: bool,
+  #[clap(long, default_value = "100")]
+  pub min_tracks: usize,
+  // Initial FAST threshold for detecting features. Without
+  // `target_feature_count` it is halved a few times if not enough features
+  // are found.
+  #[clap(long, default_value = "128")]
+  pub detection_threshold: i16,
+  // Number of detections per frame that the FAST threshold is adjusted
+  // towards, starting from `detection_threshold`, instead of halving the
+  // threshold within the frame. Counts all the corners in the image, also
+  // when fewer features are needed. Zero disables the adjustment.
+  #[clap(long, default_value = "0")]
+  pub target_feature_count: usize,
+  // Grid of image cells, columns and rows, that the detections are spread
+  // over: each cell keeps its detections with the highest Shi-Tomasi scores,
+  // at most an equal share of the needed features. Zero disables the grid.
+  #[clap(long, default_value = "0")]
+  pub detection_grid_cols: usize,
+  #[clap(long, default_value = "0")]
+  pub detection_grid_rows: usize,
+  // Pyramid level features are detected on, 0 for full resolution. Coarser
+  // levels find larger-scale features, which can be more stable. Detections
+  // are mapped back to full resolution. At most `lk_levels`.
+  #[clap(long, default_value = "0")]
+  pub detection_level: usize,
+  // Minimum Shi-Tomasi score (squared intensity gradient) for a detected
+  // feature to become a track. Zero disables the check.
+  #[clap(long, default_value = "0")]
+  pub track_min_score: f64,
+  // Maximum anisotropy of the local gradients, between 0 and 1, for a detected
+  // feature to become a track. Features on strong edges such as object
+  // silhouettes have values close to 1 and track unreliably. 1 disables the check.
+  #[clap(long, default_value = "1")]
+  pub edge_max_anisotropy: f64,
+  // Radius in pixels of a circle in the first camera image where features are
+  // neither detected nor tracked, eg the hole of a catadioptric mirror. Zero
+  // disables the exclusion.
+  #[clap(long, default_value = "0")]
+  pub detection_exclusion_radius: f64,
+  // Center of the `detection_exclusion_radius` circle. Defaults to the
+  // principal point of the first camera.
+  #[clap(long)]
+  pub detection_exclusion_x: Option<f64>,
+  #[clap(long)]
+  pub detection_exclusion_y: Option<f64>,
+  // PNG image of the size of the camera images whose black pixels are neither
+  // detected nor tracked in any camera, eg the hood of a car or a timestamp
+  // overlay, as in the input images before `image_flip`. Cannot be combined
+  // with `rectify`. Relative paths are in the input folder.
+  #[clap(long)]
+  pub detection_mask: Option<String>,
+  // Track features in each camera separately, as two monocular cameras, instead
+  // of matching them between the cameras. Use this for rigs whose fields of
+  // view barely overlap, eg front and back cameras, where stereo matching finds
+  // nothing. Overlapping stereo pairs should leave it off: without the known
+  // baseline the depths of the features must come from the motion alone.
+  #[clap(long)]
+  pub independent_cameras: bool,
   #[clap(long, default_value = "0.5")]
   pub stationarity_threshold: f64,
+  // Seconds of IMU samples whose variation is checked on each frame to detect
+  // that the device is at rest and apply a zero-velocity update, also when
+  // there are no tracks. Zero disables the check.
+  #[clap(long, default_value = "0.5")]
+  pub imu_stationary_window: f64,
+  // Maximum standard deviations of the gyroscope (rad/s) and accelerometer
+  // (m/s^2) samples within `imu_stationary_window` for the device to be at rest.
+  #[clap(long, default_value = "0.01")]
+  pub imu_stationary_gyroscope_std: f64,
+  #[clap(long, default_value = "0.05")]
+  pub imu_stationary_accelerometer_std: f64,
+  // Seconds the device must be at rest, by the above thresholds, before the
+  // orientation is initialized from the mean accelerometer sample. Until then
+  // the input is skipped. Zero initializes from the first sample, assuming the
+  // device starts at rest.
+  #[clap(long, default_value = "0")]
+  pub imu_init_window: f64,
+  // Number of frames a track that failed to be tracked is kept as lost and
+  // re-attempted from the frame where it was last seen, before removal.
+  #[clap(long, default_value = "0")]
+  pub lost_track_grace: usize,
+  // End tracks once they have been seen on this many frames, so that fresh
+  // detections replace them and the tracks turn over also in a static scene.
+  // Zero disables the limit.
+  #[clap(long, default_value = "0")]
+  pub max_track_length: usize,
+  // Frames with at most this many tracks are treated as a tracking blackout
+  // where only the IMU is used.
+  #[clap(long, default_value = "0")]
+  pub blackout_max_tracks: usize,
 
   // (Extended) Kalman Filter.
   #[clap(long, default_value = "1e-3")]
@@ -35,34 +158,296 @@ pub struct ParameterSet {
   pub kf_noise_bga: f64,
   #[clap(long, default_value = "1e-6")]
   pub kf_noise_baa: f64,
+  // Random walks of the gyroscope (rad/s/sqrt(s)) and accelerometer
+  // (m/s^2/sqrt(s)) biases, see `imu_noise_calibration_time`.
+  #[clap(long, default_value = "1e-4")]
+  pub kf_noise_bga_walk: f64,
+  #[clap(long, default_value = "1e-5")]
+  pub kf_noise_baa_walk: f64,
   #[clap(long, default_value = "1e-7")]
   pub kf_noise_zero_velocity: f64,
   #[clap(long, default_value = "1e-1")]
   pub kf_noise_visual: f64,
+  // Multipliers of `kf_noise_visual` for the observations of the first and
+  // second camera, for stereo rigs where one camera is worse than the other.
+  #[clap(long, default_value = "1")]
+  pub kf_noise_visual_scale0: f64,
+  #[clap(long, default_value = "1")]
+  pub kf_noise_visual_scale1: f64,
+  // Scale `kf_noise_visual` of a track observed for `age` frames by
+  // 1 / (1 + kf_noise_visual_age_scale * age), but not below
+  // `kf_noise_visual_age_floor`, to trust tracks that have survived long more.
+  // Zero disables the scaling.
+  #[clap(long, default_value = "0")]
+  pub kf_noise_visual_age_scale: f64,
+  #[clap(long, default_value = "0.5")]
+  pub kf_noise_visual_age_floor: f64,
+  // Initial guess of the camera-IMU time offset (seconds), added to the frame
+  // timestamps to get the IMU time at which the frames were exposed.
+  #[clap(long, default_value = "0", allow_hyphen_values = true)]
+  pub time_offset: f64,
+  // Standard deviation (seconds) of `time_offset`. Zero keeps the offset
+  // fixed, otherwise it is estimated in the visual update.
+  #[clap(long, default_value = "0")]
+  pub time_offset_std: f64,
+
+  // Reset the filter when the standard deviation (meters) of the position
+  // grows above this, see `KalmanFilter::check_divergence()`. Zero disables
+  // the check.
+  #[clap(long, default_value = "0")]
+  pub divergence_max_position_std: f64,
+  // Reset the filter after this many consecutive frames whose visual update
+  // was skipped because the outlier gate rejected its tracks. Zero disables
+  // the check.
+  #[clap(long, default_value = "0")]
+  pub divergence_max_rejected_updates: usize,
+
+  // Visual update.
+  // Maximum number of tracks considered for the visual update per frame.
+  #[clap(long, default_value = "50")]
+  pub visual_update_max_tracks: usize,
+  // Maximum number of tracks that passed the outlier gate to stack in the
+  // visual update per frame. Landmarks of the filter state are not counted.
+  #[clap(long, default_value = "5")]
+  pub visual_update_max_inlier_tracks: usize,
+  // Threads measuring the tracks for the visual update in parallel. Zero uses
+  // one per core, one measures them on the calling thread. The measurement is
+  // only a few percent of the visual update time, which the serial Kalman
+  // filter update dominates at any track count, so more threads hardly help,
+  // see `bench_visual_update`.
+  #[clap(long, default_value = "1")]
+  pub visual_update_threads: usize,
+  // Select the tracks so that they cover the image evenly instead of randomly.
+  #[clap(long)]
+  pub visual_update_binning: bool,
+  // Maximum squared Mahalanobis distance per measurement dimension of a
+  // track's reprojection residuals. Tracks above it are rejected as outliers.
+  // Zero disables the check.
+  #[clap(long, default_value = "0")]
+  pub visual_update_outlier_gate: f64,
+  // Confidence level, eg 0.95, of a chi-squared bound for the squared
+  // Mahalanobis distance of a track's reprojection residuals, given its
+  // degrees of freedom. Used instead of `visual_update_outlier_gate` after the
+  // warmup. Zero disables it.
+  #[clap(long, default_value = "0")]
+  pub visual_update_outlier_confidence: f64,
+  // Number of visual updates at the start during which
+  // `visual_update_warmup_outlier_gate` is used instead, while the filter
+  // covariance is still unreliable for gating.
+  #[clap(long, default_value = "0")]
+  pub visual_update_outlier_gate_warmup: usize,
+  // Outlier gate during the warmup, usually larger. Zero disables the check.
+  #[clap(long, default_value = "0")]
+  pub visual_update_warmup_outlier_gate: f64,
+  // Tracks that must pass the outlier gate for the visual update to be
+  // applied. With fewer, the frame is propagated using only the IMU. Larger
+  // values avoid updates from a few outliers that happened to pass the gate.
+  #[clap(long, default_value = "1")]
+  pub visual_update_min_inliers: usize,
+  // Minimum depth in meters of a triangulated point in every camera for its
+  // track to be used. The Jacobian of the projection grows as 1 / depth^2, so
+  // points very close to a camera give huge entries that destabilize the
+  // update. Zero only requires the points to be in front of the cameras.
+  #[clap(long, default_value = "0")]
+  pub visual_update_min_depth: f64,
+  // Seconds between the exposures of consecutive image rows of a rolling
+  // shutter camera. Nonzero overrides the shutter of the calibration.
+  #[clap(long, default_value = "0")]
+  pub line_readout_time: f64,
+  // Maximum number of poses a track is triangulated and updated from. Longer
+  // tracks use the subset of their poses that best covers the parallax. Zero
+  // uses all of them.
+  #[clap(long, default_value = "0")]
+  pub triangulation_max_views: usize,
+  // How tracks are triangulated for the visual update, see `TriangulationMethod`.
+  #[clap(long, arg_enum, default_value = "linear")]
+  pub triangulation_method: TriangulationMethod,
+  // Regularization added to the normal matrix S of the triangulation, which is
+  // the sum of I - v v^T over the rays v. Nearly parallel rays make S close to
+  // singular and the triangulated point unreliable. The regularization pulls
+  // such points towards the cameras, and their measurement noise is scaled by
+  // 1 + lambda / (smallest eigenvalue of S), which is about the number of rays
+  // times the squared parallax angle. Zero disables the regularization. Only
+  // used by the `linear` triangulation method.
+  #[clap(long, default_value = "0")]
+  pub triangulation_lambda: f64,
+  // How tracks constrain the state, see `LandmarkMode`.
+  #[clap(long, arg_enum, default_value = "msckf")]
+  pub landmark_mode: LandmarkMode,
+  // Maximum number of landmarks in the state in `slam` mode.
+  #[clap(long, default_value = "20")]
+  pub slam_max_landmarks: usize,
+  // Number of observations before a track becomes a landmark in `slam` mode.
+  #[clap(long, default_value = "5")]
+  pub slam_min_track_len: usize,
+  // Initial standard deviation of the landmark coordinates in meters.
+  #[clap(long, default_value = "0.5")]
+  pub slam_landmark_std: f64,
+  // Number of frames a landmark stays in the state in `slam` mode before it
+  // is marginalized, even if its track continues. The track is then used as
+  // in `msckf` mode. Zero keeps landmarks until their track is lost.
+  #[clap(long, default_value = "0")]
+  pub slam_max_landmark_age: usize,
+  // Maximum pixel distance for matching a track to a reprojected anchor
+  // landmark, see `anchors.json`.
+  #[clap(long, default_value = "5")]
+  pub anchor_match_max_dist: f64,
 
   // TODO Use another parameter struct with the clap flattening option?
   // Pyramidal Lucas-Kanade feature tracker.
-  #[clap(long, default_value = "3")]
+  // Number of pyramid levels below the full resolution image. More levels
+  // track larger motion at some extra cost.
+  #[clap(long, alias = "pyramid-levels", default_value = "3")]
   pub lk_levels: usize,
+  // Downscale factor between consecutive pyramid levels, above 1. Larger
+  // factors reach larger motion with fewer levels but track less reliably.
+  // Factors other than 2 use a slower resampling.
+  #[clap(long, default_value = "2")]
+  pub pyramid_scale: f64,
+  // Pyramid level where tracking starts. Coarser levels handle larger motion,
+  // finer levels are faster. Defaults to the coarsest level `lk_levels`, and
+  // larger values are clamped to it.
+  #[clap(long)]
+  pub lk_start_level: Option<usize>,
   #[clap(long, default_value = "10")]
   pub lk_iters: usize,
   #[clap(long, default_value = "7")]
   pub lk_win_size: usize,
+  // Choose the window size per feature instead of using `lk_win_size`: the
+  // smallest size between `lk_min_win_size` and `lk_max_win_size` where the
+  // smaller eigenvalue of the spatial gradient matrix reaches
+  // `lk_adaptive_win_min_eig`. Well-textured features get small windows that
+  // are precise near depth edges, and bland ones large windows that are robust.
+  #[clap(long)]
+  pub lk_adaptive_win: bool,
+  #[clap(long, default_value = "5")]
+  pub lk_min_win_size: usize,
+  #[clap(long, default_value = "15")]
+  pub lk_max_win_size: usize,
+  #[clap(long, default_value = "5e3")]
+  pub lk_adaptive_win_min_eig: f64,
   #[clap(long, default_value = "0.1")]
   pub lk_term: f64,
   #[clap(long, default_value = "1e-4")]
   pub lk_min_eig: f64,
   #[clap(long, default_value = "2")]
   pub lk_epipolar_max_dist: f64,
+  // Estimate the change of the image intensities between consecutive frames,
+  // eg from auto-exposure, as a gain and bias and undo it when tracking the
+  // features over time, see `estimate_intensity_gain()`.
+  #[clap(long)]
+  pub lk_intensity_compensation: bool,
+  // Track the features tracked over time also back from the current frame to
+  // the previous one, and drop those that return farther than this many
+  // pixels from where they started, eg because they got occluded or matched
+  // to a repetition of the texture. Zero disables the check.
+  #[clap(long, default_value = "0")]
+  pub lk_backward_max_dist: f64,
+  // Drop features tracked from the previous frames whose flow is farther
+  // than this many pixels from the epipolar geometry of the gyroscope
+  // rotation and a translation fitted with a two-point RANSAC, eg features
+  // on moving objects. Needs the rotation from the pose trail. Zero disables
+  // the check.
+  #[clap(long, default_value = "0")]
+  pub flow_ransac_max_error: f64,
 
-  #[clap(long, default_value = "20")]
+  // Undo a flip of the input images relative to the calibration.
+  #[clap(long, arg_enum, default_value = "none")]
+  pub image_flip: ImageFlip,
+  // Warn if the feature motion suggests the images are flipped relative to
+  // the calibration.
+  #[clap(long)]
+  pub flip_check: bool,
+
+  // Remap the stereo images to a common plane so that matches lie on the same row.
+  #[clap(long)]
+  pub rectify: bool,
+
+  // Hold each frame until the next IMU sample arrives and propagate the filter
+  // exactly to the frame time with a sample interpolated from the two
+  // bracketing samples. Otherwise propagation ends at the last sample before
+  // the frame. A frame at the very end of the input is then not processed.
+  #[clap(long)]
+  pub imu_frame_interpolation: bool,
+  // Units and signs the IMU samples are reported in, see `ImuConvention`.
+  #[clap(long, arg_enum, default_value = "none")]
+  pub imu_convention: ImuConvention,
+  // Longest interval in seconds between IMU samples to propagate over. Longer
+  // gaps, eg sensor dropouts, are skipped with a warning and the tracks are
+  // restarted. Zero disables the check.
+  #[clap(long, default_value = "0")]
+  pub imu_max_gap: f64,
+  // Discard this many IMU samples from the start of the input, eg while the
+  // sensor is settling after power-on.
+  #[clap(long, default_value = "0")]
+  pub imu_warmup_samples: usize,
+  // Discard the IMU samples within this many seconds of the first one.
+  #[clap(long, default_value = "0")]
+  pub imu_warmup_time: f64,
+  // Estimate the IMU noise from the Allan variance of the samples within this
+  // many seconds from the start, during which the device must be static, and
+  // log the matching `kf_noise_g` and `kf_noise_a`. Long static segments, ideally
+  // hours, also give the bias random walks. Zero disables the estimation.
+  #[clap(long, default_value = "0")]
+  pub imu_noise_calibration_time: f64,
+  // Use the noise and bias random walks estimated with
+  // `imu_noise_calibration_time` in the filter for the rest of the run.
+  #[clap(long)]
+  pub imu_noise_calibration_apply: bool,
+
+  // Maximum number of poses in the filter state, which bounds the memory and
+  // computation per frame. Beyond it the oldest pose is marginalized, see
+  // `KalmanFilter::marginalize_oldest_pose()`. The state is allocated for the
+  // full trail, so this is also the length of `pose_trail_frame_numbers`.
+  #[clap(long, alias = "max-pose-trail-length", default_value = "20")]
   pub pose_trail_len: usize,
+  // Evaluate the visual update Jacobians at the first estimates of the poses
+  // of the trail (FEJ) instead of the current ones. The global position and
+  // yaw are not observable, and linearizing each update at different
+  // estimates makes their covariance disagree with the actual error, which
+  // FEJ avoids. In simulation this keeps the yaw covariance from growing far
+  // beyond the error, see `test_fej_consistency`.
+  #[clap(long)]
+  pub use_fej: bool,
   #[clap(long, default_value = "9.81")]
   pub gravity: f64,
+  // Fuse the GPS fixes of the input, `gps.csv` in the `csv` input format, as
+  // absolute position measurements, see `KalmanFilter::gps_update()`.
+  #[clap(long)]
+  pub use_gps: bool,
+  // Horizontal distance in meters from the first GPS fix at which the yaw
+  // between the fixes and the world frame is estimated. Closer fixes only
+  // update the altitude.
+  #[clap(long, default_value = "20")]
+  pub gps_alignment_distance: f64,
+  // Confidence level of a chi-squared bound for the squared Mahalanobis
+  // distance of a GPS fix from the position estimate. Fixes above it are
+  // skipped as outliers, eg multipath jumps. Zero disables the check.
+  #[clap(long, default_value = "0.999")]
+  pub gps_outlier_confidence: f64,
+
+  // Ablation, for measuring the contribution of each stage.
+  // Use every track that passes triangulation, ignoring the outlier gates.
+  #[clap(long)]
+  pub disable_outlier_rejection: bool,
+  // Keep the IMU biases at their initial values, zero or `--imu-bias-input`.
+  #[clap(long)]
+  pub disable_bias_estimation: bool,
+  // Triangulate and update stereo tracks from the first camera only, as if
+  // the rig were monocular.
+  #[clap(long)]
+  pub disable_stereo: bool,
+  // Skip the zero-velocity updates, both from stationary features and from
+  // `imu_stationary_window`.
+  #[clap(long)]
+  pub disable_zupt: bool,
 
   // Visualizations.
   #[clap(long)]
   pub show_3d: bool,
+  // Text overlay with the frame rate, track and outlier counts and speed.
+  #[clap(long)]
+  pub show_hud: bool,
   #[clap(long)]
   pub show_features: bool,
   #[clap(long)]
@@ -71,12 +456,35 @@ pub struct ParameterSet {
   pub show_pyramid: bool,
   #[clap(long)]
   pub show_tracks: bool,
+  // Draw the recent positions of each track in the first camera as a polyline.
   #[clap(long)]
+  pub show_trails: bool,
+  // Number of positions in the trails of `show_trails`.
+  #[clap(long, default_value = "10")]
+  pub trail_len: usize,
+  // The optical flow overlays draw over each other, give at most one of them.
+  #[clap(long, conflicts_with_all = &["show-flow1", "show-flow2"])]
   pub show_flow0: bool,
-  #[clap(long)]
+  #[clap(long, conflicts_with = "show-flow2")]
   pub show_flow1: bool,
   #[clap(long)]
   pub show_flow2: bool,
   #[clap(long)]
   pub show_epipolar: bool,
+  // Draw in red the features dropped by `lk_backward_max_dist` and
+  // `flow_ransac_max_error`, from their previous position to where they were
+  // tracked.
+  #[clap(long)]
+  pub show_flow_rejected: bool,
+  // Draw a 3-sigma ellipse of the position uncertainty around the
+  // reprojection of each landmark triangulated by the visual update.
+  #[clap(long)]
+  pub show_landmark_uncertainty: bool,
+  // Write the sparsity pattern of the visual update Jacobian of each frame to
+  // a text file in this folder, one line per track. See `jacobian_sparsity()`.
+  #[clap(long)]
+  pub jacobian_sparsity_output: Option<String>,
+  // Requires ground-truth poses in the input and a `landmarks.json` file.
+  #[clap(long)]
+  pub show_ground_truth: bool,
 }
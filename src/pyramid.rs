@@ -6,6 +6,8 @@ pub struct Pyramid {
   pub levels: Vec<Image>,
   // Size of the parent Image. Needed?
   pub size: [usize; 2],
+  // Downscale factor between consecutive levels, see `level_scale()`.
+  pub scale: f64,
 }
 
 impl Pyramid {
@@ -13,6 +15,7 @@ impl Pyramid {
     Pyramid {
       levels: vec![],
       size: [0, 0],
+      scale: 2.,
     }
   }
 
@@ -21,38 +24,98 @@ impl Pyramid {
     pyramid: &mut Pyramid,
     video_frame: &Image,
     level_count: usize,
+    scale: f64,
   ) -> Result<()> {
+    if scale.is_nan() || scale <= 1. { bail!("Pyramid scale must be larger than 1, got {}.", scale) }
     pyramid.levels = compute_levels(
       video_frame,
       mem::take(&mut pyramid.levels),
       level_count,
+      scale,
     )?;
     pyramid.size = [video_frame.width, video_frame.height];
+    pyramid.scale = scale;
     Ok(())
   }
+
+  // Full resolution coordinates divided by this give the coordinates on
+  // `level`, where 0 is the full resolution image. Pixel centers of a level
+  // coincide with those of the parent at multiples of `scale`.
+  pub fn level_scale(&self, level: usize) -> f64 {
+    self.scale.powi(level as i32)
+  }
 }
 
 fn compute_levels(
   video_frame: &Image,
   mut levels: Vec<Image>,
   level_count: usize,
+  scale: f64,
 ) -> Result<Vec<Image>> {
   while levels.len() < level_count {
     levels.push(Image::empty());
   }
+  levels.truncate(level_count);
   if level_count == 0 { return Ok(levels) }
-  downscale(&video_frame, &mut levels[0])?;
+  let downscale = |parent: &Image, child: &mut Image| {
+    if scale == 2. { downscale(parent, child) } else { resample(parent, child, scale) }
+  };
+  downscale(video_frame, &mut levels[0])?;
   for i in 0..(level_count - 1) {
     let rest = &mut levels[i..];
     // Need to use a split function to get a mutable and non-mutable reference
     // to different elements of the vector.
     if let Some((parent, rest)) = rest.split_first_mut() {
-      downscale(&parent, &mut rest[0])?;
+      downscale(parent, &mut rest[0])?;
     }
   }
   Ok(levels)
 }
 
+// Downscale by an arbitrary factor. Each pixel is a tent-filtered average of
+// the parent pixels within `scale` of its center, which for a factor of 2 is
+// the same filter as in `downscale()`.
+fn resample(
+  parent: &Image,
+  child: &mut Image,
+  scale: f64,
+) -> Result<()> {
+  let w = (parent.width as f64 / scale).floor() as usize;
+  let h = (parent.height as f64 / scale).floor() as usize;
+  if w == 0 || h == 0 {
+    bail!("Cannot downscale image with dimensions {}x{} by {}", parent.width, parent.height, scale);
+  }
+  child.data.clear();
+  child.width = w;
+  child.height = h;
+
+  // Parent pixels and weights of the filter along one axis.
+  let taps = |center: f64, size: usize| -> Vec<(usize, f64)> {
+    let start = (center - scale).floor().max(0.) as usize;
+    let end = ((center + scale).ceil() as usize).min(size - 1);
+    (start..=end)
+      .map(|i| (i, 1. - (i as f64 - center).abs() / scale))
+      .filter(|(_, weight)| *weight > 0.)
+      .collect()
+  };
+  let taps_x: Vec<_> = (0..w).map(|x| taps(scale * x as f64, parent.width)).collect();
+  for y in 0..h {
+    let taps_y = taps(scale * y as f64, parent.height);
+    for taps_x in &taps_x {
+      let mut sum = 0.;
+      let mut weights = 0.;
+      for (py, wy) in &taps_y {
+        for (px, wx) in taps_x {
+          sum += wy * wx * parent.data[py * parent.width + px] as f64;
+          weights += wy * wx;
+        }
+      }
+      child.data.push((sum / weights).round() as u8);
+    }
+  }
+  Ok(())
+}
+
 fn downscale(
   parent: &Image,
   child: &mut Image
@@ -94,3 +157,41 @@ fn downscale(
   }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pyramid_scale() {
+    let image = textured_image(96, 64, 1);
+    let mut pyramid = Pyramid::empty();
+    for (scale, sizes) in [(2., [(48, 32), (24, 16), (12, 8)]), (3., [(32, 21), (10, 7), (3, 2)]), (1.5, [(64, 42), (42, 28), (28, 18)])] {
+      Pyramid::compute(&mut pyramid, &image, 3, scale).unwrap();
+      let level_sizes: Vec<_> = pyramid.levels.iter().map(|level| (level.size(0), level.size(1))).collect();
+      assert_eq!(level_sizes, sizes);
+      assert_eq!(pyramid.level_scale(0), 1.);
+      assert_eq!(pyramid.level_scale(2), scale * scale);
+    }
+    // Fewer levels on reuse.
+    Pyramid::compute(&mut pyramid, &image, 1, 2.).unwrap();
+    assert_eq!(pyramid.levels.len(), 1);
+
+    // The general resampling uses the same filter as the fast halving.
+    let mut halved = Image::empty();
+    let mut resampled = Image::empty();
+    downscale(&image, &mut halved).unwrap();
+    resample(&image, &mut resampled, 2.).unwrap();
+    assert_eq!((resampled.width, resampled.height), (halved.width, halved.height));
+    // Away from the borders, which are clamped differently, up to the integer
+    // divisions of `downscale()`.
+    for y in 1..(halved.height - 1) {
+      for x in 1..(halved.width - 1) {
+        assert!((halved.value(x, y) as i32 - resampled.value(x, y) as i32).abs() <= 3);
+      }
+    }
+
+    assert!(Pyramid::compute(&mut pyramid, &image, 3, 1.).is_err());
+    assert!(Pyramid::compute(&mut pyramid, &image, 8, 3.).is_err());
+  }
+}
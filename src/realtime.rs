@@ -0,0 +1,132 @@
+// Paces the processing to the timestamps of the input, see `--realtime`. Only
+// the frames wait, the IMU samples between them are processed as they come.
+
+use crate::all::*;
+
+use std::time::{Duration, Instant};
+
+// Seconds a frame may start late before the schedule is restarted from it.
+const MAX_LATENESS: f64 = 0.05;
+
+pub struct Realtime {
+  // Input seconds per wall-clock second.
+  speed: f64,
+  // Input time and wall-clock instant that the schedule starts from.
+  anchor: Option<(f64, Instant)>,
+  // Since when the clock is stopped, see `pause()`.
+  paused: Option<Instant>,
+}
+
+impl Realtime {
+  pub fn new(speed: f64) -> Result<Realtime> {
+    if !(speed > 0. && speed.is_finite()) { bail!("Invalid playback speed {}.", speed) }
+    Ok(Realtime { speed, anchor: None, paused: None })
+  }
+
+  // Sleeps until the frame at input `time` is due.
+  pub fn wait(&mut self, time: f64) {
+    if let Some(delay) = self.delay(time, Instant::now()) {
+      std::thread::sleep(delay);
+    }
+  }
+
+  // Stops the clock while the processing is paused, so that the paused time
+  // does not make the next frames miss their deadlines. Frames stepped
+  // through while paused do not wait.
+  #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+  pub fn pause(&mut self) {
+    self.pause_at(Instant::now());
+  }
+
+  #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+  pub fn resume(&mut self) {
+    self.resume_at(Instant::now());
+  }
+
+  fn pause_at(&mut self, now: Instant) {
+    if self.paused.is_none() { self.paused = Some(now) }
+  }
+
+  fn resume_at(&mut self, now: Instant) {
+    let Some(paused) = self.paused.take() else { return };
+    if let Some((_, anchor_instant)) = &mut self.anchor {
+      *anchor_instant += now - paused;
+    }
+  }
+
+  // How long to wait at `now` for the frame at `time`. Processing slower than
+  // real time would make the frames later and later, so a frame that missed
+  // its deadline by more than `MAX_LATENESS` is warned about and the schedule
+  // continues from it. Input time going backwards, eg after a restart, also
+  // restarts the schedule.
+  fn delay(&mut self, time: f64, now: Instant) -> Option<Duration> {
+    if let Some(paused) = self.paused {
+      // Continue from the last stepped frame on resume.
+      self.anchor = Some((time, paused));
+      return None;
+    }
+    let Some((anchor_time, anchor_instant)) = self.anchor.filter(|(anchor_time, _)| time >= *anchor_time) else {
+      self.anchor = Some((time, now));
+      return None;
+    };
+    let due = (time - anchor_time) / self.speed;
+    let elapsed = (now - anchor_instant).as_secs_f64();
+    if due >= elapsed { return Some(Duration::from_secs_f64(due - elapsed)) }
+    if elapsed - due > MAX_LATENESS {
+      warn!("Missed the real-time deadline of the frame at {:.3} s by {:.0} ms.", time, 1e3 * (elapsed - due));
+      self.anchor = Some((time, now));
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_realtime() {
+    assert!(Realtime::new(0.).is_err());
+    let start = Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+    let ms = |delay: Option<Duration>| delay.map(|delay| (1e3 * delay.as_secs_f64()).round() as i32);
+
+    // Half speed: frames 0.1 s apart in the input are due 0.2 s apart.
+    let mut realtime = Realtime::new(0.5).unwrap();
+    assert_eq!(ms(realtime.delay(10.0, at(0.))), None);
+    assert_eq!(ms(realtime.delay(10.1, at(0.05))), Some(150));
+    // Processing time is subtracted from the wait.
+    assert_eq!(ms(realtime.delay(10.2, at(0.35))), Some(50));
+    // Slightly late frames keep the schedule and can catch up.
+    assert_eq!(ms(realtime.delay(10.3, at(0.62))), None);
+    assert_eq!(ms(realtime.delay(10.4, at(0.7))), Some(100));
+    // A missed deadline restarts the schedule instead of accumulating lag.
+    assert_eq!(ms(realtime.delay(10.5, at(1.5))), None);
+    assert_eq!(ms(realtime.delay(10.6, at(1.55))), Some(150));
+    // So does going back to the beginning of the input.
+    assert_eq!(ms(realtime.delay(10.0, at(2.))), None);
+    assert_eq!(ms(realtime.delay(10.1, at(2.))), Some(200));
+  }
+
+  #[test]
+  fn test_realtime_pause() {
+    let start = Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+    let ms = |delay: Option<Duration>| delay.map(|delay| (1e3 * delay.as_secs_f64()).round() as i32);
+
+    let mut realtime = Realtime::new(1.).unwrap();
+    assert_eq!(ms(realtime.delay(10.0, at(0.))), None);
+    // Paused for a second, after which the schedule continues as if the
+    // pause did not happen.
+    realtime.pause_at(at(0.05));
+    realtime.resume_at(at(1.05));
+    assert_eq!(ms(realtime.delay(10.1, at(1.05))), Some(50));
+    // Frames stepped through while paused do not wait, and the schedule
+    // continues from the last of them.
+    realtime.pause_at(at(1.2));
+    assert_eq!(ms(realtime.delay(10.2, at(2.))), None);
+    assert_eq!(ms(realtime.delay(10.3, at(3.))), None);
+    realtime.resume_at(at(5.));
+    assert_eq!(ms(realtime.delay(10.4, at(5.02))), Some(80));
+  }
+}
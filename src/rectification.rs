@@ -0,0 +1,201 @@
+// Stereo rectification: both images are remapped to a common image plane
+// parallel to the baseline so that corresponding points lie on the same image
+// row and matching reduces to a horizontal disparity search.
+//
+// The rectified cameras replace the calibrated ones everywhere in the
+// pipeline. They are undistorted pinhole cameras rotated from the originals,
+// so the poses and triangulation account for the rectification through their
+// `imu_to_camera` transforms.
+
+use crate::all::*;
+
+pub struct Rectification {
+  // Rotations from the original to the rectified camera coordinates.
+  rotations: [Matrix3d; 2],
+  // Shared intrinsics of the rectified cameras.
+  camera_matrix: Matrix3d,
+  // Original pixel coordinates for each rectified pixel, per camera.
+  maps: [Vec<Option<Vector2d>>; 2],
+  map_size: (usize, usize),
+}
+
+impl Rectification {
+  // Returns the rectification and the rectified cameras.
+  pub fn new(cameras: &[Camera]) -> Result<(Rectification, Vec<Camera>)> {
+    if cameras.len() != 2 { bail!("Rectification requires two cameras.") }
+    let camera0_to_camera1 = cameras[1].imu_to_camera * affine_inverse(cameras[0].imu_to_camera);
+    // Position of the second camera in the first camera coordinates.
+    let baseline: Vector3d = position!(affine_inverse(camera0_to_camera1)).into();
+    if baseline.norm() <= 0. { bail!("Rectification requires a non-zero baseline.") }
+
+    // The new x-axis points along the baseline, z-axis stays as close as
+    // possible to the optical axis of the first camera.
+    let x = baseline.normalize();
+    let y = Vector3d::new(0., 0., 1.).cross(&x).normalize();
+    let z = x.cross(&y);
+    let R0 = Matrix3d::from_rows(&[x.transpose(), y.transpose(), z.transpose()]);
+    let R1 = R0 * rotation!(camera0_to_camera1).transpose();
+    let rotations = [R0, R1];
+
+    // Keep the focal length and principal point of the first camera.
+    let camera = &cameras[0];
+    let c = camera.project(Vector2d::zeros())
+      .ok_or(anyhow!("Failed to project the optical axis."))?;
+    let d = 1e-3;
+    let fx = (camera.project(Vector2d::new(d, 0.)).unwrap_or(c) - c)[0] / d;
+    let fy = (camera.project(Vector2d::new(0., d)).unwrap_or(c) - c)[1] / d;
+    if !(fx > 0. && fy > 0.) { bail!("Failed to compute focal length for rectification.") }
+    let camera_matrix = Matrix3d::new(
+      fx, 0., c[0],
+      0., fy, c[1],
+      0., 0., 1.,
+    );
+
+    let rectified_cameras = (0..2).map(|i| {
+      let mut rectify = Matrix4d::identity();
+      rectify.fixed_slice_mut::<3, 3>(0, 0).copy_from(&rotations[i]);
+      Camera {
+        imu_to_camera: rectify * cameras[i].imu_to_camera,
+        model: Box::new(PinholeModel::new(camera_matrix, vec![])),
+        shutter: cameras[i].shutter,
+      }
+    }).collect();
+
+    Ok((Rectification {
+      rotations,
+      camera_matrix,
+      maps: [vec![], vec![]],
+      map_size: (0, 0),
+    }, rectified_cameras))
+  }
+
+  // Pixel in the original image `i` seen at the rectified pixel.
+  pub fn rectified_to_original(&self, i: usize, camera: &Camera, pixel: Vector2d) -> Option<Vector2d> {
+    let ray = self.camera_matrix.try_inverse()? * Vector3d::new(pixel[0], pixel[1], 1.);
+    camera.model.ray_to_pixel(self.rotations[i].transpose() * ray)
+  }
+
+  // Remap the original images of the (non-rectified) `cameras`. Pixels that
+  // fall outside the original images are black.
+  pub fn rectify(&mut self, cameras: &[Camera], images: &[&Image], rectified: &mut Vec<Image>) {
+    let (w, h) = (images[0].width, images[0].height);
+    if self.map_size != (w, h) {
+      for (i, camera) in cameras.iter().enumerate().take(2) {
+        self.maps[i].clear();
+        for y in 0..h {
+          for x in 0..w {
+            let p = self.rectified_to_original(i, camera, Vector2d::new(x as f64, y as f64));
+            let inside = |p: &Vector2d| p[0] >= 0. && p[1] >= 0. && p[0] <= (w - 1) as f64 && p[1] <= (h - 1) as f64;
+            self.maps[i].push(p.filter(inside));
+          }
+        }
+      }
+      self.map_size = (w, h);
+    }
+    rectified.resize(2, Image::empty());
+    for i in 0..2 {
+      let image = images[i];
+      assert_eq!((image.width, image.height), (w, h));
+      rectified[i].width = w;
+      rectified[i].height = h;
+      rectified[i].data.clear();
+      rectified[i].data.extend(self.maps[i].iter().map(|p| {
+        p.map_or(0, |p| bilinear(image, p).round() as u8)
+      }));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_rectification() {
+    // A stereo pair with a vertical offset and a rotated second camera.
+    let mut cameras = test_cameras(160, 120);
+    let mut R = Matrix4d::identity();
+    R.fixed_slice_mut::<3, 3>(0, 0).copy_from(&to_rotation_matrix(Vector4d::new(1., 0.02, -0.05, 0.03).normalize()));
+    cameras[1].imu_to_camera = R * cameras[1].imu_to_camera;
+    cameras[1].imu_to_camera[(1, 3)] += 0.02;
+
+    let (rectification, rectified_cameras) = Rectification::new(&cameras).unwrap();
+    let project = |camera: &Camera, p: &Vector3d| camera.model.ray_to_pixel(transform_3d(&camera.imu_to_camera, p)).unwrap();
+    let mut max_row_difference: f64 = 0.;
+    for p in [
+      Vector3d::new(0., 0., 2.),
+      Vector3d::new(0.3, -0.2, 1.5),
+      Vector3d::new(-0.5, 0.4, 4.),
+      Vector3d::new(0.1, 0.3, 0.8),
+    ] {
+      let original = [project(&cameras[0], &p), project(&cameras[1], &p)];
+      assert!((original[0][1] - original[1][1]).abs() > 0.5);
+      let rectified = [project(&rectified_cameras[0], &p), project(&rectified_cameras[1], &p)];
+      max_row_difference = max_row_difference.max((rectified[0][1] - rectified[1][1]).abs());
+      // The remapping takes the rectified pixels to the original ones.
+      for i in 0..2 {
+        let q = rectification.rectified_to_original(i, &cameras[i], rectified[i]).unwrap();
+        assert!((q - original[i]).norm() < 1e-6);
+      }
+    }
+    assert!(max_row_difference < 1e-6);
+  }
+  #[test]
+  fn test_rectify() {
+    let (w, h) = (160, 120);
+    // Intensity is linear in the pixel coordinates, so bilinear interpolation
+    // reproduces it exactly.
+    let intensity = |p: Vector2d| 0.5 * p[0] + p[1];
+    let mut image = Image { data: vec![0; w * h], width: w, height: h };
+    for y in 0..h {
+      for x in 0..w {
+        image.data[y * w + x] = intensity(Vector2d::new(x as f64, y as f64)).round() as u8;
+      }
+    }
+    let mut rectified = vec![];
+
+    // An already rectified pair is remapped to itself, up to rounding and the
+    // border pixels.
+    let cameras = test_cameras(w, h);
+    let (mut rectification, _) = Rectification::new(&cameras).unwrap();
+    rectification.rectify(&cameras, &[&image, &image], &mut rectified);
+    assert_eq!(rectified.len(), 2);
+    for rectified in &rectified {
+      for y in 1..(h - 1) {
+        for x in 1..(w - 1) {
+          let d = rectified.data[y * w + x] as i32 - image.data[y * w + x] as i32;
+          assert!(d.abs() <= 1);
+        }
+      }
+    }
+
+    let mut cameras = test_cameras(w, h);
+    let mut R = Matrix4d::identity();
+    R.fixed_slice_mut::<3, 3>(0, 0).copy_from(&to_rotation_matrix(Vector4d::new(1., 0.02, -0.05, 0.03).normalize()));
+    cameras[1].imu_to_camera = R * cameras[1].imu_to_camera;
+    let (mut rectification, _) = Rectification::new(&cameras).unwrap();
+    // The second call reuses the maps.
+    for _ in 0..2 {
+      rectification.rectify(&cameras, &[&image, &image], &mut rectified);
+      let mut outside = 0;
+      for i in 0..2 {
+        assert_eq!((rectified[i].width, rectified[i].height), (w, h));
+        for y in 0..h {
+          for x in 0..w {
+            let value = rectified[i].data[y * w + x] as f64;
+            let p = rectification.rectified_to_original(i, &cameras[i], Vector2d::new(x as f64, y as f64)).unwrap();
+            if p[0] < 0. || p[1] < 0. || p[0] > (w - 1) as f64 || p[1] > (h - 1) as f64 {
+              assert_eq!(value, 0.);
+              outside += 1;
+            }
+            else {
+              assert!((value - intensity(p)).abs() <= 1.);
+            }
+          }
+        }
+      }
+      // The second camera is rotated, so some of its pixels have no source.
+      assert!(outside > 0);
+    }
+  }
+}
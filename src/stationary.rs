@@ -17,7 +17,8 @@ impl Stationary {
     for track in tracks {
       if track.points.len() < 2 { continue }
       let mut it = track.points.iter().rev();
-      // Compare two last points from the first stereo camera.
+      // Compare two last points from the first stereo camera, or the camera
+      // of a monocular track.
       let p0: Vector2d = it.next().unwrap().coordinates[0];
       let p1: Vector2d = it.next().unwrap().coordinates[0];
       if (p0 - p1).norm_squared() > self.max_error { return false }
@@ -25,3 +26,129 @@ impl Stationary {
     true
   }
 }
+
+// Detects that the device is at rest from the variation of the IMU samples
+// within a time window, see `imu_stationary_window`.
+pub struct ImuStationary {
+  window: f64,
+  max_gyroscope_std: f64,
+  max_accelerometer_std: f64,
+  // Time, gyroscope and accelerometer. The first sample is at or before the
+  // start of the window once enough samples have been added.
+  samples: VecDeque<(f64, Vector3d, Vector3d)>,
+  stationary: bool,
+  // Zero-velocity updates during the current stationary period.
+  update_count: usize,
+}
+
+impl ImuStationary {
+  pub fn new(window: f64) -> ImuStationary {
+    let p = PARAMETER_SET.lock().unwrap();
+    ImuStationary {
+      window,
+      max_gyroscope_std: p.imu_stationary_gyroscope_std,
+      max_accelerometer_std: p.imu_stationary_accelerometer_std,
+      samples: VecDeque::new(),
+      stationary: false,
+      update_count: 0,
+    }
+  }
+
+  pub fn add(&mut self, time: f64, gyroscope: Vector3d, accelerometer: Vector3d) {
+    self.samples.push_back((time, gyroscope, accelerometer));
+    while self.samples.len() > 1 && self.samples[1].0 <= time - self.window {
+      self.samples.pop_front();
+    }
+  }
+
+  // Forget the samples, eg over a gap in the IMU data.
+  pub fn clear(&mut self) {
+    self.samples.clear();
+  }
+
+  // True if the samples span the whole window.
+  pub fn is_full(&self) -> bool {
+    match (self.samples.front(), self.samples.back()) {
+      (Some(first), Some(last)) => first.0 <= last.0 - self.window,
+      _ => false,
+    }
+  }
+
+  // True if the samples cover the whole window and vary less than the
+  // thresholds.
+  pub fn is_stationary(&self) -> bool {
+    self.is_full()
+      && std(self.samples.iter().map(|s| s.1)) <= self.max_gyroscope_std
+      && std(self.samples.iter().map(|s| s.2)) <= self.max_accelerometer_std
+  }
+
+  // Mean accelerometer sample in the window, the gravity when at rest.
+  pub fn mean_accelerometer(&self) -> Vector3d {
+    if self.samples.is_empty() { return Vector3d::zeros() }
+    self.samples.iter().map(|s| s.2).sum::<Vector3d>() / self.samples.len() as f64
+  }
+
+  // Like `is_stationary()`, but logs the changes. The caller applies a
+  // zero-velocity update for each true.
+  pub fn check(&mut self) -> bool {
+    let stationary = self.is_stationary();
+    if stationary {
+      if !self.stationary {
+        info!("IMU at rest at time {:.3}, applying zero-velocity updates.", self.samples.back().unwrap().0);
+      }
+      self.update_count += 1;
+    }
+    else if self.stationary {
+      info!("IMU moving at time {:.3} after {} zero-velocity updates.", self.samples.back().map_or(0., |s| s.0), self.update_count);
+      self.update_count = 0;
+    }
+    self.stationary = stationary;
+    stationary
+  }
+}
+
+// Root-mean-square distance of the vectors from their mean.
+fn std(values: impl Iterator<Item = Vector3d> + Clone) -> f64 {
+  let n = values.clone().count();
+  if n == 0 { return 0. }
+  let mean = values.clone().sum::<Vector3d>() / n as f64;
+  (values.map(|v| (v - mean).norm_squared()).sum::<f64>() / n as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::SeedableRng;
+
+  #[test]
+  fn test_imu_stationary() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let mut detector = ImuStationary::new(0.5);
+    let gravity = Vector3d::new(0., 0., 9.81);
+    let mut noise = |std: f64| Vector3d::new(rng.gen(), rng.gen(), rng.gen()).map(|x: f64| std * (2. * x - 1.));
+    let mut states = vec![];
+    // At rest for a second, shaken for half a second, and at rest again.
+    for i in 0..250 {
+      let time = 0.01 * i as f64;
+      let (g, a) = if (100..150).contains(&i) {
+        (noise(0.5), gravity + noise(2.))
+      } else {
+        (noise(0.002), gravity + noise(0.01))
+      };
+      detector.add(time, g, a);
+      states.push(detector.check());
+    }
+    // The window must be full and free of the shaking.
+    assert!(states[..49].iter().all(|x| !x));
+    assert!(states[50..100].iter().all(|x| *x));
+    assert!(states[100..199].iter().all(|x| !x));
+    assert!(states[200..].iter().all(|x| *x));
+    assert_eq!(detector.update_count, 50);
+    assert!((detector.mean_accelerometer() - gravity).norm() < 0.01);
+
+    detector.clear();
+    detector.add(3., Vector3d::zeros(), gravity);
+    assert!(!detector.check());
+  }
+}
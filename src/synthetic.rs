@@ -0,0 +1,202 @@
+// Synthetic data for tests.
+
+use crate::all::*;
+
+use rand::SeedableRng;
+
+pub fn test_parameters_guard() -> std::sync::MutexGuard<'static, ()> {
+  TEST_PARAMETERS_LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+lazy_static! {
+  static ref TEST_PARAMETERS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// Sets the global parameters to the command line defaults modified by `f`.
+// Hold the returned guard during the test so that tests using the
+// parameters do not run concurrently.
+pub fn set_test_parameters<F: FnOnce(&mut ParameterSet)>(f: F) -> std::sync::MutexGuard<'static, ()> {
+  use clap::Parser;
+  let guard = test_parameters_guard();
+  let mut p = ParameterSet::parse_from(["violet"]);
+  f(&mut p);
+  *PARAMETER_SET.lock().unwrap() = p;
+  guard
+}
+
+pub const TEST_FOCAL_LENGTH: f64 = 100.;
+pub const TEST_BASELINE: f64 = 0.1;
+
+// Stereo pair of undistorted pinhole cameras looking forward along the IMU z-axis.
+pub fn test_cameras(width: usize, height: usize) -> Vec<Camera> {
+  let K = Matrix3d::new(
+    TEST_FOCAL_LENGTH, 0., 0.5 * (width - 1) as f64,
+    0., TEST_FOCAL_LENGTH, 0.5 * (height - 1) as f64,
+    0., 0., 1.,
+  );
+  (0..2).map(|i| {
+    let mut imu_to_camera = Matrix4d::identity();
+    imu_to_camera[(0, 3)] = -TEST_BASELINE * i as f64;
+    Camera {
+      imu_to_camera,
+      shutter: Shutter::Global,
+      model: Box::new(PinholeModel::new(K, vec![])),
+    }
+  }).collect()
+}
+
+// Random blocks of constant intensity, good for detection and tracking.
+pub fn textured_image(width: usize, height: usize, seed: u64) -> Image {
+  let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+  let block = 4;
+  let bw = width.div_ceil(block);
+  let values: Vec<u8> = (0..(bw * height.div_ceil(block))).map(|_| rng.gen()).collect();
+  let mut data = vec![0; width * height];
+  for y in 0..height {
+    for x in 0..width {
+      data[y * width + x] = values[(y / block) * bw + x / block];
+    }
+  }
+  Image { data, width, height }
+}
+
+pub fn black_image(width: usize, height: usize) -> Image {
+  Image { data: vec![0; width * height], width, height }
+}
+
+pub fn process_imu(vio: &mut Vio, time: f64, gyroscope: Vector3d, accelerometer: Vector3d) {
+  vio.process(&InputData {
+    time,
+    sensor: InputDataSensor::Gyroscope(gyroscope),
+  }).unwrap();
+  vio.process(&InputData {
+    time,
+    sensor: InputDataSensor::Accelerometer(accelerometer),
+  }).unwrap();
+}
+
+// Feed IMU samples of a device at rest.
+pub fn process_static_imu(vio: &mut Vio, time: f64) {
+  let gravity = PARAMETER_SET.lock().unwrap().gravity;
+  process_imu(vio, time, Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+}
+
+pub fn process_frame(vio: &mut Vio, time: f64, images: [&Image; 2]) -> bool {
+  vio.process(&InputData {
+    time,
+    sensor: InputDataSensor::Frame(InputFrame { images: images.to_vec() }),
+  }).unwrap()
+}
+
+// Feed ten 100Hz IMU samples of gyroscope and accelerometer readings `imu(time)`
+// starting from `time`, and then a frame. Returns whether the frame was
+// processed.
+pub fn process_imu_and_frame<F: Fn(f64) -> (Vector3d, Vector3d)>(
+  vio: &mut Vio,
+  time: &mut f64,
+  imu: F,
+  images: [&Image; 2],
+) -> bool {
+  for _ in 0..10 {
+    let (gyroscope, accelerometer) = imu(*time);
+    process_imu(vio, *time, gyroscope, accelerometer);
+    *time += 0.01;
+  }
+  process_frame(vio, *time, images)
+}
+
+// As `process_imu_and_frame()` for a device at rest.
+pub fn process_static_frame(vio: &mut Vio, time: &mut f64, images: [&Image; 2]) -> bool {
+  let gravity = PARAMETER_SET.lock().unwrap().gravity;
+  process_imu_and_frame(vio, time, |_| (Vector3d::zeros(), Vector3d::new(0., 0., gravity)), images)
+}
+
+// Normalized coordinates of `landmark` in the two `cameras` of a device at
+// the origin.
+pub fn observe(cameras: &[Camera], landmark: &Vector3d) -> [Vector2d; 2] {
+  [0, 1].map(|j| hnormalize(transform_3d(&cameras[j].imu_to_camera, landmark)).unwrap())
+}
+
+// Normalized coordinates of `landmark` from the camera poses `pose` of one
+// frame, see `KalmanFilter::get_camera_pose_trail()`.
+pub fn observe_from(pose: &[KalmanFilterPose], landmark: &Vector3d) -> [Vector2d; 2] {
+  [0, 1].map(|j| hnormalize(pose[j].R * (landmark - pose[j].p)).unwrap())
+}
+
+// Track with a single point on frame `frame_number`, at the normalized
+// coordinates `normalized` in the two `cameras`.
+pub fn stereo_track(cameras: &[Camera], id: usize, normalized: [Vector2d; 2], frame_number: usize) -> Track {
+  let features = [0, 1].map(|j| Feature {
+    point: cameras[j].project(normalized[j]).unwrap(),
+    id: TrackId(id),
+  });
+  Track::new(features, normalized, TrackerStep(0), frame_number)
+}
+
+// Appends the point of the single point `track` to the track with the same id
+// in `tracks`, or adds it as a new track.
+pub fn extend_tracks(tracks: &mut Vec<Track>, track: Track) {
+  match tracks.iter_mut().find(|t| t.id == track.id) {
+    Some(t) => t.points.push(track.points[0].clone()),
+    None => tracks.push(track),
+  }
+}
+
+// Filter with a trail of `len` poses, the `k`th newest at `step * k` along the
+// x-axis with the initial orientation.
+pub fn sideways_filter(len: usize, step: f64) -> KalmanFilter {
+  let mut kalman_filter = KalmanFilter::new();
+  for k in 0..len {
+    let (col_pos, col_ori) = (kalman_filter.get_camera_pos_ind(k), kalman_filter.get_camera_ori_ind(k));
+    let x = kalman_filter.get_state_mut();
+    x.fixed_rows_mut::<3>(col_pos).copy_from(&Vector3d::new(step * k as f64, 0., 0.));
+    x.fixed_rows_mut::<4>(col_ori).copy_from(&Vector4d::new(1., 0., 0., 0.));
+  }
+  kalman_filter
+}
+
+// Track of `landmark` seen from the `len` poses of the trail of
+// `kalman_filter`, on frames 1 to `len` from the oldest pose to the newest.
+// `modify(frame_number, normalized)` may change the exact observations.
+pub fn pose_trail_track<F: FnMut(usize, &mut [Vector2d; 2])>(
+  kalman_filter: &KalmanFilter,
+  cameras: &[Camera],
+  id: usize,
+  landmark: &Vector3d,
+  len: usize,
+  mut modify: F,
+) -> Track {
+  let mut poses = vec![];
+  let mut tracks = vec![];
+  for frame_number in 1..=len {
+    kalman_filter.get_camera_pose_trail(&[len - frame_number], &[&cameras[0], &cameras[1]], &mut poses);
+    let mut normalized = observe_from(&poses[0], landmark);
+    modify(frame_number, &mut normalized);
+    extend_tracks(&mut tracks, stereo_track(cameras, id, normalized, frame_number));
+  }
+  tracks.pop().unwrap()
+}
+
+// Frame numbers of the pose trail of a filter driven like `Vio` does. Call at
+// the start of a frame, before the visual update.
+pub fn start_pose_trail_frame(pose_trail_frame_numbers: &mut VecDeque<usize>, frame_number: usize) {
+  pose_trail_frame_numbers.pop_back();
+  pose_trail_frame_numbers.push_back(frame_number);
+}
+
+// Call after the visual update of a frame to add the pose of the next one.
+pub fn augment_pose_trail(kalman_filter: &mut KalmanFilter, pose_trail_frame_numbers: &mut VecDeque<usize>) {
+  let pose_trail_len = PARAMETER_SET.lock().unwrap().pose_trail_len;
+  kalman_filter.augment_pose();
+  pose_trail_frame_numbers.push_back(*pose_trail_frame_numbers.back().unwrap());
+  while pose_trail_frame_numbers.len() > pose_trail_len {
+    pose_trail_frame_numbers.pop_front();
+  }
+}
+
+// Sample of the standard normal distribution, by the Box-Muller transform.
+pub fn standard_normal(rng: &mut Xoshiro256PlusPlus) -> f64 {
+  let u1: f64 = 1. - rng.gen::<f64>();
+  let u2: f64 = rng.gen();
+  (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+}
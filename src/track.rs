@@ -9,16 +9,23 @@ pub struct Feature {
   pub id: TrackId,
 }
 
+// A feature followed over the frames. Tracks start from detections in the
+// first camera and are matched to the second camera on every frame, so a
+// landmark seen by both cameras forms a single track. With
+// `independent_cameras` the tracks stay in the camera they were detected in.
 #[derive(Clone, Debug)]
 pub struct Track {
   pub points: Vec<TrackPoint>,
   pub id: TrackId,
   pub last_seen: TrackerStep,
+  // The only camera of a monocular track, whose points then have the same
+  // coordinates at both camera indices. None for stereo tracks.
+  pub camera: Option<usize>,
 }
 
 #[derive(Clone, Debug)]
 pub struct TrackPoint {
-  // Pixels.
+  // Pixels, indexed by camera.
   pub coordinates: [Vector2d; 2],
   pub normalized_coordinates: [Vector2d; 2],
   pub frame_number: usize,
@@ -40,6 +47,27 @@ impl Track {
       }],
       id: features[0].id,
       last_seen,
+      camera: None,
     }
   }
+
+  // Cameras the track is observed in.
+  pub fn cameras(&self) -> &'static [usize] {
+    match self.camera {
+      None => &[0, 1],
+      Some(0) => &[0],
+      Some(_) => &[1],
+    }
+  }
+
+  // Latest pixel coordinates in both cameras.
+  #[allow(dead_code)]
+  pub fn position(&self) -> [Vector2d; 2] {
+    self.points.iter().last().unwrap().coordinates
+  }
+
+  // Number of frames since the track was started on frame `frame_number`.
+  pub fn age(&self, frame_number: usize) -> usize {
+    frame_number.saturating_sub(self.points[0].frame_number)
+  }
 }
@@ -1,5 +1,10 @@
 use crate::all::*;
 
+use rand::SeedableRng;
+
+// `texture_energy()` at and above which `adaptive_tracks` uses `max_tracks`.
+const FULL_BUDGET_TEXTURE_ENERGY: f64 = 400.;
+
 // Could this be replaced with frame numbers?
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct TrackerStep(pub usize);
@@ -9,30 +14,94 @@ pub struct Tracker {
   optical_flow: OpticalFlow,
   tracks: Vec<Track>,
   max_tracks: usize,
+  // Lower bound of the track count for `adaptive_tracks`, if enabled.
+  min_tracks: Option<usize>,
+  // Number of features detected on the first frame.
+  bootstrap_tracks: usize,
+  // Number of frames lost tracks are kept.
+  lost_track_grace: usize,
+  // See `max_track_length`, zero if unlimited.
+  max_track_length: usize,
+  // See `detection_exclusion_radius`. The zone is set on the first frame,
+  // where the cameras are known.
+  exclusion: Option<ExclusionZone>,
+  exclusion_center: Option<Vector2d>,
+  exclusion_radius: f64,
+  // See `detection_mask`, applied in all the cameras.
+  detection_mask: Option<DetectionMask>,
+  // See `independent_cameras`.
+  independent_cameras: bool,
+  // See `lk_intensity_compensation`.
+  intensity_compensation: bool,
+  // See `flow_ransac_max_error`, zero if not used.
+  flow_ransac_max_error: f64,
+  rng: Xoshiro256PlusPlus,
   next_id: TrackId,
   step: TrackerStep,
+  // Features detected on the last frame, in all the cameras.
+  detected_count: usize,
+  // Tracks ended by `max_track_length` and tracks started on the last frame.
+  retired_count: usize,
+  new_track_count: usize,
   // Workspace.
   features0: Vec<Feature>,
   features1: Vec<Feature>,
   features2: Vec<Feature>,
+  features3: Vec<Feature>,
 }
 
 impl Tracker {
   pub fn new() -> Result<Tracker> {
-    let max_tracks = {
+    let max_track_length = PARAMETER_SET.lock().unwrap().max_track_length;
+    let (flow_ransac_max_error, seed) = {
+      let p = PARAMETER_SET.lock().unwrap();
+      (p.flow_ransac_max_error, p.seed)
+    };
+    let (max_tracks, min_tracks, bootstrap_tracks, lost_track_grace, exclusion_center, exclusion_radius, independent_cameras, intensity_compensation) = {
       let p = PARAMETER_SET.lock().unwrap();
-      p.max_tracks
+      let min_tracks = if p.adaptive_tracks { Some(p.min_tracks.min(p.max_tracks)) } else { None };
+      let exclusion_center = match (p.detection_exclusion_x, p.detection_exclusion_y) {
+        (Some(x), Some(y)) => Some(Vector2d::new(x, y)),
+        (None, None) => None,
+        _ => bail!("Give both or neither of `detection_exclusion_x` and `detection_exclusion_y`."),
+      };
+      (
+        p.max_tracks,
+        min_tracks,
+        p.bootstrap_tracks.unwrap_or(p.max_tracks).min(p.max_tracks),
+        p.lost_track_grace,
+        exclusion_center,
+        p.detection_exclusion_radius,
+        p.independent_cameras,
+        p.lk_intensity_compensation,
+      )
     };
     Ok(Tracker {
       detector: Detector::new(),
       optical_flow: OpticalFlow::new()?,
       tracks: vec![],
       max_tracks,
+      min_tracks,
+      bootstrap_tracks,
+      lost_track_grace,
+      max_track_length,
+      exclusion: None,
+      exclusion_center,
+      exclusion_radius,
+      detection_mask: None,
+      independent_cameras,
+      intensity_compensation,
+      flow_ransac_max_error,
+      rng: Xoshiro256PlusPlus::seed_from_u64(seed),
       next_id: TrackId(0),
       step: TrackerStep(0),
+      detected_count: 0,
+      retired_count: 0,
+      new_track_count: 0,
       features0: vec![],
       features1: vec![],
       features2: vec![],
+      features3: vec![],
     })
   }
 
@@ -40,34 +109,81 @@ impl Tracker {
     &self.tracks
   }
 
+  // Number of tracks to maintain on the image. More texture supports more
+  // good features.
+  pub fn feature_budget(&self, image: &Image) -> usize {
+    let Some(min_tracks) = self.min_tracks else { return self.max_tracks };
+    let t = (texture_energy(image) / FULL_BUDGET_TEXTURE_ENERGY).min(1.);
+    min_tracks + (t * (self.max_tracks - min_tracks) as f64).round() as usize
+  }
+
+  // Remove all tracks. New ones are detected on the next frame. The IDs of
+  // the removed tracks are not reused.
+  pub fn clear_tracks(&mut self) {
+    self.tracks.clear();
+  }
+
+  pub fn set_detection_mask(&mut self, detection_mask: Option<DetectionMask>) {
+    self.detector.set_detection_mask(detection_mask.clone());
+    self.detection_mask = detection_mask;
+  }
+
+  pub fn get_detected_count(&self) -> usize {
+    self.detected_count
+  }
+
+  pub fn get_retired_count(&self) -> usize {
+    self.retired_count
+  }
+
+  pub fn get_new_track_count(&self) -> usize {
+    self.new_track_count
+  }
+
+  // True if the last processed frame was the first one, where features were
+  // only detected and none were tracked.
+  pub fn is_bootstrap_frame(&self) -> bool {
+    self.step.0 == 1
+  }
+
+  // `frames` are the latest frames in memory, the current one last.
+  // `rotations[i]` is the first camera rotation from `i` frames ago to the
+  // current frame, if known.
   pub fn process(
     &mut self,
-    frame0: Option<&Frame>,
-    frame1: &Frame,
+    frames: &[Frame],
     cameras: &[Camera],
     frame_number: usize,
+    rotations: &[Matrix3d],
   ) {
-    if let Some(frame0) = frame0 {
-      self.features1.clear();
-      for track in &self.tracks {
-        if track.last_seen.0 + 1 == self.step.0 {
-          self.features1.push(Feature {
-            point: track.points.iter().last().unwrap().coordinates[0],
-            id: track.id,
-          });
-        }
-      }
-
-      self.optical_flow.process(
-        OpticalFlowKind::LeftPreviousToCurrent,
-        &frame0.cameras[0],
-        &frame1.cameras[0],
-        &[&cameras[0], &cameras[0]],
-        &self.features1,
-        &mut self.features2,
-        &mut self.features0,
-      );
+    let frame1 = frames.last().unwrap();
+    self.detected_count = 0;
+    self.retired_count = 0;
+    if PARAMETER_SET.lock().unwrap().show_flow_rejected {
+      DEBUG_DATA.lock().unwrap().flow_rejected.clear();
+    }
+    if self.step.0 == 0 {
+      self.bootstrap(frame1, cameras, frame_number);
+      return;
+    }
+    if !invariant(frames.len() >= 2, "Previous frame is in memory after the first frame.") { return }
 
+    let mut tracked = [0; 2];
+    for c in self.source_cameras(cameras) {
+      self.track_previous(frames, cameras, c, rotations);
+      if self.monocular_tracks(cameras) {
+        update_tracks(
+          &mut self.tracks,
+          [&self.features0, &self.features0],
+          [&cameras[c], &cameras[c]],
+          Some(c),
+          false,
+          self.step,
+          frame_number,
+        );
+        tracked[c] = self.features0.len();
+        continue;
+      }
       self.optical_flow.process(
         OpticalFlowKind::LeftCurrentToRightCurrent,
         &frame1.cameras[0],
@@ -76,30 +192,198 @@ impl Tracker {
         &self.features0,
         &mut self.features1,
         &mut self.features2,
+        None,
       );
+      remove_masked_matches(self.detection_mask.as_ref(), [&mut self.features1, &mut self.features2]);
       update_tracks(
         &mut self.tracks,
         [&self.features1, &self.features2],
         [&cameras[0], &cameras[1]],
+        None,
         false,
         self.step,
         frame_number,
       );
+      tracked[c] = self.features2.len();
+    }
+    // Only once all the cameras have been tracked.
+    remove_lost_tracks(&mut self.tracks, self.step, self.lost_track_grace);
+    // The retired tracks leave room for the detection below, which may start
+    // new tracks at the same features.
+    if self.max_track_length > 0 {
+      let max_track_length = self.max_track_length;
+      let step = self.step;
+      let count = self.tracks.len();
+      self.tracks.retain(|track| {
+        if track.points.len() < max_track_length { return true }
+        if track.last_seen == step {
+          let c = track.camera.unwrap_or(0);
+          tracked[c] = tracked[c].saturating_sub(1);
+        }
+        false
+      });
+      self.retired_count = count - self.tracks.len();
     }
 
     // TODO Make this adaptive.
     let min_distance = 5.0;
-    sparsify_tracks(&mut self.tracks, min_distance);
+    sparsify_tracks(&mut self.tracks, min_distance, self.step);
 
-    assert!(self.features2.len() <= self.max_tracks);
-    let needed_features_count = self.max_tracks - self.features2.len();
+    let track_count = self.tracks.len();
+    for c in self.source_cameras(cameras) {
+      if !invariant(tracked[c] <= self.max_tracks, "Track count is at most maximum.") { continue }
+      let budget = self.feature_budget(&frame1.cameras[c].image);
+      let needed_features_count = budget.saturating_sub(tracked[c]);
+      self.detect(frame1, cameras, c, frame_number, needed_features_count);
+    }
+    self.new_track_count = self.tracks.len() - track_count;
+    self.step.0 += 1
+  }
 
+  // Cameras that tracks are detected and followed over time in. The second
+  // camera only gets stereo matches unless `independent_cameras` is set.
+  fn source_cameras(&self, cameras: &[Camera]) -> std::ops::Range<usize> {
+    0..if self.independent_cameras { cameras.len() } else { 1 }
+  }
+
+  // True if the tracks stay in the camera they were detected in, also when
+  // there is only one camera.
+  fn monocular_tracks(&self, cameras: &[Camera]) -> bool {
+    self.independent_cameras || cameras.len() == 1
+  }
+
+  // Track the features of camera `c` from the frame where each track was last
+  // seen into `features0`. Lost tracks are predicted to the current frame
+  // with the rotation.
+  fn track_previous(&mut self, frames: &[Frame], cameras: &[Camera], c: usize, rotations: &[Matrix3d]) {
+    let frame1 = frames.last().unwrap();
+    // The rotations are given for the first camera.
+    let to_camera = rotation!(cameras[c].imu_to_camera) * rotation!(cameras[0].imu_to_camera).transpose();
+    self.features0.clear();
+    for age in 1..=(self.lost_track_grace + 1) {
+      if age >= frames.len() { break }
+      let frame0 = &frames[frames.len() - 1 - age];
+      self.features1.clear();
+      for track in &self.tracks {
+        if track.last_seen.0 + age == self.step.0 && track.camera.unwrap_or(0) == c {
+          self.features1.push(Feature {
+            point: track.points.iter().last().unwrap().coordinates[c],
+            id: track.id,
+          });
+        }
+      }
+      if self.features1.is_empty() { continue }
+
+      let camera_rotation = rotations.get(age).map(|r| to_camera * r * to_camera.transpose());
+      let rotation = if age > 1 { camera_rotation } else { None };
+      if self.intensity_compensation {
+        // The windows around the previous points, which have moved little.
+        let pairs: Vec<_> = self.features1.iter().map(|f| (f.point, f.point)).collect();
+        let (gain, bias) = estimate_intensity_gain(&frame0.cameras[c].image, &frame1.cameras[c].image, &pairs);
+        self.optical_flow.set_intensity_gain(gain as f64, bias as f64);
+      }
+      self.optical_flow.process(
+        OpticalFlowKind::LeftPreviousToCurrent,
+        &frame0.cameras[c],
+        &frame1.cameras[c],
+        &[&cameras[c], &cameras[c]],
+        &self.features1,
+        &mut self.features2,
+        &mut self.features3,
+        rotation.as_ref(),
+      );
+      self.optical_flow.set_intensity_gain(1., 0.);
+      let rejected = self.optical_flow.get_backward_rejected();
+      if !rejected.is_empty() && PARAMETER_SET.lock().unwrap().show_flow_rejected {
+        let d = &mut DEBUG_DATA.lock().unwrap();
+        d.flow_rejected.extend(rejected.iter().map(|(f0, f1)| (c, f0.point, f1.point)));
+      }
+      if let (true, Some(camera_rotation)) = (self.flow_ransac_max_error > 0., camera_rotation) {
+        self.reject_flow_outliers(&cameras[c], c, &camera_rotation);
+      }
+      self.features0.extend(self.features3.iter());
+    }
+    if let (0, Some(exclusion)) = (c, self.exclusion) {
+      self.features0.retain(|f| !exclusion.contains(f.point));
+    }
+    if let Some(detection_mask) = &self.detection_mask {
+      self.features0.retain(|f| !detection_mask.contains(f.point));
+    }
+  }
+
+  // Removes the correspondences of `features2` and `features3` that do not
+  // fit the rotation of camera `c` and a common translation, see
+  // `flow_ransac_max_error`.
+  fn reject_flow_outliers(&mut self, camera: &Camera, c: usize, rotation: &Matrix3d) {
+    let Some(pixels_per_radian) = pixels_per_radian(camera) else { return };
+    let mut flow = vec![];
+    let mut indices = vec![];
+    for (i, (f0, f1)) in self.features2.iter().zip(&self.features3).enumerate() {
+      // Those without rays are dropped by `update_tracks()`.
+      let (Some(r0), Some(r1)) = (camera.model.pixel_to_ray(f0.point), camera.model.pixel_to_ray(f1.point)) else { continue };
+      flow.push((r0, r1));
+      indices.push(i);
+    }
+    let max_angle = self.flow_ransac_max_error / pixels_per_radian;
+    let inliers = ransac_flow(&flow, rotation, max_angle, &mut self.rng);
+    let mut keep = vec![true; self.features3.len()];
+    for (i, inlier) in indices.iter().zip(inliers) {
+      keep[*i] = inlier;
+    }
+    if PARAMETER_SET.lock().unwrap().show_flow_rejected {
+      let d = &mut DEBUG_DATA.lock().unwrap();
+      d.flow_rejected.extend(self.features2.iter().zip(&self.features3).zip(&keep)
+        .filter(|(_, keep)| !**keep)
+        .map(|((f0, f1), _)| (c, f0.point, f1.point)));
+    }
+    (self.features2, self.features3) = self.features2.iter().zip(&self.features3).zip(&keep)
+      .filter(|(_, keep)| **keep)
+      .map(|((f0, f1), _)| (*f0, *f1))
+      .unzip();
+  }
+
+  // The first frame has no tracks to follow, so only detect new features.
+  fn bootstrap(&mut self, frame: &Frame, cameras: &[Camera], frame_number: usize) {
+    if !invariant(self.tracks.is_empty(), "No tracks before the first frame.") {
+      self.tracks.clear();
+    }
+    if self.exclusion_radius > 0. {
+      let center = self.exclusion_center.or_else(|| cameras[0].project(Vector2d::zeros()));
+      self.exclusion = center.map(|center| ExclusionZone { center, radius: self.exclusion_radius });
+    }
+    for c in self.source_cameras(cameras) {
+      let budget = self.feature_budget(&frame.cameras[c].image);
+      self.detect(frame, cameras, c, frame_number, self.bootstrap_tracks.min(budget));
+    }
+    self.new_track_count = self.tracks.len();
+    info!("Bootstrapped tracking with {} features.", self.tracks.len());
+    self.step.0 += 1
+  }
+
+  // Detect features in camera `c` and start tracks from them. Stereo tracks
+  // start from the features of the first camera that match to the second one.
+  fn detect(&mut self, frame1: &Frame, cameras: &[Camera], c: usize, frame_number: usize, needed_features_count: usize) {
+    // The exclusion zone is in the first camera.
+    self.detector.set_exclusion(if c == 0 { self.exclusion } else { None });
     self.detector.process(
-      &frame1.cameras[0].image,
+      &frame1.cameras[c],
       &mut self.features0,
       needed_features_count,
       &mut self.next_id
     );
+    self.detected_count += self.features0.len();
+    if self.monocular_tracks(cameras) {
+      update_tracks(
+        &mut self.tracks,
+        [&self.features0, &self.features0],
+        [&cameras[c], &cameras[c]],
+        Some(c),
+        true,
+        self.step,
+        frame_number,
+      );
+      return;
+    }
     self.optical_flow.process(
       OpticalFlowKind::LeftCurrentToRightCurrentDetection,
       &frame1.cameras[0],
@@ -108,24 +392,40 @@ impl Tracker {
       &self.features0,
       &mut self.features1,
       &mut self.features2,
+      None,
     );
+    remove_masked_matches(self.detection_mask.as_ref(), [&mut self.features1, &mut self.features2]);
     update_tracks(
       &mut self.tracks,
       [&self.features1, &self.features2],
       [&cameras[0], &cameras[1]],
+      None,
       true,
       self.step,
       frame_number,
     );
-
-    self.step.0 += 1
   }
 }
 
+// Removes the stereo matches whose second camera feature is in the masked
+// region. The first camera features are already outside it.
+fn remove_masked_matches(detection_mask: Option<&DetectionMask>, [features0, features1]: [&mut Vec<Feature>; 2]) {
+  let Some(detection_mask) = detection_mask else { return };
+  let mut i = 0;
+  features0.retain(|_| {
+    i += 1;
+    !detection_mask.contains(features1[i - 1].point)
+  });
+  features1.retain(|f| !detection_mask.contains(f.point));
+}
+
+// `features` are the matching features in both cameras of the tracks, the
+// same ones twice for the monocular tracks of `camera`.
 fn update_tracks(
   tracks: &mut Vec<Track>,
   features: [&[Feature]; 2],
   cameras: [&Camera; 2],
+  camera: Option<usize>,
   new_tracks: bool,
   step: TrackerStep,
   frame_number: usize,
@@ -136,12 +436,14 @@ fn update_tracks(
     // may require existence of normalized coordinates so it's easiest to
     // guarantee them already here.
     let normalized_coordinates = [
-      hnormalize(cameras[0].model.pixel_to_ray(features[0].point)?)?,
-      hnormalize(cameras[1].model.pixel_to_ray(features[1].point)?)?,
+      cameras[0].unproject(features[0].point)?,
+      cameras[1].unproject(features[1].point)?,
     ];
     assert_eq!(features[0].id, features[1].id);
     if new_tracks {
-      tracks.push(Track::new(features, normalized_coordinates, step, frame_number));
+      let mut track = Track::new(features, normalized_coordinates, step, frame_number);
+      track.camera = camera;
+      tracks.push(track);
     }
     else {
       for track in tracks.iter_mut() {
@@ -164,34 +466,45 @@ fn update_tracks(
     update_track([*feature0, *feature1]);
   }
 
-  // Remove tracks that could not be tracked, even if some may not have been
-  // used for visual updates yet. Fresh tracks are always needed to reliably
-  // estimate the current pose.
+  let d = &mut DEBUG_DATA.lock().unwrap();
+  let p = PARAMETER_SET.lock().unwrap();
+  if p.show_tracks {
+    d.tracks.clear();
+    d.tracks.extend(tracks.iter().cloned());
+  }
+  if p.show_trails {
+    update_trails(tracks, p.trail_len, &mut d.trails);
+  }
+}
+
+// Remove tracks that could not be tracked within the grace period, even if
+// some may not have been used for visual updates yet. Fresh tracks are
+// always needed to reliably estimate the current pose.
+fn remove_lost_tracks(tracks: &mut Vec<Track>, step: TrackerStep, lost_track_grace: usize) {
   let mut i = 0;
   while i < tracks.len() {
-    if tracks[i].last_seen.0 == step.0 {
+    if tracks[i].last_seen.0 + lost_track_grace >= step.0 {
       i += 1;
       continue;
     }
     tracks.swap_remove(i);
   }
-  let d = &mut DEBUG_DATA.lock().unwrap();
-  let p = PARAMETER_SET.lock().unwrap();
-  if p.show_tracks {
-    d.tracks.clear();
-    d.tracks.extend(tracks.iter().cloned());
-  }
 }
 
+// Only considers the tracks seen on `step`, lost tracks are left alone.
 fn sparsify_tracks(
   tracks: &mut Vec<Track>,
   min_distance: f64,
+  step: TrackerStep,
 ) {
   let d2 = min_distance.powi(2);
   for i0 in 0..tracks.len() {
     for i1 in (i0 + 1)..tracks.len() {
       if tracks[i0].points.is_empty() { continue }
       if tracks[i1].points.is_empty() { continue }
+      if tracks[i0].last_seen != step || tracks[i1].last_seen != step { continue }
+      // Monocular tracks of different cameras are unrelated.
+      if tracks[i0].camera != tracks[i1].camera { continue }
       let p0 = &tracks[i0].points.iter().last().unwrap().coordinates;
       let p1 = &tracks[i1].points.iter().last().unwrap().coordinates;
       for k in 0..2 {
@@ -217,3 +530,479 @@ fn sparsify_tracks(
     tracks.swap_remove(i);
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Tracks from the first frame that are alive after an occluded frame.
+  fn survivors(lost_track_grace: usize) -> Vec<Track> {
+    let (width, height) = (160, 120);
+    let textured = textured_image(width, height, 1);
+    survivors_rotated(lost_track_grace, &textured, &[])
+  }
+
+  // As `survivors()`, but the last frame shows `last_image` and the camera
+  // `rotations` are given on each frame.
+  fn survivors_rotated(lost_track_grace: usize, last_image: &Image, rotations: &[Matrix3d]) -> Vec<Track> {
+    let _guard = set_test_parameters(|p| p.lost_track_grace = lost_track_grace);
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    let textured = textured_image(width, height, 1);
+    let black = black_image(width, height);
+    let mut tracker = Tracker::new().unwrap();
+    let mut frames = vec![];
+    for (frame_number, image) in [&textured, &textured, &black, last_image].iter().enumerate() {
+      frames.push(Frame::new(&InputFrame { images: vec![image, image] }, None).unwrap());
+      tracker.process(&frames, &cameras, frame_number, rotations);
+    }
+    tracker.get_tracks().iter()
+      .filter(|track| track.points[0].frame_number == 0)
+      .cloned()
+      .collect()
+  }
+
+  #[test]
+  fn test_invariant_production() {
+    let _guard = set_test_parameters(|p| p.assertion_policy = AssertionPolicy::Production);
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    let textured = textured_image(width, height, 1);
+    let frames = vec![Frame::new(&InputFrame { images: vec![&textured, &textured] }, None).unwrap()];
+    let mut tracker = Tracker::new().unwrap();
+    tracker.process(&frames, &cameras, 0, &[]);
+    let tracks = tracker.get_tracks().clone();
+    assert!(!tracks.is_empty());
+    // Without the previous frame the frame is skipped instead of panicking.
+    tracker.process(&frames, &cameras, 1, &[]);
+    assert_eq!(tracker.get_tracks().len(), tracks.len());
+    assert!(tracker.get_tracks().iter().all(|track| track.points.len() == 1));
+  }
+
+  #[test]
+  fn test_lost_track_grace() {
+    assert!(survivors(0).is_empty());
+
+    let tracks = survivors(1);
+    assert!(!tracks.is_empty());
+    for track in &tracks {
+      let frame_numbers: Vec<usize> = track.points.iter().map(|p| p.frame_number).collect();
+      assert_eq!(frame_numbers, vec![0, 1, 3]);
+      assert_eq!(track.last_seen, TrackerStep(3));
+    }
+  }
+
+  #[test]
+  fn test_lost_track_rotation() {
+    let (width, height) = (160, 120);
+    let camera = &test_cameras(width, height)[0];
+    let textured = textured_image(width, height, 1);
+    // Render the textured plane after a rotation of the camera, so that the
+    // image content moves by roughly 30 pixels.
+    let rotation = nalgebra::Rotation3::from_axis_angle(&Vector3d::y_axis(), 0.3).into_inner();
+    let mut rotated = black_image(width, height);
+    for y in 0..height {
+      for x in 0..width {
+        let ray = camera.model.pixel_to_ray(Vector2d::new(x as f64, y as f64)).unwrap();
+        let Some(p) = camera.model.ray_to_pixel(rotation.transpose() * ray) else { continue };
+        let (x0, y0) = (p[0].round(), p[1].round());
+        if x0 < 0. || y0 < 0. || x0 >= width as f64 || y0 >= height as f64 { continue }
+        rotated.data[y * width + x] = textured.data[y0 as usize * width + x0 as usize];
+      }
+    }
+    // Tracks that ended up where the rotation moves their frame 1 point.
+    let moved = |tracks: &[Track]| -> usize {
+      tracks.iter().filter(|track| {
+        let p1 = track.points[1].coordinates[0];
+        let p3 = track.points.last().unwrap().coordinates[0];
+        let expected = camera.model.ray_to_pixel(rotation * camera.model.pixel_to_ray(p1).unwrap()).unwrap();
+        track.last_seen == TrackerStep(3) && (p3 - expected).norm() < 1.
+      }).count()
+    };
+
+    // `rotations[2]` is the rotation from the frame where the tracks were last
+    // seen to the current frame.
+    let rotations = [Matrix3d::identity(), Matrix3d::identity(), rotation];
+    let predicted = moved(&survivors_rotated(1, &rotated, &rotations));
+    let unpredicted = moved(&survivors_rotated(1, &rotated, &[]));
+    assert!(predicted >= 5);
+    assert!(predicted > unpredicted);
+  }
+
+  // IDs of the tracks of the first frame, the numbers of retired and new
+  // tracks on each frame, and the tracks at the end of a static clip.
+  fn static_clip(max_track_length: usize) -> (Vec<TrackId>, Vec<(usize, usize)>, Vec<Track>) {
+    let _guard = set_test_parameters(|p| {
+      p.max_track_length = max_track_length;
+      p.max_tracks = 50;
+    });
+    let (width, height) = (160, 120);
+    // Monocular, so that every detection starts a track.
+    let cameras = vec![test_cameras(width, height).remove(0)];
+    let textured = textured_image(width, height, 1);
+    let mut tracker = Tracker::new().unwrap();
+    let mut frames = vec![];
+    let mut ids = vec![];
+    let mut counts = vec![];
+    for frame_number in 0..30 {
+      if frames.len() > 2 { frames.remove(0); }
+      frames.push(Frame::new(&InputFrame { images: vec![&textured] }, None).unwrap());
+      tracker.process(&frames, &cameras, frame_number, &[]);
+      counts.push((tracker.get_retired_count(), tracker.get_new_track_count()));
+      if frame_number == 0 {
+        ids = tracker.get_tracks().iter().map(|track| track.id).collect();
+      }
+    }
+    (ids, counts, tracker.get_tracks().clone())
+  }
+
+  #[test]
+  fn test_max_track_length() {
+    // Without a limit some tracks of the first frame last the whole clip.
+    let (first_ids, counts, tracks) = static_clip(0);
+    assert!(counts.iter().all(|(retired, _)| *retired == 0));
+    let kept: Vec<_> = tracks.iter().filter(|track| first_ids.contains(&track.id)).collect();
+    assert!(!kept.is_empty());
+    assert!(kept.iter().all(|track| track.points.len() == 30));
+    let track_count = tracks.len();
+
+    // With the limit they turn over, and new tracks take their place.
+    let (first_ids, counts, tracks) = static_clip(5);
+    assert!(tracks.iter().all(|track| track.points.len() < 5));
+    assert!(tracks.iter().all(|track| !first_ids.contains(&track.id)));
+    assert!(tracks.len() >= track_count - 5);
+    assert!(counts[..4].iter().all(|(retired, _)| *retired == 0));
+    let retired: usize = counts.iter().map(|(retired, _)| retired).sum();
+    assert!(retired >= 5 * kept.len());
+    assert!(counts[4..].iter().all(|(retired, new)| new >= retired));
+  }
+
+  // Counts over the frames of the tracks followed from earlier frames, and
+  // of those on a patch that moves down across a background that moves right.
+  fn moving_patch_clip(flow_ransac_max_error: f64) -> (usize, usize) {
+    let _guard = set_test_parameters(|p| {
+      p.flow_ransac_max_error = flow_ransac_max_error;
+      p.detection_threshold = 16;
+    });
+    let (width, height) = (160, 120);
+    let cameras = vec![test_cameras(width, height).remove(0)];
+    let background = textured_image(width + 60, height, 1);
+    let patch = textured_image(40, 40, 2);
+    let mut tracker = Tracker::new().unwrap();
+    let mut frames = vec![];
+    let patch_corner = |frame_number: usize| (60, 10 + 4 * frame_number);
+    let mut counts = (0, 0);
+    for frame_number in 0..8 {
+      let offset = 50 - 4 * frame_number;
+      let mut image = Image {
+        data: (0..height).flat_map(|y| {
+          let row = y * background.width + offset;
+          background.data[row..(row + width)].to_vec()
+        }).collect(),
+        width,
+        height,
+      };
+      let (x, y) = patch_corner(frame_number);
+      image.set_sub_image(x, y, &patch);
+      if frames.len() > 2 { frames.remove(0); }
+      frames.push(Frame::new(&InputFrame { images: vec![&image] }, None).unwrap());
+      // The camera does not rotate.
+      tracker.process(&frames, &cameras, frame_number, &[Matrix3d::identity(); 3]);
+
+      let on_patch = |p: Vector2d| {
+        let margin = 5.;
+        p[0] > x as f64 + margin && p[0] < (x + patch.width) as f64 - margin
+          && p[1] > y as f64 + margin && p[1] < (y + patch.height) as f64 - margin
+      };
+      let tracked: Vec<_> = tracker.get_tracks().iter()
+        .filter(|track| track.last_seen == TrackerStep(frame_number) && track.points.len() > 1)
+        .collect();
+      counts.0 += tracked.len();
+      counts.1 += tracked.iter()
+        .filter(|track| on_patch(track.points.iter().last().unwrap().coordinates[0]))
+        .count();
+    }
+    counts
+  }
+
+  #[test]
+  fn test_flow_ransac() {
+    let (tracked, on_patch) = moving_patch_clip(0.);
+    assert!(on_patch > 10, "{} {}", tracked, on_patch);
+    let (tracked_ransac, on_patch) = moving_patch_clip(1.);
+    assert_eq!(on_patch, 0);
+    assert!(tracked_ransac > tracked / 2, "{} {}", tracked, tracked_ransac);
+  }
+
+  // Number of tracks followed from earlier frames, summed over the frames of
+  // a camera that translates without rotating and keeps only a few tracks.
+  fn few_tracks_followed(flow_ransac_max_error: f64) -> usize {
+    let _guard = set_test_parameters(|p| {
+      p.flow_ransac_max_error = flow_ransac_max_error;
+      // One track in each cell of the detection grid.
+      p.max_tracks = 4;
+      p.detection_grid_cols = 2;
+      p.detection_grid_rows = 2;
+      p.detection_threshold = 16;
+      p.lk_start_level = Some(1);
+    });
+    let (width, height) = (320, 240);
+    let cameras = vec![test_cameras(width, height).remove(0)];
+    let background = textured_image(width + 60, height, 1);
+    let mut tracker = Tracker::new().unwrap();
+    let mut frames = vec![];
+    let mut followed = 0;
+    for frame_number in 0..8 {
+      let offset = 50 - 2 * frame_number;
+      let image = Image {
+        data: (0..height).flat_map(|y| {
+          let row = y * background.width + offset;
+          background.data[row..(row + width)].to_vec()
+        }).collect(),
+        width,
+        height,
+      };
+      if frames.len() > 2 { frames.remove(0); }
+      frames.push(Frame::new(&InputFrame { images: vec![&image] }, None).unwrap());
+      tracker.process(&frames, &cameras, frame_number, &[Matrix3d::identity(); 3]);
+      followed += tracker.get_tracks().iter()
+        .filter(|track| track.last_seen == TrackerStep(frame_number) && track.points.len() > 1)
+        .count();
+    }
+    followed
+  }
+
+  #[test]
+  fn test_flow_ransac_few_tracks() {
+    // Too few for a translation hypothesis, but all moving consistently.
+    let followed = few_tracks_followed(0.);
+    assert!(followed > 10, "{}", followed);
+    assert_eq!(few_tracks_followed(0.5), followed);
+  }
+
+  #[test]
+  fn test_feature_budget() {
+    let guard = set_test_parameters(|p| {
+      p.adaptive_tracks = true;
+      p.min_tracks = 50;
+      p.max_tracks = 300;
+    });
+    let (width, height) = (160, 120);
+    let tracker = Tracker::new().unwrap();
+    let textured = textured_image(width, height, 1);
+    // A faint version of the same pattern.
+    let bland = Image {
+      data: textured.data.iter().map(|v| 100 + v / 32).collect(),
+      width,
+      height,
+    };
+    let high = tracker.feature_budget(&textured);
+    let low = tracker.feature_budget(&bland);
+    assert_eq!(high, 300);
+    assert!(low < 100);
+    assert!(low >= 50);
+    assert_eq!(tracker.feature_budget(&black_image(width, height)), 50);
+
+    // Without `adaptive_tracks` the budget is fixed.
+    drop(guard);
+    let _guard = set_test_parameters(|p| p.max_tracks = 300);
+    assert_eq!(Tracker::new().unwrap().feature_budget(&bland), 300);
+  }
+
+  #[test]
+  fn test_detection_exclusion() {
+    let radius = 30.;
+    let _guard = set_test_parameters(|p| {
+      p.detection_exclusion_radius = radius;
+      p.detection_threshold = 16;
+    });
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    let mut tracker = Tracker::new().unwrap();
+    let mut frames = vec![];
+    // Scroll the texture so that tracks move towards the principal point. The
+    // second camera sees it with a disparity of a few pixels.
+    let texture = textured_image(width + 60, height, 1);
+    let crop = |offset: usize| Image {
+      data: (0..height).flat_map(|y| {
+        let row = y * texture.width + offset;
+        texture.data[row..(row + width)].to_vec()
+      }).collect(),
+      width,
+      height,
+    };
+    for frame_number in 0..8 {
+      let images = [crop(45 - 5 * frame_number), crop(50 - 5 * frame_number)];
+      frames.push(Frame::new(&InputFrame { images: vec![&images[0], &images[1]] }, None).unwrap());
+      tracker.process(&frames, &cameras, frame_number, &[]);
+
+      let center = Vector2d::new(0.5 * (width - 1) as f64, 0.5 * (height - 1) as f64);
+      let current: Vec<_> = tracker.get_tracks().iter()
+        .filter(|track| track.last_seen == TrackerStep(frame_number))
+        .collect();
+      assert!(current.len() > 10);
+      for track in current {
+        let point = track.points.iter().last().unwrap().coordinates[0];
+        assert!((point - center).norm() >= radius);
+      }
+    }
+  }
+
+  #[test]
+  fn test_detection_mask() {
+    let _guard = set_test_parameters(|p| p.detection_threshold = 16);
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    let mut tracker = Tracker::new().unwrap();
+    // Mask the right side, where the texture scrolls to.
+    let mut mask = Image { data: vec![255; width * height], width, height };
+    for y in 0..height {
+      for x in 110..width {
+        mask.set_value(x, y, 0);
+      }
+    }
+    tracker.set_detection_mask(Some(DetectionMask::from_image(mask)));
+    let texture = textured_image(width + 60, height, 1);
+    let crop = |offset: usize| Image {
+      data: (0..height).flat_map(|y| {
+        let row = y * texture.width + offset;
+        texture.data[row..(row + width)].to_vec()
+      }).collect(),
+      width,
+      height,
+    };
+    let mut frames = vec![];
+    for frame_number in 0..8 {
+      let images = [crop(45 - 5 * frame_number), crop(50 - 5 * frame_number)];
+      frames.push(Frame::new(&InputFrame { images: vec![&images[0], &images[1]] }, None).unwrap());
+      tracker.process(&frames, &cameras, frame_number, &[]);
+
+      let current: Vec<_> = tracker.get_tracks().iter()
+        .filter(|track| track.last_seen == TrackerStep(frame_number))
+        .collect();
+      assert!(current.len() > 10);
+      for track in current {
+        let coordinates = track.points.iter().last().unwrap().coordinates;
+        assert!(coordinates.iter().all(|p| p[0].round() < 110.), "{:?}", coordinates);
+      }
+    }
+  }
+
+  #[test]
+  fn test_stereo_track() {
+    let _guard = set_test_parameters(|p| p.max_tracks = 1);
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    // A textured patch moving right, seen by both cameras with a disparity of
+    // 5 pixels, which puts it two meters away.
+    let disparity = 5;
+    let texture = textured_image(30, 30, 1);
+    let patch = |x0: usize| {
+      let mut image = black_image(width, height);
+      for y in 0..texture.height {
+        for x in 0..texture.width {
+          image.set_value(x0 + x, 45 + y, texture.value(x, y));
+        }
+      }
+      image
+    };
+    let mut tracker = Tracker::new().unwrap();
+    let mut frames = vec![];
+    for frame_number in 0..5 {
+      let x0 = 60 + 3 * frame_number;
+      let images = [patch(x0), patch(x0 - disparity)];
+      frames.push(Frame::new(&InputFrame { images: vec![&images[0], &images[1]] }, None).unwrap());
+      tracker.process(&frames, &cameras, frame_number, &[]);
+      // Detected on the first frame and followed after that.
+      assert_eq!(tracker.get_detected_count(), if frame_number == 0 { 1 } else { 0 });
+    }
+
+    // The feature forms a single track with an observation from both cameras
+    // on every frame.
+    let tracks = tracker.get_tracks();
+    assert_eq!(tracks.len(), 1);
+    let points = &tracks[0].points;
+    assert_eq!(points.len(), 5);
+    for (frame_number, point) in points.iter().enumerate() {
+      assert_eq!(point.frame_number, frame_number);
+      let motion = point.coordinates[0] - points[0].coordinates[0];
+      assert!((motion - Vector2d::new(3. * frame_number as f64, 0.)).norm() < 0.5);
+      let d = point.coordinates[0] - point.coordinates[1];
+      assert!((d - Vector2d::new(disparity as f64, 0.)).norm() < 0.5);
+    }
+  }
+
+  #[test]
+  fn test_independent_cameras() {
+    let _guard = set_test_parameters(|p| {
+      p.independent_cameras = true;
+      p.max_tracks = 50;
+    });
+    let (width, height) = (160, 120);
+    let cameras = test_cameras(width, height);
+    // Unrelated views, which have no stereo matches.
+    let images = [textured_image(width, height, 1), textured_image(width, height, 2)];
+    let mut tracker = Tracker::new().unwrap();
+    let mut frames = vec![];
+    for frame_number in 0..3 {
+      frames.push(Frame::new(&InputFrame { images: vec![&images[0], &images[1]] }, None).unwrap());
+      tracker.process(&frames, &cameras, frame_number, &[]);
+    }
+
+    // Each camera has its own monocular tracks that follow the static image.
+    let tracks = tracker.get_tracks();
+    for c in 0..2 {
+      let camera_tracks: Vec<&Track> = tracks.iter().filter(|t| t.camera == Some(c)).collect();
+      assert!(camera_tracks.len() > 10);
+      assert!(camera_tracks.len() <= 50);
+      assert!(camera_tracks.iter().any(|t| t.points.len() == 3));
+    }
+    for track in tracks {
+      assert!(track.camera.is_some());
+      for point in &track.points {
+        assert_eq!(point.coordinates[0], point.coordinates[1]);
+        assert!((point.coordinates[0] - track.points[0].coordinates[0]).norm() < 0.1);
+      }
+    }
+  }
+
+  #[test]
+  fn test_intensity_compensation() {
+    // A scrolling texture whose brightness changes every frame, like with
+    // auto-exposure. Count the tracks from the first frame that survive.
+    let surviving = |compensation: bool| {
+      let _guard = set_test_parameters(|p| {
+        p.lk_intensity_compensation = compensation;
+        // The coarsest levels of the blocky texture mislead the flow.
+        p.lk_start_level = Some(1);
+      });
+      let (width, height) = (160, 120);
+      let mut cameras = test_cameras(width, height);
+      cameras.truncate(1);
+      let texture = textured_image(width + 20, height, 1);
+      let mut tracker = Tracker::new().unwrap();
+      let mut frames = vec![];
+      for frame_number in 0..5 {
+        let (gain, bias) = if frame_number % 2 == 0 { (1., 0.) } else { (0.5, 20.) };
+        let offset = 2 * frame_number;
+        let image = Image {
+          data: (0..height).flat_map(|y| {
+            let row = y * texture.width + offset;
+            texture.data[row..(row + width)].iter().map(|v| (gain * *v as f64 + bias).round() as u8).collect::<Vec<_>>()
+          }).collect(),
+          width,
+          height,
+        };
+        frames.push(Frame::new(&InputFrame { images: vec![&image] }, None).unwrap());
+        tracker.process(&frames, &cameras, frame_number, &[]);
+      }
+      tracker.get_tracks().iter()
+        .filter(|track| track.points.len() == 5)
+        .count()
+    };
+    let without = surviving(false);
+    let with = surviving(true);
+    assert!(with > 100, "{} {}", without, with);
+    assert!(with > 2 * without, "{} {}", without, with);
+  }
+}
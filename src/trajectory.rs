@@ -0,0 +1,267 @@
+// Estimated trajectory output and offline post-processing.
+
+use crate::all::*;
+
+use std::io::Write;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrajectoryPose {
+  pub time: f64,
+  // IMU position in world coordinates.
+  pub position: Vector3d,
+  // IMU-to-world rotation as a quaternion [w, x, y, z].
+  pub orientation: Vector4d,
+}
+
+impl TrajectoryPose {
+  pub fn new(time: f64, imu_to_world: &Matrix4d) -> TrajectoryPose {
+    TrajectoryPose {
+      time,
+      position: position!(imu_to_world).into(),
+      orientation: to_quaternion(rotation!(imu_to_world).into()),
+    }
+  }
+}
+
+// Writes the poses in the TUM format, one `timestamp tx ty tz qx qy qz qw`
+// line per pose. Note that the quaternion is reordered to put `w` last.
+pub fn write_tum(path: &Path, poses: &[TrajectoryPose]) -> Result<()> {
+  let file = File::create(path)
+    .context(format!("Failed to create trajectory file {}.", path.display()))?;
+  let mut writer = std::io::BufWriter::new(file);
+  for pose in poses {
+    let (p, q) = (pose.position, pose.orientation);
+    writeln!(writer, "{} {} {} {} {} {} {} {}", pose.time, p[0], p[1], p[2], q[1], q[2], q[3], q[0])?;
+  }
+  Ok(())
+}
+
+// Writes poses in the TUM format one at a time as they are estimated, for
+// tools such as `evo` to compute the ATE and RPE against ground truth. Each
+// line is `timestamp tx ty tz qx qy qz qw`:
+// * `timestamp` is the input time of the frame in seconds.
+// * `tx ty tz` is the position in the VIO world frame, whose z-axis points up
+//   (against gravity) and whose origin and yaw are set by the starting pose.
+// * `qx qy qz qw` is the rotation from the pose's frame to the world frame,
+//   with `w` last as TUM and `evo` expect.
+// With `stds` six more columns `std_x std_y std_z std_rx std_ry std_rz` give
+// the standard deviations of the position and orientation, the latter as a
+// rotation vector in the pose's frame. Plain TUM readers do not accept them.
+// Lines are flushed as they are written so that an interrupted run still
+// leaves a usable file.
+pub struct TumWriter {
+  writer: std::io::BufWriter<File>,
+  stds: bool,
+}
+
+impl TumWriter {
+  pub fn create(path: &Path, stds: bool) -> Result<TumWriter> {
+    let file = File::create(path)
+      .context(format!("Failed to create trajectory file {}.", path.display()))?;
+    Ok(TumWriter { writer: std::io::BufWriter::new(file), stds })
+  }
+
+  pub fn write(&mut self, pose: &TrajectoryPose, stds: &Vector6d) -> Result<()> {
+    let (p, q) = (pose.position, pose.orientation);
+    write!(self.writer, "{} {} {} {} {} {} {} {}", pose.time, p[0], p[1], p[2], q[1], q[2], q[3], q[0])?;
+    if self.stds {
+      for std in stds.iter() {
+        write!(self.writer, " {}", std)?;
+      }
+    }
+    writeln!(self.writer)?;
+    self.writer.flush()?;
+    Ok(())
+  }
+}
+
+// Writes the poses as a `geometry_msgs/PoseStamped` stream in the CSV format
+// of `rostopic echo -p`, which ROS tooling (eg `rqt_plot`, pandas scripts
+// written for bag exports) can load directly. Read in order, the rows form a
+// `nav_msgs/Path`. The poses are of the IMU in the VIO world frame named
+// `frame_id`: z-axis is up (against gravity), the origin and yaw are set by the
+// starting pose. Stamps are nanoseconds of the input time.
+pub fn write_ros_pose_stamped(path: &Path, poses: &[TrajectoryPose], frame_id: &str) -> Result<()> {
+  let file = File::create(path)
+    .context(format!("Failed to create ROS pose file {}.", path.display()))?;
+  let mut writer = std::io::BufWriter::new(file);
+  writeln!(writer, "%time,field.header.seq,field.header.stamp,field.header.frame_id,\
+    field.pose.position.x,field.pose.position.y,field.pose.position.z,\
+    field.pose.orientation.x,field.pose.orientation.y,field.pose.orientation.z,field.pose.orientation.w")?;
+  for (seq, pose) in poses.iter().enumerate() {
+    let (p, q) = (pose.position, pose.orientation);
+    let stamp = (pose.time * 1e9).round() as i64;
+    writeln!(writer, "{},{},{},{},{},{},{},{},{},{},{}",
+      stamp, seq, stamp, frame_id, p[0], p[1], p[2], q[1], q[2], q[3], q[0])?;
+  }
+  Ok(())
+}
+
+// Offline smoothing that removes high-frequency jitter from the estimated
+// trajectory. This is not a spline fit: each pass replaces the interior poses
+// by a weighted average of themselves and their neighbours,
+//   p_i <- (p_{i-1} + 4 p_i + p_{i+1}) / 6,
+// so repeated passes act as a low-pass filter whose width grows with the
+// square root of `strength`, the number of passes. Positions and orientations
+// are averaged separately, the orientations as sign-aligned quaternions
+// renormalized after each pass, which assumes small rotations between
+// consecutive poses. The poses keep their times and the end points are kept
+// fixed. Zero strength returns the input unchanged.
+pub fn smooth_trajectory(poses: &[TrajectoryPose], strength: usize) -> Vec<TrajectoryPose> {
+  if strength == 0 { return poses.to_vec() }
+  let mut smoothed = poses.to_vec();
+  for i in 1..smoothed.len() {
+    if smoothed[i].orientation.dot(&smoothed[i - 1].orientation) < 0. {
+      smoothed[i].orientation *= -1.;
+    }
+  }
+  let mut previous = smoothed.clone();
+  for _ in 0..strength {
+    mem::swap(&mut previous, &mut smoothed);
+    for i in 1..(previous.len().max(1) - 1) {
+      let (p0, p1, p2) = (&previous[i - 1], &previous[i], &previous[i + 1]);
+      smoothed[i].position = (p0.position + 4. * p1.position + p2.position) / 6.;
+      smoothed[i].orientation = ((p0.orientation + 4. * p1.orientation + p2.orientation) / 6.).normalize();
+    }
+  }
+  smoothed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::SeedableRng;
+
+  #[test]
+  fn test_to_quaternion() {
+    for q in [
+      Vector4d::new(1., 0., 0., 0.),
+      Vector4d::new(0., 1., 0., 0.),
+      Vector4d::new(0.1, -0.2, 0.9, 0.3).normalize(),
+      Vector4d::new(0.5, 0.5, -0.5, 0.5),
+    ] {
+      assert!((to_quaternion(to_rotation_matrix(q)) - q).norm() < 1e-10);
+    }
+  }
+
+  fn read_ros_pose_stamped(path: &Path) -> Vec<(String, TrajectoryPose)> {
+    let s = std::fs::read_to_string(path).unwrap();
+    let mut lines = s.lines();
+    assert_eq!(lines.next().unwrap().split(',').count(), 11);
+    lines.enumerate().map(|(seq, line)| {
+      let fields: Vec<&str> = line.split(',').collect();
+      assert_eq!(fields[1].parse::<usize>().unwrap(), seq);
+      let x: Vec<f64> = fields[4..].iter().map(|x| x.parse().unwrap()).collect();
+      (fields[3].to_string(), TrajectoryPose {
+        time: fields[2].parse::<i64>().unwrap() as f64 * 1e-9,
+        position: Vector3d::new(x[0], x[1], x[2]),
+        orientation: Vector4d::new(x[6], x[3], x[4], x[5]),
+      })
+    }).collect()
+  }
+
+  #[test]
+  fn test_ros_pose_stamped_round_trip() {
+    let poses: Vec<TrajectoryPose> = (0..5).map(|i| TrajectoryPose {
+      time: 1.25 + 0.05 * i as f64,
+      position: Vector3d::new(0.1 * i as f64, -2.5, 1e-3),
+      orientation: Vector4d::new(0.9, 0.1 * i as f64, -0.2, 0.3).normalize(),
+    }).collect();
+    let path = std::env::temp_dir().join(format!("violet-ros-{}.csv", std::process::id()));
+    write_ros_pose_stamped(&path, &poses, "world").unwrap();
+    let read = read_ros_pose_stamped(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(read.len(), poses.len());
+    for ((frame_id, a), b) in read.iter().zip(poses.iter()) {
+      assert_eq!(frame_id, "world");
+      assert!((a.time - b.time).abs() < 1e-9);
+      assert_eq!(a.position, b.position);
+      assert_eq!(a.orientation, b.orientation);
+    }
+  }
+
+  #[test]
+  fn test_tum_writer() {
+    let poses: Vec<TrajectoryPose> = (0..3).map(|i| TrajectoryPose {
+      time: 1403636580.25 + 0.05 * i as f64,
+      position: Vector3d::new(0.1 * i as f64, -2.5, 1e-3),
+      orientation: Vector4d::new(0.9, 0.1 * i as f64, -0.2, 0.3).normalize(),
+    }).collect();
+    let path = std::env::temp_dir().join(format!("violet-tum-{}.txt", std::process::id()));
+    let expected_path = std::env::temp_dir().join(format!("violet-tum-expected-{}.txt", std::process::id()));
+    write_tum(&expected_path, &poses).unwrap();
+    let expected = std::fs::read_to_string(&expected_path).unwrap();
+    std::fs::remove_file(&expected_path).unwrap();
+
+    let stds = Vector6d::new(0.1, 0.2, 0.3, 0.01, 0.02, 0.03);
+    let mut writer = TumWriter::create(&path, false).unwrap();
+    writer.write(&poses[0], &stds).unwrap();
+    // Readable while the run goes on.
+    let partial = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(partial.lines().count(), 1);
+    let fields: Vec<f64> = partial.split_whitespace().map(|x| x.parse().unwrap()).collect();
+    let q = poses[0].orientation;
+    assert_eq!(fields, vec![poses[0].time, 0., -2.5, 1e-3, q[1], q[2], q[3], q[0]]);
+    for pose in &poses[1..] {
+      writer.write(pose, &stds).unwrap();
+    }
+    drop(writer);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+
+    // The standard deviations follow the TUM columns.
+    let mut writer = TumWriter::create(&path, true).unwrap();
+    writer.write(&poses[0], &stds).unwrap();
+    drop(writer);
+    let fields: Vec<f64> = std::fs::read_to_string(&path).unwrap()
+      .split_whitespace().map(|x| x.parse().unwrap()).collect();
+    assert_eq!(fields.len(), 14);
+    assert_eq!(&fields[8..], stds.as_slice());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_smooth_trajectory() {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let jitter = 0.01;
+    let n = 200;
+    let truth: Vec<TrajectoryPose> = (0..n).map(|i| {
+      let t = i as f64 * 0.05;
+      let mut T = Matrix4d::identity();
+      let angle = 0.5 * t;
+      T.fixed_slice_mut::<3, 3>(0, 0).copy_from(&to_rotation_matrix(
+        Vector4d::new((0.5 * angle).cos(), 0., 0., (0.5 * angle).sin())));
+      T.fixed_slice_mut::<3, 1>(0, 3).copy_from(&Vector3d::new(angle.cos(), angle.sin(), 0.1 * t));
+      TrajectoryPose::new(t, &T)
+    }).collect();
+    let raw: Vec<TrajectoryPose> = truth.iter().enumerate().map(|(i, pose)| {
+      let mut pose = pose.clone();
+      pose.position += jitter * Vector3d::new(rng.gen_range(-1. ..1.), rng.gen_range(-1. ..1.), rng.gen_range(-1. ..1.));
+      // The same rotation, which the smoothing should not average towards zero.
+      if i % 7 == 0 { pose.orientation *= -1. }
+      pose
+    }).collect();
+
+    let smoothed = smooth_trajectory(&raw, 5);
+    assert_eq!(smoothed.len(), raw.len());
+    let roughness = |poses: &[TrajectoryPose]| -> f64 {
+      poses.windows(3).map(|w| (w[0].position - 2. * w[1].position + w[2].position).norm()).sum()
+    };
+    assert!(roughness(&smoothed) < 0.3 * roughness(&raw));
+    let mut error_raw = 0.;
+    let mut error_smoothed = 0.;
+    for i in 0..n {
+      // Smoothed trajectory stays close to the raw one.
+      assert!((smoothed[i].position - raw[i].position).norm() < 3. * jitter);
+      assert!((smoothed[i].orientation.norm() - 1.).abs() < 1e-10);
+      assert!(smoothed[i].orientation.dot(&truth[i].orientation).abs() > 1. - 1e-6);
+      error_raw += (raw[i].position - truth[i].position).norm();
+      error_smoothed += (smoothed[i].position - truth[i].position).norm();
+    }
+    assert!(error_smoothed < error_raw);
+    for (a, b) in smooth_trajectory(&raw, 0).iter().zip(raw.iter()) {
+      assert_eq!(a.position, b.position);
+      assert_eq!(a.orientation, b.orientation);
+    }
+  }
+}
@@ -2,17 +2,21 @@
 pub type Vector2d = nalgebra::Vector2::<f64>;
 pub type Vector3d = nalgebra::Vector3::<f64>;
 pub type Vector4d = nalgebra::Vector4::<f64>;
+pub type Vector6d = nalgebra::Vector6::<f64>;
 pub type Vectord = nalgebra::DVector::<f64>;
 pub type Matrixd = nalgebra::DMatrix::<f64>;
 pub type Matrix2d = nalgebra::Matrix2::<f64>;
 pub type Matrix3d = nalgebra::Matrix3::<f64>;
 pub type Matrix4d = nalgebra::Matrix4::<f64>;
+pub type Matrix6d = nalgebra::Matrix6::<f64>;
 pub type Matrix23d = nalgebra::Matrix2x3::<f64>;
+pub type Matrix32d = nalgebra::Matrix3x2::<f64>;
 pub type Matrix34d = nalgebra::Matrix3x4::<f64>;
 
 pub type Vector2i = nalgebra::Vector2::<i32>;
 pub type Vector2usize = nalgebra::Vector2::<usize>;
 
+#[cfg(feature = "gui")]
 pub type Point3f = nalgebra::Point::<f32, 3>;
 
 pub fn from_usize(p: Vector2usize) -> Vector2i {
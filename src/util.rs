@@ -23,3 +23,40 @@ pub fn format_log(
   );
   writeln!(buf, "{}", style.value(s))
 }
+
+// Check of an internal invariant. In the development mode a failure panics to
+// catch bugs early. In the production mode the failure is logged and false is
+// returned so that the caller can skip the offending track or frame instead of
+// aborting a long unattended run.
+pub fn invariant(condition: bool, description: &str) -> bool {
+  use crate::parameters::{AssertionPolicy, PARAMETER_SET};
+  if condition { return true }
+  let policy = PARAMETER_SET.lock().unwrap().assertion_policy;
+  match policy {
+    AssertionPolicy::Development => panic!("Invariant failed: {}", description),
+    AssertionPolicy::Production => {
+      log::error!("Invariant failed, skipping: {}", description);
+      false
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::all::*;
+
+  #[test]
+  fn test_invariant_production() {
+    let _guard = set_test_parameters(|p| p.assertion_policy = AssertionPolicy::Production);
+    assert!(invariant(true, "true"));
+    assert!(!invariant(false, "false"));
+  }
+
+  #[test]
+  fn test_invariant_development() {
+    let _guard = set_test_parameters(|p| p.assertion_policy = AssertionPolicy::Development);
+    assert!(invariant(true, "true"));
+    assert!(std::panic::catch_unwind(|| invariant(false, "false")).is_err());
+  }
+}
@@ -2,6 +2,18 @@ use crate::all::*;
 
 use std::process::{ChildStdout, Command, Stdio};
 
+// Radius of the windows compared in `estimate_intensity_gain()`.
+const INTENSITY_WINDOW_RADIUS: usize = 7;
+// With fewer usable windows the intensities are assumed unchanged.
+const INTENSITY_MIN_WINDOWS: usize = 5;
+// Windows with a smaller standard deviation in the previous frame are too
+// flat to tell the gain.
+const INTENSITY_MIN_STD: f64 = 2.;
+// Half-width of the window in `refine_corner()`.
+const REFINE_RADIUS: i32 = 2;
+// Standard deviation in pixels of the window weights in `refine_corner()`.
+const REFINE_SIGMA: f64 = 1.5;
+
 pub struct VideoInput {
   child_stdout: ChildStdout,
   video_frame: Image,
@@ -48,3 +60,156 @@ impl VideoInput {
     Ok(&self.video_frame)
   }
 }
+
+// Affine change of the intensities `curr = gain * prev + bias` between two
+// frames, eg from auto-exposure, estimated from windows around the `tracks`,
+// pairs of points in `prev` and `curr`. The mean and standard deviation of a
+// window barely depend on small errors in the points, so predicted points
+// work too. Medians over the windows ignore those that moved or got occluded.
+// Returns `(1, 0)` if there are too few usable windows.
+pub fn estimate_intensity_gain(prev: &Image, curr: &Image, tracks: &[(Vector2d, Vector2d)]) -> (f32, f32) {
+  let mut windows = vec![];
+  for (p0, p1) in tracks {
+    let (Some((mean0, std0)), Some((mean1, std1))) = (window_statistics(prev, *p0), window_statistics(curr, *p1))
+      else { continue };
+    if std0 < INTENSITY_MIN_STD { continue }
+    windows.push((mean0, mean1, std1 / std0));
+  }
+  if windows.len() < INTENSITY_MIN_WINDOWS { return (1., 0.) }
+  let median = |values: &mut Vec<f64>| {
+    values.sort_by(|a, b| a.total_cmp(b));
+    values[values.len() / 2]
+  };
+  let gain = median(&mut windows.iter().map(|w| w.2).collect());
+  let bias = median(&mut windows.iter().map(|w| w.1 - gain * w.0).collect());
+  (gain as f32, bias as f32)
+}
+
+// Mean and standard deviation of the pixels in the window centered at `p`,
+// None if it does not fit in the image.
+fn window_statistics(image: &Image, p: Vector2d) -> Option<(f64, f64)> {
+  let r = INTENSITY_WINDOW_RADIUS;
+  let (x, y) = (p[0].round(), p[1].round());
+  if x < r as f64 || y < r as f64 { return None }
+  let (x, y) = (x as usize, y as usize);
+  if x + r >= image.width || y + r >= image.height { return None }
+  let mut sum = 0.;
+  let mut sum2 = 0.;
+  for v in (y - r)..=(y + r) {
+    for u in (x - r)..=(x + r) {
+      let value = image.value(u, v) as f64;
+      sum += value;
+      sum2 += value * value;
+    }
+  }
+  let n = ((2 * r + 1) * (2 * r + 1)) as f64;
+  let mean = sum / n;
+  Some((mean, (sum2 / n - mean * mean).max(0.).sqrt()))
+}
+
+// Sub-pixel location of the corner detected at pixel `p`, the point closest
+// to the lines through the edges around it in the least squares sense (the
+// Förstner operator, like `cornerSubPix()` in OpenCV): the gradient of each
+// pixel on an edge is orthogonal to the direction to the corner. Fitting a
+// quadratic to the corner response is less accurate, because the response of a
+// sharp corner is flat around its peak. Returns `p` near the image border,
+// on straight edges and if the corner is not at the pixel.
+pub fn refine_corner(image: &Image, p: Vector2d) -> Vector2d {
+  let (x, y) = (p[0].round() as i32, p[1].round() as i32);
+  // The gradients read one pixel outside the window.
+  let margin = REFINE_RADIUS + 1;
+  if x < margin || y < margin || x >= image.width as i32 - margin || y >= image.height as i32 - margin {
+    return p;
+  }
+  let mut A = Matrix2d::zeros();
+  let mut b = Vector2d::zeros();
+  for v in (y - REFINE_RADIUS)..=(y + REFINE_RADIUS) {
+    for u in (x - REFINE_RADIUS)..=(x + REFINE_RADIUS) {
+      let w = (-0.5 * ((u - x).pow(2) + (v - y).pow(2)) as f64 / REFINE_SIGMA.powi(2)).exp();
+      let g = Vector2d::new(
+        0.5 * (image.value_i32(u + 1, v) as f64 - image.value_i32(u - 1, v) as f64),
+        0.5 * (image.value_i32(u, v + 1) as f64 - image.value_i32(u, v - 1) as f64),
+      );
+      let ggt = w * g * g.transpose();
+      A += ggt;
+      b += ggt * Vector2d::new(u as f64, v as f64);
+    }
+  }
+  // Both eigenvalues of `A` are large at a corner, one is near zero on an edge.
+  let (xx, yy, xy) = (A[(0, 0)], A[(1, 1)], A[(0, 1)]);
+  let min_eigenvalue = 0.5 * (xx + yy) - (0.25 * (xx - yy).powi(2) + xy * xy).sqrt();
+  if min_eigenvalue <= 1e-3 * (xx + yy) { return p }
+  let Some(inverse) = A.try_inverse() else { return p };
+  let q = inverse * b;
+  if (q - Vector2d::new(x as f64, y as f64)).amax() > 1. { return p }
+  q
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_estimate_intensity_gain() {
+    let (width, height) = (160, 120);
+    let prev = textured_image(width, height, 1);
+    // Darker and shifted by a pixel.
+    let mut curr = black_image(width, height);
+    for y in 0..height {
+      for x in 1..width {
+        curr.set_value(x, y, (0.6 * prev.value(x - 1, y) as f64 + 30.).round() as u8);
+      }
+    }
+    let tracks: Vec<_> = (0..40)
+      .map(|i| Vector2d::new((10 + 35 * i % 140) as f64, (10 + 13 * i % 100) as f64))
+      .map(|p| (p, p + Vector2d::new(1., 0.)))
+      .collect();
+    let (gain, bias) = estimate_intensity_gain(&prev, &curr, &tracks);
+    assert!((gain - 0.6).abs() < 0.02, "{}", gain);
+    assert!((bias - 30.).abs() < 3., "{}", bias);
+    // The points barely matter for the window statistics.
+    let unmoved: Vec<_> = tracks.iter().map(|(p, _)| (*p, *p)).collect();
+    let (gain, bias) = estimate_intensity_gain(&prev, &curr, &unmoved);
+    assert!((gain - 0.6).abs() < 0.05, "{}", gain);
+    assert!((bias - 30.).abs() < 5., "{}", bias);
+    assert_eq!(estimate_intensity_gain(&prev, &curr, &tracks[..3]), (1., 0.));
+  }
+
+  #[test]
+  fn test_refine_corner() {
+    // Checkerboards whose corner is at sub-pixel positions, with the pixels
+    // rendered by averaging samples over their area.
+    let (width, height) = (32, 32);
+    let checkerboard = |corner: Vector2d| {
+      let samples = 8;
+      let mut image = Image { data: vec![0; width * height], width, height };
+      for y in 0..height {
+        for x in 0..width {
+          let mut sum = 0.;
+          for i in 0..samples {
+            for j in 0..samples {
+              let u = x as f64 - 0.5 + (i as f64 + 0.5) / samples as f64 - corner[0];
+              let v = y as f64 - 0.5 + (j as f64 + 0.5) / samples as f64 - corner[1];
+              if (u >= 0.) == (v >= 0.) { sum += 1. }
+            }
+          }
+          image.set_value(x, y, (30. + 180. * sum / (samples * samples) as f64).round() as u8);
+        }
+      }
+      image
+    };
+    for corner in [Vector2d::new(15.3, 16.8), Vector2d::new(16.45, 15.6), Vector2d::new(16., 16.)] {
+      let image = checkerboard(corner);
+      let p = corner.map(|x| x.round());
+      let refined = refine_corner(&image, p);
+      assert!((refined - corner).norm() < 0.2, "{} {}", refined, corner);
+    }
+
+    // Near the border and on a straight edge the detection is returned as is.
+    let image = checkerboard(Vector2d::new(2.3, 28.6));
+    let p = Vector2d::new(2., 29.);
+    assert_eq!(refine_corner(&image, p), p);
+    let p = Vector2d::new(12., 29.);
+    assert_eq!(refine_corner(&image, p), p);
+  }
+}
@@ -2,7 +2,8 @@
 
 use crate::all::*;
 
-const MAX_FRAMES_IN_MEMORY: usize = 2;
+const MIN_FRAMES_IN_MEMORY: usize = 2;
+const _: () = assert!(MIN_FRAMES_IN_MEMORY >= 1);
 
 #[allow(dead_code)]
 pub struct Vio {
@@ -10,9 +11,22 @@ pub struct Vio {
   tracker: Tracker,
   kalman_filter: KalmanFilter,
   stationary: Stationary,
+  // See `imu_stationary_window`.
+  imu_stationary: Option<ImuStationary>,
   visual_update: VisualUpdate,
+  anchors: Anchors,
+  // Rectified cameras if `rectification` is set.
   cameras: Vec<Camera>,
+  rectification: Option<Rectification>,
+  // Calibrated cameras the input images are from, when rectifying.
+  original_cameras: Vec<Camera>,
+  rectified_images: Vec<Image>,
+  image_flip: ImageFlip,
+  flipped_images: Vec<Image>,
+  flip_check: Option<FlipCheck>,
   frames: Vec<Frame>,
+  // Older frames are kept for re-tracking lost tracks.
+  max_frames_in_memory: usize,
   // Incremented just before processing a new frame. 0 before the first frame.
   frame_number: usize,
   // Last element is the current pose. Augmentation duplicates the last element
@@ -21,18 +35,92 @@ pub struct Vio {
   pose_trail_frame_numbers: VecDeque<usize>,
   frame_sub: usize,
   initialized_orientation: bool,
+  // See `imu_init_window`.
+  imu_init: Option<ImuStationary>,
+  warned_moving_init: bool,
+  // True if `check_gravity()` warned about the first accelerometer sample.
+  warned_gravity: bool,
   last_gyroscope: Option<(f64, Vector3d)>,
   last_accelerometer: Option<(f64, Vector3d)>,
+  // The sample of `last_accelerometer` before applying `imu_convention`.
+  last_raw_accelerometer: Vector3d,
   last_time: Option<f64>,
+  // Last IMU sample passed to the filter: time, gyroscope and accelerometer.
+  last_imu: Option<(f64, Vector3d, Vector3d)>,
+  // Set by `set_kalman_filter()` until the next IMU sample.
+  resumed: bool,
+  // If set, frames wait in `pending_frame` for the next IMU sample.
+  imu_frame_interpolation: bool,
+  // Time of the waiting frame, its images are in `pending_images`.
+  pending_frame: Option<f64>,
+  pending_images: Vec<Image>,
+  imu_max_gap: f64,
+  imu_convention: ImuConvention,
+  gravity: f64,
+  imu_warmup_samples: usize,
+  imu_warmup_time: f64,
+  // Number and time of the first of the IMU samples seen, including discarded ones.
+  imu_sample_count: usize,
+  first_imu_time: Option<f64>,
+  // Collects static IMU samples until the noise estimate is done.
+  imu_noise_calibration: Option<ImuNoiseCalibration>,
+  imu_noise_calibration_apply: bool,
+  imu_noise: Option<ImuNoise>,
   kf_noise_zero_velocity: f64,
+  // See `disable_zupt`.
+  zupt: bool,
   pose_trail_len: usize,
+  ground_truth: Option<GroundTruth>,
+  // See `use_gps`.
+  use_gps: bool,
+  // Local frame of the first GPS fix.
+  gps_enu: Option<LocalEnu>,
+  // Current pose after each processed frame.
+  trajectory: Vec<TrajectoryPose>,
+  // IMU model state after each processed frame.
+  imu_states: Vec<ImuState>,
+  // Frames with at most this many tracks are treated as tracking blackout.
+  blackout_max_tracks: usize,
+  blackout: bool,
+  // See `get_reprojection_rms()`.
+  reprojection_rms: Option<f64>,
+  // Filter resets after `KalmanFilter::check_divergence()`.
+  divergence_count: usize,
+  // Wall-clock time of the last processed frame, for `HudStats::fps`.
+  last_frame_instant: Option<std::time::Instant>,
 }
 
 impl Vio {
-  pub fn new(cameras: Vec<Camera>, frame_scale: f64) -> Result<Vio> {
-    let (frame_sub, kf_noise_zero_velocity, pose_trail_len) = {
+  pub fn new(
+    cameras: Vec<Camera>,
+    frame_scale: f64,
+    ground_truth: Option<GroundTruth>,
+  ) -> Result<Vio> {
+    let (frame_sub, kf_noise_zero_velocity, pose_trail_len, blackout_max_tracks, lost_track_grace) = {
       let p = PARAMETER_SET.lock().unwrap();
-      (p.frame_sub, p.kf_noise_zero_velocity, p.pose_trail_len)
+      (p.frame_sub, p.kf_noise_zero_velocity, p.pose_trail_len, p.blackout_max_tracks, p.lost_track_grace)
+    };
+    let (rectify, image_flip, flip_check, imu_frame_interpolation, imu_max_gap) = {
+      let p = PARAMETER_SET.lock().unwrap();
+      (p.rectify, p.image_flip, p.flip_check, p.imu_frame_interpolation, p.imu_max_gap)
+    };
+    let (imu_warmup_samples, imu_warmup_time, imu_noise_calibration_time, imu_noise_calibration_apply) = {
+      let p = PARAMETER_SET.lock().unwrap();
+      (p.imu_warmup_samples, p.imu_warmup_time, p.imu_noise_calibration_time, p.imu_noise_calibration_apply)
+    };
+    let (imu_convention, gravity, disable_zupt, imu_stationary_window, imu_init_window) = {
+      let p = PARAMETER_SET.lock().unwrap();
+      (p.imu_convention, p.gravity, p.disable_zupt, p.imu_stationary_window, p.imu_init_window)
+    };
+    let use_gps = PARAMETER_SET.lock().unwrap().use_gps;
+    // With one camera all the tracks are monocular, like with `independent_cameras`.
+    if cameras.is_empty() || cameras.len() > 2 { bail!("Expected one or two cameras, found {}.", cameras.len()) }
+    let (rectification, cameras, original_cameras) = if rectify {
+      let (rectification, rectified_cameras) = Rectification::new(&cameras)?;
+      (Some(rectification), rectified_cameras, cameras)
+    }
+    else {
+      (None, cameras, vec![])
     };
     let mut pose_trail_frame_numbers = VecDeque::new();
     pose_trail_frame_numbers.push_back(0);
@@ -41,18 +129,59 @@ impl Vio {
       tracker: Tracker::new()?,
       kalman_filter: KalmanFilter::new(),
       stationary: Stationary::new(frame_scale),
+      imu_stationary: if imu_stationary_window > 0. && !disable_zupt { Some(ImuStationary::new(imu_stationary_window)) } else { None },
       visual_update: VisualUpdate::new(),
+      anchors: Anchors::new(),
       cameras,
+      rectification,
+      original_cameras,
+      rectified_images: vec![],
+      image_flip,
+      flipped_images: vec![],
+      flip_check: if flip_check { Some(FlipCheck::new()) } else { None },
       frames: vec![],
+      max_frames_in_memory: MIN_FRAMES_IN_MEMORY.max(lost_track_grace + 2),
       pose_trail_frame_numbers,
       frame_number: 0,
       frame_sub,
       initialized_orientation: false,
+      imu_init: if imu_init_window > 0. { Some(ImuStationary::new(imu_init_window)) } else { None },
+      warned_moving_init: false,
+      warned_gravity: false,
       last_gyroscope: None,
       last_accelerometer: None,
+      last_raw_accelerometer: Vector3d::zeros(),
       last_time: None,
+      last_imu: None,
+      resumed: false,
+      imu_frame_interpolation,
+      pending_frame: None,
+      pending_images: vec![],
+      imu_max_gap,
+      imu_convention,
+      gravity,
+      imu_warmup_samples,
+      imu_warmup_time,
+      imu_sample_count: 0,
+      first_imu_time: None,
+      imu_noise_calibration: if imu_noise_calibration_time > 0. {
+        Some(ImuNoiseCalibration::new(imu_noise_calibration_time))
+      } else { None },
+      imu_noise_calibration_apply,
+      imu_noise: None,
       kf_noise_zero_velocity,
+      zupt: !disable_zupt,
       pose_trail_len,
+      ground_truth,
+      use_gps,
+      gps_enu: None,
+      trajectory: vec![],
+      imu_states: vec![],
+      blackout_max_tracks,
+      blackout: false,
+      reprojection_rms: None,
+      divergence_count: 0,
+      last_frame_instant: None,
     })
   }
 
@@ -60,6 +189,144 @@ impl Vio {
     &self.frames
   }
 
+  pub fn get_trajectory(&self) -> &[TrajectoryPose] {
+    &self.trajectory
+  }
+
+  // Pose of the first camera at the last processed frame. With `rectify` it is
+  // the camera as calibrated, not the rotated rectified one.
+  pub fn get_camera_pose(&self) -> Option<TrajectoryPose> {
+    let time = self.trajectory.last()?.time;
+    let mut imu_to_worlds = vec![];
+    self.kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+    let camera = self.original_cameras.first().unwrap_or(&self.cameras[0]);
+    Some(TrajectoryPose::new(time, &(imu_to_worlds.first()? * affine_inverse(camera.imu_to_camera))))
+  }
+
+  // Standard deviations of the position (m) and orientation (rad) of
+  // `get_camera_pose()`, see `KalmanFilter::current_camera_pose_covariance()`.
+  pub fn get_camera_pose_stds(&self) -> Vector6d {
+    let camera = self.original_cameras.first().unwrap_or(&self.cameras[0]);
+    let covariance = self.kalman_filter.current_camera_pose_covariance(&camera.imu_to_camera);
+    covariance.diagonal().map(|x| x.max(0.).sqrt())
+  }
+
+  // Register a landmark with known world coordinates. Tracks matched to it
+  // constrain the pose to the world coordinates in the visual update.
+  pub fn add_anchor(&mut self, position: Vector3d) {
+    self.anchors.add(position);
+  }
+
+  // Active tracks, including those kept within `lost_track_grace`. Track IDs
+  // are unique for the lifetime of the `Vio` instance: a track keeps its ID
+  // until it is removed, and IDs are never reused, also not after
+  // `clear_tracks()`. A feature that is detected again after its track was
+  // removed gets a new ID.
+  #[allow(dead_code)]
+  pub fn get_tracks(&self) -> &[Track] {
+    self.tracker.get_tracks()
+  }
+
+  // Remove all tracks without touching the filter state. The tracker starts
+  // new ones on the next frame.
+  #[allow(dead_code)]
+  pub fn clear_tracks(&mut self) {
+    self.tracker.clear_tracks();
+  }
+
+  // Number of the last processed frame, for `Track::age()`.
+  pub fn get_frame_number(&self) -> usize {
+    self.frame_number
+  }
+
+  pub fn get_imu_states(&self) -> &[ImuState] {
+    &self.imu_states
+  }
+
+  pub fn get_imu_biases(&self) -> ImuBiases {
+    self.kalman_filter.get_imu_biases()
+  }
+
+  pub fn set_imu_biases(&mut self, biases: &ImuBiases) {
+    self.kalman_filter.set_imu_biases(biases);
+  }
+
+  // The mask is in the coordinates of the input images. Rectified images
+  // would need it remapped separately for each camera, which is not done.
+  pub fn set_detection_mask(&mut self, detection_mask: Option<DetectionMask>) -> Result<()> {
+    let Some(detection_mask) = detection_mask else {
+      self.tracker.set_detection_mask(None);
+      return Ok(());
+    };
+    if self.rectification.is_some() { bail!("The detection mask cannot be used with rectification.") }
+    self.tracker.set_detection_mask(Some(detection_mask.flipped(self.image_flip)));
+    Ok(())
+  }
+
+  pub fn get_kalman_filter(&self) -> &KalmanFilter {
+    &self.kalman_filter
+  }
+
+  // Continues from a filter saved at the end of a previous run, see
+  // `KalmanFilter::save()`. Call before processing any input. The tracks of
+  // its landmarks are not saved, so the landmarks are dropped, and the time
+  // since the saved run is skipped like an IMU gap.
+  pub fn set_kalman_filter(&mut self, mut kalman_filter: KalmanFilter) {
+    kalman_filter.retain_landmarks(|_| false);
+    self.kalman_filter = kalman_filter;
+    self.initialized_orientation = true;
+    self.imu_init = None;
+    self.resumed = true;
+  }
+
+  // Flip of the input images suggested by `--flip-check`, if any.
+  #[cfg(test)]
+  pub fn get_suspected_flip(&self) -> Option<ImageFlip> {
+    self.flip_check.as_ref().and_then(|flip_check| flip_check.get_suspected())
+  }
+
+  // True if no features could be tracked in the last frame (eg camera covered).
+  #[cfg(test)]
+  pub fn is_blackout(&self) -> bool {
+    self.blackout
+  }
+
+  // The state after the last processed frame, None before the first one.
+  #[cfg_attr(not(feature = "json-output"), allow(dead_code))]
+  pub fn get_frame_state(&self) -> Option<FrameState> {
+    let pose = self.trajectory.last()?;
+    let biases = self.kalman_filter.get_imu_biases();
+    let (inlier_count, outlier_count) = if self.blackout {
+      (0, 0)
+    } else {
+      (self.visual_update.get_inlier_count(), self.visual_update.get_outlier_count())
+    };
+    Some(FrameState {
+      time: pose.time,
+      frame_number: self.frame_number,
+      position: pose.position.into(),
+      orientation: pose.orientation.into(),
+      velocity: self.kalman_filter.get_velocity().into(),
+      gyroscope_bias: biases.gyroscope.into(),
+      accelerometer_bias: biases.accelerometer.into(),
+      tracks_used: inlier_count,
+      outliers: outlier_count,
+    })
+  }
+
+  // Root-mean-square reprojection error of the visual update inliers on the
+  // last frame, in normalized image coordinates. A rising value is an early
+  // warning of tracking or calibration trouble. None if there was no visual
+  // update, eg during blackout.
+  pub fn get_reprojection_rms(&self) -> Option<f64> {
+    self.reprojection_rms
+  }
+
+  #[cfg(test)]
+  pub fn get_divergence_count(&self) -> usize {
+    self.divergence_count
+  }
+
   // Returns true if processed a frame.
   pub fn process(&mut self, input_data: &InputData) -> Result<bool> {
     if let Some(last_time) = self.last_time {
@@ -72,27 +339,54 @@ impl Vio {
 
     match input_data.sensor {
       InputDataSensor::Frame(ref frame) => {
-        // Processing must begin with a predict step to compute initial orientation.
-        if !self.initialized_orientation { return Ok(false) }
-
-        assert!(frame.images[0].width > 0 && frame.images[0].height > 0);
-        assert!(frame.images[1].width > 0 && frame.images[1].height > 0);
-        self.frame_number += 1;
-        if (self.frame_number - 1) % self.frame_sub == 0 {
-          self.pose_trail_frame_numbers.pop_back();
-          self.pose_trail_frame_numbers.push_back(self.frame_number);
-
-          self.process_frame(frame)?;
-          self.update_debug_data_3d();
-          return Ok(true);
+        if let Err(err) = frame.validate() {
+          warn!("Skipping frame at time {}: {}", input_data.time, err);
+          return Ok(false);
+        }
+        if !self.imu_frame_interpolation {
+          return self.process_frame_at(input_data.time, frame);
         }
+        // Without IMU samples between two frames, the previous frame is
+        // processed as is.
+        let processed = self.flush()?;
+        self.pending_images.resize(frame.images.len(), Image::empty());
+        for (pending, image) in self.pending_images.iter_mut().zip(&frame.images) {
+          pending.copy_from(image);
+        }
+        self.pending_frame = Some(input_data.time);
+        return Ok(processed);
       },
       InputDataSensor::Gyroscope(gyroscope) => {
+        let gyroscope = self.imu_convention.gyroscope(gyroscope, self.gravity);
         self.last_gyroscope = Some((input_data.time, gyroscope));
       },
       InputDataSensor::Accelerometer(accelerometer) => {
+        self.last_raw_accelerometer = accelerometer;
+        let accelerometer = self.imu_convention.accelerometer(accelerometer, self.gravity);
         self.last_accelerometer = Some((input_data.time, accelerometer));
       },
+      InputDataSensor::GroundTruth(imu_to_world) => {
+        if let Some(ground_truth) = &mut self.ground_truth {
+          ground_truth.imu_to_world = Some(imu_to_world);
+        }
+        return Ok(false);
+      },
+      InputDataSensor::Gps(fix) => {
+        if !self.use_gps { return Ok(false) }
+        if fix.accuracy.is_nan() || fix.accuracy <= 0. {
+          warn!("Skipping GPS fix at time {} with accuracy {}.", input_data.time, fix.accuracy);
+          return Ok(false);
+        }
+        // The filter has no position before the IMU initializes it.
+        if self.initialized_orientation {
+          let enu = self.gps_enu.get_or_insert_with(|| LocalEnu::new(&fix));
+          let covariance = fix.accuracy.powi(2) * Matrix3d::identity();
+          if !self.kalman_filter.gps_update(input_data.time, enu.to_enu(&fix), covariance) {
+            warn!("Skipping GPS fix at time {} as an outlier.", input_data.time);
+          }
+        }
+        return Ok(false);
+      },
     }
 
     // Very basic sample synchronization that only aims to cover the case that
@@ -102,39 +396,199 @@ impl Vio {
     if let (Some((time_g, gyroscope)), Some((time_a, accelerometer)))
       = (self.last_gyroscope, self.last_accelerometer)
     {
+      if time_a >= time_g && self.in_imu_warmup(time_g) {
+        self.last_gyroscope = None;
+        return Ok(false);
+      }
+      if time_a >= time_g && !self.initialized_orientation
+        && !self.initialize_orientation(time_g, gyroscope, accelerometer)
+      {
+        self.last_gyroscope = None;
+        return Ok(false);
+      }
       if time_a >= time_g {
-        self.process_imu(time_g, gyroscope, accelerometer);
+        // Without `imu_init_window`, the first sample initializes the
+        // orientation assuming the device is static.
+        if !self.initialized_orientation {
+          if let Some(warning) = check_gravity(self.last_raw_accelerometer, self.gravity, self.imu_convention) {
+            warn!("{}", warning);
+            self.warned_gravity = true;
+          }
+        }
+        let processed = self.process_imu(time_g, gyroscope, accelerometer)?;
         self.initialized_orientation = true;
         self.last_gyroscope = None;
         // Allow reuse of accelerometer samples.
+        return Ok(processed);
       }
     }
     Ok(false)
   }
 
+  // With `imu_init_window`, collects samples until the device has been at rest
+  // for the window and initializes the orientation from their mean. Returns
+  // false while the sample should be discarded.
+  fn initialize_orientation(&mut self, time: f64, gyroscope: Vector3d, accelerometer: Vector3d) -> bool {
+    let Some(imu_init) = &mut self.imu_init else { return true };
+    imu_init.add(time, gyroscope, accelerometer);
+    if !imu_init.is_full() { return false }
+    if !imu_init.is_stationary() {
+      if !self.warned_moving_init {
+        warn!("Device moving at time {:.3}, waiting for it to be at rest to initialize the orientation.", time);
+        self.warned_moving_init = true;
+      }
+      return false;
+    }
+    let gravity = imu_init.mean_accelerometer();
+    info!("Device at rest at time {:.3}, initializing the orientation.", time);
+    self.kalman_filter.initialize_orientation(gravity);
+    self.imu_init = None;
+    true
+  }
+
+  // True if the IMU sample at `time` should be discarded. Call once per sample.
+  fn in_imu_warmup(&mut self, time: f64) -> bool {
+    self.imu_sample_count += 1;
+    let first_imu_time = *self.first_imu_time.get_or_insert(time);
+    self.imu_sample_count <= self.imu_warmup_samples || time - first_imu_time < self.imu_warmup_time
+  }
+
+  // Processes the frame waiting for the next IMU sample, if any, as is. Call
+  // at the end of the input so that the last frame is not lost. Returns true
+  // if processed a frame.
+  pub fn flush(&mut self) -> Result<bool> {
+    let Some(time) = self.pending_frame.take() else { return Ok(false) };
+    let images = std::mem::take(&mut self.pending_images);
+    let processed = self.process_frame_at(time, &InputFrame { images: images.iter().collect() });
+    // Keep the buffers for the next frame.
+    self.pending_images = images;
+    processed
+  }
+
+  // Returns true if processed the frame.
+  fn process_frame_at(&mut self, time: f64, frame: &InputFrame) -> Result<bool> {
+    // Processing must begin with a predict step to compute initial orientation.
+    if !self.initialized_orientation { return Ok(false) }
+
+    let non_empty = frame.images.iter().all(|image| image.width > 0 && image.height > 0);
+    if !invariant(non_empty, "Frame images are not empty.") { return Ok(false) }
+    self.frame_number += 1;
+    if !(self.frame_number - 1).is_multiple_of(self.frame_sub) { return Ok(false) }
+    self.pose_trail_frame_numbers.pop_back();
+    self.pose_trail_frame_numbers.push_back(self.frame_number);
+
+    let start = std::time::Instant::now();
+    self.process_frame(frame)?;
+    let processing_time = start.elapsed().as_secs_f64();
+    self.update_trajectory(time);
+    self.update_debug_data_3d();
+    self.update_debug_data_hud(processing_time);
+    Ok(true)
+  }
+
   fn process_frame(&mut self, frame: &InputFrame) -> Result<()> {
-    assert!(MAX_FRAMES_IN_MEMORY >= 1);
     let mut unused_frame = None;
-    if self.frames.len() >= MAX_FRAMES_IN_MEMORY {
+    if self.frames.len() >= self.max_frames_in_memory {
       unused_frame = Some(self.frames.remove(0));
     };
 
-    self.frames.push(Frame::new(frame, unused_frame)?);
+    let mut images = frame.images.clone();
+    if images.len() < self.cameras.len() {
+      bail!("Frame has {} images but there are {} cameras.", images.len(), self.cameras.len());
+    }
+    // Extra images, eg the second one of a stereo video with one calibrated
+    // camera, are not used.
+    images.truncate(self.cameras.len());
+    if self.image_flip != ImageFlip::None {
+      self.flipped_images.resize(images.len(), Image::empty());
+      for (image, flipped) in images.iter().zip(self.flipped_images.iter_mut()) {
+        flip_image(image, self.image_flip, flipped);
+      }
+      images = self.flipped_images.iter().collect();
+    }
+    if let Some(rectification) = &mut self.rectification {
+      rectification.rectify(&self.original_cameras, &images, &mut self.rectified_images);
+      images = self.rectified_images.iter().collect();
+    }
+    self.frames.push(Frame::new(&InputFrame { images }, unused_frame)?);
 
-    let frame0 = self.frames.iter().rev().nth(1);
     let frame1 = self.frames.iter().rev().nth(0).unwrap();
-    self.tracker.process(frame0, frame1, &self.cameras, self.frame_number);
+    let rotations = self.camera_rotations(self.frames.len());
+    self.tracker.process(&self.frames, &self.cameras, self.frame_number, &rotations);
+    self.update_debug_data_ground_truth();
+
+    if let (Some(flip_check), Some(rotation)) = (&mut self.flip_check, rotations.get(1)) {
+      let n = self.pose_trail_frame_numbers.len();
+      let image = &frame1.cameras[0].image;
+      flip_check.process(
+        self.tracker.get_tracks(),
+        &self.cameras[0],
+        rotation,
+        [self.pose_trail_frame_numbers[n - 2], self.pose_trail_frame_numbers[n - 1]],
+        (image.width, image.height),
+      );
+    }
+
+    // During blackout the pose is propagated only with the IMU. The tracker
+    // keeps trying to detect new features on every frame. Note that the
+    // stationarity check would trivially pass without tracks.
+    let seen_count = self.tracker.get_tracks().iter()
+      .filter(|track| track.points.iter().last().unwrap().frame_number == self.frame_number)
+      .count();
+    let blackout = seen_count <= self.blackout_max_tracks;
+    if blackout != self.blackout {
+      if blackout {
+        info!("Tracking blackout started at frame {}.", self.frame_number);
+      }
+      else {
+        info!("Tracking blackout ended at frame {}.", self.frame_number);
+      }
+      self.blackout = blackout;
+    }
 
-    if self.stationary.check(self.tracker.get_tracks()) {
+    // The tracks of the first frame have a single observation each, so they
+    // cannot support any update yet.
+    self.reprojection_rms = None;
+    let bootstrap = self.tracker.is_bootstrap_frame();
+    // The IMU detects rest also during blackouts, where the tracks cannot.
+    let imu_stationary = match &mut self.imu_stationary {
+      Some(imu_window) => self.kalman_filter.zero_velocity_update(imu_window, self.kf_noise_zero_velocity),
+      None => false,
+    };
+    let visual_stationary = !blackout && !bootstrap && self.stationary.check(self.tracker.get_tracks());
+    if self.zupt && visual_stationary && !imu_stationary {
       self.kalman_filter.update_zero_velocity(self.kf_noise_zero_velocity);
     }
+    if !blackout && !bootstrap {
 
-    self.visual_update.process(
-      &mut self.kalman_filter,
-      self.tracker.get_tracks(),
-      [&self.cameras[0], &self.cameras[1]],
-      &self.pose_trail_frame_numbers,
-    );
+      if !self.anchors.is_empty() {
+        let mut imu_to_worlds = vec![];
+        self.kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+        if let Some(imu_to_world) = imu_to_worlds.first() {
+          self.anchors.match_tracks(
+            self.tracker.get_tracks(),
+            &self.cameras[0],
+            imu_to_world,
+            self.frame_number,
+          );
+        }
+      }
+
+      // One or two cameras, see `new()`.
+      let cameras = [&self.cameras[0], self.cameras.last().unwrap()];
+      self.visual_update.process(
+        &mut self.kalman_filter,
+        self.tracker.get_tracks(),
+        &cameras[..self.cameras.len()],
+        &self.pose_trail_frame_numbers,
+        &self.anchors,
+        frame1.cameras[0].image.height,
+      );
+      self.reprojection_rms = self.visual_update.get_reprojection_rms();
+    }
+    if let Some(divergence) = self.kalman_filter.check_divergence() {
+      self.reset_filter(divergence);
+    }
 
     self.kalman_filter.augment_pose();
     self.pose_trail_frame_numbers.push_back(*self.pose_trail_frame_numbers.back().unwrap());
@@ -145,8 +599,166 @@ impl Vio {
     Ok(())
   }
 
-  fn process_imu(&mut self, time: f64, gyroscope: Vector3d, accelerometer: Vector3d) {
+  // See `KalmanFilter::soft_reset()`. The tracks are kept, but only their
+  // observations from the frame of the reset on are used, as the pose trail
+  // starts over.
+  fn reset_filter(&mut self, divergence: Divergence) {
+    warn!("Filter diverged at frame {}: {}. Resetting.", self.frame_number, divergence);
+    let gravity = self.last_imu.map_or(Vector3d::new(0., 0., self.gravity), |(_, _, accelerometer)| accelerometer);
+    self.kalman_filter.soft_reset(gravity);
+    self.pose_trail_frame_numbers.clear();
+    self.pose_trail_frame_numbers.push_back(self.frame_number);
+    self.divergence_count += 1;
+    DEBUG_DATA.lock().unwrap().divergence_events.push(DivergenceEvent {
+      frame_number: self.frame_number,
+      divergence,
+    });
+  }
+
+  // Rotations of the first camera from the previous `count - 1` frames to the
+  // current one, using the predicted current pose. Empty if the pose trail
+  // does not reach that far.
+  fn camera_rotations(&self, count: usize) -> Vec<Matrix3d> {
+    if count > self.pose_trail_len { return vec![] }
+    let indices: Vec<usize> = (0..count).collect();
+    let mut imu_to_worlds = vec![];
+    self.kalman_filter.get_imu_to_worlds(&indices, &mut imu_to_worlds);
+    if imu_to_worlds.len() != count { return vec![] }
+    let imu_to_camera = self.cameras[0].imu_to_camera;
+    let world_to_camera = imu_to_camera * affine_inverse(imu_to_worlds[0]);
+    imu_to_worlds.iter()
+      .map(|imu_to_world| rotation!(world_to_camera * imu_to_world * affine_inverse(imu_to_camera)).into())
+      .collect()
+  }
+
+  // Returns true if processed a pending frame.
+  fn process_imu(&mut self, time: f64, gyroscope: Vector3d, accelerometer: Vector3d) -> Result<bool> {
+    if mem::take(&mut self.resumed) {
+      self.kalman_filter.skip_gap(time);
+    }
+    if let Some((last_time, _, _)) = self.last_imu {
+      if self.imu_max_gap > 0. && time - last_time > self.imu_max_gap {
+        // Propagating over the gap with a single sample would make the state
+        // and its covariance meaningless, and tracks from before the gap
+        // would not fit the resulting poses.
+        warn!("IMU gap of {:.3} seconds at time {:.3}, not propagating over it.", time - last_time, last_time);
+        self.kalman_filter.skip_gap(time);
+        self.tracker.clear_tracks();
+        if let Some(imu_stationary) = &mut self.imu_stationary {
+          imu_stationary.clear();
+        }
+        self.last_imu = None;
+      }
+    }
+    self.calibrate_imu_noise(time, gyroscope, accelerometer);
+    if let Some(imu_stationary) = &mut self.imu_stationary {
+      imu_stationary.add(time, gyroscope, accelerometer);
+    }
+    let mut processed = false;
+    if let Some(frame_time) = self.pending_frame {
+      // Propagate exactly to the frame time.
+      if let Some(last_imu) = self.last_imu {
+        if last_imu.0 < frame_time {
+          let (g, a) = interpolate_imu(frame_time, last_imu, (time, gyroscope, accelerometer));
+          self.kalman_filter.predict(frame_time, g, a);
+        }
+      }
+      processed = self.flush()?;
+    }
     self.kalman_filter.predict(time, gyroscope, accelerometer);
+    self.last_imu = Some((time, gyroscope, accelerometer));
+    Ok(processed)
+  }
+
+  fn calibrate_imu_noise(&mut self, time: f64, gyroscope: Vector3d, accelerometer: Vector3d) {
+    let Some(calibration) = &mut self.imu_noise_calibration else { return };
+    let Some(imu_noise) = calibration.add(time, gyroscope, accelerometer) else { return };
+    self.imu_noise_calibration = None;
+    let (noise_g, noise_a) = imu_noise.sample_stds();
+    info!(
+      "Estimated IMU noise at {:.1} Hz: gyroscope {:.3e} rad/s/sqrt(Hz), random walk {:.3e} rad/s^2/sqrt(Hz); \
+      accelerometer {:.3e} m/s^2/sqrt(Hz), random walk {:.3e} m/s^3/sqrt(Hz). \
+      Suggested parameters: --kf-noise-g {:.3e} --kf-noise-a {:.3e}",
+      1. / imu_noise.dt,
+      imu_noise.gyroscope.white_noise, imu_noise.gyroscope.random_walk,
+      imu_noise.accelerometer.white_noise, imu_noise.accelerometer.random_walk,
+      noise_g, noise_a,
+    );
+    if self.imu_noise_calibration_apply {
+      self.kalman_filter.set_imu_noise(
+        noise_g,
+        noise_a,
+        imu_noise.gyroscope.random_walk,
+        imu_noise.accelerometer.random_walk,
+      );
+    }
+    self.imu_noise = Some(imu_noise);
+  }
+
+  // Result of `imu_noise_calibration_time`, once done.
+  #[allow(dead_code)]
+  pub fn get_imu_noise(&self) -> Option<ImuNoise> {
+    self.imu_noise
+  }
+
+  fn update_trajectory(&mut self, time: f64) {
+    let mut imu_to_worlds = vec![];
+    self.kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+    if let Some(imu_to_world) = imu_to_worlds.first() {
+      self.trajectory.push(TrajectoryPose::new(time, imu_to_world));
+    }
+    let (roll, pitch) = self.kalman_filter.get_roll_pitch();
+    self.imu_states.push(ImuState {
+      time,
+      roll,
+      pitch,
+      tilt_std: self.kalman_filter.get_tilt_std(),
+      gravity: self.kalman_filter.get_gravity(),
+      biases: self.kalman_filter.get_imu_biases(),
+      bias_stds: self.kalman_filter.get_imu_bias_stds(),
+      residual_acceleration: self.last_imu
+        .map_or(Vector3d::zeros(), |(_, _, accelerometer)| self.kalman_filter.get_residual_acceleration(accelerometer)),
+      velocity: self.kalman_filter.get_velocity(),
+      velocity_std: self.kalman_filter.get_velocity_std(),
+    });
+  }
+
+  fn update_debug_data_ground_truth(&self) {
+    let show_ground_truth = PARAMETER_SET.lock().unwrap().show_ground_truth;
+    if !show_ground_truth { return }
+    let d = &mut DEBUG_DATA.lock().unwrap();
+    for i in 0..2 {
+      if let (Some(ground_truth), Some(camera)) = (&self.ground_truth, self.cameras.get(i)) {
+        ground_truth.reproject(camera, &mut d.ground_truth_reprojections[i]);
+      }
+      else {
+        d.ground_truth_reprojections[i].clear();
+      }
+    }
+  }
+
+  fn update_debug_data_hud(&mut self, processing_time: f64) {
+    let now = std::time::Instant::now();
+    let elapsed = self.last_frame_instant.map(|instant| (now - instant).as_secs_f64());
+    self.last_frame_instant = Some(now);
+    if !PARAMETER_SET.lock().unwrap().show_hud { return }
+    let track_count = self.tracker.get_tracks().iter()
+      .filter(|track| track.points.iter().last().unwrap().frame_number == self.frame_number)
+      .count();
+    let d = &mut DEBUG_DATA.lock().unwrap();
+    d.hud = HudStats {
+      frame_number: self.frame_number,
+      fps: elapsed.map_or(0., |elapsed| if elapsed > 0. { 1. / elapsed } else { 0. }),
+      processing_time: 1e3 * processing_time,
+      track_count,
+      detected_count: self.tracker.get_detected_count(),
+      retired_count: self.tracker.get_retired_count(),
+      new_track_count: self.tracker.get_new_track_count(),
+      outlier_count: if self.blackout { 0 } else { self.visual_update.get_outlier_count() },
+      speed: self.kalman_filter.get_velocity().norm(),
+      position: self.trajectory.last().map_or(Vector3d::zeros(), |pose| pose.position),
+      reprojection_rms: self.reprojection_rms,
+    };
   }
 
   fn update_debug_data_3d(&self) {
@@ -155,3 +767,602 @@ impl Vio {
     self.kalman_filter.get_imu_to_worlds(&indices, &mut d.pose_trail);
   }
 }
+
+// Gyroscope and accelerometer at `time` between the samples `s0` and `s1`,
+// linearly interpolated.
+pub fn interpolate_imu(
+  time: f64,
+  s0: (f64, Vector3d, Vector3d),
+  s1: (f64, Vector3d, Vector3d),
+) -> (Vector3d, Vector3d) {
+  let dt = s1.0 - s0.0;
+  if dt <= 0. { return (s1.1, s1.2) }
+  let t = ((time - s0.0) / dt).clamp(0., 1.);
+  (s0.1 + t * (s1.1 - s0.1), s0.2 + t * (s1.2 - s0.2))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_blackout() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let textured = textured_image(width, height, 1);
+    let black = black_image(width, height);
+
+    let mut time = 0.;
+    let mut blackouts = vec![];
+    for frame_ind in 0..15 {
+      let image = if (5..10).contains(&frame_ind) { &black } else { &textured };
+      assert!(process_static_frame(&mut vio, &mut time, [image, image]));
+      blackouts.push(vio.is_blackout());
+    }
+
+    assert!(blackouts[..5].iter().all(|x| !x));
+    assert!(blackouts[5..10].iter().all(|x| *x));
+    assert!(blackouts[10..].iter().all(|x| !x));
+    // The filter coasted on IMU during the blackout.
+    let trajectory = vio.get_trajectory();
+    assert_eq!(trajectory.len(), 15);
+    for pose in trajectory {
+      assert!(pose.position.iter().all(|x| x.is_finite()));
+      assert!(pose.position.norm() < 1.);
+    }
+  }
+
+  #[test]
+  fn test_frame_state() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let textured = textured_image(width, height, 1);
+    let black = black_image(width, height);
+    assert!(vio.get_frame_state().is_none());
+
+    let mut time = 0.;
+    let mut states = vec![];
+    for frame_ind in 0..8 {
+      let image = if (4..6).contains(&frame_ind) { &black } else { &textured };
+      assert!(process_static_frame(&mut vio, &mut time, [image, image]));
+      states.push(vio.get_frame_state().unwrap());
+    }
+
+    let pose = vio.get_trajectory().last().unwrap();
+    let state = states.last().unwrap();
+    assert_eq!(state.time, pose.time);
+    assert_eq!(state.frame_number, 8);
+    assert_eq!(state.position, <[f64; 3]>::from(pose.position));
+    assert_eq!(state.orientation, <[f64; 4]>::from(pose.orientation));
+    assert_eq!(state.velocity, <[f64; 3]>::from(vio.kalman_filter.get_velocity()));
+    assert!(states[2].tracks_used > 0);
+    // No visual update during the blackout.
+    assert!(states[4..6].iter().all(|state| state.tracks_used == 0 && state.outliers == 0));
+  }
+
+  #[test]
+  fn test_divergence_reset() {
+    // Above the initial position uncertainty, reached after a few seconds of
+    // coasting on IMU, which the static IMU must not hold at rest.
+    let _guard = set_test_parameters(|p| {
+      p.divergence_max_position_std = 0.02;
+      p.imu_stationary_window = 0.;
+    });
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let textured = textured_image(width, height, 1);
+    let black = black_image(width, height);
+
+    let mut time = 0.;
+    let mut divergence_counts = vec![];
+    let mut update_counts = vec![];
+    for frame_ind in 0..55 {
+      let image = if (5..45).contains(&frame_ind) { &black } else { &textured };
+      assert!(process_static_frame(&mut vio, &mut time, [image, image]));
+      divergence_counts.push(vio.get_divergence_count());
+      update_counts.push(vio.kalman_filter.get_update_count());
+    }
+
+    assert!(divergence_counts[..25].iter().all(|count| *count == 0));
+    assert!(divergence_counts[44] > 0);
+    // Tracking again keeps the restarted filter in bounds.
+    assert!(divergence_counts[45..].iter().all(|count| *count == divergence_counts[44]));
+    assert!(update_counts[54] > update_counts[45]);
+    for pose in vio.get_trajectory() {
+      assert!(pose.position.iter().all(|x| x.is_finite()));
+      assert!(pose.position.norm() < 1.);
+    }
+  }
+
+  #[test]
+  fn test_invalid_frames() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let image = textured_image(width, height, 1);
+    let empty = black_image(0, 0);
+    let tiny = black_image(1, 1);
+    fn frame(time: f64, image: &Image) -> InputData<'_> {
+      InputData { time, sensor: InputDataSensor::Frame(InputFrame { images: vec![image, image] }) }
+    }
+
+    // Frames that cannot be processed do not initialize `Vio`.
+    let mut vio_init = VioInit::new(test_cameras(width, height), None, vec![], None, None, None);
+    assert!(vio_init.try_init(&frame(0., &empty)).is_none());
+    assert!(vio_init.try_init(&frame(0., &tiny)).is_none());
+    let mut vio = vio_init.try_init(&frame(0., &image)).unwrap().unwrap();
+
+    let mut time = 0.;
+    let mut processed = vec![];
+    for frame_ind in 0..6 {
+      for _ in 0..10 {
+        process_static_imu(&mut vio, time);
+        time += 0.01;
+      }
+      let image = match frame_ind { 2 => &empty, 3 => &tiny, _ => &image };
+      processed.push(vio.process(&frame(time, image)).unwrap());
+    }
+    assert_eq!(processed, vec![true, true, false, false, true, true]);
+    assert_eq!(vio.get_trajectory().len(), 4);
+    assert!(vio.get_tracks().iter().any(|track| track.points.len() >= 3));
+  }
+
+  #[test]
+  fn test_detection_mask_rectify() {
+    let (width, height) = (160, 120);
+    let image = textured_image(width, height, 1);
+    let frame = InputData { time: 0., sensor: InputDataSensor::Frame(InputFrame { images: vec![&image, &image] }) };
+    let init = |rectify: bool| {
+      let _guard = set_test_parameters(|p| p.rectify = rectify);
+      let mask = DetectionMask::from_image(Image { data: vec![255; width * height], width, height });
+      let mut vio_init = VioInit::new(test_cameras(width, height), None, vec![], None, Some(mask), None);
+      vio_init.try_init(&frame).unwrap().map(|_| ())
+    };
+    assert!(init(false).is_ok());
+    assert!(init(true).is_err());
+  }
+
+  #[test]
+  fn test_use_gps() {
+    let (width, height) = (160, 120);
+    let image = textured_image(width, height, 1);
+    let fix = |altitude: f64, accuracy: f64| GpsFix { latitude: 60.17, longitude: 24.94, altitude, accuracy };
+    let altitude = |vio: &Vio| {
+      let mut imu_to_worlds = vec![];
+      vio.kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+      imu_to_worlds[0][(2, 3)]
+    };
+    // Number of updates that each fix made.
+    let run = |use_gps: bool| {
+      let _guard = set_test_parameters(|p| p.use_gps = use_gps);
+      let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+      let mut time = 0.;
+      let mut updates = vec![];
+      // The origin, a fix 1 m up, one without accuracy and a jump of 1 km.
+      for fix in [fix(20., 1.), fix(21., 1.), fix(21., f64::NAN), fix(1020., 1.)] {
+        assert!(process_static_frame(&mut vio, &mut time, [&image, &image]));
+        let update_count = vio.kalman_filter.get_update_count();
+        let z = altitude(&vio);
+        assert!(!vio.process(&InputData { time, sensor: InputDataSensor::Gps(fix) }).unwrap());
+        updates.push(vio.kalman_filter.get_update_count() - update_count);
+        if update_count != vio.kalman_filter.get_update_count() {
+          // Only the altitude is used before the yaw is known.
+          assert!(altitude(&vio) > z);
+        }
+      }
+      updates
+    };
+    assert_eq!(run(false), [0, 0, 0, 0]);
+    // The first fix only sets the origin.
+    assert_eq!(run(true), [0, 1, 0, 0]);
+  }
+
+  #[test]
+  fn test_bootstrap() {
+    let _guard = set_test_parameters(|p| p.bootstrap_tracks = Some(20));
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let image = textured_image(width, height, 1);
+    let mut time = 0.;
+    let mut process = |vio: &mut Vio| assert!(process_static_frame(vio, &mut time, [&image, &image]));
+
+    // The first frame only seeds tracks.
+    process(&mut vio);
+    assert!(vio.tracker.is_bootstrap_frame());
+    let tracks = vio.tracker.get_tracks();
+    assert!(!tracks.is_empty());
+    assert!(tracks.len() <= 20);
+    assert!(tracks.iter().all(|track| track.points.len() == 1 && track.points[0].frame_number == 1));
+    assert_eq!(vio.kalman_filter.get_update_count(), 0);
+    assert!(!vio.is_blackout());
+
+    // The next frame tracks them, detects up to the usual maximum and updates.
+    process(&mut vio);
+    assert!(!vio.tracker.is_bootstrap_frame());
+    let tracks = vio.tracker.get_tracks();
+    assert!(tracks.len() > 20);
+    assert!(tracks.iter().any(|track| track.points.len() == 2));
+    assert!(vio.kalman_filter.get_update_count() > 0);
+  }
+
+  #[test]
+  fn test_imu_frame_interpolation() {
+    let _guard = set_test_parameters(|p| p.imu_frame_interpolation = true);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let accelerometer = Vector3d::new(0., 0., gravity);
+    // Accelerating rotation about the gravity axis, yaw `t + t^2`.
+    let gyroscope = |t: f64| Vector3d::new(0., 0., 1. + 2. * t);
+    let frame_time: f64 = 0.155;
+    let yaw = |kalman_filter: &KalmanFilter| {
+      let mut imu_to_worlds = vec![];
+      kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+      let R = imu_to_worlds[0];
+      R[(1, 0)].atan2(R[(0, 0)])
+    };
+    let propagate = |dt: f64, end: f64| {
+      let mut kalman_filter = KalmanFilter::new();
+      let mut i = 0;
+      while dt * i as f64 <= end + 1e-9 {
+        kalman_filter.predict(dt * i as f64, gyroscope(dt * i as f64), accelerometer);
+        i += 1;
+      }
+      kalman_filter
+    };
+
+    let reference = frame_time + frame_time.powi(2);
+    assert!((yaw(&propagate(1e-5, frame_time)) - reference).abs() < 1e-4);
+    // 100Hz samples, propagation stops at the sample before the frame.
+    let mut kalman_filter = propagate(0.01, frame_time);
+    let error_last_sample = (yaw(&kalman_filter) - reference).abs();
+    // Continue to the frame time with an interpolated sample.
+    let (g, a) = interpolate_imu(frame_time, (0.15, gyroscope(0.15), accelerometer), (0.16, gyroscope(0.16), accelerometer));
+    kalman_filter.predict(frame_time, g, a);
+    let error_interpolated = (yaw(&kalman_filter) - reference).abs();
+    assert!(error_interpolated < 0.5 * error_last_sample, "{} {}", error_interpolated, error_last_sample);
+
+    // `Vio` holds the frame until the next IMU sample.
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let image = textured_image(width, height, 1);
+    process_static_imu(&mut vio, 0.);
+    assert!(!process_frame(&mut vio, 0.005, [&image, &image]));
+    assert!(vio.get_trajectory().is_empty());
+    vio.process(&InputData { time: 0.01, sensor: InputDataSensor::Gyroscope(Vector3d::zeros()) }).unwrap();
+    assert!(vio.process(&InputData { time: 0.01, sensor: InputDataSensor::Accelerometer(accelerometer) }).unwrap());
+    assert_eq!(vio.get_trajectory().len(), 1);
+    assert_eq!(vio.get_trajectory()[0].time, 0.005);
+
+    // The last frame of the input waits until flushed.
+    assert!(!process_frame(&mut vio, 0.015, [&image, &image]));
+    assert_eq!(vio.get_trajectory().len(), 1);
+    assert!(vio.flush().unwrap());
+    assert!(!vio.flush().unwrap());
+    assert_eq!(vio.get_trajectory().len(), 2);
+    assert_eq!(vio.get_trajectory()[1].time, 0.015);
+  }
+
+  #[test]
+  fn test_imu_convention() {
+    // A static device with iOS samples, whose accelerometer is in units of g
+    // and points down.
+    let imu = |_| (Vector3d::zeros(), Vector3d::new(0., 0., -1.));
+    let (width, height) = (160, 120);
+    {
+      let _guard = set_test_parameters(|p| p.imu_convention = ImuConvention::Ios);
+      let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+      let image = textured_image(width, height, 1);
+      let mut time = 0.;
+      for _ in 0..10 {
+        assert!(process_imu_and_frame(&mut vio, &mut time, imu, [&image, &image]));
+      }
+      assert!(!vio.warned_gravity);
+      assert_eq!(vio.get_trajectory().len(), 10);
+      for pose in vio.get_trajectory() {
+        assert!(pose.position.norm() < 0.01);
+      }
+    }
+
+    // The same samples without the convention.
+    let _guard = set_test_parameters(|_| {});
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let (gyroscope, accelerometer) = imu(0.);
+    process_imu(&mut vio, 0., gyroscope, accelerometer);
+    assert!(vio.warned_gravity);
+  }
+
+  #[test]
+  fn test_clear_tracks() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let image = textured_image(width, height, 1);
+    let mut time = 0.;
+    let mut process = |vio: &mut Vio| assert!(process_static_frame(vio, &mut time, [&image, &image]));
+    for _ in 0..3 {
+      process(&mut vio);
+    }
+
+    let tracks = vio.get_tracks();
+    assert!(!tracks.is_empty());
+    let frame_number = vio.get_frame_number();
+    let mut ids: Vec<usize> = tracks.iter().map(|track| track.id.0).collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), tracks.len());
+    for track in tracks {
+      assert_eq!(track.points.last().unwrap().frame_number, frame_number);
+      let p = track.position()[0];
+      assert!(p[0] >= 0. && p[1] >= 0. && p[0] < width as f64 && p[1] < height as f64);
+      assert_eq!(track.age(frame_number), frame_number - track.points[0].frame_number);
+    }
+    // The static camera keeps the tracks from the first frame.
+    assert!(tracks.iter().any(|track| track.age(frame_number) == 2));
+
+    let max_id = *ids.last().unwrap();
+    vio.clear_tracks();
+    assert!(vio.get_tracks().is_empty());
+    process(&mut vio);
+    let tracks = vio.get_tracks();
+    assert!(!tracks.is_empty());
+    assert!(tracks.iter().all(|track| track.id.0 > max_id && track.points.len() == 1));
+  }
+
+  #[test]
+  fn test_monocular() {
+    let _guard = set_test_parameters(|_| {});
+    let (width, height) = (160, 120);
+    assert!(Vio::new(vec![], 0.2, None).is_err());
+    let run = |image: &Image| {
+      let mut cameras = test_cameras(width, height);
+      cameras.truncate(1);
+      let mut vio = Vio::new(cameras, 0.2, None).unwrap();
+      let mut time = 0.;
+      for frame_ind in 0..6 {
+        for _ in 0..10 {
+          process_static_imu(&mut vio, time);
+          time += 0.01;
+        }
+        // The second image of stereo frames is ignored.
+        let images = if frame_ind % 2 == 0 { vec![image] } else { vec![image, image] };
+        assert!(vio.process(&InputData { time, sensor: InputDataSensor::Frame(InputFrame { images }) }).unwrap());
+      }
+      vio
+    };
+
+    let vio = run(&textured_image(width, height, 1));
+    let tracks = vio.get_tracks();
+    assert!(tracks.iter().any(|track| track.points.len() == 6));
+    assert!(tracks.iter().all(|track| track.camera == Some(0)));
+    let trajectory = vio.get_trajectory();
+    assert_eq!(trajectory.len(), 6);
+    for pose in trajectory {
+      assert!(pose.position.iter().all(|x| x.is_finite()));
+      assert!(pose.position.norm() < 0.1);
+    }
+    // The static features of the single camera hold the position, which
+    // drifts with the IMU alone.
+    let position_std = |vio: &Vio| vio.get_camera_pose_stds().fixed_rows::<3>(0).amax();
+    assert!(position_std(&vio) < 1e-2 * position_std(&run(&black_image(width, height))));
+  }
+
+  #[test]
+  fn test_imu_gap() {
+    let _guard = set_test_parameters(|p| p.imu_max_gap = 0.5);
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let image = textured_image(width, height, 1);
+    let mut time = 0.;
+    for frame_ind in 0..20 {
+      // The IMU drops out for three seconds while the camera keeps going.
+      let imu = !(5..8).contains(&frame_ind);
+      for _ in 0..100 {
+        if imu {
+          process_static_imu(&mut vio, time);
+        }
+        time += 0.01;
+      }
+      assert!(process_frame(&mut vio, time, [&image, &image]));
+    }
+
+    let trajectory = vio.get_trajectory();
+    assert_eq!(trajectory.len(), 20);
+    for pose in trajectory {
+      assert!(pose.position.iter().all(|x| x.is_finite()));
+      assert!(pose.position.norm() < 0.1);
+    }
+    for state in vio.get_imu_states() {
+      assert!(state.tilt_std.is_finite() && state.tilt_std < 0.1);
+    }
+    let velocity_std = vio.kalman_filter.get_velocity_std().norm();
+    assert!(velocity_std.is_finite() && velocity_std < 0.2);
+    // Tracks were restarted when the IMU came back before the ninth frame.
+    assert!(!vio.get_tracks().is_empty());
+    assert!(vio.get_tracks().iter().all(|track| track.points[0].frame_number >= 9));
+  }
+
+  #[test]
+  fn test_imu_warmup() {
+    // Estimated tilt of the device after garbage samples followed by static
+    // ones. Without visual updates the initial orientation is never corrected.
+    let tilt = |f: fn(&mut ParameterSet)| {
+      let _guard = set_test_parameters(f);
+      let gravity = PARAMETER_SET.lock().unwrap().gravity;
+      let mut vio = Vio::new(test_cameras(160, 120), 0.2, None).unwrap();
+      let mut time = 0.;
+      for _ in 0..5 {
+        vio.process(&InputData { time, sensor: InputDataSensor::Gyroscope(Vector3d::new(1., 0., 0.)) }).unwrap();
+        vio.process(&InputData { time, sensor: InputDataSensor::Accelerometer(Vector3d::new(gravity, 0., 0.)) }).unwrap();
+        time += 0.01;
+      }
+      for _ in 0..50 {
+        process_static_imu(&mut vio, time);
+        time += 0.01;
+      }
+      let mut imu_to_worlds = vec![];
+      vio.kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+      // Angle between the IMU z-axis and up.
+      imu_to_worlds[0][(2, 2)].clamp(-1., 1.).acos()
+    };
+
+    assert!(tilt(|_| {}) > 0.5);
+    assert!(tilt(|p| p.imu_warmup_samples = 5) < 1e-3);
+    assert!(tilt(|p| p.imu_warmup_samples = 4) > 0.5);
+    assert!(tilt(|p| p.imu_warmup_time = 0.045) < 1e-3);
+  }
+
+  #[test]
+  fn test_imu_init_window() {
+    // Initialization time and estimated tilt of the device shaken for a while
+    // and then at rest.
+    let run = |f: fn(&mut ParameterSet)| {
+      let _guard = set_test_parameters(f);
+      let gravity = PARAMETER_SET.lock().unwrap().gravity;
+      let (width, height) = (160, 120);
+      let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+      let image = textured_image(width, height, 1);
+      let mut time = 0.;
+      let mut init_time = None;
+      for i in 0..100 {
+        if i < 30 {
+          let a = Vector3d::new(gravity * (0.3 * i as f64).sin(), 0., gravity * (0.3 * i as f64).cos());
+          vio.process(&InputData { time, sensor: InputDataSensor::Gyroscope(Vector3d::new(1., 0., 0.)) }).unwrap();
+          vio.process(&InputData { time, sensor: InputDataSensor::Accelerometer(a) }).unwrap();
+        }
+        else {
+          process_static_imu(&mut vio, time);
+        }
+        if i % 10 == 9 && process_frame(&mut vio, time, [&image, &image]) && init_time.is_none() {
+          init_time = Some(time);
+        }
+        time += 0.01;
+      }
+      let mut imu_to_worlds = vec![];
+      vio.kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+      (init_time.unwrap(), imu_to_worlds[0][(2, 2)].clamp(-1., 1.).acos())
+    };
+
+    let (init_time, tilt) = run(|_| {});
+    assert!(init_time < 0.1);
+    assert!(tilt > 0.1);
+    // No frames are processed until the device has been at rest for the window.
+    let (init_time, tilt) = run(|p| p.imu_init_window = 0.2);
+    assert!(init_time > 0.49);
+    assert!(tilt < 1e-3);
+  }
+
+  #[test]
+  fn test_disable_stages() {
+    // Update count and IMU biases after a static sequence with a biased gyroscope.
+    let run = |f: fn(&mut ParameterSet)| {
+      let _guard = set_test_parameters(f);
+      let gravity = PARAMETER_SET.lock().unwrap().gravity;
+      let (width, height) = (160, 120);
+      let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+      let image = textured_image(width, height, 1);
+      let mut time = 0.;
+      let imu = |_| (Vector3d::new(0.01, 0., 0.), Vector3d::new(0., 0., gravity));
+      for _ in 0..10 {
+        assert!(process_imu_and_frame(&mut vio, &mut time, imu, [&image, &image]));
+      }
+      (vio.kalman_filter.get_update_count(), vio.get_imu_biases())
+    };
+
+    let (update_count, biases) = run(|_| {});
+    assert!(biases.gyroscope.norm() > 1e-4);
+    assert!(biases.accelerometer.norm() > 0.);
+    // Without zero-velocity updates only the visual ones remain.
+    let (update_count_no_zupt, _) = run(|p| p.disable_zupt = true);
+    assert!(update_count_no_zupt > 0);
+    assert!(update_count_no_zupt < update_count);
+    let (_, biases) = run(|p| p.disable_bias_estimation = true);
+    assert_eq!(biases.gyroscope, Vector3d::zeros());
+    assert_eq!(biases.accelerometer, Vector3d::zeros());
+  }
+
+  #[test]
+  fn test_imu_stationary_update() {
+    // Update count and velocity uncertainty after a static sequence without
+    // any tracks, so that only the IMU can tell the device is at rest.
+    let run = |f: fn(&mut ParameterSet)| {
+      let _guard = set_test_parameters(f);
+      let (width, height) = (160, 120);
+      let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+      let black = black_image(width, height);
+      let mut time = 0.;
+      for _ in 0..10 {
+        assert!(process_static_frame(&mut vio, &mut time, [&black, &black]));
+        assert!(vio.is_blackout());
+      }
+      (vio.kalman_filter.get_update_count(), vio.kalman_filter.get_velocity_std().norm())
+    };
+
+    let (update_count, velocity_std) = run(|p| p.imu_stationary_window = 0.);
+    assert_eq!(update_count, 0);
+    // The first frames are within the window.
+    let (update_count_imu, velocity_std_imu) = run(|p| p.imu_stationary_window = 0.25);
+    assert_eq!(update_count_imu, 8);
+    assert!(velocity_std_imu < 0.1 * velocity_std);
+    let (update_count_default, _) = run(|_| {});
+    assert_eq!(update_count_default, 5);
+    let (update_count, _) = run(|p| {
+      p.imu_stationary_window = 0.25;
+      p.disable_zupt = true;
+    });
+    assert_eq!(update_count, 0);
+  }
+
+  #[test]
+  fn test_gyroscope_bias_estimation() {
+    let _guard = set_test_parameters(|p| p.kf_noise_bga = 0.05);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let bias = Vector3d::new(0.01, -0.02, 0.005);
+    let (width, height) = (160, 120);
+    let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+    let image = textured_image(width, height, 1);
+    let mut time = 0.;
+    // Four seconds at rest.
+    for _ in 0..40 {
+      assert!(process_imu_and_frame(&mut vio, &mut time, |_| (bias, Vector3d::new(0., 0., gravity)), [&image, &image]));
+    }
+    let ind = vio.kalman_filter.get_gyro_bias_ind();
+    let estimate: Vector3d = vio.kalman_filter.get_state_mut().fixed_rows::<3>(ind).into();
+    assert_eq!(estimate, vio.get_imu_biases().gyroscope);
+    assert!((estimate - bias).norm() < 0.1 * bias.norm(), "{}", estimate);
+    let ind = vio.kalman_filter.get_accel_bias_ind();
+    assert!(vio.kalman_filter.get_state_mut().fixed_rows::<3>(ind).norm() < 0.1);
+  }
+
+  #[test]
+  fn test_seed() {
+    // Camera moving sideways over a textured scene, with fewer tracks per
+    // update than tracked so that the visual update picks them randomly, and
+    // the flow RANSAC enabled.
+    let (width, height) = (160, 120);
+    let scene = textured_image(width + 40, height, 1);
+    let run = |seed: u64| {
+      let _guard = set_test_parameters(|p| {
+        p.seed = seed;
+        p.visual_update_max_tracks = 10;
+        p.flow_ransac_max_error = 1.;
+      });
+      let mut vio = Vio::new(test_cameras(width, height), 0.2, None).unwrap();
+      let mut time = 0.;
+      for frame_ind in 0..20 {
+        let data = (0..height)
+          .flat_map(|y| scene.data[(y * scene.width + 2 * frame_ind)..][..width].iter().copied())
+          .collect();
+        let image = Image { data, width, height };
+        assert!(process_static_frame(&mut vio, &mut time, [&image, &image]));
+      }
+      let path = std::env::temp_dir().join(format!("violet-seed-{}-{}.txt", seed, std::process::id()));
+      write_tum(&path, vio.get_trajectory()).unwrap();
+      let output = std::fs::read(&path).unwrap();
+      std::fs::remove_file(&path).unwrap();
+      output
+    };
+    let output = run(1);
+    assert_eq!(output, run(1));
+    assert_ne!(output, run(2));
+  }
+}
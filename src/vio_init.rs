@@ -5,21 +5,64 @@ use crate::all::*;
 
 pub struct VioInit {
   cameras: Vec<Camera>,
+  ground_truth: Option<GroundTruth>,
+  anchors: Vec<Vector3d>,
+  imu_biases: Option<ImuBiases>,
+  detection_mask: Option<DetectionMask>,
+  // See `--filter-state-input`.
+  kalman_filter: Option<KalmanFilter>,
 }
 
 impl VioInit {
-  pub fn new(cameras: Vec<Camera>) -> VioInit {
+  pub fn new(
+    cameras: Vec<Camera>,
+    ground_truth: Option<GroundTruth>,
+    anchors: Vec<Vector3d>,
+    imu_biases: Option<ImuBiases>,
+    detection_mask: Option<DetectionMask>,
+    kalman_filter: Option<KalmanFilter>,
+  ) -> VioInit {
     VioInit {
       cameras,
+      ground_truth,
+      anchors,
+      imu_biases,
+      detection_mask,
+      kalman_filter,
     }
   }
 
   pub fn try_init(&mut self, input_data: &InputData) -> Option<Result<Vio>> {
     if let InputDataSensor::Frame(ref frame) = input_data.sensor {
+      if let Err(err) = frame.validate() {
+        warn!("Skipping frame at time {}: {}", input_data.time, err);
+        return None;
+      }
+      if let Some(detection_mask) = &self.detection_mask {
+        if let Some(err) = frame.images.iter().find_map(|image| detection_mask.check_size(image).err()) {
+          return Some(Err(err));
+        }
+      }
       let frame_scale = compute_frame_scale(&frame.images);
       let mut cameras = vec![];
       mem::swap(&mut self.cameras, &mut cameras);
-      Some(Vio::new(cameras, frame_scale))
+      let anchors = mem::take(&mut self.anchors);
+      let imu_biases = self.imu_biases.take();
+      let detection_mask = self.detection_mask.take();
+      let kalman_filter = self.kalman_filter.take();
+      Some(Vio::new(cameras, frame_scale, self.ground_truth.take()).and_then(|mut vio| {
+        for position in anchors {
+          vio.add_anchor(position);
+        }
+        if let Some(imu_biases) = &imu_biases {
+          vio.set_imu_biases(imu_biases);
+        }
+        vio.set_detection_mask(detection_mask)?;
+        if let Some(kalman_filter) = kalman_filter {
+          vio.set_kalman_filter(kalman_filter);
+        }
+        Ok(vio)
+      }))
     }
     else {
       // Ignore data before the first frame. Could also save in buffer and replay.
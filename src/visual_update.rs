@@ -2,178 +2,1090 @@ use crate::all::*;
 
 use rand::seq::SliceRandom;
 use rand::SeedableRng;
+use rayon::prelude::*;
+
+// Time offset step (seconds) for differentiating the triangulation numerically.
+const TIME_OFFSET_STEP: f64 = 1e-6;
+
+// How tracks constrain the filter state. Tracks are always triangulated from
+// the pose trail, which bounds the cost by `pose_trail_len`, but what such a
+// track tells about its landmark is forgotten once its observations leave the trail.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(clap::ArgEnum)]
+pub enum LandmarkMode {
+  // Nothing is kept in the filter state (Multi-State Constraint Kalman Filter).
+  #[default]
+  Msckf,
+  // Long-lived tracks also become landmarks in the filter state (EKF-SLAM).
+  // They are updated with each new observation until the track is lost, which
+  // limits drift while looking at the same scene, but each landmark grows the
+  // state by three and the covariance update cost grows quadratically with it.
+  Slam,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(clap::ArgEnum)]
+pub enum TriangulationMethod {
+  // Closest point to all camera rays, treating them as equal.
+  #[default]
+  Linear,
+  // Point on the first camera ray of the newest frame, at the depth that best
+  // fits the other rays, including the second camera rays given by the known
+  // stereo extrinsics. The reference ray is taken as exact, so this suits
+  // setups where the first camera observations are more precise than the
+  // others, eg when the second camera matches are coarse. With equally noisy
+  // observations `Linear` is more accurate.
+  Stereo,
+}
 
 pub struct VisualUpdate {
   kf_noise_visual: f64,
+  // Multipliers of `kf_noise_visual` for the observations of each camera.
+  camera_noise_scales: [f64; 2],
+  // See `age_noise_scale()`.
+  age_noise_scale: f64,
+  age_noise_floor: f64,
+  max_tracks: usize,
+  max_inlier_tracks: usize,
+  binning: bool,
+  outlier_gate: f64,
+  outlier_confidence: f64,
+  outlier_gate_warmup: usize,
+  warmup_outlier_gate: f64,
+  min_inliers: usize,
+  max_views: usize,
+  triangulation_method: TriangulationMethod,
+  triangulation_lambda: f64,
+  // See `visual_update_min_depth`.
+  min_depth: f64,
+  // See `disable_outlier_rejection`.
+  outlier_rejection: bool,
+  // See `disable_stereo`.
+  stereo: bool,
+  // See `use_fej`.
+  fej: bool,
+  // See `line_readout_time`, overrides the shutter of the cameras if nonzero.
+  line_readout_time: f64,
+  // True if the observations are corrected by the time offset, see `time_offset_std`.
+  time_offset: bool,
+  // See `show_landmark_uncertainty`, read on each update since it can be
+  // toggled while running.
+  landmark_uncertainty: bool,
+  landmark_mode: LandmarkMode,
+  slam_max_landmarks: usize,
+  slam_min_track_len: usize,
+  slam_landmark_std: f64,
+  slam_max_landmark_age: usize,
+  // Tracks that have been landmarks and the frame numbers they were added on.
+  // Kept while the track continues so that it is not added again.
+  landmark_frames: Vec<(TrackId, usize)>,
+  jacobian_sparsity_output: Option<PathBuf>,
   rng: Xoshiro256PlusPlus,
+  // See `visual_update_threads`.
+  threads: usize,
+  // None uses the global pool.
+  thread_pool: Option<rayon::ThreadPool>,
+  // Indices of the tracks considered for the update.
+  selected_tracks: Vec<usize>,
+  // Tracks rejected by the outlier gate in the last update.
+  outlier_count: usize,
+  // Tracks used in the last update, zero if it was skipped.
+  inlier_count: usize,
+  // See `get_reprojection_rms()`.
+  reprojection_rms: Option<f64>,
+  // Number of calls to `process()`, including the current one.
+  process_count: usize,
   tmp: Tmp,
 }
 
 struct Tmp {
-  kalman_filter_poses: Vec<[KalmanFilterPose; 2]>,
+  // One per parallel task, see `track_measurement()`.
+  tracks: Vec<TrackTmp>,
+  // Indices of the tracks sorted by ID.
+  order: Vec<usize>,
+  // `H`, `y` and `r` of the tracks that passed the outlier gate, stacked.
+  inlier_H: Matrixd,
+  inlier_y: Vectord,
+  inlier_r: Vectord,
+  // Track and number of rows of each block of `inlier_H`.
+  inlier_tracks: Vec<(TrackId, usize)>,
+  // Labels of the columns of `inlier_H`, see `KalmanFilter::get_state_labels()`.
+  state_labels: String,
+  // Tracks to add as landmarks after the update, their triangulated positions
+  // and the derivatives of the positions with respect to the filter state.
+  new_landmarks: Vec<(TrackId, Vector3d, Matrixd)>,
+}
+
+// The measurement of a single track. Each parallel task has its own.
+struct TrackTmp {
+  kalman_filter_poses: Vec<Vec<KalmanFilterPose>>,
+  // The poses at their first estimates for the Jacobian, if `fej` is set.
+  first_estimate_poses: Vec<Vec<KalmanFilterPose>>,
   indices: Vec<usize>,
   normalized_coordinates: Vec<[Vector2d; 2]>,
+  // Pixel coordinates matching `normalized_coordinates`.
+  coordinates: Vec<[Vector2d; 2]>,
+  // Velocities (1/s) of `normalized_coordinates`, if the time offset is used.
+  velocities: Vec<[Vector2d; 2]>,
+  // Poses of the neighbouring frames for the rolling shutter, see `move_pose_in_time()`.
+  neighbour_poses: Vec<Vec<KalmanFilterPose>>,
+  // Seconds the camera poses were moved along the filter velocity, see
+  // `move_pose_in_time()`.
+  velocity_times: Vec<[f64; 2]>,
+  // `normalized_coordinates` moved by `TIME_OFFSET_STEP` along `velocities`.
+  shifted_coordinates: Vec<[Vector2d; 2]>,
+  shifted_output: TriangulateOutput,
+  // Triangulation from `first_estimate_poses`, if `fej` is set.
+  first_estimate_output: TriangulateOutput,
+  // Which of the track's poses to use, see `select_views()`.
+  keep_views: Vec<bool>,
   triangulate_output: TriangulateOutput,
+  // Triangulated position, None if the triangulation failed.
+  position: Option<Vector3d>,
+  // Derivative of `position` with respect to the filter state.
+  d_position: Matrixd,
+  // The triangulated landmark for `show_landmark_uncertainty`.
+  landmark: Option<DebugLandmark>,
+  // True if the rows below were computed, ie the track was triangulated in
+  // front of all the cameras.
+  measured: bool,
   // EKF measurement function Jacobian.
   H: Matrixd,
   // Stacked measured features minus stacked triangulated and reprojected features.
   // `z - h(x)` in EKF update.
   y: Vectord,
+  // Measurement noise standard deviations matching the rows of `y`.
+  r: Vectord,
+  // Result of `KalmanFilter::mahalanobis_gate()`, true if the outlier gate is
+  // not in use.
+  passes_gate: bool,
+}
+
+impl TrackTmp {
+  fn new() -> TrackTmp {
+    TrackTmp {
+      kalman_filter_poses: vec![],
+      first_estimate_poses: vec![],
+      indices: vec![],
+      normalized_coordinates: vec![],
+      coordinates: vec![],
+      velocities: vec![],
+      neighbour_poses: vec![],
+      velocity_times: vec![],
+      shifted_coordinates: vec![],
+      shifted_output: TriangulateOutput::new(),
+      first_estimate_output: TriangulateOutput::new(),
+      keep_views: vec![],
+      triangulate_output: TriangulateOutput::new(),
+      position: None,
+      d_position: Matrixd::zeros(0, 0),
+      landmark: None,
+      measured: false,
+      H: Matrixd::zeros(0, 0),
+      y: Vectord::zeros(0),
+      r: Vectord::zeros(0),
+      passes_gate: true,
+    }
+  }
 }
 
 impl VisualUpdate {
   pub fn new() -> VisualUpdate {
     let p = PARAMETER_SET.lock().unwrap();
+    let thread_pool = if p.visual_update_threads > 1 {
+      match rayon::ThreadPoolBuilder::new().num_threads(p.visual_update_threads).build() {
+        Ok(thread_pool) => Some(thread_pool),
+        Err(err) => {
+          warn!("Could not start {} visual update threads, using the global pool: {}", p.visual_update_threads, err);
+          None
+        },
+      }
+    } else {
+      None
+    };
     VisualUpdate {
       kf_noise_visual: p.kf_noise_visual,
-      rng: Xoshiro256PlusPlus::seed_from_u64(0),
+      camera_noise_scales: [p.kf_noise_visual_scale0, p.kf_noise_visual_scale1],
+      age_noise_scale: p.kf_noise_visual_age_scale,
+      age_noise_floor: p.kf_noise_visual_age_floor,
+      max_tracks: p.visual_update_max_tracks,
+      max_inlier_tracks: p.visual_update_max_inlier_tracks,
+      binning: p.visual_update_binning,
+      outlier_gate: p.visual_update_outlier_gate,
+      outlier_confidence: p.visual_update_outlier_confidence,
+      outlier_gate_warmup: p.visual_update_outlier_gate_warmup,
+      warmup_outlier_gate: p.visual_update_warmup_outlier_gate,
+      min_inliers: p.visual_update_min_inliers,
+      max_views: p.triangulation_max_views,
+      triangulation_method: p.triangulation_method,
+      triangulation_lambda: p.triangulation_lambda,
+      min_depth: p.visual_update_min_depth,
+      outlier_rejection: !p.disable_outlier_rejection,
+      stereo: !p.disable_stereo,
+      fej: p.use_fej,
+      line_readout_time: p.line_readout_time,
+      time_offset: p.time_offset != 0. || p.time_offset_std > 0.,
+      landmark_uncertainty: p.show_landmark_uncertainty,
+      landmark_mode: p.landmark_mode,
+      slam_max_landmarks: p.slam_max_landmarks,
+      slam_min_track_len: p.slam_min_track_len,
+      slam_landmark_std: p.slam_landmark_std,
+      slam_max_landmark_age: p.slam_max_landmark_age,
+      landmark_frames: vec![],
+      jacobian_sparsity_output: p.jacobian_sparsity_output.as_ref().map(PathBuf::from),
+      rng: Xoshiro256PlusPlus::seed_from_u64(p.seed),
+      threads: p.visual_update_threads,
+      thread_pool,
+      selected_tracks: vec![],
+      outlier_count: 0,
+      inlier_count: 0,
+      reprojection_rms: None,
+      process_count: 0,
       tmp: Tmp {
-        kalman_filter_poses: vec![],
-        indices: vec![],
-        normalized_coordinates: vec![],
-        triangulate_output: TriangulateOutput {
-          a: Vector3d::zeros(),
-          da_dp: vec![],
-          da_dq: vec![],
-        },
-        H: Matrixd::zeros(0, 0),
-        y: Vectord::zeros(0),
+        tracks: vec![],
+        order: vec![],
+        inlier_H: Matrixd::zeros(0, 0),
+        inlier_y: Vectord::zeros(0),
+        inlier_r: Vectord::zeros(0),
+        inlier_tracks: vec![],
+        state_labels: String::new(),
+        new_landmarks: vec![],
       },
     }
   }
 
+  pub fn get_outlier_count(&self) -> usize {
+    self.outlier_count
+  }
+
+  #[cfg_attr(not(feature = "json-output"), allow(dead_code))]
+  pub fn get_inlier_count(&self) -> usize {
+    self.inlier_count
+  }
+
+  // Root-mean-square reprojection error of the inlier observations of the last
+  // update, in normalized image coordinates. None if there were no inliers.
+  pub fn get_reprojection_rms(&self) -> Option<f64> {
+    self.reprojection_rms
+  }
+
   pub fn process(
     &mut self,
     kalman_filter: &mut KalmanFilter,
     tracks: &[Track],
-    cameras: [&Camera; 2],
+    cameras: &[&Camera],
     pose_trail_frame_numbers: &VecDeque<usize>,
+    anchors: &Anchors,
+    image_height: usize,
   ) {
     {
       let d = &mut DEBUG_DATA_3D.lock().unwrap();
       d.triangulation_positions.clear();
     }
+    self.landmark_uncertainty = PARAMETER_SET.lock().unwrap().show_landmark_uncertainty;
+    if self.landmark_uncertainty {
+      DEBUG_DATA.lock().unwrap().landmark_uncertainty.clear();
+    }
+    self.process_count += 1;
 
-    let mut successful_update_count = 0;
+    // The order of the tracks decides which ones are used and in which order
+    // their rows are stacked. Make it independent of the order they are given
+    // in so that the update is reproducible.
+    self.tmp.order.clear();
+    self.tmp.order.extend(0..tracks.len());
+    self.tmp.order.sort_by_key(|i| tracks[*i].id.0);
+    let mut selected_tracks = mem::take(&mut self.selected_tracks);
+    if self.binning {
+      // Already ordered by the bins and the track lengths and IDs within them.
+      select_tracks(tracks, self.max_tracks, &mut selected_tracks);
+    }
+    else {
+      selected_tracks.clear();
+      selected_tracks.extend(self.tmp.order.choose_multiple(&mut self.rng, self.max_tracks));
+      selected_tracks.sort_by_key(|i| tracks[*i].id.0);
+    }
+    // Tracks of anchor landmarks go first so that they are always used.
+    if !anchors.is_empty() {
+      selected_tracks.retain(|i| anchors.get_position(tracks[*i].id).is_none());
+      for i in self.tmp.order.iter().rev() {
+        if anchors.get_position(tracks[*i].id).is_some() {
+          selected_tracks.insert(0, *i);
+        }
+      }
+    }
+
+    let frame_number = *pose_trail_frame_numbers.back().unwrap();
+    if self.landmark_mode == LandmarkMode::Slam {
+      self.landmark_frames.retain(|(id, _)| tracks.iter().any(|track| track.id == *id));
+      let expired = |id: TrackId| self.slam_max_landmark_age > 0 && self.landmark_frames.iter()
+        .any(|(i, added)| *i == id && frame_number - added >= self.slam_max_landmark_age);
+      kalman_filter.retain_landmarks(|id| tracks.iter().any(|track| track.id == id) && !expired(id));
+      let landmark_ids = kalman_filter.get_landmark_ids();
+      selected_tracks.retain(|i| !landmark_ids.contains(&tracks[*i].id));
+    }
+    self.tmp.new_landmarks.clear();
+
+    let state_len = kalman_filter.get_state_len();
+    self.tmp.inlier_H.resize_mut(0, state_len, 0.);
+    self.tmp.inlier_y.resize_vertically_mut(0, 0.);
+    self.tmp.inlier_r.resize_vertically_mut(0, 0.);
+    self.tmp.inlier_tracks.clear();
+    kalman_filter.get_state_labels(&mut self.tmp.state_labels);
+    let mut inlier_count = 0;
+    self.outlier_count = 0;
+    self.inlier_count = 0;
+    self.reprojection_rms = None;
+
+    // Each task measures one track at a time, the first one also the landmarks.
+    let task_count = match self.threads {
+      0 => rayon::current_num_threads(),
+      threads => threads,
+    };
+    let mut track_tmps = mem::take(&mut self.tmp.tracks);
+    track_tmps.resize_with(task_count, TrackTmp::new);
+
+    for l in 0..kalman_filter.get_landmark_ids().len() {
+      let id = kalman_filter.get_landmark_ids()[l];
+      let Some(track) = tracks.iter().find(|track| track.id == id) else { continue };
+      let point = track.points.iter().last().unwrap();
+      if point.frame_number != frame_number { continue }
+      let cams = self.track_cameras(track);
+      let tmp = &mut track_tmps[0];
+      if !self.landmark_update(kalman_filter, l, point, cams, cameras, image_height, tmp) { continue }
+      self.measurement_noise(kalman_filter, 1, cams, self.age_noise_scale(track.age(frame_number)), tmp);
+      if self.add_inlier(tmp) {
+        self.tmp.inlier_tracks.push((id, 2 * cams.len()));
+        inlier_count += 1;
+      }
+    }
+
+    // The tracks are measured in parallel a chunk at a time and stacked in
+    // order, so that the result does not depend on the number of threads.
+    let mut msckf_count = 0;
+    'chunk:
+    for chunk in selected_tracks.chunks(task_count) {
+      let measure = |(tmp, i): (&mut TrackTmp, &usize)| {
+        self.track_measurement(
+          kalman_filter,
+          &tracks[*i],
+          cameras,
+          pose_trail_frame_numbers,
+          anchors,
+          image_height,
+          tmp,
+        );
+      };
+      match &self.thread_pool {
+        _ if task_count == 1 => track_tmps.iter_mut().zip(chunk).for_each(measure),
+        Some(pool) => pool.install(|| track_tmps.par_iter_mut().zip(chunk).for_each(measure)),
+        None => track_tmps.par_iter_mut().zip(chunk).for_each(measure),
+      }
 
-    'track:
-    for track in tracks.choose_multiple(&mut self.rng, 50) {
-      self.tmp.indices.clear();
-      self.tmp.normalized_coordinates.clear();
-      let mut i = 0;
-      for point in &track.points {
-        if point.frame_number < pose_trail_frame_numbers[i] { continue }
-        while i < pose_trail_frame_numbers.len() && pose_trail_frame_numbers[i] < point.frame_number {
-          i += 1;
+      for (tmp, i) in track_tmps.iter().zip(chunk) {
+        let track = &tracks[*i];
+        let Some(aw) = tmp.position else { continue };
+        {
+          let d = &mut DEBUG_DATA_3D.lock().unwrap();
+          d.triangulation_positions.push(aw);
+        }
+        if let Some(landmark) = &tmp.landmark {
+          DEBUG_DATA.lock().unwrap().landmark_uncertainty.push(landmark.clone());
         }
-        if pose_trail_frame_numbers[i] == point.frame_number {
-          // In the Kalman Filter state the newest pose is first: reverse indices.
-          self.tmp.indices.push(pose_trail_frame_numbers.len() - i - 1);
-          self.tmp.normalized_coordinates.push(point.normalized_coordinates);
+        if !tmp.measured || !self.add_inlier(tmp) { continue }
+        self.tmp.inlier_tracks.push((track.id, tmp.y.nrows()));
+
+        let landmark_count = kalman_filter.get_landmark_ids().len() + self.tmp.new_landmarks.len();
+        if self.landmark_mode == LandmarkMode::Slam
+          && track.points.len() >= self.slam_min_track_len
+          && landmark_count < self.slam_max_landmarks
+          && anchors.get_position(track.id).is_none()
+          && !self.landmark_frames.iter().any(|(id, _)| *id == track.id)
+        {
+          let mut d_position = Matrixd::zeros(0, 0);
+          triangulation_jacobian(kalman_filter, self.track_cameras(track), tmp, &mut d_position);
+          self.tmp.new_landmarks.push((track.id, aw, d_position));
         }
-        if i >= pose_trail_frame_numbers.len() { break }
+
+        inlier_count += 1;
+        msckf_count += 1;
+        if msckf_count >= self.max_inlier_tracks { break 'chunk }
       }
+    }
+    self.tmp.tracks = track_tmps;
+    self.selected_tracks = selected_tracks;
 
-      let success = kalman_filter.get_camera_pose_trail(
-        &self.tmp.indices,
-        cameras,
-        &mut self.tmp.kalman_filter_poses,
+    self.reprojection_rms = reprojection_rms(&self.tmp.inlier_y);
+    if self.outlier_count > 0 {
+      debug!("Outlier gate rejected {} tracks.", self.outlier_count);
+    }
+
+    // A few measurements can pull the state far off if they happen to be
+    // outliers that passed the gate. Rather rely on the IMU for this frame.
+    if inlier_count < self.min_inliers {
+      if !tracks.is_empty() {
+        info!("Skipping visual update with {} inliers.", inlier_count);
+      }
+      kalman_filter.record_visual_update(self.outlier_count > 0);
+    }
+    else {
+      kalman_filter.update_visual(
+        &self.tmp.inlier_H,
+        &self.tmp.inlier_y,
+        &self.tmp.inlier_r,
       );
-      assert!(success);
-
-      if triangulate(
-        &self.tmp.normalized_coordinates,
-        &self.tmp.kalman_filter_poses,
-        &mut self.tmp.triangulate_output,
-      ).is_none() {
-        continue;
-      }
-
-      // The visual update is defined by the measurement function `h()`
-      // operating on the EKF state `x` as:
-      //   h_i(x) = hnormalize(pose_i.R * (aw - pose_i.p)),
-      // where
-      //   aw = triangulate(x)
-      // is given in world coordinates.
-      //
-      // As part of the triangulation we have computed all derivatives of `aw`
-      // and it remains to differentiate `h_i(x)` for all poses k. The result
-      // depends on if i == k.
-      //
-      // As an example, to compute the position derivatives:
-      // d_{k_p}h_i(x) = d_hnormalize * [
-      //   d_{k_p}(pose_i.R) * (aw - pose_i.p)
-      //   + pose_i.R * d_{k_p}(aw - pose_i.p)
-      // ]
-      // = d_hnormalized * pose_i.R * d_{k_p}(aw - pose_i.p)
-      let n = self.tmp.kalman_filter_poses.len();
-      self.tmp.H.resize_mut(4 * n, kalman_filter.get_state_len(), 0.);
-      self.tmp.y.resize_vertically_mut(4 * n, 0.);
-      let aw = self.tmp.triangulate_output.a;
-
-      {
-        let d = &mut DEBUG_DATA_3D.lock().unwrap();
-        d.triangulation_positions.push(aw);
+      self.inlier_count = inlier_count;
+      kalman_filter.record_visual_update(false);
+    }
+    for (id, position, d_position) in &self.tmp.new_landmarks {
+      kalman_filter.add_landmark(*id, *position, d_position, self.slam_landmark_std);
+      self.landmark_frames.push((*id, frame_number));
+    }
+    if let Some(folder) = &self.jacobian_sparsity_output {
+      let path = folder.join(format!("{:06}.txt", frame_number));
+      if let Err(err) = std::fs::write(&path, self.jacobian_sparsity()) {
+        warn!("Could not write {}: {}", path.display(), err);
+      }
+    }
+  } // process()
+
+  // Sparsity pattern of the stacked Jacobian of the last update, for checking
+  // the placement of its blocks. The first line labels the state columns as
+  // `KalmanFilter::get_state_labels()` and each following line gives a track
+  // ID and `#` for the columns any of its rows depends on, `.` for the others.
+  pub fn jacobian_sparsity(&self) -> String {
+    let H = &self.tmp.inlier_H;
+    let mut s = format!("{:>8} {}\n", "", self.tmp.state_labels);
+    let mut row = 0;
+    for (id, rows) in &self.tmp.inlier_tracks {
+      s += &format!("{:>8} ", id.0);
+      for col in 0..H.ncols() {
+        let nonzero = H.slice((row, col), (*rows, 1)).iter().any(|x| *x != 0.);
+        s.push(if nonzero { '#' } else { '.' });
       }
+      s.push('\n');
+      row += rows;
+    }
+    s
+  }
 
+  // Triangulates the track from the poses of the trail it is observed in and
+  // computes its measurement rows in `tmp`. Only reads the shared state so
+  // that the tracks can be measured in parallel.
+  #[allow(clippy::too_many_arguments)]
+  fn track_measurement(
+    &self,
+    kalman_filter: &KalmanFilter,
+    track: &Track,
+    cameras: &[&Camera],
+    pose_trail_frame_numbers: &VecDeque<usize>,
+    anchors: &Anchors,
+    image_height: usize,
+    tmp: &mut TrackTmp,
+  ) {
+    tmp.position = None;
+    tmp.landmark = None;
+    tmp.measured = false;
+    let state_len = kalman_filter.get_state_len();
+    let frame_number = *pose_trail_frame_numbers.back().unwrap();
+    // Monocular tracks are triangulated and updated from their own camera only.
+    let cams = self.track_cameras(track);
+    tmp.indices.clear();
+    tmp.normalized_coordinates.clear();
+    tmp.coordinates.clear();
+    let mut i = 0;
+    for point in &track.points {
+      if point.frame_number < pose_trail_frame_numbers[i] { continue }
+      while i < pose_trail_frame_numbers.len() && pose_trail_frame_numbers[i] < point.frame_number {
+        i += 1;
+      }
+      if pose_trail_frame_numbers[i] == point.frame_number {
+        // In the Kalman Filter state the newest pose is first: reverse indices.
+        tmp.indices.push(pose_trail_frame_numbers.len() - i - 1);
+        tmp.normalized_coordinates.push(point.normalized_coordinates);
+        tmp.coordinates.push(point.coordinates);
+      }
+      if i >= pose_trail_frame_numbers.len() { break }
+    }
+
+    let success = kalman_filter.get_camera_pose_trail(
+      &tmp.indices,
+      cameras,
+      &mut tmp.kalman_filter_poses,
+    );
+    if !invariant(success, "Camera poses of the track are in the pose trail.") { return }
+
+    tmp.velocities.clear();
+    if self.time_offset {
+      // The frames were exposed `t_d` after their timestamps, at which the
+      // poses are. Move the observations back along the image motion of the
+      // track, estimated from up to three neighbouring observations in the trail.
+      let t_d = kalman_filter.get_time_offset();
+      let n = tmp.indices.len();
+      let times: Option<Vec<f64>> = tmp.indices.iter().map(|&k| kalman_filter.get_pose_time(k)).collect();
       for i in 0..n {
-        for j in 0..2 {
-          let row = 2 * (2 * i + j);
-          let pose = &self.tmp.kalman_filter_poses[i][j];
-          // let wcp = position!(world_to_camera); // TODO This is not needed, right?
-          // We decompose this for clarity with the derivatives but it's the same as:
-          //   let ac = affine_transform(world_to_camera, aw); // TODO Verify.
-          let ac = pose.R * (aw - pose.p);
-
-          // Check the triangulated point is in front of all cameras.
-          if ac[2] <= 0. { continue 'track; }
-
-          // Compute normalized coordinates ("project" the triangulated point).
-          let normalized_ac = hnormalize(ac).unwrap();
-          let mut d_normalized_ac = Matrix23d::zeros();
-          d_normalized_ac[(0, 0)] = 1. / ac[2];
-          d_normalized_ac[(1, 1)] = 1. / ac[2];
-          d_normalized_ac[(0, 2)] = -ac[0] / ac[2];
-          d_normalized_ac[(1, 2)] = -ac[1] / ac[2];
-
-          self.tmp.y[row + 0] = self.tmp.normalized_coordinates[i][j][0] - normalized_ac[0];
-          self.tmp.y[row + 1] = self.tmp.normalized_coordinates[i][j][1] - normalized_ac[1];
-
-          // This is the contribution to the derivatives in the case
-          // i == k. Using again the position as example and ignoring the `aw` term:
-          //   d_{i_p}h_i(x) = d_hnormalized * pose_i.R * d_{i_p}(aw - pose_i.p)
-          //   -> d_hormalized * pose_i.R * (-I)
-          let col_pos = kalman_filter.get_camera_pos_ind(i);
-          let col_ori = kalman_filter.get_camera_ori_ind(i);
-          self.tmp.H.fixed_slice_mut::<2, 3>(row, col_pos).copy_from(&(-d_normalized_ac * pose.R));
-          for m in 0..4 {
-            self.tmp.H.fixed_slice_mut::<2, 1>(row, col_ori + m).copy_from(&(
-              d_normalized_ac * pose.dR_dq[m] * (aw - pose.p)
-            ));
-          }
+        let velocity = match &times {
+          Some(times) if n > 1 => {
+            let a = i.saturating_sub(1).min(n.saturating_sub(3));
+            let b = (a + 3).min(n);
+            let nc = &tmp.normalized_coordinates[a..b];
+            [0, 1].map(|j| interpolated_derivative(&times[a..b], |k| nc[k][j], times[i]))
+          },
+          _ => [Vector2d::zeros(); 2],
+        };
+        tmp.velocities.push(velocity);
+      }
+      for (nc, velocity) in tmp.normalized_coordinates.iter_mut().zip(&tmp.velocities) {
+        for &j in cams {
+          nc[j] -= t_d * velocity[j];
+        }
+      }
+    }
+
+    // Move each camera to where it was when the observed row was exposed.
+    tmp.velocity_times.clear();
+    tmp.velocity_times.resize(tmp.indices.len(), [0.; 2]);
+    for i in 0..tmp.indices.len() {
+      for &j in cams {
+        let t = self.row_time(cameras[j], tmp.coordinates[i][j][1], image_height);
+        tmp.velocity_times[i][j] = move_pose_in_time(
+          kalman_filter,
+          cameras[j],
+          tmp.indices[i],
+          t,
+          &mut tmp.neighbour_poses,
+          &mut tmp.kalman_filter_poses[i][j],
+        );
+      }
+    }
+
+    if self.max_views > 0 && tmp.indices.len() > self.max_views {
+      let keep = &mut tmp.keep_views;
+      select_views(&tmp.normalized_coordinates, &tmp.kalman_filter_poses, cams[0], self.max_views, keep);
+      let mut k = 0..;
+      tmp.indices.retain(|_| keep[k.next().unwrap()]);
+      let mut k = 0..;
+      tmp.normalized_coordinates.retain(|_| keep[k.next().unwrap()]);
+      let mut k = 0..;
+      tmp.coordinates.retain(|_| keep[k.next().unwrap()]);
+      if !tmp.velocities.is_empty() {
+        let mut k = 0..;
+        tmp.velocities.retain(|_| keep[k.next().unwrap()]);
+      }
+      let mut k = 0..;
+      tmp.kalman_filter_poses.retain(|_| keep[k.next().unwrap()]);
+      let mut k = 0..;
+      tmp.velocity_times.retain(|_| keep[k.next().unwrap()]);
+    }
+    // Not moved for the rolling shutter and time offset like the current
+    // poses: a slightly different linearization point is harmless.
+    if self.fej {
+      let success = kalman_filter.get_first_estimate_camera_pose_trail(
+        &tmp.indices,
+        cameras,
+        &mut tmp.first_estimate_poses,
+      );
+      if !invariant(success, "Camera poses of the track are in the pose trail.") { return }
+    }
 
-          for k in 0..n {
-            let col_pos_k = kalman_filter.get_camera_pos_ind(k);
-            let col_ori_k = kalman_filter.get_camera_ori_ind(k);
-            let da_dp = &self.tmp.triangulate_output.da_dp;
-            let da_dq = &self.tmp.triangulate_output.da_dq;
-            let mut pos = self.tmp.H.fixed_slice_mut::<2, 3>(row, col_pos_k);
-            pos += d_normalized_ac * pose.R * da_dp[k];
-            let mut ori = self.tmp.H.fixed_slice_mut::<2, 4>(row, col_ori_k);
-            ori += d_normalized_ac * pose.R * da_dq[k];
+    let anchor = anchors.get_position(track.id);
+    if let Some(position) = anchor {
+      // The position of an anchor is exact and does not depend on the poses.
+      let n = tmp.kalman_filter_poses.len();
+      let output = &mut tmp.triangulate_output;
+      output.a = position;
+      output.da_dp.clear();
+      output.da_dp.resize(2 * n, Matrix3d::zeros());
+      output.da_dq.clear();
+      output.da_dq.resize(2 * n, Matrix34d::zeros());
+      output.condition_number = 1.;
+      output.noise_scale = 1.;
+    }
+    else if self.triangulate_track(
+      &tmp.normalized_coordinates,
+      &tmp.kalman_filter_poses,
+      cams,
+      &mut tmp.triangulate_output,
+    ).is_none() {
+      return;
+    }
+    if self.triangulation_lambda > 0. && tmp.triangulate_output.noise_scale > 2. {
+      debug!(
+        "Regularized triangulation of track {} with condition number {:.3e}.",
+        track.id.0, tmp.triangulate_output.condition_number,
+      );
+    }
+
+    // The visual update is defined by the measurement function `h()`
+    // operating on the EKF state `x` as:
+    //   h_i(x) = hnormalize(pose_i.R * (aw - pose_i.p)),
+    // where
+    //   aw = triangulate(x)
+    // is given in world coordinates.
+    //
+    // As part of the triangulation we have computed all derivatives of `aw`
+    // and it remains to differentiate `h_i(x)` for all poses k of the
+    // filter state. Pose i appears directly in `h_i(x)`, and every pose k
+    // through `aw`. As an example, the position derivatives are:
+    //   d_{k_p}h_i(x) = d_hnormalized * pose_i.R * d_{k_p}(aw - pose_i.p)
+    //   = d_hnormalized * pose_i.R * (d_{k_p}aw - [i == k] I)
+    // The camera poses of both cameras j move with the IMU pose, so
+    //   d_{k_p}aw = sum_j da_dp[2k + j]
+    // and in addition the camera positions depend on the IMU orientation.
+    // With the time offset `t_d` the measurement is moved by the feature
+    // velocity, so
+    //   d_{t_d}h_i(x) = velocity_i + d_hnormalized * pose_i.R * d_{t_d}aw.
+    // Monocular tracks have rows and derivatives for their camera only.
+    // A camera pose moved for the rolling shutter by `t_v` along the velocity
+    // `v` has position `pose_i.p + t_v v`, which adds the `v` derivative
+    //   d_v h_i(x) = d_hnormalized * pose_i.R * (d_v aw - t_v I).
+    //
+    // With `fej` the derivatives are evaluated at the first estimates of the
+    // poses, while the residual uses the current ones. The current estimates
+    // of different poses have absorbed different updates, and linearizing at
+    // them makes the covariance of the unobservable global position and yaw
+    // inconsistent with their error.
+    let n = tmp.kalman_filter_poses.len();
+    let rows = 2 * cams.len() * n;
+    tmp.H.resize_mut(rows, state_len, 0.);
+    tmp.H.fill(0.);
+    tmp.y.resize_vertically_mut(rows, 0.);
+    let aw = tmp.triangulate_output.a;
+
+    tmp.position = Some(aw);
+
+    // The time offset moves all the observations and the triangulated point
+    // follows them, differentiate it numerically.
+    let mut da_dtd = Vector3d::zeros();
+    if !tmp.velocities.is_empty() && anchor.is_none() {
+      tmp.shifted_coordinates.clear();
+      for (nc, velocity) in tmp.normalized_coordinates.iter().zip(&tmp.velocities) {
+        tmp.shifted_coordinates.push([0, 1].map(|j| nc[j] - TIME_OFFSET_STEP * velocity[j]));
+      }
+      if self.triangulate_track(
+        &tmp.shifted_coordinates,
+        &tmp.kalman_filter_poses,
+        cams,
+        &mut tmp.shifted_output,
+      ).is_some() {
+        da_dtd = (tmp.shifted_output.a - aw) / TIME_OFFSET_STEP;
+      }
+    }
+
+    // The derivatives of `aw` are those of the triangulation from the
+    // linearization poses, so that `h_i(x)` is differentiated at one point.
+    let fej = self.fej && anchor.is_none();
+    if fej && self.triangulate_track(
+      &tmp.normalized_coordinates,
+      &tmp.first_estimate_poses,
+      cams,
+      &mut tmp.first_estimate_output,
+    ).is_none() {
+      return;
+    }
+    let (linearization_poses, linearization) = if fej {
+      (&tmp.first_estimate_poses, &tmp.first_estimate_output)
+    }
+    else {
+      (&tmp.kalman_filter_poses, &tmp.triangulate_output)
+    };
+    let linearization_aw = linearization.a;
+    for i in 0..n {
+      for (jj, &j) in cams.iter().enumerate() {
+        let row = 2 * (cams.len() * i + jj);
+        let current = &tmp.kalman_filter_poses[i][j];
+        // We decompose this for clarity with the derivatives but it's the same as:
+        //   let ac = affine_transform(world_to_camera, aw);
+        let ac = current.R * (aw - current.p);
+
+        // Check the triangulated point is in front of all cameras, and not
+        // so close that the projection and its derivatives blow up.
+        if ac[2] <= 0. || ac[2] < self.min_depth { return }
+
+        // Compute normalized coordinates ("project" the triangulated point).
+        let normalized_ac = hnormalize(ac).unwrap();
+        tmp.y[row] = tmp.normalized_coordinates[i][j][0] - normalized_ac[0];
+        tmp.y[row + 1] = tmp.normalized_coordinates[i][j][1] - normalized_ac[1];
+
+        let pose = &linearization_poses[i][j];
+        let ac = pose.R * (linearization_aw - pose.p);
+        if ac[2] <= 0. { return }
+        let d_normalized_ac = Matrix23d::new(
+          1. / ac[2], 0., -ac[0] / ac[2].powi(2),
+          0., 1. / ac[2], -ac[1] / ac[2].powi(2),
+        );
+
+        if let Some(velocity) = tmp.velocities.get(i) {
+          tmp.H.fixed_slice_mut::<2, 1>(row, kalman_filter.get_time_offset_ind())
+            .copy_from(&(velocity[j] + d_normalized_ac * pose.R * da_dtd));
+        }
+
+        // The direct contribution of pose i, ignoring the `aw` term.
+        let col_pos = kalman_filter.get_camera_pos_ind(tmp.indices[i]);
+        let col_ori = kalman_filter.get_camera_ori_ind(tmp.indices[i]);
+        tmp.H.fixed_slice_mut::<2, 3>(row, col_pos).copy_from(&(-d_normalized_ac * pose.R));
+        for m in 0..4 {
+          tmp.H.fixed_slice_mut::<2, 1>(row, col_ori + m).copy_from(&(
+            d_normalized_ac * (pose.dR_dq[m] * (linearization_aw - pose.p) - pose.R * pose.dp_dq.column(m))
+          ));
+        }
+
+        // The contribution of all poses k through `aw`.
+        let d_R = d_normalized_ac * pose.R;
+        let col_vel = kalman_filter.get_velocity_ind();
+        let mut vel = tmp.H.fixed_slice_mut::<2, 3>(row, col_vel);
+        vel -= tmp.velocity_times[i][j] * d_R;
+        for (k, poses) in linearization_poses.iter().enumerate().take(n) {
+          let col_pos_k = kalman_filter.get_camera_pos_ind(tmp.indices[k]);
+          let col_ori_k = kalman_filter.get_camera_ori_ind(tmp.indices[k]);
+          for &l in cams {
+            let da_dp = &linearization.da_dp[2 * k + l];
+            let mut vel = tmp.H.fixed_slice_mut::<2, 3>(row, col_vel);
+            vel += tmp.velocity_times[k][l] * d_R * da_dp;
+            let da_dq = &linearization.da_dq[2 * k + l];
+            let dp_dq = &poses[l].dp_dq;
+            let mut pos = tmp.H.fixed_slice_mut::<2, 3>(row, col_pos_k);
+            pos += d_R * da_dp;
+            let mut ori = tmp.H.fixed_slice_mut::<2, 4>(row, col_ori_k);
+            ori += d_R * (da_dp * dp_dq + da_dq);
           }
-        } // for j in 0..2
-      } // for i in 0..n
+        }
+      } // for j in cams
+    } // for i in 0..n
 
-      kalman_filter.update_visual(
-        &self.tmp.H,
-        &self.tmp.y,
-        self.kf_noise_visual,
+    let noise_scale = self.age_noise_scale(track.age(frame_number)) * tmp.triangulate_output.noise_scale;
+    self.measurement_noise(kalman_filter, n, cams, noise_scale, tmp);
+    tmp.measured = true;
+    if self.landmark_uncertainty && anchor.is_none() {
+      tmp.landmark = landmark_uncertainty(kalman_filter, cameras, cams, tmp);
+    }
+  }
+
+  // Computes `H` and `y` for the observation of landmark `l` of the filter
+  // state on the current frame:
+  //   h_j(x) = hnormalize(pose_j.R * (l - pose_j.p))
+  // for the cameras j in `cams` of the newest pose. Returns false if the
+  // landmark is not in front of the cameras by at least `min_depth`.
+  // The time offset is not applied since a single observation gives no
+  // feature velocity.
+  #[allow(clippy::too_many_arguments)]
+  fn landmark_update(
+    &self,
+    kalman_filter: &KalmanFilter,
+    l: usize,
+    point: &TrackPoint,
+    cams: &[usize],
+    cameras: &[&Camera],
+    image_height: usize,
+    tmp: &mut TrackTmp,
+  ) -> bool {
+    let success = kalman_filter.get_camera_pose_trail(&[0], cameras, &mut tmp.kalman_filter_poses);
+    if !invariant(success, "Current camera pose is in the pose trail.") { return false }
+    tmp.velocity_times.clear();
+    tmp.velocity_times.push([0.; 2]);
+    for &j in cams {
+      let t = self.row_time(cameras[j], point.coordinates[j][1], image_height);
+      tmp.velocity_times[0][j] =
+        move_pose_in_time(kalman_filter, cameras[j], 0, t, &mut tmp.neighbour_poses, &mut tmp.kalman_filter_poses[0][j]);
+    }
+
+    let landmark = kalman_filter.get_landmark(l);
+    let col_pos = kalman_filter.get_camera_pos_ind(0);
+    let col_ori = kalman_filter.get_camera_ori_ind(0);
+    let col_landmark = kalman_filter.get_landmark_ind(l);
+    tmp.H.resize_mut(2 * cams.len(), kalman_filter.get_state_len(), 0.);
+    tmp.H.fill(0.);
+    tmp.y.resize_vertically_mut(2 * cams.len(), 0.);
+    for (jj, &j) in cams.iter().enumerate() {
+      let row = 2 * jj;
+      let pose = &tmp.kalman_filter_poses[0][j];
+      let ac = pose.R * (landmark - pose.p);
+      if ac[2] <= 0. || ac[2] < self.min_depth { return false }
+      let normalized_ac = hnormalize(ac).unwrap();
+      let d_normalized_ac = Matrix23d::new(
+        1. / ac[2], 0., -ac[0] / ac[2].powi(2),
+        0., 1. / ac[2], -ac[1] / ac[2].powi(2),
       );
+      tmp.y[row] = point.normalized_coordinates[j][0] - normalized_ac[0];
+      tmp.y[row + 1] = point.normalized_coordinates[j][1] - normalized_ac[1];
+      tmp.H.fixed_slice_mut::<2, 3>(row, col_pos).copy_from(&(-d_normalized_ac * pose.R));
+      for m in 0..4 {
+        tmp.H.fixed_slice_mut::<2, 1>(row, col_ori + m).copy_from(&(
+          d_normalized_ac * (pose.dR_dq[m] * (landmark - pose.p) - pose.R * pose.dp_dq.column(m))
+        ));
+      }
+      tmp.H.fixed_slice_mut::<2, 3>(row, col_landmark).copy_from(&(d_normalized_ac * pose.R));
+      tmp.H.fixed_slice_mut::<2, 3>(row, kalman_filter.get_velocity_ind())
+        .copy_from(&(-tmp.velocity_times[0][j] * d_normalized_ac * pose.R));
+    }
+    true
+  }
 
-      successful_update_count += 1;
-      if successful_update_count >= 5 { break }
-    } // process()
+  // Seconds from the frame timestamp to the exposure of the image row `row`
+  // of `camera`.
+  fn row_time(&self, camera: &Camera, row: f64, image_height: usize) -> f64 {
+    if self.line_readout_time > 0. {
+      return self.line_readout_time * (row - 0.5 * image_height as f64);
+    }
+    match camera.shutter {
+      Shutter::Global => 0.,
+      Shutter::Rolling { readout_time } => readout_time * (row / image_height as f64 - 0.5),
+    }
+  }
+
+  fn triangulate_track(
+    &self,
+    normalized_coordinates: &[[Vector2d; 2]],
+    kalman_filter_poses: &[Vec<KalmanFilterPose>],
+    cams: &[usize],
+    output: &mut TriangulateOutput,
+  ) -> Option<()> {
+    if self.triangulation_method == TriangulationMethod::Stereo {
+      triangulate_stereo(normalized_coordinates, kalman_filter_poses, cams, output)
+    }
+    else {
+      triangulate(normalized_coordinates, kalman_filter_poses, cams, self.triangulation_lambda, output)
+    }
+  }
+
+  // Cameras whose observations of the track are used.
+  fn track_cameras(&self, track: &Track) -> &'static [usize] {
+    if self.stereo { track.cameras() } else { &track.cameras()[..1] }
+  }
+
+  fn age_noise_scale(&self, age: usize) -> f64 {
+    age_noise_scale(age, self.age_noise_scale, self.age_noise_floor)
+  }
+
+  // Sets the measurement noise of `n` observations in the cameras `cams` to
+  // `kf_noise_visual` times `noise_scale`, and tests the observations against
+  // the outlier gate if it is in use.
+  fn measurement_noise(&self, kalman_filter: &KalmanFilter, n: usize, cams: &[usize], noise_scale: f64, tmp: &mut TrackTmp) {
+    let noise = self.kf_noise_visual * noise_scale;
+    measurement_noise(n, noise, self.camera_noise_scales, cams, &mut tmp.r);
+    tmp.passes_gate = match self.outlier_gate(tmp.y.nrows()) {
+      Some(outlier_gate) => kalman_filter.mahalanobis_gate(&tmp.H, &tmp.y, &tmp.r, outlier_gate),
+      None => true,
+    };
+  }
+
+  // The outlier gate in use for a measurement of `dof` dimensions, None if
+  // disabled.
+  fn outlier_gate(&self, dof: usize) -> Option<f64> {
+    let outlier_gate = if self.process_count <= self.outlier_gate_warmup {
+      self.warmup_outlier_gate
+    } else if self.outlier_confidence > 0. {
+      chi_squared_quantile(self.outlier_confidence, dof) / dof as f64
+    } else {
+      self.outlier_gate
+    };
+    if self.outlier_rejection && outlier_gate > 0. { Some(outlier_gate) } else { None }
+  }
+
+  // Applies the outlier gate to the measurement and stacks it for the update
+  // if it passes.
+  fn add_inlier(&mut self, tmp: &TrackTmp) -> bool {
+    if !tmp.passes_gate {
+      self.outlier_count += 1;
+      return false;
+    }
+
+    let rows = self.tmp.inlier_y.nrows();
+    let m = tmp.y.nrows();
+    self.tmp.inlier_H.resize_vertically_mut(rows + m, 0.);
+    self.tmp.inlier_H.rows_mut(rows, m).copy_from(&tmp.H);
+    self.tmp.inlier_y.resize_vertically_mut(rows + m, 0.);
+    self.tmp.inlier_y.rows_mut(rows, m).copy_from(&tmp.y);
+    self.tmp.inlier_r.resize_vertically_mut(rows + m, 0.);
+    self.tmp.inlier_r.rows_mut(rows, m).copy_from(&tmp.r);
+    true
+  }
+}
+
+// Root-mean-square of the norms of the 2D residuals stacked in `y`.
+fn reprojection_rms(y: &Vectord) -> Option<f64> {
+  if y.is_empty() { return None }
+  Some((y.norm_squared() / (y.len() / 2) as f64).sqrt())
+}
+
+// Standard deviations of the measurement rows of `n` observations in the
+// cameras `cams`, laid out like in `VisualUpdate::process()`: for each pose
+// the x and y coordinates in the first camera, then in the second.
+fn measurement_noise(n: usize, noise: f64, camera_scales: [f64; 2], cams: &[usize], r: &mut Vectord) {
+  r.resize_vertically_mut(2 * cams.len() * n, 0.);
+  for i in 0..n {
+    for (jj, &j) in cams.iter().enumerate() {
+      let row = 2 * (cams.len() * i + jj);
+      r[row] = camera_scales[j] * noise;
+      r[row + 1] = camera_scales[j] * noise;
+    }
+  }
+}
+
+// Derivative `D` of the triangulated position of a measured track in world
+// coordinates with respect to the filter state. The camera poses of both
+// cameras move with the IMU pose, see `VisualUpdate::track_measurement()`.
+fn triangulation_jacobian(kalman_filter: &KalmanFilter, cams: &[usize], tmp: &TrackTmp, D: &mut Matrixd) {
+  D.resize_mut(3, kalman_filter.get_state_len(), 0.);
+  D.fill(0.);
+  for k in 0..tmp.kalman_filter_poses.len() {
+    let col_pos = kalman_filter.get_camera_pos_ind(tmp.indices[k]);
+    let col_ori = kalman_filter.get_camera_ori_ind(tmp.indices[k]);
+    for &l in cams {
+      let da_dp = &tmp.triangulate_output.da_dp[2 * k + l];
+      let da_dq = &tmp.triangulate_output.da_dq[2 * k + l];
+      let dp_dq = &tmp.kalman_filter_poses[k][l].dp_dq;
+      let mut pos = D.fixed_slice_mut::<3, 3>(0, col_pos);
+      pos += da_dp;
+      let mut ori = D.fixed_slice_mut::<3, 4>(0, col_ori);
+      ori += da_dp * dp_dq + da_dq;
+    }
+  }
+}
+
+// The triangulated landmark of a measured track in the camera of its newest
+// observation, see `DebugLandmark`. The position covariance is that of the
+// triangulation from the observations with the noise `r`, plus that of the
+// poses propagated through the triangulation and the transformation to the
+// camera. The latter cancels the uncertainty that the landmark and the camera
+// share. None if the observations do not constrain the position.
+fn landmark_uncertainty(
+  kalman_filter: &KalmanFilter,
+  cameras: &[&Camera],
+  cams: &[usize],
+  tmp: &mut TrackTmp,
+) -> Option<DebugLandmark> {
+  let aw = tmp.position?;
+  let n = tmp.kalman_filter_poses.len();
+  let mut information = Matrix3d::zeros();
+  for i in 0..n {
+    for (jj, &j) in cams.iter().enumerate() {
+      let row = 2 * (cams.len() * i + jj);
+      let pose = &tmp.kalman_filter_poses[i][j];
+      let ac = pose.R * (aw - pose.p);
+      let d_normalized_ac = Matrix23d::new(
+        1. / ac[2], 0., -ac[0] / ac[2].powi(2),
+        0., 1. / ac[2], -ac[1] / ac[2].powi(2),
+      );
+      let J = d_normalized_ac * pose.R;
+      information += J.transpose() * J / tmp.r[row].powi(2);
+    }
+  }
+
+  let camera = cams[0];
+  let pose = &tmp.kalman_filter_poses[n - 1][camera];
+  let ac = pose.R * (aw - pose.p);
+  let mut d_position = mem::replace(&mut tmp.d_position, Matrixd::zeros(0, 0));
+  triangulation_jacobian(kalman_filter, cams, tmp, &mut d_position);
+  let mut D = Matrixd::from_iterator(3, d_position.ncols(), (pose.R * &d_position).iter().cloned());
+  tmp.d_position = d_position;
+  let col_pos = kalman_filter.get_camera_pos_ind(tmp.indices[n - 1]);
+  let col_ori = kalman_filter.get_camera_ori_ind(tmp.indices[n - 1]);
+  let mut pos = D.fixed_slice_mut::<3, 3>(0, col_pos);
+  pos -= pose.R;
+  for m in 0..4 {
+    let mut ori = D.fixed_slice_mut::<3, 1>(0, col_ori + m);
+    ori += pose.dR_dq[m] * (aw - pose.p) - pose.R * pose.dp_dq.column(m);
+  }
+  let pose_covariance = kalman_filter.propagate_covariance(&D);
+  let covariance = pose.R * information.try_inverse()? * pose.R.transpose()
+    + pose_covariance.fixed_slice::<3, 3>(0, 0);
+
+  let (pixel, d_pixel) = cameras[camera].model.ray_to_pixel_d(ac, true);
+  Some(DebugLandmark {
+    camera,
+    covariance,
+    pixel: pixel?,
+    d_pixel: d_pixel?,
+  })
+}
+
+// Multiplier of the measurement noise of a track observed for `age` frames.
+// Decreases from 1 with the age as 1 / (1 + scale * age) down to `floor`.
+fn age_noise_scale(age: usize, scale: f64, floor: f64) -> f64 {
+  if scale <= 0. { return 1. }
+  (1. / (1. + scale * age as f64)).max(floor)
+}
+
+// Chooses `max_views` of the poses of a track, given in observation order, to
+// triangulate it from. Views far apart see the landmark from different
+// directions, which is what the triangulation needs, while nearby views add
+// little. Starting from the newest view, the one whose ray in `camera` is
+// farthest from the chosen rays in world coordinates is added until there
+// are enough. Sets `keep[i]` for the chosen poses.
+fn select_views(
+  normalized_coordinates: &[[Vector2d; 2]],
+  kalman_filter_poses: &[Vec<KalmanFilterPose>],
+  camera: usize,
+  max_views: usize,
+  keep: &mut Vec<bool>,
+) {
+  let n = normalized_coordinates.len();
+  keep.clear();
+  keep.resize(n, false);
+  if n == 0 { return }
+  let rays: Vec<Vector3d> = (0..n).map(|i| {
+    let ip = normalized_coordinates[i][camera];
+    (kalman_filter_poses[i][camera].R.transpose() * Vector3d::new(ip[0], ip[1], 1.)).normalize()
+  }).collect();
+  // Distance of each ray to the nearest chosen one.
+  let mut distances = vec![f64::INFINITY; n];
+  let mut next = n - 1;
+  for _ in 0..max_views.min(n) {
+    keep[next] = true;
+    for i in 0..n {
+      distances[i] = distances[i].min((rays[i] - rays[next]).norm());
+    }
+    let mut max_distance = -1.;
+    for i in 0..n {
+      if !keep[i] && distances[i] > max_distance {
+        max_distance = distances[i];
+        next = i;
+      }
+    }
+  }
+}
+
+// Select up to `max_count` tracks so that they cover the image evenly. The
+// tracks are binned into a grid fitted around their latest positions in the
+// first camera and the bins are visited in turns, taking the longest (most
+// informative) remaining track of each bin.
+fn select_tracks(tracks: &[Track], max_count: usize, selected: &mut Vec<usize>) {
+  selected.clear();
+  if tracks.is_empty() || max_count == 0 { return }
+  let last_point = |track: &Track| track.points.iter().last().unwrap().coordinates[0];
+
+  let mut min = last_point(&tracks[0]);
+  let mut max = min;
+  for track in tracks {
+    let p = last_point(track);
+    min = min.inf(&p);
+    max = max.sup(&p);
+  }
+
+  let n = (max_count as f64).sqrt().ceil() as usize;
+  let mut bins: Vec<Vec<usize>> = vec![vec![]; n * n];
+  let size = (max - min).map(|x| x.max(1.));
+  for (i, track) in tracks.iter().enumerate() {
+    let p = last_point(track) - min;
+    let bx = ((n as f64 * p[0] / size[0]) as usize).min(n - 1);
+    let by = ((n as f64 * p[1] / size[1]) as usize).min(n - 1);
+    bins[by * n + bx].push(i);
+  }
+  for bin in bins.iter_mut() {
+    // Reverse order so that the best track can be popped from the end.
+    bin.sort_by_key(|i| (tracks[*i].points.len(), std::cmp::Reverse(tracks[*i].id.0)));
+  }
+
+  while selected.len() < max_count {
+    let mut any = false;
+    for bin in bins.iter_mut() {
+      if let Some(i) = bin.pop() {
+        selected.push(i);
+        any = true;
+        if selected.len() >= max_count { break }
+      }
+    }
+    if !any { break }
   }
 }
 
@@ -184,6 +1096,87 @@ struct TriangulateOutput {
   da_dp: Vec<Matrix3d>,
   // Triangulated position differentiated wrt camera orientations.
   da_dq: Vec<Matrix34d>,
+  // Ratio of the largest and smallest eigenvalue of the normal matrix.
+  condition_number: f64,
+  // Multiplier of the measurement noise for the regularization, 1 without.
+  noise_scale: f64,
+}
+
+impl TriangulateOutput {
+  fn new() -> TriangulateOutput {
+    TriangulateOutput {
+      a: Vector3d::zeros(),
+      da_dp: vec![],
+      da_dq: vec![],
+      condition_number: 1.,
+      noise_scale: 1.,
+    }
+  }
+}
+
+// Moves the camera `pose` of the trail pose `k` by `t` seconds, interpolating
+// towards the newer neighbouring pose of the trail or the older one, and
+// extrapolating from the older one for the newest pose. Without a neighbour
+// only the position is moved, with the current velocity, and the seconds moved
+// are returned for the velocity derivative, otherwise zero. The derivatives wrt
+// the neighbour are ignored, which is accurate enough for the short readout
+// times.
+fn move_pose_in_time(
+  kalman_filter: &KalmanFilter,
+  camera: &Camera,
+  k: usize,
+  t: f64,
+  neighbour_poses: &mut Vec<Vec<KalmanFilterPose>>,
+  pose: &mut KalmanFilterPose,
+) -> f64 {
+  if t == 0. { return 0. }
+  let older = k + 1 < kalman_filter.get_pose_trail_len();
+  let m = if k > 0 && (t > 0. || !older) { k - 1 } else { k + 1 };
+  let times = (kalman_filter.get_pose_time(k), kalman_filter.get_pose_time(m));
+  let neighbour = m < kalman_filter.get_pose_trail_len()
+    && kalman_filter.get_camera_pose_trail(&[m], &[camera], neighbour_poses);
+  match times {
+    (Some(time_k), Some(time_m)) if neighbour && time_m != time_k => {
+      let s = t / (time_m - time_k);
+      let neighbour = &neighbour_poses[0][0];
+      pose.p += s * (neighbour.p - pose.p);
+      // Rotation from the camera of pose `k` to that of the neighbour.
+      let D = nalgebra::Rotation3::from_matrix_unchecked(neighbour.R * pose.R.transpose())
+        .powf(s)
+        .into_inner();
+      pose.R = D * pose.R;
+      for dR_dq in &mut pose.dR_dq {
+        *dR_dq = D * *dR_dq;
+      }
+      0.
+    },
+    _ => {
+      pose.p += t * kalman_filter.get_velocity();
+      t
+    },
+  }
+}
+
+// Derivative at `t` of the polynomial through the points `(times[k], value(k))`.
+// Zero if some of the times are equal.
+fn interpolated_derivative<F: Fn(usize) -> Vector2d>(times: &[f64], value: F, t: f64) -> Vector2d {
+  let mut derivative = Vector2d::zeros();
+  for k in 0..times.len() {
+    // Derivative of the Lagrange basis polynomial of point k.
+    let mut d = 0.;
+    for m in 0..times.len() {
+      if m == k { continue }
+      if times[k] == times[m] { return Vector2d::zeros() }
+      let mut product = 1. / (times[k] - times[m]);
+      for l in 0..times.len() {
+        if l == k || l == m { continue }
+        product *= (t - times[l]) / (times[k] - times[l]);
+      }
+      d += product;
+    }
+    derivative += d * value(k);
+  }
+  derivative
 }
 
 // Algorithm from the book Computer Vision: Algorithms and Applications
@@ -194,49 +1187,69 @@ struct TriangulateOutput {
 // However, it has a particular weakness in that it ignores the fact that the
 // fixed transformation between the stereo cameras is known, and instead treats
 // all the camera rays as equal. Using this triangulation function may degrade
-// quality of the visual updates considerably.
+// quality of the visual updates considerably. See `triangulate_stereo()`.
 //
 // NOTE This function is heavily based on the HybVIO implementation here:
 //   <https://github.com/SpectacularAI/HybVIO/blob/main/src/odometry/triangulation.cpp>
 //   (see `triangulateLinear()`)
 fn triangulate(
   normalized_coordinates: &[[Vector2d; 2]],
-  kalman_filter_poses: &[[KalmanFilterPose; 2]],
+  kalman_filter_poses: &[Vec<KalmanFilterPose>],
+  // Cameras whose rays are used, see `Track::cameras()`.
+  cams: &[usize],
+  // See `triangulation_lambda`.
+  lambda: f64,
   output: &mut TriangulateOutput,
 ) -> Option<()> {
-  // TODO This function has not been tested at all.
+  // Cameras not in `cams` get zero derivatives.
+  let n = normalized_coordinates.len();
   output.a = Vector3d::zeros();
   output.da_dp.clear();
+  output.da_dp.resize(2 * n, Matrix3d::zeros());
   output.da_dq.clear();
+  output.da_dq.resize(2 * n, Matrix34d::zeros());
 
-  // Triangulation function.
+  // Triangulation function. Relative to the mean camera position `c`, so that
+  // the regularization pulls the point towards the cameras:
+  //   a = c + (S + lambda I)^-1 (t - S c),
+  // which is S^-1 t without the regularization.
   assert_eq!(normalized_coordinates.len(), kalman_filter_poses.len());
   let mut S = Matrix3d::zeros();
   let mut t = Vector3d::zeros();
-  for i in 0..normalized_coordinates.len() {
-    for j in 0..2 {
+  let mut c = Vector3d::zeros();
+  for i in 0..n {
+    for &j in cams {
       let pose = &kalman_filter_poses[i][j];
       let ip = &normalized_coordinates[i][j];
       let ip = Vector3d::new(ip[0], ip[1], 1.);
-      let vn = pose.R.transpose() * ip.normalize();
+      let vn = (pose.R.transpose() * ip).normalize();
       let A = Matrix3d::identity() - vn * vn.transpose();
       S += A;
       t += A * pose.p;
+      c += pose.p;
     }
   }
-  let inv_S = S.try_inverse()?;
-  output.a = inv_S * t;
+  let m = (cams.len() * n) as f64;
+  c /= m;
+  let eigenvalues = S.symmetric_eigenvalues();
+  let (min_eigenvalue, max_eigenvalue) = (eigenvalues.min(), eigenvalues.max());
+  if min_eigenvalue <= 0. { return None }
+  output.condition_number = max_eigenvalue / min_eigenvalue;
+  output.noise_scale = 1. + lambda / min_eigenvalue;
+  let inv_S = (S + lambda * Matrix3d::identity()).try_inverse()?;
+  let b = t - S * c;
+  output.a = c + inv_S * b;
 
   // Derivatives of the triangulation function.
-  for i in 0..normalized_coordinates.len() {
-    for j in 0..2 {
+  for i in 0..n {
+    for &j in cams {
       let pose = &kalman_filter_poses[i][j];
       let ip = &normalized_coordinates[i][j];
       let ip = Vector3d::new(ip[0], ip[1], 1.);
       let v = pose.R.transpose() * ip;
       let vn = v.normalize();
       let A = Matrix3d::identity() - vn * vn.transpose();
-      output.da_dp.push(inv_S * A);
+      output.da_dp[2 * i + j] = inv_S * A + (Matrix3d::identity() - inv_S * S) / m;
 
       // Derivative of v wrt q.
       let mut dv_dq = Matrix34d::zeros();
@@ -256,12 +1269,1450 @@ fn triangulate(
         let mut ek = Vector3d::zeros();
         ek[k] = 1.;
         let Q = ek * vn.transpose() + vn * ek.transpose();
-        da_dvn.column_mut(k).copy_from(&(inv_S * Q * inv_S * t - inv_S * Q * pose.p));
+        da_dvn.column_mut(k).copy_from(&(inv_S * Q * inv_S * b - inv_S * Q * (pose.p - c)));
       }
 
-      output.da_dq.push(da_dvn * dvn_dv * dv_dq);
+      output.da_dq[2 * i + j] = da_dvn * dvn_dv * dv_dq;
     }
   }
 
   Some(())
 }
+
+// Triangulation that parametrizes the point by its depth `d` along the ray
+// `u` of the first camera in `cams` on the newest frame, the reference:
+//   a = o + d u,
+// where `o` is the reference camera position and `u = R^T [x, y, 1]`, so that
+// `d` is the depth in the reference camera. The depth minimizes the squared
+// distances of the point to all camera rays, which is linear in `d`:
+//   d = N / D,  N = sum_k u^T A_k (p_k - o),  D = sum_k u^T A_k u,
+// with `A_k = I - v_k v_k^T` for the unit ray `v_k`. The reference ray has
+// `A u = 0` and does not contribute. The poses of the second cameras come
+// from the first ones and the fixed stereo extrinsics of `Camera`, so the
+// rays of each frame are consistent with the known baseline.
+fn triangulate_stereo(
+  normalized_coordinates: &[[Vector2d; 2]],
+  kalman_filter_poses: &[Vec<KalmanFilterPose>],
+  cams: &[usize],
+  output: &mut TriangulateOutput,
+) -> Option<()> {
+  assert_eq!(normalized_coordinates.len(), kalman_filter_poses.len());
+  let n = normalized_coordinates.len();
+  output.a = Vector3d::zeros();
+  output.da_dp.clear();
+  output.da_dp.resize(2 * n, Matrix3d::zeros());
+  output.da_dq.clear();
+  output.da_dq.resize(2 * n, Matrix34d::zeros());
+  output.condition_number = 1.;
+  output.noise_scale = 1.;
+  if n == 0 { return None }
+
+  let ray = |i: usize, j: usize| -> Vector3d {
+    let ip = &normalized_coordinates[i][j];
+    kalman_filter_poses[i][j].R.transpose() * Vector3d::new(ip[0], ip[1], 1.)
+  };
+  let r = cams[0];
+  let reference = &kalman_filter_poses[n - 1][r];
+  let o = reference.p;
+  let u = ray(n - 1, r);
+  // `w = sum_k A_k u` and `b = sum_k A_k (p_k - o)`.
+  let mut w = Vector3d::zeros();
+  let mut b = Vector3d::zeros();
+  for (i, poses) in kalman_filter_poses.iter().enumerate().take(n) {
+    for &j in cams {
+      let vn = ray(i, j).normalize();
+      let A = Matrix3d::identity() - vn * vn.transpose();
+      w += A * u;
+      b += A * (poses[j].p - o);
+    }
+  }
+  let D = u.dot(&w);
+  if D <= 1e-12 * u.norm_squared() { return None }
+  let d = u.dot(&b) / D;
+  output.a = o + d * u;
+  let a = output.a;
+
+  // Derivatives of the triangulation function. Through the ray of each
+  // camera k, with `Q = e_m v^T + v e_m^T` the derivative of `-A_k` wrt the
+  // component m of the unit ray:
+  //   dd/dv_k[m] = -u^T Q (p_k - a) / D.
+  // The reference camera further moves `o` and `u`.
+  for i in 0..n {
+    for &j in cams {
+      let pose = &kalman_filter_poses[i][j];
+      let ip = &normalized_coordinates[i][j];
+      let ip = Vector3d::new(ip[0], ip[1], 1.);
+      let v = pose.R.transpose() * ip;
+      let vn = v.normalize();
+      let A = Matrix3d::identity() - vn * vn.transpose();
+
+      let mut dv_dq = Matrix34d::zeros();
+      for k in 0..4 {
+        dv_dq.column_mut(k).copy_from(&(pose.dR_dq[k].transpose() * ip));
+      }
+      let dvn_dv = A / v.norm();
+      let mut dd_dvn = Vector3d::zeros();
+      for m in 0..3 {
+        let mut em = Vector3d::zeros();
+        em[m] = 1.;
+        let Q = em * vn.transpose() + vn * em.transpose();
+        dd_dvn[m] = -u.dot(&(Q * (pose.p - a))) / D;
+      }
+      let mut da_dp = u * (u.transpose() * A) / D;
+      let mut da_dq = u * (dd_dvn.transpose() * dvn_dv * dv_dq);
+
+      if i == n - 1 && j == r {
+        // d = N / D with `dN/do = -w^T` and `dN/du = b^T`, `dD/du = 2 w^T`.
+        da_dp += Matrix3d::identity() - u * w.transpose() / D;
+        let da_du = d * Matrix3d::identity() + u * (b - 2. * d * w).transpose() / D;
+        da_dq += da_du * dv_dq;
+      }
+      output.da_dp[2 * i + j] = da_dp;
+      output.da_dq[2 * i + j] = da_dq;
+    }
+  }
+
+  Some(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::f64::consts::PI;
+
+  fn make_track(id: usize, point: Vector2d, len: usize) -> Track {
+    let feature = Feature { point, id: TrackId(id) };
+    let mut track = Track::new([feature, feature], [point, point], TrackerStep(0), 0);
+    for _ in 1..len {
+      track.points.push(track.points[0].clone());
+    }
+    track
+  }
+
+  #[test]
+  fn test_select_tracks_coverage() {
+    let mut tracks = vec![];
+    // A dense cluster of long tracks in the top-left corner.
+    for i in 0..100 {
+      let p = Vector2d::new((i % 10) as f64, (i / 10) as f64);
+      tracks.push(make_track(i, p, 10));
+    }
+    // Sparse short tracks around the rest of the image.
+    for i in 0..9 {
+      let p = Vector2d::new(100. + 200. * (i % 3) as f64, 100. + 200. * (i / 3) as f64);
+      tracks.push(make_track(100 + i, p, 2));
+    }
+
+    let mut selected = vec![];
+    select_tracks(&tracks, 9, &mut selected);
+    assert_eq!(selected.len(), 9);
+    // Every 3x3 region of the image is covered.
+    let mut covered = [false; 9];
+    for i in &selected {
+      let p = tracks[*i].points[0].coordinates[0];
+      let cx = ((p[0] / 700. * 3.) as usize).min(2);
+      let cy = ((p[1] / 700. * 3.) as usize).min(2);
+      covered[cy * 3 + cx] = true;
+    }
+    assert!(covered.iter().all(|x| *x));
+
+    // Cap larger than the number of tracks selects all of them.
+    select_tracks(&tracks, 1000, &mut selected);
+    assert_eq!(selected.len(), tracks.len());
+  }
+
+  #[test]
+  fn test_anchor_constrains_pose() {
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_pos = 1.;
+      p.anchor_match_max_dist = 50.;
+    });
+    let cameras = test_cameras(160, 120);
+    let landmarks = [
+      Vector3d::new(0.5, 0.3, 2.),
+      Vector3d::new(-0.4, -0.2, 3.),
+      Vector3d::new(0.1, 0.4, 2.5),
+    ];
+    // The device is actually displaced from the origin where the filter starts.
+    let mut true_imu_to_world = Matrix4d::identity();
+    true_imu_to_world[(0, 3)] = 0.2;
+    let tracks: Vec<Track> = landmarks.iter().enumerate().map(|(i, landmark)| {
+      let landmark = transform_3d(&affine_inverse(true_imu_to_world), landmark);
+      stereo_track(&cameras, i, observe(&cameras, &landmark), 1)
+    }).collect();
+    let pose_trail_frame_numbers = VecDeque::from(vec![1]);
+
+    let run = |anchors: &Anchors| {
+      let mut kalman_filter = KalmanFilter::new();
+      let gravity = PARAMETER_SET.lock().unwrap().gravity;
+      kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+      let mut visual_update = VisualUpdate::new();
+      for _ in 0..10 {
+        visual_update.process(
+          &mut kalman_filter,
+          &tracks,
+          &[&cameras[0], &cameras[1]],
+          &pose_trail_frame_numbers,
+          anchors,
+          120,
+        );
+      }
+      let mut imu_to_worlds = vec![];
+      kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+      Vector3d::from(position!(imu_to_worlds[0]))
+    };
+
+    // Triangulated landmarks move along with the pose: nothing is learned
+    // about the global position.
+    let position = run(&Anchors::new());
+    assert!(position.norm() < 1e-6);
+
+    let mut anchors = Anchors::new();
+    for landmark in landmarks {
+      anchors.add(landmark);
+    }
+    anchors.match_tracks(&tracks, &cameras[0], &Matrix4d::identity(), 1);
+    let position = run(&anchors);
+    assert!((position - position!(true_imu_to_world)).norm() < 0.02);
+  }
+
+  fn outlier_landmark(i: usize) -> Vector3d {
+    Vector3d::new(-0.5 + 0.2 * i as f64, 0.1 * (i % 3) as f64, 2. + 0.3 * i as f64)
+  }
+
+  // Tracks of six landmarks seen from the origin on frame 1. The second
+  // camera observations of the first `outlier_count` tracks are shifted
+  // vertically, which no landmark position explains.
+  fn outlier_tracks(cameras: &[Camera], outlier_count: usize) -> Vec<Track> {
+    (0..6).map(|i| {
+      let mut normalized = observe(cameras, &outlier_landmark(i));
+      if i < outlier_count {
+        normalized[1][1] += 0.5;
+      }
+      stereo_track(cameras, i, normalized, 1)
+    }).collect()
+  }
+
+  #[test]
+  fn test_min_inliers() {
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_visual = 1e-2;
+      p.visual_update_outlier_gate = 10.;
+      p.visual_update_min_inliers = 3;
+    });
+    let cameras = test_cameras(160, 120);
+    let pose_trail_frame_numbers = VecDeque::from(vec![1]);
+
+    let update_count = |tracks: &[Track]| {
+      let mut kalman_filter = KalmanFilter::new();
+      let gravity = PARAMETER_SET.lock().unwrap().gravity;
+      kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+      let mut visual_update = VisualUpdate::new();
+      visual_update.process(
+        &mut kalman_filter,
+        tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      kalman_filter.get_update_count()
+    };
+
+    // Only one track passes the gate: the frame is IMU-only.
+    assert_eq!(update_count(&outlier_tracks(&cameras, 5)), 0);
+    assert_eq!(update_count(&outlier_tracks(&cameras, 2)), 1);
+    // Without the gate the outliers are used.
+    PARAMETER_SET.lock().unwrap().visual_update_outlier_gate = 0.;
+    assert_eq!(update_count(&outlier_tracks(&cameras, 5)), 1);
+    // The chi-squared bound for the degrees of freedom of each track.
+    PARAMETER_SET.lock().unwrap().visual_update_outlier_confidence = 0.95;
+    assert_eq!(update_count(&outlier_tracks(&cameras, 5)), 0);
+    assert_eq!(update_count(&outlier_tracks(&cameras, 2)), 1);
+  }
+
+  #[test]
+  fn test_disable_stages() {
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_visual = 1e-2;
+      p.visual_update_outlier_gate = 10.;
+      p.pose_trail_len = 3;
+    });
+    let cameras = test_cameras(160, 120);
+    // Three poses moving sideways. The second camera observations of the
+    // first two tracks are shifted vertically, as in `outlier_tracks()`.
+    let new_filter = || sideways_filter(3, -0.1);
+    let kalman_filter = new_filter();
+    let pose_trail_frame_numbers = VecDeque::from(vec![1, 2, 3]);
+    let tracks: Vec<Track> = (0..5).map(|i| {
+      pose_trail_track(&kalman_filter, &cameras, i, &outlier_landmark(i), 3, |_, normalized| {
+        if i < 2 {
+          normalized[1][1] += 0.5;
+        }
+      })
+    }).collect();
+
+    // Ids and measurement rows of the tracks used in the update.
+    let inliers = || {
+      let mut kalman_filter = new_filter();
+      let mut visual_update = VisualUpdate::new();
+      visual_update.process(
+        &mut kalman_filter,
+        &tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      visual_update.tmp.inlier_tracks.iter().map(|(id, rows)| (id.0, *rows)).collect::<Vec<_>>()
+    };
+
+    assert_eq!(inliers(), vec![(2, 12), (3, 12), (4, 12)]);
+    PARAMETER_SET.lock().unwrap().disable_outlier_rejection = true;
+    assert_eq!(inliers(), (0..5).map(|i| (i, 12)).collect::<Vec<_>>());
+    // Only the first camera is used, where the outliers are consistent.
+    PARAMETER_SET.lock().unwrap().disable_outlier_rejection = false;
+    PARAMETER_SET.lock().unwrap().disable_stereo = true;
+    assert_eq!(inliers(), (0..5).map(|i| (i, 6)).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn test_divergence_reset() {
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_visual = 1e-2;
+      p.visual_update_outlier_gate = 10.;
+      p.divergence_max_rejected_updates = 3;
+    });
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let cameras = test_cameras(160, 120);
+    let pose_trail_frame_numbers = VecDeque::from(vec![1]);
+
+    let mut kalman_filter = KalmanFilter::new();
+    let mut visual_update = VisualUpdate::new();
+    let mut time = 0.;
+    let mut process = |kalman_filter: &mut KalmanFilter, tracks: &[Track]| {
+      kalman_filter.predict(time, Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+      time += 0.1;
+      visual_update.process(
+        kalman_filter,
+        tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      kalman_filter.check_divergence()
+    };
+
+    let good_tracks = outlier_tracks(&cameras, 0);
+    assert_eq!(process(&mut kalman_filter, &good_tracks), None);
+    // A burst of bad measurements. A good frame in between restarts the count.
+    let bad_tracks = outlier_tracks(&cameras, 6);
+    assert_eq!(process(&mut kalman_filter, &bad_tracks), None);
+    assert_eq!(process(&mut kalman_filter, &good_tracks), None);
+    assert_eq!(process(&mut kalman_filter, &bad_tracks), None);
+    assert_eq!(process(&mut kalman_filter, &bad_tracks), None);
+    assert_eq!(process(&mut kalman_filter, &bad_tracks), Some(Divergence::RejectedUpdates(3)));
+    let update_count = kalman_filter.get_update_count();
+    kalman_filter.soft_reset(Vector3d::new(0., 0., gravity));
+    assert_eq!(kalman_filter.check_divergence(), None);
+
+    // After the reset the good tracks are used again.
+    assert_eq!(process(&mut kalman_filter, &good_tracks), None);
+    assert_eq!(kalman_filter.get_update_count(), update_count + 1);
+    let mut imu_to_worlds = vec![];
+    kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+    assert!(position!(imu_to_worlds[0]).norm() < 0.01, "{}", imu_to_worlds[0]);
+  }
+
+  #[test]
+  fn test_outlier_gate_warmup() {
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_visual = 1e-2;
+      p.visual_update_outlier_gate = 10.;
+      p.visual_update_outlier_gate_warmup = 2;
+      p.visual_update_warmup_outlier_gate = 0.;
+    });
+    let cameras = test_cameras(160, 120);
+    let tracks = outlier_tracks(&cameras, 2);
+    let pose_trail_frame_numbers = VecDeque::from(vec![1]);
+
+    let mut visual_update = VisualUpdate::new();
+    let mut outlier_counts = vec![];
+    for _ in 0..4 {
+      let mut kalman_filter = KalmanFilter::new();
+      let gravity = PARAMETER_SET.lock().unwrap().gravity;
+      kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+      visual_update.process(
+        &mut kalman_filter,
+        &tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      outlier_counts.push(visual_update.get_outlier_count());
+    }
+    // The gate is disabled during the warmup.
+    assert_eq!(outlier_counts, vec![0, 0, 2, 2]);
+  }
+
+  #[test]
+  fn test_track_behind_cameras() {
+    let _guard = set_test_parameters(|p| p.kf_noise_visual = 1e-2);
+    let cameras = test_cameras(160, 120);
+    // The second camera observations of the first track are shifted the wrong
+    // way, so that it triangulates behind the cameras.
+    let tracks: Vec<Track> = (0..4).map(|i| {
+      let landmark = Vector3d::new(-0.3 + 0.2 * i as f64, 0.1 * (i % 3) as f64, 2. + 0.3 * i as f64);
+      let mut normalized = observe(&cameras, &landmark);
+      if i == 0 {
+        normalized[1][0] = normalized[0][0] + 0.05;
+      }
+      stereo_track(&cameras, i, normalized, 1)
+    }).collect();
+    let pose_trail_frame_numbers = VecDeque::from(vec![1]);
+
+    let mut kalman_filter = KalmanFilter::new();
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+    let mut visual_update = VisualUpdate::new();
+    visual_update.process(
+      &mut kalman_filter,
+      &tracks,
+      &[&cameras[0], &cameras[1]],
+      &pose_trail_frame_numbers,
+      &Anchors::new(),
+      120,
+    );
+    // The track is skipped without rows in the stacked update, which is
+    // applied from the other tracks.
+    let ids: Vec<usize> = visual_update.tmp.inlier_tracks.iter().map(|(id, _)| id.0).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert_eq!(visual_update.tmp.inlier_H.shape(), (12, kalman_filter.get_state_len()));
+    assert_eq!(visual_update.tmp.inlier_y.len(), 12);
+    assert_eq!(visual_update.get_outlier_count(), 0);
+    assert_eq!(kalman_filter.get_update_count(), 1);
+  }
+
+  #[test]
+  fn test_min_depth() {
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_visual = 1e-2;
+      p.visual_update_min_depth = 0.1;
+      p.pose_trail_len = 3;
+    });
+    let cameras = test_cameras(160, 120);
+    // Three poses moving sideways. The first landmark is four centimeters in
+    // front of the cameras and its observations have a small error.
+    let new_filter = || sideways_filter(3, -0.01);
+    let kalman_filter = new_filter();
+    let pose_trail_frame_numbers = VecDeque::from(vec![1, 2, 3]);
+    let tracks: Vec<Track> = (0..4).map(|i| {
+      let landmark = if i == 0 {
+        Vector3d::new(0.05, 0.01, 0.04)
+      } else {
+        Vector3d::new(-0.3 + 0.2 * i as f64, 0.1 * (i % 3) as f64, 2. + 0.3 * i as f64)
+      };
+      pose_trail_track(&kalman_filter, &cameras, i, &landmark, 3, |frame_number, normalized| {
+        if i == 0 && frame_number == 2 {
+          normalized[1][1] += 0.01;
+        }
+      })
+    }).collect();
+
+    let update = || {
+      let mut kalman_filter = new_filter();
+      let x0 = kalman_filter.get_state_mut().clone();
+      let mut visual_update = VisualUpdate::new();
+      visual_update.process(
+        &mut kalman_filter,
+        &tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      let ids: Vec<usize> = visual_update.tmp.inlier_tracks.iter().map(|(id, _)| id.0).collect();
+      let change = (kalman_filter.get_state_mut().clone() - x0).amax();
+      (ids, visual_update.tmp.inlier_H.amax(), change)
+    };
+
+    // The near track is skipped and the others agree with the state.
+    let (ids, max_H, change) = update();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert!(change.is_finite());
+    assert!(change < 1e-6);
+
+    // Without the guard its Jacobian dominates the update.
+    PARAMETER_SET.lock().unwrap().visual_update_min_depth = 0.;
+    let (ids, max_H_near, change_near) = update();
+    assert_eq!(ids, vec![0, 1, 2, 3]);
+    assert!(max_H_near > 10. * max_H);
+    assert!(change_near > 1e-4);
+  }
+
+  // A filter whose `pose_trail_len` poses move sideways, and `count` tracks
+  // observed in all of them, with small errors.
+  fn sideways_tracks(count: usize, cameras: &[Camera]) -> (KalmanFilter, Vec<Track>, VecDeque<usize>) {
+    let len = PARAMETER_SET.lock().unwrap().pose_trail_len;
+    let kalman_filter = sideways_filter(len, -0.05);
+    let tracks = (0..count).map(|i| {
+      let landmark = Vector3d::new(-1. + 2. * (i % 10) as f64 / 9., -0.5 + 0.1 * (i / 10 % 10) as f64, 2. + 0.1 * i as f64);
+      pose_trail_track(&kalman_filter, cameras, i, &landmark, len, |frame_number, normalized| {
+        for (j, normalized) in normalized.iter_mut().enumerate() {
+          *normalized += 1e-3 * Vector2d::new((i + frame_number + j) as f64, (i * frame_number) as f64).map(|x| x.sin());
+        }
+      })
+    }).collect();
+    (kalman_filter, tracks, (1..=len).collect())
+  }
+
+  #[test]
+  fn test_thread_count() {
+    let cameras = test_cameras(160, 120);
+    let update = |threads: usize| {
+      let _guard = set_test_parameters(|p| {
+        p.pose_trail_len = 4;
+        p.visual_update_threads = threads;
+      });
+      let (mut kalman_filter, tracks, pose_trail_frame_numbers) = sideways_tracks(20, &cameras);
+      let mut visual_update = VisualUpdate::new();
+      visual_update.process(
+        &mut kalman_filter,
+        &tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      let ids: Vec<usize> = visual_update.tmp.inlier_tracks.iter().map(|(id, _)| id.0).collect();
+      (ids, visual_update.tmp.inlier_H.clone(), kalman_filter.get_state_mut().clone())
+    };
+
+    let (ids, H, x) = update(1);
+    assert_eq!(ids.len(), 5);
+    for threads in [0, 2, 3] {
+      assert_eq!(update(threads), (ids.clone(), H.clone(), x.clone()));
+    }
+  }
+
+  // Times the visual update stacking all of `count` tracks, and only the
+  // measurement of the tracks that the threads share, by skipping the Kalman
+  // filter update. The outlier gate is computed for every track but rejects
+  // none. Run with
+  //   cargo test --release bench_visual_update -- --ignored --nocapture
+  #[test]
+  #[ignore]
+  fn bench_visual_update() {
+    let cameras = test_cameras(640, 480);
+    let repeats = 20;
+    println!("{:>8} {:>8} {:>12} {:>12}", "tracks", "threads", "ms/update", "ms/measure");
+    for count in [5, 10, 20, 40] {
+      for threads in [1, 2, 4, 8] {
+        let time = |min_inliers: usize| {
+          let _guard = set_test_parameters(|p| {
+            p.pose_trail_len = 10;
+            p.visual_update_max_tracks = count;
+            p.visual_update_max_inlier_tracks = count;
+            p.visual_update_min_inliers = min_inliers;
+            p.visual_update_outlier_gate = 1e6;
+            p.visual_update_threads = threads;
+          });
+          let mut visual_update = VisualUpdate::new();
+          let mut elapsed = std::time::Duration::ZERO;
+          for _ in 0..repeats {
+            let (mut kalman_filter, tracks, pose_trail_frame_numbers) = sideways_tracks(count, &cameras);
+            let start = std::time::Instant::now();
+            visual_update.process(
+              &mut kalman_filter,
+              &tracks,
+              &[&cameras[0], &cameras[1]],
+              &pose_trail_frame_numbers,
+              &Anchors::new(),
+              480,
+            );
+            elapsed += start.elapsed();
+          }
+          assert_eq!(visual_update.tmp.inlier_tracks.len(), count);
+          1e3 * elapsed.as_secs_f64() / repeats as f64
+        };
+        let update = time(1);
+        let measure = time(usize::MAX);
+        println!("{:>8} {:>8} {:>12.3} {:>12.3}", count, threads, update, measure);
+      }
+    }
+  }
+
+  #[test]
+  fn test_track_order() {
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_visual = 1e-2;
+      p.visual_update_max_tracks = 4;
+    });
+    let cameras = test_cameras(160, 120);
+    let tracks: Vec<Track> = (0..8).map(|i| {
+      let landmark = Vector3d::new(-0.5 + 0.15 * i as f64, 0.1 * (i % 3) as f64 - 0.1, 2. + 0.2 * i as f64);
+      stereo_track(&cameras, 10 + 7 * i, observe(&cameras, &landmark), 1)
+    }).collect();
+    let pose_trail_frame_numbers = VecDeque::from(vec![1]);
+
+    let stacked = |tracks: &[Track]| {
+      let mut kalman_filter = KalmanFilter::new();
+      let gravity = PARAMETER_SET.lock().unwrap().gravity;
+      kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+      let mut visual_update = VisualUpdate::new();
+      visual_update.process(
+        &mut kalman_filter,
+        tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      (visual_update.tmp.inlier_H.clone(), visual_update.tmp.inlier_y.clone())
+    };
+
+    let mut shuffled = tracks.clone();
+    shuffled.reverse();
+    shuffled.swap(1, 5);
+    for binning in [false, true] {
+      PARAMETER_SET.lock().unwrap().visual_update_binning = binning;
+      let (H, y) = stacked(&tracks);
+      assert!(H.nrows() > 0);
+      assert_eq!(H.nrows(), y.len());
+      let (H_shuffled, y_shuffled) = stacked(&shuffled);
+      assert_eq!(H, H_shuffled);
+      assert_eq!(y, y_shuffled);
+    }
+  }
+
+  #[test]
+  fn test_reprojection_rms() {
+    assert_eq!(reprojection_rms(&Vectord::zeros(0)), None);
+    // Residual norms 5, 0, 1 and 1.
+    let y = Vectord::from_vec(vec![3., 4., 0., 0., 1., 0., 0., -1.]);
+    let rms = reprojection_rms(&y).unwrap();
+    assert!((rms - (27f64 / 4.).sqrt()).abs() < 1e-12);
+  }
+
+  #[test]
+  fn test_measurement_noise() {
+    let mut r = Vectord::zeros(0);
+    measurement_noise(3, 0.1, [1., 4.], &[0, 1], &mut r);
+    assert_eq!(r.len(), 12);
+    for (row, x) in r.iter().enumerate() {
+      // Rows follow `let row = 2 * (2 * i + j)` of the Jacobian.
+      let camera = (row / 2) % 2;
+      assert_eq!(*x, if camera == 0 { 0.1 } else { 0.4 });
+    }
+    // A monocular track of the second camera has only its rows.
+    measurement_noise(3, 0.1, [1., 4.], &[1], &mut r);
+    assert_eq!(r.len(), 6);
+    assert!(r.iter().all(|x| *x == 0.4));
+
+    // The observations of the noisier camera weigh less in the update. Here
+    // the x-coordinate rows of both cameras measure the position x-coordinate
+    // and disagree about it.
+    let _guard = set_test_parameters(|p| p.kf_noise_pos = 1.);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let mut kalman_filter = KalmanFilter::new();
+    kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+    let col = kalman_filter.get_camera_pos_ind(0);
+    let mut H = Matrixd::zeros(4, kalman_filter.get_state_len());
+    H[(0, col)] = 1.;
+    H[(2, col)] = 1.;
+    let y = Vectord::from_vec(vec![1., 0., -1., 0.]);
+    measurement_noise(1, 0.1, [1., 10.], &[0, 1], &mut r);
+    kalman_filter.update_visual(&H, &y, &r);
+    let mut imu_to_worlds = vec![];
+    kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+    assert!(imu_to_worlds[0][(0, 3)] > 0.9);
+  }
+
+  #[test]
+  fn test_jacobian_sparsity() {
+    let _guard = set_test_parameters(|p| p.pose_trail_len = 4);
+    let cameras = test_cameras(160, 120);
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let mut kalman_filter = KalmanFilter::new();
+    kalman_filter.predict(0., Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+    kalman_filter.augment_pose();
+    kalman_filter.augment_pose();
+    // Static landmarks seen on frames 1 to 3 and 2 to 3.
+    let mut tracks = vec![];
+    for (id, first_frame) in [(3, 1), (5, 2), (8, 2)] {
+      let landmark = Vector3d::new(0.1 * id as f64 - 0.5, 0.2, 2. + 0.1 * id as f64);
+      for frame_number in first_frame..=3 {
+        extend_tracks(&mut tracks, stereo_track(&cameras, id, observe(&cameras, &landmark), frame_number));
+      }
+    }
+    let mut visual_update = VisualUpdate::new();
+    visual_update.process(
+      &mut kalman_filter,
+      &tracks,
+      &[&cameras[0], &cameras[1]],
+      &VecDeque::from(vec![1, 2, 3]),
+      &Anchors::new(),
+      120,
+    );
+
+    // The newest pose comes first in the state and the fourth is unused.
+    let expected = [
+      format!("{:>8} vvvgggaaatpppqqqqPPPQQQQpppqqqqPPPQQQQ", ""),
+      format!("{:>8} ..........#####################.......", 3),
+      format!("{:>8} ..........##############..............", 5),
+      format!("{:>8} ..........##############..............", 8),
+    ];
+    assert_eq!(visual_update.jacobian_sparsity(), expected.join("\n") + "\n");
+  }
+
+  // A static device looking at static landmarks, with an accelerometer bias
+  // the filter has to estimate. `on_update` is called after each visual
+  // update. Returns the RMS position error over the second half of the
+  // updates and the state size before and after losing all the tracks.
+  fn static_landmarks<F: FnMut(&KalmanFilter)>(mut on_update: F) -> (f64, usize, usize) {
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let cameras = test_cameras(160, 120);
+    let landmarks: Vec<Vector3d> = (0..20)
+      .map(|i| Vector3d::new(-1. + 0.1 * i as f64, 0.5 * ((i % 5) as f64 - 2.) / 2., 3. + 0.1 * i as f64))
+      .collect();
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let accelerometer = Vector3d::new(0.05, -0.03, gravity);
+
+    let mut kalman_filter = KalmanFilter::new();
+    let mut visual_update = VisualUpdate::new();
+    let mut pose_trail_frame_numbers = VecDeque::from(vec![0]);
+    let mut tracks: Vec<Track> = vec![];
+    let mut time = 0.;
+    let mut errors2 = vec![];
+    for frame_number in 1..100 {
+      for _ in 0..10 {
+        kalman_filter.predict(time, Vector3d::zeros(), accelerometer);
+        time += 0.01;
+      }
+      start_pose_trail_frame(&mut pose_trail_frame_numbers, frame_number);
+      for (i, landmark) in landmarks.iter().enumerate() {
+        let mut noise = || Vector2d::new(rng.gen_range(-5e-3..5e-3), rng.gen_range(-5e-3..5e-3));
+        let normalized = observe(&cameras, landmark).map(|x| x + noise());
+        extend_tracks(&mut tracks, stereo_track(&cameras, i, normalized, frame_number));
+      }
+      visual_update.process(
+        &mut kalman_filter,
+        &tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      on_update(&kalman_filter);
+      let mut imu_to_worlds = vec![];
+      kalman_filter.get_imu_to_worlds(&[0], &mut imu_to_worlds);
+      if frame_number >= 50 {
+        errors2.push(Vector3d::from(position!(imu_to_worlds[0])).norm_squared());
+      }
+      augment_pose_trail(&mut kalman_filter, &mut pose_trail_frame_numbers);
+    }
+    let error = (errors2.iter().sum::<f64>() / errors2.len() as f64).sqrt();
+    let state_len = kalman_filter.get_state_len();
+    visual_update.process(
+      &mut kalman_filter,
+      &[],
+      &[&cameras[0], &cameras[1]],
+      &pose_trail_frame_numbers,
+      &Anchors::new(),
+      120,
+    );
+    (error, state_len, kalman_filter.get_state_len())
+  }
+
+  #[test]
+  fn test_landmark_modes() {
+    let run = |landmark_mode: LandmarkMode| -> (f64, usize, usize) {
+      let _guard = set_test_parameters(|p| {
+        p.landmark_mode = landmark_mode;
+        p.pose_trail_len = 4;
+        p.kf_noise_visual = 1e-2;
+        p.slam_min_track_len = 3;
+        p.slam_max_landmarks = 10;
+      });
+      static_landmarks(|_| {})
+    };
+
+    let (error_msckf, state_len_msckf, _) = run(LandmarkMode::Msckf);
+    let (error_slam, state_len_slam, state_len_lost) = run(LandmarkMode::Slam);
+    // The landmarks persist beyond the short pose trail.
+    assert!(error_slam < 0.5 * error_msckf, "{} {}", error_slam, error_msckf);
+    assert_eq!(state_len_slam, state_len_msckf + 3 * 10);
+    // Landmarks of lost tracks are marginalized.
+    assert_eq!(state_len_lost, state_len_msckf);
+  }
+
+  #[test]
+  fn test_slam_max_landmark_age() {
+    let _guard = set_test_parameters(|p| {
+      p.landmark_mode = LandmarkMode::Slam;
+      p.pose_trail_len = 4;
+      p.kf_noise_visual = 1e-2;
+      p.slam_min_track_len = 3;
+      p.slam_max_landmarks = 10;
+      p.slam_max_landmark_age = 5;
+    });
+    let mut landmark_ids: Vec<Vec<TrackId>> = vec![];
+    let mut max_state_len = 0;
+    let (error, state_len, _) = static_landmarks(|kalman_filter| {
+      landmark_ids.push(kalman_filter.get_landmark_ids().to_vec());
+      max_state_len = max_state_len.max(kalman_filter.get_state_len());
+    });
+    assert!(error < 0.05);
+    // All the tracks continue to the end, but each is a landmark for five
+    // consecutive updates at most and not added again after being marginalized.
+    let mut added = 0;
+    for id in 0..20 {
+      let frames: Vec<usize> = (0..landmark_ids.len()).filter(|i| landmark_ids[*i].contains(&TrackId(id))).collect();
+      if frames.is_empty() { continue }
+      assert_eq!(frames.len(), 5);
+      assert_eq!(frames[4] - frames[0], 4);
+      added += 1;
+    }
+    assert!(added >= 5);
+    assert!(landmark_ids.last().unwrap().is_empty());
+    assert!(max_state_len <= state_len + 3 * 10);
+  }
+
+  // A device swinging back and forth about its z-axis, along which the
+  // cameras look, so that the landmarks move on arcs in the images. The row
+  // `row` of the frame with timestamp `time` is exposed at
+  // `exposure_time(time, row)`, and the calibration of both cameras has
+  // `shutter`. Returns the filter and the mean reprojection RMS of the updates.
+  fn swinging_device<F: Fn(f64, f64) -> f64>(shutter: Shutter, exposure_time: F) -> (KalmanFilter, f64) {
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let mut cameras = test_cameras(160, 120);
+    for camera in &mut cameras {
+      camera.shutter = shutter;
+    }
+    let landmarks: Vec<Vector3d> = (0..20)
+      .map(|i| {
+        let angle = 0.7 * i as f64;
+        let r = 0.5 + 0.05 * (i % 5) as f64;
+        Vector3d::new(r * angle.cos(), r * angle.sin(), 2. + 0.05 * i as f64)
+      })
+      .collect();
+    // The rotation angle and its derivative.
+    let angle = |t: f64| 0.5 * (PI * t).sin();
+    let angular_velocity = |t: f64| 0.5 * PI * (PI * t).cos();
+    let project = |t: f64, j: usize, landmark: &Vector3d| {
+      let (s, c) = angle(t).sin_cos();
+      let world_to_imu = Matrix3d::new(
+        c, s, 0.,
+        -s, c, 0.,
+        0., 0., 1.,
+      );
+      hnormalize(transform_3d(&cameras[j].imu_to_camera, &(world_to_imu * landmark))).unwrap()
+    };
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+
+    let mut kalman_filter = KalmanFilter::new();
+    let mut visual_update = VisualUpdate::new();
+    let mut pose_trail_frame_numbers = VecDeque::from(vec![0]);
+    let mut tracks: Vec<Track> = vec![];
+    let mut rms = vec![];
+    let mut time: f64 = 0.;
+    let accelerometer = Vector3d::new(0., 0., gravity);
+    kalman_filter.predict(time, Vector3d::zeros(), accelerometer);
+    for frame_number in 1..60 {
+      for _ in 0..50 {
+        // The midpoint of the sample interval integrates the rotation accurately.
+        let dt = 0.001;
+        time += dt;
+        kalman_filter.predict(time, Vector3d::new(0., 0., angular_velocity(time - 0.5 * dt)), accelerometer);
+      }
+      start_pose_trail_frame(&mut pose_trail_frame_numbers, frame_number);
+      for (i, landmark) in landmarks.iter().enumerate() {
+        let mut noise = || Vector2d::new(rng.gen_range(-1e-4..1e-4), rng.gen_range(-1e-4..1e-4));
+        let normalized = [0, 1].map(|j| {
+          // The exposure time depends on the row the landmark is seen on.
+          let mut normalized = project(time, j, landmark);
+          for _ in 0..3 {
+            let row = cameras[j].project(normalized).unwrap()[1];
+            normalized = project(exposure_time(time, row), j, landmark);
+          }
+          normalized + noise()
+        });
+        extend_tracks(&mut tracks, stereo_track(&cameras, i, normalized, frame_number));
+      }
+      visual_update.process(
+        &mut kalman_filter,
+        &tracks,
+        &[&cameras[0], &cameras[1]],
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+      rms.extend(visual_update.get_reprojection_rms());
+      augment_pose_trail(&mut kalman_filter, &mut pose_trail_frame_numbers);
+    }
+    let mean_rms = rms.iter().sum::<f64>() / rms.len() as f64;
+    (kalman_filter, mean_rms)
+  }
+
+  #[test]
+  fn test_landmark_uncertainty() {
+    let _guard = set_test_parameters(|p| {
+      p.show_landmark_uncertainty = true;
+      p.kf_noise_visual = 1e-4;
+    });
+    swinging_device(Shutter::Global, |time, _| time);
+    let cameras = test_cameras(160, 120);
+    let d = DEBUG_DATA.lock().unwrap();
+    assert!(!d.landmark_uncertainty.is_empty());
+    for landmark in &d.landmark_uncertainty {
+      let ray = cameras[landmark.camera].model.pixel_to_ray(landmark.pixel).unwrap();
+      let C = landmark.covariance;
+      assert!((C - C.transpose()).norm() < 1e-12 * C.norm());
+      // Landmarks at about 2 m seen by a stereo pair are least certain along
+      // the viewing ray.
+      let eigen = C.symmetric_eigen();
+      assert!(eigen.eigenvalues.min() > 0.);
+      let i = eigen.eigenvalues.imax();
+      assert!(eigen.eigenvectors.column(i).dot(&ray.normalize()).abs() > 0.99);
+      let others = eigen.eigenvalues.sum() - eigen.eigenvalues[i];
+      assert!(eigen.eigenvalues[i] > 100. * others);
+      // The viewing ray projects to a point, leaving a small pixel uncertainty.
+      let pixel_covariance = landmark.d_pixel * C * landmark.d_pixel.transpose();
+      assert!(pixel_covariance.trace().sqrt() < 0.1, "{}", pixel_covariance);
+    }
+  }
+
+  #[test]
+  fn test_time_offset() {
+    // The frames are exposed `true_offset` after their timestamps. At a
+    // constant angular velocity the offset could not be told apart from a
+    // constant error in the orientation, hence the swinging. Returns the
+    // estimated offset.
+    let run = |true_offset: f64, time_offset_std: f64| -> f64 {
+      let _guard = set_test_parameters(|p| {
+        p.pose_trail_len = 4;
+        p.kf_noise_visual = 1e-3;
+        p.time_offset_std = time_offset_std;
+      });
+      let (kalman_filter, _) = swinging_device(Shutter::Global, |time, _| time + true_offset);
+      kalman_filter.get_time_offset()
+    };
+
+    // Without estimation the offset stays at the initial guess.
+    assert_eq!(run(0.02, 0.), 0.);
+    let offset = run(0.02, 0.05);
+    assert!((offset - 0.02).abs() < 1e-3, "{}", offset);
+    let offset = run(0., 0.05);
+    assert!(offset.abs() < 1e-3, "{}", offset);
+  }
+
+  #[test]
+  fn test_pose_covariance() {
+    let _guard = set_test_parameters(|p| {
+      p.pose_trail_len = 4;
+      p.kf_noise_visual = 1e-3;
+    });
+    let (kalman_filter, _) = swinging_device(Shutter::Global, |time, _| time);
+    // The same duration of a static device predicted from the IMU alone,
+    // which observes neither the position nor the yaw.
+    let gravity = PARAMETER_SET.lock().unwrap().gravity;
+    let mut imu_only = KalmanFilter::new();
+    let mut time = 0.;
+    imu_only.predict(time, Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+    for _ in 0..(59 * 50) {
+      time += 0.001;
+      imu_only.predict(time, Vector3d::zeros(), Vector3d::new(0., 0., gravity));
+    }
+    let C = kalman_filter.current_pose_covariance();
+    assert!((C - C.transpose()).norm() < 1e-12 * C.norm());
+    assert!(C.diagonal().iter().all(|&x| x > 0.));
+    // The tracks tie the current pose to the earlier poses of the trail,
+    // undoing most of the drift of the IMU-only prediction.
+    let imu_only_trace = imu_only.current_pose_covariance().trace();
+    assert!(C.trace() < 1e-2 * imu_only_trace, "{} {}", C.trace(), imu_only_trace);
+  }
+
+  #[test]
+  fn test_rolling_shutter() {
+    // The 120 rows are exposed during 30 ms centered at the frame timestamp.
+    let line_readout_time = 2.5e-4;
+    let run = |shutter: Shutter, line_readout: f64| -> f64 {
+      let _guard = set_test_parameters(|p| {
+        p.pose_trail_len = 4;
+        p.kf_noise_visual = 1e-3;
+        p.line_readout_time = line_readout;
+      });
+      let (_, rms) = swinging_device(shutter, |time, row| time + line_readout_time * (row - 60.));
+      rms
+    };
+    let rms_global = run(Shutter::Global, 0.);
+    let rms_rolling = run(Shutter::Global, line_readout_time);
+    assert!(rms_rolling < 0.5 * rms_global, "{} {}", rms_rolling, rms_global);
+    // The same compensation from the rolling shutter of the calibration.
+    let rms_calibration = run(Shutter::Rolling { readout_time: 120. * line_readout_time }, 0.);
+    assert!((rms_calibration - rms_rolling).abs() < 0.05 * rms_rolling, "{} {}", rms_calibration, rms_rolling);
+  }
+
+  // Checks `H` of a track observed over the three pose trail poses against the
+  // numerical derivatives of `y`, for both triangulation methods. Returns `H`
+  // of each method.
+  fn check_jacobian(kalman_filter: &mut KalmanFilter) -> Vec<Matrixd> {
+    let cameras = test_cameras(160, 120);
+    let landmark = Vector3d::new(0.3, -0.2, 3.);
+    // A trail of three distinct poses, the newest first.
+    for k in 0..3 {
+      let (col_pos, col_ori) = (kalman_filter.get_camera_pos_ind(k), kalman_filter.get_camera_ori_ind(k));
+      let x = kalman_filter.get_state_mut();
+      let c = 0.1 * k as f64;
+      x.fixed_rows_mut::<3>(col_pos).copy_from(&Vector3d::new(c, -0.5 * c, 0.2 * c));
+      x.fixed_rows_mut::<4>(col_ori).copy_from(&Vector4d::new(1., 0.2 * c, -c, 0.05).normalize());
+    }
+    // Observed on frames 1 to 3 with noise, so that the residuals are not zero.
+    let pose_trail_frame_numbers = VecDeque::from(vec![1, 2, 3]);
+    let track = pose_trail_track(kalman_filter, &cameras, 0, &landmark, 3, |frame_number, normalized| {
+      for (j, normalized) in normalized.iter_mut().enumerate() {
+        *normalized += 1e-3 * Vector2d::new((frame_number + j) as f64, -(frame_number as f64));
+      }
+    });
+    let tracks = vec![track];
+
+    let mut Hs = vec![];
+    for method in [TriangulationMethod::Linear, TriangulationMethod::Stereo] {
+      PARAMETER_SET.lock().unwrap().triangulation_method = method;
+      let mut visual_update = VisualUpdate::new();
+      let mut compute = |kalman_filter: &mut KalmanFilter| {
+        visual_update.process(
+          kalman_filter,
+          &tracks,
+          &[&cameras[0], &cameras[1]],
+          &pose_trail_frame_numbers,
+          &Anchors::new(),
+          120,
+        );
+        (visual_update.tmp.inlier_H.clone(), visual_update.tmp.inlier_y.clone())
+      };
+      let (H, y) = compute(kalman_filter);
+      assert_eq!(H.nrows(), 12);
+      assert!(y.norm() > 1e-4);
+
+      // Since y = z - h(x), the numerical derivatives of `y` are `-H`.
+      let h = 1e-6;
+      for col in 0..kalman_filter.get_state_len() {
+        let mut differentiate = |delta: f64| {
+          kalman_filter.get_state_mut()[col] += delta;
+          let (_, y) = compute(kalman_filter);
+          kalman_filter.get_state_mut()[col] -= delta;
+          y
+        };
+        let dy = (differentiate(h) - differentiate(-h)) / (2. * h);
+        for row in 0..H.nrows() {
+          assert!((dy[row] + H[(row, col)]).abs() < 1e-5, "row {} col {}: {} vs {}", row, col, -dy[row], H[(row, col)]);
+        }
+      }
+      Hs.push(H);
+    }
+    Hs
+  }
+
+  #[test]
+  fn test_jacobian() {
+    let _guard = set_test_parameters(|p| {
+      p.pose_trail_len = 3;
+      // Only compute `H` and `y` without updating the filter.
+      p.visual_update_min_inliers = 100;
+    });
+    check_jacobian(&mut KalmanFilter::new());
+  }
+
+  #[test]
+  fn test_time_offset_jacobian() {
+    let _guard = set_test_parameters(|p| {
+      p.pose_trail_len = 3;
+      p.visual_update_min_inliers = 100;
+      p.time_offset = 0.01;
+      p.time_offset_std = 0.01;
+    });
+    let mut kalman_filter = KalmanFilter::new();
+    // Poses at 0.1 s intervals, for the feature velocities of the time offset.
+    for i in 0..3 {
+      if i > 0 { kalman_filter.augment_pose() }
+      kalman_filter.predict(0.1 * i as f64, Vector3d::zeros(), Vector3d::new(0., 0., 9.81));
+    }
+    for H in check_jacobian(&mut kalman_filter) {
+      assert!(H.column(kalman_filter.get_time_offset_ind()).norm() > 1e-2);
+    }
+  }
+
+  #[test]
+  fn test_rolling_shutter_jacobian() {
+    let _guard = set_test_parameters(|p| {
+      p.pose_trail_len = 3;
+      p.visual_update_min_inliers = 100;
+      p.line_readout_time = 1e-3;
+    });
+    // Without pose times the poses are moved along the velocity.
+    let mut kalman_filter = KalmanFilter::new();
+    let col_vel = kalman_filter.get_velocity_ind();
+    kalman_filter.get_state_mut().fixed_rows_mut::<3>(col_vel).copy_from(&Vector3d::new(0.5, -0.3, 0.2));
+    for H in check_jacobian(&mut kalman_filter) {
+      assert!(H.fixed_columns::<3>(col_vel).norm() > 1e-3);
+    }
+  }
+
+  #[test]
+  fn test_monocular_tracks() {
+    let _guard = set_test_parameters(|p| {
+      p.pose_trail_len = 3;
+      p.visual_update_min_inliers = 100;
+    });
+    let cameras = test_cameras(160, 120);
+    let landmark = Vector3d::new(0.3, -0.2, 3.);
+    let mut kalman_filter = sideways_filter(3, 0.2);
+    let pose_trail_frame_numbers = VecDeque::from(vec![1, 2, 3]);
+    for camera in [None, Some(0), Some(1)] {
+      // Exact observations in the camera of the track and nonsense in the other.
+      let mut track = pose_trail_track(&kalman_filter, &cameras, 0, &landmark, 3, |_, normalized| {
+        if let Some(c) = camera {
+          normalized[1 - c] = Vector2d::new(0.5, -0.5);
+        }
+      });
+      track.camera = camera;
+
+      for method in [TriangulationMethod::Linear, TriangulationMethod::Stereo] {
+        PARAMETER_SET.lock().unwrap().triangulation_method = method;
+        let mut visual_update = VisualUpdate::new();
+        visual_update.process(
+          &mut kalman_filter,
+          std::slice::from_ref(&track),
+          &[&cameras[0], &cameras[1]],
+          &pose_trail_frame_numbers,
+          &Anchors::new(),
+          120,
+        );
+        let y = &visual_update.tmp.inlier_y;
+        // The other camera is neither triangulated nor updated from.
+        assert_eq!(y.len(), 2 * track.cameras().len() * 3);
+        assert!(y.norm() < 1e-9);
+      }
+    }
+  }
+
+  #[test]
+  fn test_stereo_triangulation() {
+    let cameras = test_cameras(160, 120);
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    // Squared position errors of the linear and stereo triangulations of
+    // random landmarks with observation noise `noise_std` in both cameras.
+    // The tracks are short with little motion, so that the depth comes mostly
+    // from the stereo baseline.
+    let mut run = |noise_std: f64| -> [f64; 2] {
+      let mut errors = [0., 0.];
+      for _ in 0..200 {
+        let landmark = Vector3d::new(rng.gen_range(-1.0..1.0), rng.gen_range(-0.5..0.5), rng.gen_range(2.0..6.0));
+        let mut poses = vec![];
+        let mut observations = vec![];
+        for i in 0..3 {
+          let mut imu_to_world = Matrix4d::identity();
+          imu_to_world[(0, 3)] = 0.02 * i as f64;
+          let pose = [0, 1].map(|j| {
+            let camera_to_world = imu_to_world * affine_inverse(cameras[j].imu_to_camera);
+            KalmanFilterPose {
+              p: camera_to_world.fixed_slice::<3, 1>(0, 3).into_owned(),
+              R: camera_to_world.fixed_slice::<3, 3>(0, 0).transpose(),
+              dR_dq: [Matrix3d::zeros(); 4],
+              dp_dq: Matrix34d::zeros(),
+            }
+          });
+          let observation = [0, 1].map(|j| {
+            hnormalize(pose[j].R * (landmark - pose[j].p)).unwrap()
+              + noise_std * Vector2d::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0))
+          });
+          poses.push(pose.to_vec());
+          observations.push(observation);
+        }
+        let mut output = TriangulateOutput::new();
+        for (method, error) in errors.iter_mut().enumerate() {
+          if method == 0 {
+            triangulate(&observations, &poses, &[0, 1], 0., &mut output).unwrap();
+          }
+          else {
+            triangulate_stereo(&observations, &poses, &[0, 1], &mut output).unwrap();
+          }
+          *error += (output.a - landmark).norm_squared();
+        }
+      }
+      errors
+    };
+
+    let errors = run(0.);
+    assert!(errors[0] < 1e-18 && errors[1] < 1e-18);
+    // Trusting the reference ray makes the stereo triangulation less accurate
+    // than the linear one when all the observations are equally noisy, but
+    // not by much.
+    let errors = run(1e-3);
+    assert!(errors[0] < errors[1]);
+    assert!(errors[1] < 3. * errors[0]);
+  }
+
+  #[test]
+  fn test_age_noise_scale() {
+    assert_eq!(age_noise_scale(0, 0.1, 0.5), 1.);
+    assert_eq!(age_noise_scale(20, 0., 0.5), 1.);
+    assert!((age_noise_scale(5, 0.1, 0.5) - 1. / 1.5).abs() < 1e-12);
+    assert_eq!(age_noise_scale(100, 0.1, 0.5), 0.5);
+
+    let _guard = set_test_parameters(|p| {
+      p.kf_noise_visual = 0.1;
+      p.kf_noise_visual_age_scale = 0.1;
+      p.kf_noise_visual_age_floor = 0.5;
+    });
+    let kalman_filter = KalmanFilter::new();
+    let mut visual_update = VisualUpdate::new();
+    visual_update.tmp.inlier_H.resize_mut(0, kalman_filter.get_state_len(), 0.);
+    let mut tmp = TrackTmp::new();
+    tmp.H = Matrixd::zeros(4, kalman_filter.get_state_len());
+    tmp.y = Vectord::zeros(4);
+    // A new and an established track.
+    for age in [0, 10] {
+      visual_update.measurement_noise(&kalman_filter, 1, &[0, 1], visual_update.age_noise_scale(age), &mut tmp);
+      assert!(visual_update.add_inlier(&tmp));
+    }
+    let r = &visual_update.tmp.inlier_r;
+    assert_eq!(r.len(), 8);
+    assert!(r.rows(0, 4).iter().all(|x| *x == 0.1));
+    assert!(r.rows(4, 4).iter().all(|x| (x - 0.05).abs() < 1e-12));
+  }
+
+  #[test]
+  fn test_select_views() {
+    let cameras = test_cameras(160, 120);
+    let landmark = Vector3d::new(0.5, 0.1, 3.);
+    // The device moves sideways, observing the landmark with a small error.
+    let n = 12;
+    let mut poses = vec![];
+    let mut normalized_coordinates = vec![];
+    for i in 0..n {
+      let mut imu_to_world = Matrix4d::identity();
+      imu_to_world[(0, 3)] = 0.1 * i as f64;
+      let pose = |camera: &Camera| {
+        let camera_to_world = imu_to_world * affine_inverse(camera.imu_to_camera);
+        let R = camera_to_world.fixed_slice::<3, 3>(0, 0).transpose();
+        let p = camera_to_world.fixed_slice::<3, 1>(0, 3).into_owned();
+        let noise = 1e-3 * Vector2d::new((i as f64).sin(), (i as f64).cos());
+        let ip = hnormalize(R * (landmark - p)).unwrap() + noise;
+        (KalmanFilterPose { p, R, dR_dq: [Matrix3d::zeros(); 4], dp_dq: Matrix34d::zeros() }, ip)
+      };
+      let (pose0, ip0) = pose(&cameras[0]);
+      let (pose1, ip1) = pose(&cameras[1]);
+      poses.push(vec![pose0, pose1]);
+      normalized_coordinates.push([ip0, ip1]);
+    }
+    let error = |keep: &[bool]| {
+      let mut output = TriangulateOutput::new();
+      let mut k = 0..;
+      let coordinates: Vec<_> = normalized_coordinates.iter().filter(|_| keep[k.next().unwrap()]).cloned().collect();
+      let mut k = 0..;
+      let poses: Vec<_> = poses.iter().filter(|_| keep[k.next().unwrap()]).cloned().collect();
+      triangulate(&coordinates, &poses, &[0, 1], 0., &mut output).unwrap();
+      assert_eq!(output.da_dp.len(), 2 * poses.len());
+      (output.a - landmark).norm()
+    };
+
+    let mut keep = vec![];
+    select_views(&normalized_coordinates, &poses, 0, 4, &mut keep);
+    assert_eq!(keep.iter().filter(|x| **x).count(), 4);
+    // The newest view and the one farthest from it are used.
+    assert!(keep[0] && keep[n - 1]);
+
+    let error_all = error(&vec![true; n]);
+    let error_selected = error(&keep);
+    let newest: Vec<bool> = (0..n).map(|i| i >= n - 4).collect();
+    let error_newest = error(&newest);
+    assert!(error_selected < 2. * error_all);
+    assert!(error_selected < 0.5 * error_newest);
+
+    // Short tracks are kept whole.
+    select_views(&normalized_coordinates[..3], &poses[..3], 0, 4, &mut keep);
+    assert_eq!(keep, vec![true; 3]);
+  }
+
+  #[test]
+  fn test_triangulation_regularization() {
+    let cameras = test_cameras(160, 120);
+    let observe = |landmark: Vector3d, noise: f64| {
+      let mut poses = vec![];
+      let mut normalized_coordinates = vec![];
+      for i in 0..2 {
+        let mut imu_to_world = Matrix4d::identity();
+        imu_to_world[(0, 3)] = 0.1 * i as f64;
+        let pose = |camera: &Camera, j: usize| {
+          let camera_to_world = imu_to_world * affine_inverse(camera.imu_to_camera);
+          let R = camera_to_world.fixed_slice::<3, 3>(0, 0).transpose();
+          let p = camera_to_world.fixed_slice::<3, 1>(0, 3).into_owned();
+          let ip = hnormalize(R * (landmark - p)).unwrap() + noise * Vector2d::new([1., -1.][j], 0.);
+          (KalmanFilterPose { p, R, dR_dq: [Matrix3d::zeros(); 4], dp_dq: Matrix34d::zeros() }, ip)
+        };
+        let (pose0, ip0) = pose(&cameras[0], 0);
+        let (pose1, ip1) = pose(&cameras[1], 1);
+        poses.push(vec![pose0, pose1]);
+        normalized_coordinates.push([ip0, ip1]);
+      }
+      (normalized_coordinates, poses)
+    };
+    let mut output = TriangulateOutput::new();
+
+    // Nearly parallel rays to a far landmark, with noise that makes them diverge.
+    let (coordinates, poses) = observe(Vector3d::new(0., 0., 1e4), 1e-3);
+    triangulate(&coordinates, &poses, &[0, 1], 0., &mut output).unwrap();
+    assert!(output.condition_number > 1e4);
+    assert_eq!(output.noise_scale, 1.);
+    let unregularized = output.a;
+    triangulate(&coordinates, &poses, &[0, 1], 1e-2, &mut output).unwrap();
+    assert!(output.a.norm() < 10.);
+    assert!(output.a.norm() < 1e-2 * unregularized.norm());
+    assert!(output.noise_scale > 1e3);
+    assert!(output.da_dp.iter().all(|d| d.norm() < 1.));
+
+    // Well-conditioned triangulation is barely affected.
+    let landmark = Vector3d::new(0.2, 0.1, 2.);
+    let (coordinates, poses) = observe(landmark, 0.);
+    triangulate(&coordinates, &poses, &[0, 1], 1e-6, &mut output).unwrap();
+    assert!((output.a - landmark).norm() < 1e-3);
+    assert!(output.noise_scale < 1.01);
+  }
+
+  // Normalized estimation errors squared (NEES) of the current pose and of
+  // its rotation about the IMU z axis, which starts vertical and stays close
+  // to it, so that it is roughly the yaw. Averaged over the frames of a
+  // simulated run where a truth filter integrates noiseless IMU samples and
+  // the estimated one gets the samples with biases and noise drawn at the
+  // levels the filter assumes. Each observation is used once, by tracks that
+  // end after `len` frames, so a consistent filter averages about 6 and 1.
+  // The yaw error changes slowly, so each run gives about one sample of its
+  // NEES and the runs of different seeds need to be averaged.
+  fn simulated_nees(use_fej: bool, seed: u64, frames: usize) -> (f64, f64) {
+    let (len, noise_a, noise_g, noise_visual) = (8, 1e-2, 1e-3, 1e-3);
+    let _guard = set_test_parameters(|p| {
+      p.pose_trail_len = len;
+      p.kf_noise_a = noise_a;
+      p.kf_noise_g = noise_g;
+      p.kf_noise_visual = noise_visual;
+      p.use_fej = use_fej;
+    });
+    let (gravity, noise_bga, noise_baa) = {
+      let p = PARAMETER_SET.lock().unwrap();
+      (p.gravity, p.kf_noise_bga, p.kf_noise_baa)
+    };
+    let cameras = test_cameras(160, 120);
+    let cameras = [&cameras[0], &cameras[1]];
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut normal = |std: f64| std * standard_normal(&mut rng);
+    let landmarks: Vec<Vector3d> = (0..40)
+      .map(|i| Vector3d::new(-2. + 0.5 * (i % 9) as f64, -1.5 + 0.75 * (i / 9) as f64, 3. + 0.25 * (i % 7) as f64))
+      .collect();
+    // Accelerations in world coordinates and angular velocities of a device
+    // that starts at rest and sways around the origin.
+    let acceleration = |t: f64| Vector3d::new(0.8, 1.1, 0.6).map(|w| -0.5 * w * w * (w * t).cos());
+    let gyroscope = |t: f64| Vector3d::new(0.1 * (1.1 * t).cos(), 0.1 * (0.9 * t).cos(), 0.3 * (0.7 * t).cos());
+    let biases = [noise_bga, noise_baa].map(|std| Vector3d::from_fn(|_, _| normal(std)));
+
+    let mut truth = KalmanFilter::new();
+    let mut kalman_filter = KalmanFilter::new();
+    let mut visual_update = VisualUpdate::new();
+    let mut pose_trail_frame_numbers = VecDeque::from(vec![0]);
+    let mut tracks: Vec<Option<Track>> = vec![None; landmarks.len()];
+    let mut poses = vec![];
+    let mut true_poses: Vec<Vec<KalmanFilterPose>> = vec![];
+    let mut time = 0.;
+    let mut nees = (0., 0.);
+    for frame_number in 0..frames {
+      for _ in 0..10 {
+        // Specific force in IMU coordinates at the orientation the truth
+        // filter propagates the velocity with.
+        let accelerometer = match true_poses.first() {
+          Some(pose) => pose[0].R * (acceleration(time) + Vector3d::new(0., 0., gravity)),
+          None => Vector3d::new(0., 0., gravity),
+        };
+        truth.predict(time, gyroscope(time), accelerometer);
+        truth.get_camera_pose_trail(&[0], &cameras, &mut true_poses);
+        kalman_filter.predict(
+          time,
+          gyroscope(time) + biases[0] + Vector3d::from_fn(|_, _| normal(noise_g)),
+          accelerometer + biases[1] + Vector3d::from_fn(|_, _| normal(noise_a)),
+        );
+        time += 0.005;
+      }
+      start_pose_trail_frame(&mut pose_trail_frame_numbers, frame_number);
+
+      let mut ended = vec![];
+      for (i, landmark) in landmarks.iter().enumerate() {
+        let mut normalized = [Vector2d::zeros(); 2];
+        let mut features = [Feature { point: Vector2d::zeros(), id: TrackId(i) }; 2];
+        let visible = (0..2).all(|j| {
+          let pose = &true_poses[0][j];
+          let ac = pose.R * (landmark - pose.p);
+          if ac[2] <= 0. { return false }
+          normalized[j] = hnormalize(ac).unwrap() + Vector2d::new(normal(noise_visual), normal(noise_visual));
+          features[j].point = cameras[j].project(normalized[j]).unwrap();
+          (0. ..160.).contains(&features[j].point[0]) && (0. ..120.).contains(&features[j].point[1])
+        });
+        let point = Track::new(features, normalized, TrackerStep(0), frame_number);
+        match (&mut tracks[i], visible) {
+          (_, false) => tracks[i] = None,
+          (Some(track), true) => track.points.push(point.points[0].clone()),
+          (None, true) => tracks[i] = Some(point),
+        }
+        // The tracks of different landmarks end at different frames.
+        if (frame_number + i + 1) % len == 0 {
+          ended.extend(tracks[i].take().filter(|track| track.points.len() == len));
+        }
+      }
+      visual_update.process(
+        &mut kalman_filter,
+        &ended,
+        &cameras,
+        &pose_trail_frame_numbers,
+        &Anchors::new(),
+        120,
+      );
+
+      // The first camera is at the IMU. The orientation error is a rotation
+      // vector in IMU coordinates, see `current_pose_covariance()`.
+      kalman_filter.get_camera_pose_trail(&[0], &cameras, &mut poses);
+      let (pose, true_pose) = (&poses[0][0], &true_poses[0][0]);
+      let A = pose.R * true_pose.R.transpose();
+      let p = pose.p - true_pose.p;
+      let error = Vector6d::new(p[0], p[1], p[2], A[(2, 1)] - A[(1, 2)], A[(0, 2)] - A[(2, 0)], A[(1, 0)] - A[(0, 1)])
+        .component_mul(&Vector6d::new(1., 1., 1., 0.5, 0.5, 0.5));
+      let C = kalman_filter.current_pose_covariance();
+      nees.0 += (error.transpose() * C.try_inverse().unwrap() * error)[0] / frames as f64;
+      nees.1 += error[5].powi(2) / C[(5, 5)] / frames as f64;
+
+      augment_pose_trail(&mut kalman_filter, &mut pose_trail_frame_numbers);
+      truth.augment_pose();
+    }
+    nees
+  }
+
+  #[test]
+  fn test_fej_consistency() {
+    let (runs, frames) = (8, 150);
+    let mean_nees = |use_fej| (0..runs)
+      .map(|seed| simulated_nees(use_fej, seed, frames))
+      .fold((0., 0.), |sum, nees| (sum.0 + nees.0 / runs as f64, sum.1 + nees.1 / runs as f64));
+    let (nees, yaw_nees) = mean_nees(true);
+    assert!(nees > 3. && nees < 9., "{}", nees);
+    // Still somewhat below 1 this early in the runs, about 0.2 to 0.6 over
+    // different sets of seeds.
+    assert!(yaw_nees > 0.1 && yaw_nees < 2., "{}", yaw_nees);
+    // Without FEJ the filter is not overconfident in the yaw, as is often
+    // reported, but the opposite: the yaw error stays far below the yaw
+    // standard deviation, with a NEES of about 0.02. This is the direction of
+    // the inconsistency that `use_fej` fixes here.
+    let (_, standard_yaw_nees) = mean_nees(false);
+    assert!(standard_yaw_nees < 0.3 * yaw_nees, "{} {}", standard_yaw_nees, yaw_nees);
+  }
+}
@@ -1,10 +1,11 @@
 use crate::all::*;
 
+use rand::SeedableRng;
+
 pub struct VisualizeArgs<'a> {
   pub buffer: &'a mut Vec<u32>,
   pub frames: &'a [Frame],
   pub video_w: usize,
-  pub video_h: usize,
   pub buffer_w: usize,
   pub buffer_h: usize,
 }
@@ -47,12 +48,64 @@ fn draw_line(args: &mut VisualizeArgs, mut p0: Vector2i, mut p1: Vector2i, v: u3
   }
 }
 
+// Outline of the `sigmas`-sigma ellipse of a 2D normal distribution with
+// the given covariance. The axes are scaled from the eigenvectors, so a
+// degenerate covariance draws a line or just the center pixel.
+fn draw_ellipse(args: &mut VisualizeArgs, center: Vector2d, covariance: Matrix2d, sigmas: f64, v: u32) {
+  if !center.iter().chain(covariance.iter()).all(|x| x.is_finite()) { return }
+  let eigen = nalgebra::SymmetricEigen::new(covariance);
+  let axes = [0, 1].map(|i| sigmas * eigen.eigenvalues[i].max(0.).sqrt() * eigen.eigenvectors.column(i));
+  // Segments of about two pixels.
+  let perimeter = 2. * std::f64::consts::PI * axes[0].norm().max(axes[1].norm());
+  let segments = (perimeter / 2.).ceil().clamp(8., 1000.) as usize;
+  let point = |i: usize| {
+    let angle = 2. * std::f64::consts::PI * i as f64 / segments as f64;
+    from_f64(center + angle.cos() * axes[0] + angle.sin() * axes[1])
+  };
+  for i in 0..segments {
+    draw_line(args, point(i), point(i + 1), v);
+  }
+}
+
+// Per-channel `alpha * a + (1 - alpha) * b` of two 0xRRGGBB colors.
+fn blend(a: u32, b: u32, alpha: f32) -> u32 {
+  let mut v = 0;
+  for shift in [0, 8, 16] {
+    let ca = ((a >> shift) & 0xff) as f32;
+    let cb = ((b >> shift) & 0xff) as f32;
+    v |= ((alpha * ca + (1. - alpha) * cb).round() as u32).min(255) << shift;
+  }
+  v
+}
+
+// Stable color of a track. The hue comes from Fibonacci hashing of the ID,
+// which spreads consecutive IDs far apart on the color wheel.
+fn track_color(id: usize) -> u32 {
+  let hue = ((id as u64).wrapping_mul(0x9e3779b97f4a7c15) >> 11) as f64 / (1u64 << 53) as f64;
+  // Fully saturated and bright HSV to RGB.
+  let h = 6. * hue;
+  let x = 1. - (h % 2. - 1.).abs();
+  let (r, g, b) = match h as usize {
+    0 => (1., x, 0.),
+    1 => (x, 1., 0.),
+    2 => (0., 1., x),
+    3 => (0., x, 1.),
+    4 => (x, 0., 1.),
+    _ => (1., 0., x),
+  };
+  let c = |v: f64| (255. * v).round() as u32;
+  (c(r) << 16) | (c(g) << 8) | c(b)
+}
+
 fn draw_buffer(
   args: &mut VisualizeArgs,
   image: &Image,
   // Top-left coordinates of drawing target.
   ax: usize,
   ay: usize,
+  // Opacity between 0 and 1. Below 1 the image is blended over what is already
+  // in the buffer, for example to draw it over overlays or ghost another frame.
+  alpha: f32,
 ) {
   let w = image.width;
   let h = image.height;
@@ -61,7 +114,9 @@ fn draw_buffer(
     for x in 0..w {
       if x + ax >= args.buffer_w { continue }
       let gray = image.data[y * w + x] as u32;
-      args.buffer[(y + ay) * args.buffer_w + x + ax] = gray | (gray << 8) | (gray << 16);
+      let v = gray | (gray << 8) | (gray << 16);
+      let target = &mut args.buffer[(y + ay) * args.buffer_w + x + ax];
+      *target = if alpha >= 1. { v } else { blend(v, *target, alpha) };
     }
   }
 }
@@ -91,6 +146,122 @@ fn draw_scaled(
   }
 }
 
+// Draw `text` with its top-left corner at `p`, each font pixel `scale` pixels wide.
+fn draw_text(args: &mut VisualizeArgs, text: &str, p: Vector2i, scale: i32, v: u32) {
+  for (i, c) in text.chars().enumerate() {
+    let rows = glyph(c);
+    let ax = p[0] + (i * (GLYPH_WIDTH + 1)) as i32 * scale;
+    for (y, row) in rows.iter().enumerate() {
+      for x in 0..GLYPH_WIDTH {
+        if row & (1 << (GLYPH_WIDTH - 1 - x)) == 0 { continue }
+        for sy in 0..scale {
+          for sx in 0..scale {
+            let q = Vector2i::new(ax + x as i32 * scale + sx, p[1] + y as i32 * scale + sy);
+            draw_pixel(args, q, v);
+          }
+        }
+      }
+    }
+  }
+}
+
+const HUD_SCALE: i32 = 2;
+const HUD_MARGIN: i32 = 4;
+
+fn hud_lines(stats: &HudStats, divergence_events: &[DivergenceEvent]) -> Vec<String> {
+  let p = stats.position;
+  let mut lines = vec![
+    format!("FRAME {}", stats.frame_number),
+    format!("FPS {:.1}", stats.fps),
+    format!("TIME {:.1} MS", stats.processing_time),
+    format!("TRACKS {}", stats.track_count),
+    format!("DETECTED {}", stats.detected_count),
+    format!("NEW {} RETIRED {}", stats.new_track_count, stats.retired_count),
+    format!("OUTLIERS {}", stats.outlier_count),
+    format!("SPEED {:.2} M/S", stats.speed),
+    format!("POS {:.2} {:.2} {:.2}", p[0], p[1], p[2]),
+    format!("RMS {}", stats.reprojection_rms.map_or("-".to_string(), |rms| format!("{:.4}", rms))),
+    format!("RESETS {}", divergence_events.len()),
+  ];
+  if let Some(event) = divergence_events.last() {
+    lines.push(format!("LAST RESET {}", event.frame_number));
+    lines.push(match event.divergence {
+      Divergence::PositionStd(std) => format!("REASON POS STD {:.2} M", std),
+      Divergence::RejectedUpdates(count) => format!("REASON {} REJECTED", count),
+    });
+  }
+  lines
+}
+
+// Width and height of the HUD box in the top-left corner.
+fn hud_size(lines: &[String]) -> (i32, i32) {
+  let chars = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) as i32;
+  (
+    2 * HUD_MARGIN + chars * (GLYPH_WIDTH as i32 + 1) * HUD_SCALE,
+    2 * HUD_MARGIN + lines.len() as i32 * (GLYPH_HEIGHT as i32 + 1) * HUD_SCALE,
+  )
+}
+
+fn draw_hud(args: &mut VisualizeArgs, stats: &HudStats, divergence_events: &[DivergenceEvent]) {
+  let lines = hud_lines(stats, divergence_events);
+  let (w, h) = hud_size(&lines);
+  // Black background for readability over the image.
+  for y in 0..h {
+    for x in 0..w {
+      draw_pixel(args, Vector2i::new(x, y), 0);
+    }
+  }
+  let white = 0xffffff;
+  for (i, line) in lines.iter().enumerate() {
+    let p = Vector2i::new(HUD_MARGIN, HUD_MARGIN + i as i32 * (GLYPH_HEIGHT as i32 + 1) * HUD_SCALE);
+    draw_text(args, line, p, HUD_SCALE, white);
+  }
+}
+
+// The key and name of each overlay toggled by `SHOW_FLAG_KEYS`, and whether
+// it is on.
+fn overlay_lines(p: &mut ParameterSet) -> Vec<(String, bool)> {
+  SHOW_FLAG_KEYS.iter()
+    .map(|(key, name, flag)| (format!("{} {}", key, name.trim_start_matches("show_").replace('_', " ")), *flag(p)))
+    .collect()
+}
+
+// Draws `overlay_lines()` below the HUD, the overlays that are off dimmed.
+fn draw_overlays(args: &mut VisualizeArgs, lines: &[(String, bool)], top: i32) {
+  let texts: Vec<String> = lines.iter().map(|(text, _)| text.clone()).collect();
+  let (w, h) = hud_size(&texts);
+  for y in top..(top + h) {
+    for x in 0..w {
+      draw_pixel(args, Vector2i::new(x, y), 0);
+    }
+  }
+  for (i, (text, on)) in lines.iter().enumerate() {
+    let p = Vector2i::new(HUD_MARGIN, top + HUD_MARGIN + i as i32 * (GLYPH_HEIGHT as i32 + 1) * HUD_SCALE);
+    draw_text(args, text, p, HUD_SCALE, if *on { 0xffffff } else { 0x606060 });
+  }
+}
+
+// Collect the last `trail_len` positions in the first camera of the tracks
+// that have moved at least once.
+pub fn update_trails(tracks: &[Track], trail_len: usize, trails: &mut Vec<Vec<Vector2d>>) {
+  trails.clear();
+  if trail_len < 2 { return }
+  for track in tracks {
+    let n = track.points.len();
+    if n < 2 || track.camera == Some(1) { continue }
+    trails.push(track.points[n.saturating_sub(trail_len)..].iter().map(|p| p.coordinates[0]).collect());
+  }
+}
+
+fn draw_trails(args: &mut VisualizeArgs, trails: &[Vec<Vector2d>], v: u32) {
+  for trail in trails {
+    for i in 1..trail.len() {
+      draw_line(args, from_f64(trail[i - 1]), from_f64(trail[i]), v);
+    }
+    draw_square(args, from_f64(*trail.last().unwrap()), v, 2);
+  }
+}
+
 pub fn visualize(args: &mut VisualizeArgs) -> Result<()> {
   // Clear buffer.
   for y in 0..args.buffer_h {
@@ -101,15 +272,16 @@ pub fn visualize(args: &mut VisualizeArgs) -> Result<()> {
 
   let frame = args.frames.iter().last().ok_or(anyhow!("Cannot visualize before processing the first frame."))?;
   let im0 = &frame.cameras[0].image;
-  let im1 = &frame.cameras[1].image;
-  draw_buffer(args, &im0, 0, 0);
-  draw_buffer(args, &im1, im0.width, 0);
+  draw_buffer(args, im0, 0, 0, 1.);
+  if let Some(camera1) = frame.cameras.get(1) {
+    draw_buffer(args, &camera1.image, im0.width, 0, 1.);
+  }
 
   let d = DEBUG_DATA.lock().unwrap();
-  let p = PARAMETER_SET.lock().unwrap();
+  let mut p = PARAMETER_SET.lock().unwrap();
   let mut ax = 0;
   for (image, s) in &d.images {
-    draw_scaled(args, &image, *s, ax, im0.height);
+    draw_scaled(args, image, *s, ax, im0.height);
     ax += (s * image.width as f64) as usize;
   }
 
@@ -117,7 +289,7 @@ pub fn visualize(args: &mut VisualizeArgs) -> Result<()> {
     let mut a = [0, 0];
     for (i, level) in frame.cameras[0].pyramid.levels.iter().enumerate() {
       a[i % 2] += level.size(i % 2);
-      draw_buffer(args, &level, a[0], a[1]);
+      draw_buffer(args, level, a[0], a[1], 1.);
     }
   }
 
@@ -148,9 +320,9 @@ pub fn visualize(args: &mut VisualizeArgs) -> Result<()> {
         let color = blue | ((green as u32) << 8) | ((red as u32) << 16);
         let tp1 = &track.points[n].coordinates;
         let tp0 = &track.points[n - 1].coordinates;
-        for k in 0..2 {
+        for &k in track.cameras() {
           if i == 1 {
-            draw_square(args, &from_f64(tp1[k]) + a[k], color, 3);
+            draw_square(args, from_f64(tp1[k]) + a[k], color, 3);
           }
           draw_line(args, from_f64(tp0[k]) + a[k], from_f64(tp1[k]) + a[k], color);
         }
@@ -158,9 +330,37 @@ pub fn visualize(args: &mut VisualizeArgs) -> Result<()> {
     }
   }
 
-  if [p.show_flow0, p.show_flow1, p.show_flow2].iter().map(|x| *x as usize).sum::<usize>() > 1 {
-    warn!("Only one optical flow visualization is supported at a time.");
+  if p.show_flow_rejected {
+    let red = 255 << 16;
+    for (k, p0, p1) in &d.flow_rejected {
+      draw_line(args, from_f64(*p0) + a[*k], from_f64(*p1) + a[*k], red);
+      draw_square(args, from_f64(*p1) + a[*k], red, 3);
+    }
   }
+
+  if p.show_landmark_uncertainty {
+    let cyan = (255 << 8) | 255;
+    for landmark in &d.landmark_uncertainty {
+      let covariance = landmark.d_pixel * landmark.covariance * landmark.d_pixel.transpose();
+      let center = landmark.pixel + Vector2d::new(a[landmark.camera][0] as f64, 0.);
+      draw_ellipse(args, center, covariance, 3., cyan);
+    }
+  }
+
+  if p.show_trails {
+    let yellow = (255 << 16) | (255 << 8);
+    draw_trails(args, &d.trails, yellow);
+  }
+
+  if p.show_ground_truth {
+    let magenta = (255 << 16) | 255;
+    for (reprojections, offset) in d.ground_truth_reprojections.iter().zip(a) {
+      for pixel in reprojections {
+        draw_square(args, from_f64(*pixel) + offset, magenta, 2);
+      }
+    }
+  }
+
   // TODO Use ids to look up previous coordinates.
   /*
   if p.show_flow0 {
@@ -175,29 +375,274 @@ pub fn visualize(args: &mut VisualizeArgs) -> Result<()> {
     for (f0, f1) in d.flow0.iter().zip(d.flow1.iter()) {
       let p0 = f0.point;
       let p1 = f1.point + ax;
-      // Could randomize a color for each track.
-      draw_line(args, from_f64(p0), from_f64(p1), 255 * 255);
-      draw_square(args, from_f64(p0), 255 * 255, 3);
-      draw_square(args, from_f64(p1), 255 * 255, 3);
+      let color = track_color(f0.id.0);
+      draw_line(args, from_f64(p0), from_f64(p1), color);
+      draw_square(args, from_f64(p0), color, 3);
+      draw_square(args, from_f64(p1), color, 3);
       draw_square(args, from_f64(p0 + ax), 255 * 255 * 255, 3);
     }
   }
 
   if p.show_epipolar {
-    let mut rng = thread_rng();
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(p.seed);
     for e in &d.epipolar {
       let color: [u8; 3] = [rng.gen(), rng.gen(), rng.gen()];
       let color = (color[0] as u32) | ((color[1] as u32) << 8) | ((color[2] as u32) << 16);
       draw_square(args, from_f64(e.p0), color, 3);
-      // if let Some(p1_initial) = e.p1_initial {
-      //   draw_square(args, from_f64(p1_initial + ax), white, 3);
-      //   draw_line(args, from_f64(p1_initial + ax),  from_f64(e.p1 + ax), white);
-      // }
+      if let Some(p1_initial) = e.p1_initial {
+        let white = 0xffffff;
+        draw_square(args, from_f64(p1_initial + ax), white, 3);
+        draw_line(args, from_f64(p1_initial + ax),  from_f64(e.p1 + ax), white);
+      }
       draw_square(args, from_f64(e.p1 + ax), color, 3);
       for i in 1..e.curve1.len() {
         draw_line(args, from_f64(e.curve1[i - 1] + ax),  from_f64(e.curve1[i] + ax), color);
       }
     }
   }
+
+  if p.show_hud {
+    draw_hud(args, &d.hud, &d.divergence_events);
+    let lines = overlay_lines(&mut p);
+    draw_overlays(args, &lines, hud_size(&hud_lines(&d.hud, &d.divergence_events)).1);
+  }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_trails() {
+    let track = |id: usize, len: usize| {
+      let feature = Feature { point: Vector2d::new(10., 10.), id: TrackId(id) };
+      let mut track = Track::new([feature, feature], [Vector2d::zeros(); 2], TrackerStep(0), 0);
+      for i in 1..len {
+        let mut point = track.points[0].clone();
+        point.coordinates[0] = Vector2d::new(10. + 5. * i as f64, 10. + 3. * id as f64);
+        track.points.push(point);
+      }
+      track
+    };
+    // Persistent tracks and a newly detected one.
+    let tracks = vec![track(0, 20), track(1, 3), track(2, 1)];
+    let mut trails = vec![];
+    update_trails(&tracks, 5, &mut trails);
+    assert_eq!(trails.iter().map(|t| t.len()).collect::<Vec<_>>(), vec![5, 3]);
+    assert_eq!(trails[0][4], tracks[0].points[19].coordinates[0]);
+    assert_eq!(trails[0][0], tracks[0].points[15].coordinates[0]);
+
+    let (w, h) = (200, 40);
+    let mut buffer = vec![0; w * h];
+    let mut args = VisualizeArgs {
+      buffer: &mut buffer,
+      frames: &[],
+      video_w: w,
+      buffer_w: w,
+      buffer_h: h,
+    };
+    draw_trails(&mut args, &trails[..1], 1);
+    let pixel = |x: usize, y: usize| buffer[y * w + x];
+    // The whole trail of the first track is drawn, but not its older positions.
+    for x in 85..=105 {
+      assert_eq!(pixel(x, 10), 1);
+    }
+    assert_eq!(pixel(80, 10), 0);
+    assert_eq!(pixel(20, 10), 0);
+  }
+
+  #[test]
+  fn test_draw_ellipse() {
+    let (w, h) = (60, 40);
+    let mut buffer = vec![0; w * h];
+    let mut args = VisualizeArgs {
+      buffer: &mut buffer,
+      frames: &[],
+      video_w: w,
+      buffer_w: w,
+      buffer_h: h,
+    };
+    let center = Vector2d::new(30., 20.);
+    // Standard deviations of 5 and 2 pixels along the axes.
+    draw_ellipse(&mut args, center, Matrix2d::new(25., 0., 0., 4.), 3., 1);
+    // Degenerate covariances.
+    draw_ellipse(&mut args, Vector2d::new(5., 5.), Matrix2d::zeros(), 3., 2);
+    draw_ellipse(&mut args, Vector2d::new(10., 5.), Matrix2d::new(f64::NAN, 0., 0., 1.), 3., 3);
+    draw_ellipse(&mut args, Vector2d::new(15., 5.), Matrix2d::new(-1e-12, 0., 0., 0.), 3., 4);
+    let pixel = |x: usize, y: usize| buffer[y * w + x];
+    for (x, y) in [(15, 20), (45, 20), (30, 14), (30, 26)] {
+      assert_eq!(pixel(x, y), 1, "{} {}", x, y);
+    }
+    assert_eq!(pixel(30, 20), 0);
+    assert_eq!(pixel(40, 20), 0);
+    assert_eq!(pixel(5, 5), 2);
+    assert_eq!(pixel(15, 5), 4);
+    assert_eq!(buffer.iter().filter(|v| **v >= 2).count(), 2);
+  }
+
+  #[test]
+  fn test_track_color() {
+    let hue = |v: u32| {
+      let [r, g, b] = [16, 8, 0].map(|shift| ((v >> shift) & 0xff) as f64 / 255.);
+      let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+      // Saturated and bright.
+      assert_eq!((max, min), (1., 0.));
+      let h = if max == r { (g - b) % 6. } else if max == g { b - r + 2. } else { r - g + 4. };
+      (h / 6.).rem_euclid(1.)
+    };
+    for id in 0..100 {
+      assert_eq!(track_color(id), track_color(id));
+      // Adjacent IDs differ by at least a tenth of the color wheel.
+      let d = (hue(track_color(id)) - hue(track_color(id + 1))).abs();
+      assert!(d.min(1. - d) > 0.1, "{}", id);
+    }
+  }
+
+  #[test]
+  fn test_hud() {
+    let stats = HudStats {
+      frame_number: 42,
+      fps: 29.97,
+      processing_time: 12.34,
+      track_count: 123,
+      detected_count: 17,
+      retired_count: 6,
+      new_track_count: 9,
+      outlier_count: 4,
+      speed: 1.5,
+      position: Vector3d::new(1., -0.5, 12.345),
+      reprojection_rms: Some(2e-3),
+    };
+    assert_eq!(hud_lines(&stats, &[]).len(), 11);
+    let events = [DivergenceEvent { frame_number: 30, divergence: Divergence::RejectedUpdates(5) }];
+    let lines = hud_lines(&stats, &events);
+    assert_eq!(lines[0], "FRAME 42");
+    assert_eq!(lines[2], "TIME 12.3 MS");
+    assert_eq!(lines[4], "DETECTED 17");
+    assert_eq!(lines[5], "NEW 9 RETIRED 6");
+    assert_eq!(lines[7], "SPEED 1.50 M/S");
+    assert_eq!(lines[8], "POS 1.00 -0.50 12.35");
+    assert_eq!(lines[9], "RMS 0.0020");
+    assert_eq!(lines[10], "RESETS 1");
+    assert_eq!(lines[11], "LAST RESET 30");
+    assert_eq!(lines[12], "REASON 5 REJECTED");
+    let (hud_w, hud_h) = hud_size(&lines);
+
+    let (w, h) = (300, 200);
+    let background = 0x123456;
+    let mut buffer = vec![background; w * h];
+    let mut args = VisualizeArgs {
+      buffer: &mut buffer,
+      frames: &[],
+      video_w: w,
+      buffer_w: w,
+      buffer_h: h,
+    };
+    draw_hud(&mut args, &stats, &events);
+    let mut text_pixels = 0;
+    for y in 0..h {
+      for x in 0..w {
+        let v = buffer[y * w + x];
+        if (x as i32) < hud_w && (y as i32) < hud_h {
+          assert!(v == 0 || v == 0xffffff);
+          if v == 0xffffff { text_pixels += 1 }
+        }
+        else {
+          assert_eq!(v, background);
+        }
+      }
+    }
+    assert!(text_pixels > 0);
+  }
+
+  #[test]
+  fn test_overlays() {
+    use clap::Parser;
+    let mut p = ParameterSet::parse_from(["violet", "--show-tracks", "--show-flow1"]);
+    let lines = overlay_lines(&mut p);
+    assert_eq!(lines[0], ("1 features".to_string(), false));
+    assert_eq!(lines[3], ("4 tracks".to_string(), true));
+    assert_eq!(lines[6], ("7 flow1".to_string(), true));
+    assert_eq!(lines[9], ("0 flow rejected".to_string(), false));
+    assert!(ParameterSet::try_parse_from(["violet", "--show-flow0", "--show-flow2"]).is_err());
+
+    let (w, h) = (300, 300);
+    let mut buffer = vec![0x123456; w * h];
+    let mut args = VisualizeArgs {
+      buffer: &mut buffer,
+      frames: &[],
+      video_w: w,
+      buffer_w: w,
+      buffer_h: h,
+    };
+    draw_overlays(&mut args, &lines, 50);
+    let count = |v: u32, y: std::ops::Range<usize>| y.flat_map(|y| (0..w).map(move |x| (x, y)))
+      .filter(|(x, y)| buffer[y * w + x] == v)
+      .count();
+    assert_eq!(count(0x123456, 0..50), 50 * w);
+    assert!(count(0xffffff, 50..h) > 0);
+    assert!(count(0x606060, 50..h) > count(0xffffff, 50..h));
+  }
+
+  #[test]
+  fn test_draw_text_clipping() {
+    // Draws into a `w` by `h` buffer.
+    let draw = |w: usize, h: usize, p: Vector2i| {
+      let mut buffer = vec![0; w * h];
+      let mut args = VisualizeArgs {
+        buffer: &mut buffer,
+        frames: &[],
+        video_w: w,
+        buffer_w: w,
+        buffer_h: h,
+      };
+      draw_text(&mut args, "TEXT 0.1", p, 2, 1);
+      buffer
+    };
+    // Text running over every edge is cut off there: the buffer shows the
+    // part of the text drawn into a larger buffer around it, without pixels
+    // wrapping over to other rows.
+    let (w, h) = (10, 8);
+    let margin = 64;
+    for (x, y) in [(-5, -3), (7, 5), (-20, 2), (3, -20), (2, 30)] {
+      let buffer = draw(w, h, Vector2i::new(x, y));
+      let large = draw(w + 2 * margin, h + 2 * margin, Vector2i::new(x + margin as i32, y + margin as i32));
+      for r in 0..h {
+        let row = (r + margin) * (w + 2 * margin) + margin;
+        assert_eq!(buffer[(r * w)..((r + 1) * w)], large[row..(row + w)], "{} {}", x, y);
+      }
+    }
+    assert!(draw(w, h, Vector2i::new(-5, -3)).contains(&1));
+    assert!(!draw(w, h, Vector2i::new(3, -20)).contains(&1));
+  }
+
+  #[test]
+  fn test_draw_buffer_alpha() {
+    let (w, h) = (4, 3);
+    let image = Image { data: vec![200; 4], width: 2, height: 2 };
+    let background = 0x10_40_80;
+    for (alpha, expected) in [
+      (1., 0xc8_c8_c8),
+      (0., background),
+      // 0.25 * 200 + 0.75 * [0x10, 0x40, 0x80] per channel.
+      (0.25, 0x3e_62_92),
+    ] {
+      let mut buffer = vec![background; w * h];
+      let mut args = VisualizeArgs {
+        buffer: &mut buffer,
+        frames: &[],
+        video_w: w,
+        buffer_w: w,
+        buffer_h: h,
+      };
+      draw_buffer(&mut args, &image, 1, 1, alpha);
+      for y in 0..h {
+        for x in 0..w {
+          let inside = (1..3).contains(&x) && (1..3).contains(&y);
+          assert_eq!(buffer[y * w + x], if inside { expected } else { background });
+        }
+      }
+    }
+  }
+}
@@ -22,7 +22,7 @@ pub fn run_visualize_3d(rx: mpsc::Receiver<()>) {
   while !window.should_close() {
     render(&mut window, &mut state);
     std::thread::sleep(std::time::Duration::from_millis(1));
-    if let Ok(_) = rx.try_recv() { break }
+    if rx.try_recv().is_ok() { break }
   }
   window.close();
 }
@@ -37,13 +37,10 @@ struct State {
   head_position_trail: Vec<Vector3d>,
 }
 
-fn render(mut window: &mut Window, state: &mut State) {
+fn render(window: &mut Window, state: &mut State) {
   for event in window.events().iter() {
-    match event.value {
-      WindowEvent::Key(key, Action::Release, _) => {
-        if key == Key::Q { window.close() }
-      }
-      _ => {}
+    if let WindowEvent::Key(key, Action::Release, _) = event.value {
+      if key == Key::Q { window.close() }
     }
   }
 
@@ -67,7 +64,7 @@ fn render(mut window: &mut Window, state: &mut State) {
     }
   }
 
-  if let Some(head_pose) = state.pose_trail.get(0) {
+  if let Some(head_pose) = state.pose_trail.first() {
     let pos = position!(head_pose);
     if state.head_position_trail.is_empty()
       || (state.head_position_trail.iter().last().unwrap() - pos).norm_squared() > 1e-4
@@ -86,7 +83,7 @@ fn render(mut window: &mut Window, state: &mut State) {
   }
 
   for T in &state.pose_trail {
-    draw_camera(&mut window, T, &state.camera_lines);
+    draw_camera(window, T, &state.camera_lines);
   }
 
   for p in &state.triangulation_positions {
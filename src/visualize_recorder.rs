@@ -0,0 +1,117 @@
+// Writes the visualization buffer of each frame as a numbered PNG, for making
+// demo videos, eg with
+//   ffmpeg -framerate 30 -pattern_type glob -i '<folder>/*.png' demo.mp4
+// The PNG encoding runs in a separate thread so that it does not slow down the
+// processing.
+
+use crate::all::*;
+
+use std::sync::mpsc::SyncSender;
+use std::thread::JoinHandle;
+
+// Frames waiting to be written. When the writer falls behind, recording blocks.
+const QUEUE_LEN: usize = 16;
+
+struct RecordedFrame {
+  frame_number: usize,
+  width: usize,
+  height: usize,
+  rgb: Vec<u8>,
+}
+
+pub struct VisualizeRecorder {
+  tx: Option<SyncSender<RecordedFrame>>,
+  handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl VisualizeRecorder {
+  pub fn new(folder: &Path) -> Result<VisualizeRecorder> {
+    std::fs::create_dir_all(folder)
+      .context(format!("Failed to create recording folder {}.", folder.display()))?;
+    let (tx, rx) = mpsc::sync_channel::<RecordedFrame>(QUEUE_LEN);
+    let folder = folder.to_path_buf();
+    let handle = std::thread::spawn(move || -> Result<()> {
+      for frame in rx {
+        let path = folder.join(format!("{:06}.png", frame.frame_number));
+        write_png(&path, &frame)?;
+      }
+      Ok(())
+    });
+    Ok(VisualizeRecorder { tx: Some(tx), handle: Some(handle) })
+  }
+
+  // Queues the `0x00RRGGBB` pixels of `buffer` to be written.
+  pub fn record(&mut self, buffer: &[u32], width: usize, height: usize, frame_number: usize) -> Result<()> {
+    assert_eq!(buffer.len(), width * height);
+    let mut rgb = Vec::with_capacity(3 * buffer.len());
+    for v in buffer {
+      rgb.extend_from_slice(&[(v >> 16) as u8, (v >> 8) as u8, *v as u8]);
+    }
+    let frame = RecordedFrame { frame_number, width, height, rgb };
+    let sent = self.tx.as_ref().is_some_and(|tx| tx.send(frame).is_ok());
+    // The writer thread stops only on an error, return it.
+    if !sent { self.finish()? }
+    Ok(())
+  }
+
+  // Writes the queued frames and stops the writer thread.
+  pub fn finish(&mut self) -> Result<()> {
+    self.tx = None;
+    match self.handle.take() {
+      Some(handle) => handle.join().map_err(|_| anyhow!("Recording thread panicked."))?,
+      None => Ok(()),
+    }
+  }
+}
+
+impl Drop for VisualizeRecorder {
+  fn drop(&mut self) {
+    if let Err(err) = self.finish() {
+      warn!("Failed to record the visualization: {}", err);
+    }
+  }
+}
+
+fn write_png(path: &Path, frame: &RecordedFrame) -> Result<()> {
+  let file = File::create(path)
+    .context(format!("Failed to create image {}.", path.display()))?;
+  let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), frame.width as u32, frame.height as u32);
+  encoder.set_color(png::ColorType::RGB);
+  encoder.set_depth(png::BitDepth::Eight);
+  encoder.write_header()
+    .and_then(|mut writer| writer.write_image_data(&frame.rgb))
+    .context(format!("Failed to encode image {}.", path.display()))?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_visualize_recorder() {
+    let folder = std::env::temp_dir().join(format!("violet-record-{}", std::process::id()));
+    let (width, height) = (4, 3);
+    let mut recorder = VisualizeRecorder::new(&folder).unwrap();
+    for frame_number in [1, 3] {
+      let buffer: Vec<u32> = (0..(width * height) as u32).map(|i| 0x123456 + frame_number * i).collect();
+      recorder.record(&buffer, width, height, frame_number as usize).unwrap();
+    }
+    recorder.finish().unwrap();
+
+    let mut names: Vec<String> = std::fs::read_dir(&folder).unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect();
+    names.sort();
+    assert_eq!(names, ["000001.png", "000003.png"]);
+    let file = File::open(folder.join("000003.png")).unwrap();
+    let (info, mut reader) = png::Decoder::new(file).read_info().unwrap();
+    assert_eq!((info.width, info.height, info.color_type), (4, 3, png::ColorType::RGB));
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).unwrap();
+    std::fs::remove_dir_all(&folder).unwrap();
+    // The pixel at (1, 2) is `0x123456 + 3 * 9`.
+    let i = 3 * (2 * width + 1);
+    assert_eq!(&data[i..(i + 3)], &[0x12, 0x34, 0x71]);
+  }
+}